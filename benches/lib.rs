@@ -8,4 +8,5 @@ extern crate ipnetwork;
 extern crate pnet;
 extern crate rips;
 
+mod routing;
 mod udp;
@@ -0,0 +1,102 @@
+use ipnetwork::Ipv4Network;
+use pnet::util::MacAddr;
+
+use rips::{Flow, Interface, RoutingPolicy, RoutingTable};
+
+use std::net::Ipv4Addr;
+use test::{Bencher, black_box};
+
+/// Simple deterministic pseudo random generator so every run benchmarks
+/// against the same tables.
+struct Lcg(u32);
+
+impl Lcg {
+    fn next(&mut self) -> u32 {
+        self.0 = self.0.wrapping_mul(1664525).wrapping_add(1013904223);
+        self.0
+    }
+}
+
+fn table_with_routes(count: usize) -> RoutingTable {
+    let mut table = RoutingTable::new();
+    add_routes(&mut table, count);
+    table
+}
+
+fn add_routes(table: &mut RoutingTable, count: usize) {
+    let interfaces: Vec<Interface> = (0..4)
+        .map(|i| Interface::new(format!("eth{}", i), MacAddr::new(0, 0, 0, 0, 0, i)))
+        .collect();
+    let mut rng = Lcg(count as u32);
+    table.add_route(Ipv4Network::from_cidr("0.0.0.0/0").unwrap(),
+                    Some(Ipv4Addr::new(10, 0, 0, 1)),
+                    interfaces[0].clone());
+    for i in 0..count {
+        let prefix = 8 + (rng.next() % 25) as u8;
+        let ip = Ipv4Addr::from(rng.next());
+        let net = Ipv4Network::new(ip, prefix).unwrap();
+        table.add_route(net, None, interfaces[i % interfaces.len()].clone());
+    }
+}
+
+fn addresses(count: usize) -> Vec<Ipv4Addr> {
+    let mut rng = Lcg(0xdeadbeef);
+    (0..count).map(|_| Ipv4Addr::from(rng.next())).collect()
+}
+
+fn bench_lookups(b: &mut Bencher, routes: usize) {
+    let table = table_with_routes(routes);
+    let ips = addresses(1024);
+    b.iter(|| {
+        for ip in &ips {
+            black_box(table.route(*ip));
+        }
+    });
+}
+
+#[bench]
+fn lookup_1k_routes(b: &mut Bencher) {
+    bench_lookups(b, 1_000);
+}
+
+#[bench]
+fn lookup_10k_routes(b: &mut Bencher) {
+    bench_lookups(b, 10_000);
+}
+
+#[bench]
+fn lookup_100k_routes(b: &mut Bencher) {
+    bench_lookups(b, 100_000);
+}
+
+#[bench]
+fn insert_10k_routes(b: &mut Bencher) {
+    b.iter(|| black_box(table_with_routes(10_000)));
+}
+
+#[bench]
+fn policy_lookup_10k_routes(b: &mut Bencher) {
+    let mut policy = RoutingPolicy::new();
+    add_routes(policy.main_table(), 10_000);
+    let ips = addresses(1024);
+    let flow = Flow::default();
+    b.iter(|| {
+        for ip in &ips {
+            black_box(policy.route(*ip, &flow));
+        }
+    });
+}
+
+#[bench]
+fn shared_lookup_10k_routes(b: &mut Bencher) {
+    let mut policy = RoutingPolicy::new();
+    add_routes(policy.main_table(), 10_000);
+    let shared = policy.shared();
+    let ips = addresses(1024);
+    let flow = Flow::default();
+    b.iter(|| {
+        for ip in &ips {
+            black_box(shared.route(*ip, &flow));
+        }
+    });
+}
@@ -4,7 +4,7 @@ use {RxError, RxResult, VersionedTx};
 use ethernet::EthernetListener;
 
 use pnet::packet::Packet;
-use pnet::packet::arp::ArpPacket;
+use pnet::packet::arp::{ArpHardwareTypes, ArpPacket};
 use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
 
 use std::sync::{Arc, Mutex};
@@ -28,25 +28,46 @@ impl ArpRx {
             vtx: vtx,
         }
     }
+
+    /// Returns the Arp packet in `pkg` if it maps IPv4 addresses to MAC
+    /// addresses, the only kind this stack uses.
+    fn get_arp_pkg<'a>(pkg: &'a EthernetPacket) -> Result<ArpPacket<'a>, RxError> {
+        let arp_pkg = match ArpPacket::new(pkg.payload()) {
+            Some(arp_pkg) => arp_pkg,
+            None => return Err(RxError::InvalidLength),
+        };
+        if arp_pkg.get_hardware_type() != ArpHardwareTypes::Ethernet ||
+           arp_pkg.get_protocol_type() != EtherTypes::Ipv4 ||
+           arp_pkg.get_hw_addr_len() != 6 || arp_pkg.get_proto_addr_len() != 4 {
+            return Err(RxError::InvalidContent);
+        }
+        Ok(arp_pkg)
+    }
 }
 
 impl EthernetListener for ArpRx {
     fn recv(&mut self, _time: SystemTime, pkg: &EthernetPacket) -> RxResult {
-        let arp_pkg = ArpPacket::new(pkg.payload()).unwrap();
+        let arp_pkg = try!(Self::get_arp_pkg(pkg));
         let ip = arp_pkg.get_sender_proto_addr();
         let mac = arp_pkg.get_sender_hw_addr();
         debug!("Arp MAC: {} -> IPv4: {}", mac, ip);
 
-        let mut data = self.data.lock().unwrap();
-        let old_mac = data.table.insert(ip, mac);
-        if old_mac.is_none() || old_mac != Some(mac) {
-            // The new MAC is different from the old one, bump tx VersionedTx
-            self.vtx.lock().unwrap().inc();
-        }
-        if let Some(listeners) = data.listeners.remove(&ip) {
-            for listener in listeners {
-                listener.send(mac).unwrap_or(());
+        let wakers = {
+            let mut data = self.data.lock().unwrap();
+            let old_mac = data.table.insert(ip, mac);
+            if old_mac.is_none() || old_mac != Some(mac) {
+                // The new MAC is different from the old one, bump tx VersionedTx
+                self.vtx.lock().unwrap().inc();
+            }
+            if let Some(listeners) = data.listeners.remove(&ip) {
+                for listener in listeners {
+                    listener.send(mac).unwrap_or(());
+                }
             }
+            data.wakers.remove(&ip)
+        };
+        if let Some((_, wakers)) = wakers {
+            wakers.wake_all();
         }
         Ok(())
     }
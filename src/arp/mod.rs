@@ -5,11 +5,14 @@ use std::sync::mpsc::{Receiver, Sender, channel};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
 
 use pnet::util::MacAddr;
 
 use ethernet::EthernetListener;
 use VersionedTx;
+use poll::{Wake, WakerSet};
+use util;
 
 mod arp_rx;
 mod arp_tx;
@@ -17,9 +20,16 @@ mod arp_tx;
 pub use self::arp_rx::ArpRx;
 pub use self::arp_tx::{ArpBuilder, ArpTx};
 
+/// How long wakers wait for the Arp reply to a request, in milliseconds.
+/// After that they are woken and forgotten, so a polling sender retries with
+/// a new request and unanswered addresses don't pile up in the table.
+pub const ARP_REQUEST_TIMEOUT_MS: u64 = 1000;
+
 pub struct TableData {
     table: HashMap<Ipv4Addr, MacAddr>,
     listeners: HashMap<Ipv4Addr, Vec<Sender<MacAddr>>>,
+    wakers: HashMap<Ipv4Addr, (Instant, WakerSet)>,
+    expiring: bool,
 }
 
 impl TableData {
@@ -27,8 +37,31 @@ impl TableData {
         TableData {
             table: HashMap::new(),
             listeners: HashMap::new(),
+            wakers: HashMap::new(),
+            expiring: false,
         }
     }
+
+    /// Removes and returns the wakers that have waited for a reply longer
+    /// than `ARP_REQUEST_TIMEOUT_MS`.
+    fn expire_wakers(&mut self, now: Instant) -> Vec<WakerSet> {
+        let timeout = Duration::from_millis(ARP_REQUEST_TIMEOUT_MS);
+        let expired = self.wakers
+            .iter()
+            .filter(|&(_, &(since, _))| now.duration_since(since) >= timeout)
+            .map(|(ip, _)| *ip)
+            .collect::<Vec<_>>();
+        expired.into_iter()
+            .filter_map(|ip| self.wakers.remove(&ip))
+            .map(|(_, wakers)| wakers)
+            .collect()
+    }
+
+    /// Returns when the next wakers expire, if any are waiting.
+    fn next_expiry(&self) -> Option<Instant> {
+        let timeout = Duration::from_millis(ARP_REQUEST_TIMEOUT_MS);
+        self.wakers.values().map(|&(since, _)| since + timeout).min()
+    }
 }
 
 impl Default for TableData {
@@ -76,8 +109,53 @@ impl ArpTable {
     /// Manually insert an IP -> MAC mapping into this Arp table
     // TODO: This should also invalidate the Tx
     pub fn insert(&mut self, ip: Ipv4Addr, mac: MacAddr) {
-        let mut data = self.data.lock().expect("Unable to lock Arp::table for writing");
-        data.table.insert(ip, mac);
+        let wakers = {
+            let mut data = self.data.lock().expect("Unable to lock Arp::table for writing");
+            data.table.insert(ip, mac);
+            data.wakers.remove(&ip)
+        };
+        if let Some((_, wakers)) = wakers {
+            wakers.wake_all();
+        }
+    }
+
+    /// Wakes `waker` when `ip` is resolved, or right away if it already is.
+    /// For polling sends that returned `WouldBlock` while waiting for it.
+    /// Also woken if no reply arrives within `ARP_REQUEST_TIMEOUT_MS` of the
+    /// first waker for `ip`, to send a new request.
+    pub fn wake_when_resolved(&self, ip: Ipv4Addr, waker: Arc<Wake>) {
+        {
+            let mut data = self.data.lock().unwrap();
+            if !data.table.contains_key(&ip) {
+                let now = Instant::now();
+                data.wakers.entry(ip).or_insert_with(|| (now, WakerSet::new())).1.register(waker);
+                if !data.expiring {
+                    data.expiring = true;
+                    Self::spawn_expiry_timer(self.data.clone());
+                }
+                return;
+            }
+        }
+        waker.wake();
+    }
+
+    /// Wakes and forgets the wakers that waited too long for a reply, from a
+    /// timer running as long as any wakers are waiting.
+    fn spawn_expiry_timer(data: Arc<Mutex<TableData>>) {
+        util::spawn_timer(move || {
+            let (expired, next) = {
+                let mut data = data.lock().unwrap();
+                let expired = data.expire_wakers(Instant::now());
+                let next = data.next_expiry();
+                data.expiring = next.is_some();
+                (expired, next)
+            };
+            // Woken without the table locked, in case they look it up
+            for wakers in expired {
+                wakers.wake_all();
+            }
+            next
+        });
     }
 
     fn add_listener(data: &mut TableData, ip: Ipv4Addr) -> Receiver<MacAddr> {
@@ -1,11 +1,26 @@
-use RxResult;
+use {RxError, RxResult};
 
 use pnet::datalink::EthernetDataLinkReceiver;
 use pnet::packet::ethernet::{EtherType, EthernetPacket};
 
+use std::cmp;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+
+/// Number of read errors in a row from the datalink backend after which an
+/// `EthernetRx` gives up and stops its thread.
+pub const MAX_CONSECUTIVE_READ_ERRORS: usize = 100;
+
+/// Milliseconds an `EthernetRx` waits before reading again after a read
+/// error. Doubled for every further error in a row, up to
+/// `MAX_READ_ERROR_BACKOFF_MS`.
+pub const READ_ERROR_BACKOFF_MS: u64 = 1;
+
+/// Longest wait between two reads failing in a row, in milliseconds.
+pub const MAX_READ_ERROR_BACKOFF_MS: u64 = 50;
 
 /// Anyone interested in receiving ethernet frames from an `EthernetRx` must
 /// implement this.
@@ -47,33 +62,70 @@ impl EthernetRx {
 
     /// Start a new thread and move the `EthernetRx` to it. This thread will
     /// constantly read from the given `EthernetDataLinkReceiver` and
-    /// distribute the packets to its listeners.
-    pub fn spawn(self, receiver: Box<EthernetDataLinkReceiver>) {
+    /// distribute the packets to its listeners. Returns a flag that is
+    /// cleared when the thread stops, after too many read errors or a panic.
+    pub fn spawn(self, receiver: Box<EthernetDataLinkReceiver>) -> Arc<AtomicBool> {
+        let running = Arc::new(AtomicBool::new(true));
+        let stopped = ClearOnDrop(running.clone());
         thread::spawn(move || {
+            let _stopped = stopped;
             self.run(receiver);
         });
+        running
+    }
+
+    /// Delivers one received frame to the listeners of its `EtherType`.
+    pub fn recv(&mut self, time: SystemTime, pkg: &EthernetPacket) -> RxResult {
+        let ethertype = pkg.get_ethertype();
+        match self.listeners.get_mut(&ethertype) {
+            Some(listeners) => {
+                for listener in listeners {
+                    if let Err(e) = listener.recv(time, pkg) {
+                        warn!("RxError: {:?}", e);
+                    }
+                }
+                Ok(())
+            }
+            None => Err(RxError::NoListener(format!("Ethernet, {:?}", ethertype))),
+        }
     }
 
     fn run(mut self, mut receiver: Box<EthernetDataLinkReceiver>) {
         let mut rx_iter = receiver.iter();
+        let mut errors = 0;
         loop {
             match rx_iter.next() {
                 Ok(pkg) => {
-                    let time = SystemTime::now();
-                    let ethertype = pkg.get_ethertype();
-                    match self.listeners.get_mut(&ethertype) {
-                        Some(listeners) => {
-                            for listener in listeners {
-                                if let Err(e) = listener.recv(time, &pkg) {
-                                    warn!("RxError: {:?}", e);
-                                }
-                            }
-                        }
-                        None => debug!("Ethernet: No listener for {:?}", ethertype),
+                    errors = 0;
+                    if let Err(e) = self.recv(SystemTime::now(), &pkg) {
+                        debug!("Ethernet: Dropped frame: {:?}", e);
                     }
                 }
-                Err(e) => panic!("EthernetRx crash: {}", e),
+                Err(e) => {
+                    errors += 1;
+                    warn!("Ethernet: Read error: {}", e);
+                    if errors >= MAX_CONSECUTIVE_READ_ERRORS {
+                        error!("Ethernet: Stopping receiver after {} read errors", errors);
+                        break;
+                    }
+                    thread::sleep(Self::backoff(errors));
+                }
             }
         }
     }
+
+    /// Returns how long to wait after `errors` read errors in a row.
+    fn backoff(errors: usize) -> Duration {
+        let ms = READ_ERROR_BACKOFF_MS << cmp::min(errors - 1, 16);
+        Duration::from_millis(cmp::min(ms, MAX_READ_ERROR_BACKOFF_MS))
+    }
+}
+
+/// Clears the flag when dropped, also while unwinding from a panic.
+struct ClearOnDrop(Arc<AtomicBool>);
+
+impl Drop for ClearOnDrop {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
 }
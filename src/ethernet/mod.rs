@@ -5,5 +5,6 @@
 mod ethernet_rx;
 mod ethernet_tx;
 
-pub use self::ethernet_rx::{EthernetListener, EthernetRx};
+pub use self::ethernet_rx::{EthernetListener, EthernetRx, MAX_CONSECUTIVE_READ_ERRORS,
+                            MAX_READ_ERROR_BACKOFF_MS, READ_ERROR_BACKOFF_MS};
 pub use self::ethernet_tx::{BasicEthernetProtocol, EthernetBuilder, EthernetProtocol, EthernetTx};
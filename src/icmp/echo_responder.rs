@@ -0,0 +1,144 @@
+use TxError;
+#[cfg(not(all(test, feature = "unit-tests")))]
+use ipv4::Ipv4Tx;
+
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use super::{EchoReplyBuilder, IcmpListener, IcmpMessage, IcmpTx};
+#[cfg(all(test, feature = "unit-tests"))]
+use testing::ipv4::Ipv4Tx;
+use util::RateLimiter;
+
+/// Settings for how a stack answers Echo Requests (ping) to its addresses.
+#[derive(Clone, Debug)]
+pub struct EchoSettings {
+    /// Answer Echo Requests. Enabled by default.
+    pub enabled: bool,
+
+    /// Don't answer Echo Requests sent to 255.255.255.255, the directed
+    /// broadcast address of a local network or a joined multicast group.
+    /// Enabled by default.
+    pub ignore_broadcast: bool,
+
+    /// Max number of Echo Replies sent per second, on average. Zero means no
+    /// limit.
+    pub rate_limit: u32,
+
+    /// Number of Echo Replies that can be sent in a burst above the rate
+    /// limit.
+    pub burst: u32,
+}
+
+impl Default for EchoSettings {
+    fn default() -> Self {
+        EchoSettings {
+            enabled: true,
+            ignore_broadcast: true,
+            rate_limit: 1000,
+            burst: 100,
+        }
+    }
+}
+
+/// The `EchoSettings` and the rate limiting shared by all `EchoResponder`s
+/// in one stack.
+pub struct EchoState {
+    settings: EchoSettings,
+    limiter: RateLimiter,
+}
+
+impl EchoState {
+    pub fn new(settings: EchoSettings) -> EchoState {
+        EchoState {
+            limiter: RateLimiter::new(settings.rate_limit, settings.burst),
+            settings: settings,
+        }
+    }
+
+    pub fn settings(&self) -> &EchoSettings {
+        &self.settings
+    }
+
+    /// Replaces the settings. Resets the rate limiting.
+    pub fn set_settings(&mut self, settings: EchoSettings) {
+        *self = EchoState::new(settings);
+    }
+
+    fn allow(&mut self, broadcast: bool) -> bool {
+        if !self.settings.enabled || (broadcast && self.settings.ignore_broadcast) {
+            return false;
+        }
+        self.limiter.check()
+    }
+}
+
+impl Default for EchoState {
+    fn default() -> Self {
+        Self::new(EchoSettings::default())
+    }
+}
+
+/// Creates the `Ipv4Tx` a reply from the first address to the second one is
+/// sent with. Returns `None` if the reply can't be sent at the moment.
+pub type ReplyTxFactory = Box<FnMut(Ipv4Addr, Ipv4Addr) -> Option<Ipv4Tx> + Send>;
+
+/// `IcmpListener` answering Echo Requests sent to one local address with
+/// Echo Replies carrying the same identifier, sequence number and payload.
+/// The stack registers one for every address added to it.
+pub struct EchoResponder {
+    local_ip: Ipv4Addr,
+    state: Arc<Mutex<EchoState>>,
+    reply_tx: ReplyTxFactory,
+}
+
+impl EchoResponder {
+    pub fn new(local_ip: Ipv4Addr,
+               state: Arc<Mutex<EchoState>>,
+               reply_tx: ReplyTxFactory)
+               -> EchoResponder {
+        EchoResponder {
+            local_ip: local_ip,
+            state: state,
+            reply_tx: reply_tx,
+        }
+    }
+}
+
+impl IcmpListener for EchoResponder {
+    fn recv(&mut self, _time: SystemTime, ip_pkg: &Ipv4Packet, message: &IcmpMessage) {
+        let src = ip_pkg.get_source();
+        if src.is_broadcast() || src.is_multicast() || src.is_unspecified() {
+            return;
+        }
+        let (identifier, sequence_number, payload) = match *message {
+            IcmpMessage::EchoRequest { identifier, sequence_number, payload } => {
+                (identifier, sequence_number, payload)
+            }
+            _ => return,
+        };
+        // Requests not sent to the local address itself came in through a
+        // broadcast or multicast address mapped to it.
+        let broadcast = ip_pkg.get_destination() != self.local_ip;
+        if !self.state.lock().unwrap().allow(broadcast) {
+            debug!("Icmp: Not answering Echo Request from {}", src);
+            return;
+        }
+        loop {
+            let ipv4_tx = match (self.reply_tx)(self.local_ip, src) {
+                Some(ipv4_tx) => ipv4_tx,
+                None => return,
+            };
+            let builder = EchoReplyBuilder::new(identifier, sequence_number, payload);
+            match IcmpTx::new(ipv4_tx).send(builder) {
+                Err(TxError::InvalidTx) => continue,
+                Err(e) => warn!("Icmp: Unable to send Echo Reply to {}: {:?}", src, e),
+                Ok(()) => (),
+            }
+            return;
+        }
+    }
+}
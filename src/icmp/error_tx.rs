@@ -0,0 +1,248 @@
+use TxError;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::Packet;
+use pnet::packet::icmp::{IcmpCode, IcmpPacket, IcmpType, IcmpTypes};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::cmp;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+
+use super::{BasicIcmpProtocol, IcmpMessage, IcmpTx, ReplyTxFactory, is_error};
+use util::{RateLimiter, directed_broadcast};
+
+/// Number of payload bytes of the offending packet quoted in an Icmp error,
+/// after its full IP header.
+pub const ICMP_ERROR_QUOTE_LEN: usize = 8;
+
+/// Settings for the Icmp error messages a stack sends about packets it
+/// can't deliver.
+#[derive(Clone, Debug)]
+pub struct IcmpErrorSettings {
+    /// Send Icmp error messages. Enabled by default.
+    pub enabled: bool,
+
+    /// Max number of Icmp errors sent per second, on average. Zero means no
+    /// limit.
+    pub rate_limit: u32,
+
+    /// Number of Icmp errors that can be sent in a burst above the rate
+    /// limit.
+    pub burst: u32,
+}
+
+impl Default for IcmpErrorSettings {
+    fn default() -> Self {
+        IcmpErrorSettings {
+            enabled: true,
+            rate_limit: 1000,
+            burst: 50,
+        }
+    }
+}
+
+/// The `IcmpErrorSettings` and the rate limiting shared by all `IcmpErrorTx`
+/// in one stack.
+pub struct IcmpErrorState {
+    settings: IcmpErrorSettings,
+    limiter: RateLimiter,
+}
+
+impl IcmpErrorState {
+    pub fn new(settings: IcmpErrorSettings) -> IcmpErrorState {
+        IcmpErrorState {
+            limiter: RateLimiter::new(settings.rate_limit, settings.burst),
+            settings: settings,
+        }
+    }
+
+    pub fn settings(&self) -> &IcmpErrorSettings {
+        &self.settings
+    }
+
+    /// Replaces the settings. Resets the rate limiting.
+    pub fn set_settings(&mut self, settings: IcmpErrorSettings) {
+        *self = IcmpErrorState::new(settings);
+    }
+
+    fn allow(&mut self) -> bool {
+        self.settings.enabled && self.limiter.check()
+    }
+}
+
+impl Default for IcmpErrorState {
+    fn default() -> Self {
+        Self::new(IcmpErrorSettings::default())
+    }
+}
+
+/// Sends Icmp error messages about received packets back to their sender.
+/// Used by the receiving side of all layers, so clones share the way replies
+/// are sent. Following RFC 1122 no error is ever sent about an Icmp error,
+/// a packet to a broadcast or multicast address, a fragment other than the
+/// first one or a packet with a source address not identifying a single
+/// host.
+#[derive(Clone)]
+pub struct IcmpErrorTx {
+    state: Arc<Mutex<IcmpErrorState>>,
+    reply_tx: Arc<Mutex<ReplyTxFactory>>,
+    nets: Arc<Mutex<Vec<Ipv4Network>>>,
+}
+
+impl IcmpErrorTx {
+    pub fn new(state: Arc<Mutex<IcmpErrorState>>, reply_tx: ReplyTxFactory) -> IcmpErrorTx {
+        IcmpErrorTx {
+            state: state,
+            reply_tx: Arc::new(Mutex::new(reply_tx)),
+            nets: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Adds a network configured on the interface the errors are sent for.
+    /// Packets to its directed broadcast address are never answered.
+    pub fn add_network(&self, net: Ipv4Network) {
+        self.nets.lock().unwrap().push(net);
+    }
+
+    /// Reports that no protocol listener exists for the protocol of `ip_pkg`.
+    pub fn protocol_unreachable(&self, ip_pkg: &Ipv4Packet) {
+        self.send(ip_pkg, IcmpTypes::DestinationUnreachable, IcmpCode(2), [0; 4]);
+    }
+
+    /// Reports that nothing listens to the destination port of `ip_pkg`.
+    pub fn port_unreachable(&self, ip_pkg: &Ipv4Packet) {
+        self.send(ip_pkg, IcmpTypes::DestinationUnreachable, IcmpCode(3), [0; 4]);
+    }
+
+    /// Reports that the fragments of a packet did not all arrive in time.
+    /// `ip_pkg` must be the first fragment.
+    pub fn reassembly_time_exceeded(&self, ip_pkg: &Ipv4Packet) {
+        self.send(ip_pkg, IcmpTypes::TimeExceeded, IcmpCode(1), [0; 4]);
+    }
+
+    /// Reports a malformed header in `ip_pkg`. `pointer` is the offset of the
+    /// offending byte, counted from the start of the IP header.
+    pub fn parameter_problem(&self, ip_pkg: &Ipv4Packet, pointer: u8) {
+        self.send(ip_pkg, IcmpTypes::ParameterProblem, IcmpCode(0), [pointer, 0, 0, 0]);
+    }
+
+    /// Sends an Icmp error of the given type and code about `ip_pkg`, quoting
+    /// its IP header and the first `ICMP_ERROR_QUOTE_LEN` bytes of its
+    /// payload. `rest_of_header` is the four bytes following the checksum.
+    pub fn send(&self,
+                ip_pkg: &Ipv4Packet,
+                icmp_type: IcmpType,
+                icmp_code: IcmpCode,
+                rest_of_header: [u8; 4]) {
+        if !may_answer(ip_pkg, &self.nets.lock().unwrap()) {
+            return;
+        }
+        let src = ip_pkg.get_destination();
+        let dst = ip_pkg.get_source();
+        if !self.state.lock().unwrap().allow() {
+            debug!("Icmp: Not sending error {:?} to {}", icmp_type, dst);
+            return;
+        }
+        let mut payload = rest_of_header.to_vec();
+        payload.extend_from_slice(quote(ip_pkg));
+        let mut reply_tx = self.reply_tx.lock().unwrap();
+        loop {
+            let ipv4_tx = match (*reply_tx)(src, dst) {
+                Some(ipv4_tx) => ipv4_tx,
+                None => return,
+            };
+            let builder = BasicIcmpProtocol::new(icmp_type, icmp_code, payload.clone());
+            match IcmpTx::new(ipv4_tx).send(builder) {
+                Err(TxError::InvalidTx) => continue,
+                Err(e) => warn!("Icmp: Unable to send error {:?} to {}: {:?}", icmp_type, dst, e),
+                Ok(()) => (),
+            }
+            return;
+        }
+    }
+}
+
+/// Parses the packet quoted in the Icmp error `ip_pkg`. Returns the quoted IP
+/// header and the first `ICMP_ERROR_QUOTE_LEN` bytes of its payload.
+pub fn parse_quoted<'a>(ip_pkg: &'a Ipv4Packet) -> Option<(Ipv4Packet<'a>, &'a [u8])> {
+    match IcmpMessage::parse(ip_pkg.payload()) {
+        Ok(message) => message.quoted_packet(),
+        Err(_) => None,
+    }
+}
+
+/// Returns the part of `ip_pkg` an Icmp error about it carries.
+fn quote<'a>(ip_pkg: &'a Ipv4Packet) -> &'a [u8] {
+    let data = ip_pkg.packet();
+    let header_len = cmp::max(ip_pkg.get_header_length() as usize * 4,
+                              Ipv4Packet::minimum_packet_size());
+    &data[..cmp::min(header_len + ICMP_ERROR_QUOTE_LEN, data.len())]
+}
+
+/// Returns true if RFC 1122 allows an Icmp error to be sent about `ip_pkg`,
+/// received on an interface with the networks `nets`.
+fn may_answer(ip_pkg: &Ipv4Packet, nets: &[Ipv4Network]) -> bool {
+    let dst = ip_pkg.get_destination();
+    if dst.is_broadcast() || dst.is_multicast() ||
+       nets.iter().any(|net| directed_broadcast(net) == Some(dst)) {
+        return false;
+    }
+    if !is_single_host(ip_pkg.get_source()) || ip_pkg.get_fragment_offset() != 0 {
+        return false;
+    }
+    if ip_pkg.get_next_level_protocol() == IpNextHeaderProtocols::Icmp {
+        match IcmpPacket::new(ip_pkg.payload()) {
+            Some(icmp_pkg) => !is_error(icmp_pkg.get_icmp_type()),
+            None => false,
+        }
+    } else {
+        true
+    }
+}
+
+fn is_single_host(ip: Ipv4Addr) -> bool {
+    !(ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() || ip.is_loopback() ||
+      ip.octets()[0] >= 240)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ipnetwork::Ipv4Network;
+
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn udp_packet(dst: Ipv4Addr) -> Vec<u8> {
+        let mut buffer = vec![0; 20 + 8];
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(&mut buffer).unwrap();
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(5);
+            ip_pkg.set_total_length(20 + 8);
+            ip_pkg.set_source(Ipv4Addr::new(10, 0, 0, 1));
+            ip_pkg.set_destination(dst);
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        }
+        buffer
+    }
+
+    #[test]
+    fn broadcast_not_answered() {
+        let nets = [Ipv4Network::from_cidr("10.0.0.2/24").unwrap()];
+        for &(dst, answer) in &[(Ipv4Addr::new(10, 0, 0, 2), true),
+                                (Ipv4Addr::new(10, 0, 0, 255), false),
+                                (Ipv4Addr::new(255, 255, 255, 255), false),
+                                (Ipv4Addr::new(224, 0, 0, 1), false)] {
+            let buffer = udp_packet(dst);
+            assert_eq!(may_answer(&Ipv4Packet::new(&buffer).unwrap(), &nets), answer);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use pnet::packet::Packet;
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use {Poll, QueueReceiver, QueueSender, Wake, bounded_queue};
+
+use super::{IcmpListener, IcmpMessage};
+
+/// How many packets an `IcmpQueue` holds by default before new ones are
+/// dropped.
+pub const ICMP_QUEUE_LEN: usize = 64;
+
+/// A packet queued by an `IcmpQueue`. The whole IP packet, and when it was
+/// received.
+pub type IcmpQueueItem = (SystemTime, Box<[u8]>);
+
+/// `IcmpListener` queueing the packets it gets, to be read from the
+/// `IcmpQueueReader` created with it instead of in the receiving thread.
+/// Register it with `NetworkStack::icmp_listen_id_in` and remove it with
+/// `icmp_unlisten_id_in` when done reading. Bounded, packets arriving when it
+/// is full are dropped and counted.
+pub struct IcmpQueue {
+    queue: QueueSender<IcmpQueueItem>,
+}
+
+impl IcmpQueue {
+    /// Creates a queue holding at most `len` packets, and the reader they
+    /// are read from.
+    pub fn new(len: usize) -> (IcmpQueue, IcmpQueueReader) {
+        let (queue, reader) = bounded_queue(len);
+        (IcmpQueue { queue: queue }, IcmpQueueReader { queue: reader })
+    }
+}
+
+impl IcmpListener for IcmpQueue {
+    fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet, _message: &IcmpMessage) {
+        self.queue.send((time, packet.packet().to_vec().into_boxed_slice()));
+    }
+}
+
+/// Reads the packets an `IcmpQueue` received, blocking or polling.
+pub struct IcmpQueueReader {
+    queue: QueueReceiver<IcmpQueueItem>,
+}
+
+impl IcmpQueueReader {
+    /// Returns the next packet, waiting at most `timeout` for one. `None`
+    /// waits forever. Returns `None` if nothing arrived in time.
+    pub fn recv(&self, timeout: Option<Duration>) -> Option<IcmpQueueItem> {
+        self.queue.recv(timeout)
+    }
+
+    /// Returns the next packet without blocking. When there is none it
+    /// returns `Poll::Pending`, and `waker` is woken when one arrives.
+    pub fn poll_recv(&self, waker: Arc<Wake>) -> Poll<IcmpQueueItem> {
+        self.queue.poll_recv(waker)
+    }
+
+    /// Returns how many packets were dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.queue.dropped()
+    }
+}
@@ -2,24 +2,89 @@ use {RxError, RxResult};
 use ipv4::Ipv4Listener;
 
 use pnet::packet::Packet;
-use pnet::packet::icmp::{IcmpPacket, IcmpType};
+use pnet::packet::icmp::{IcmpCode, IcmpPacket, IcmpType, checksum};
 use pnet::packet::ipv4::Ipv4Packet;
 
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::SystemTime;
 
+use super::IcmpMessage;
+
 /// Trait that must be implemented by any struct who want to receive Icmp
 /// packets.
 pub trait IcmpListener: Send {
-    /// Called by `IcmpRx` when there is a incoming packet for this listener
-    fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet);
+    /// Called by `IcmpRx` when there is a incoming packet for this listener.
+    /// `message` is the Icmp payload of `packet`, already decoded.
+    fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet, message: &IcmpMessage);
+}
+
+/// Selects the Icmp messages an `IcmpListener` receives. Matches every code
+/// of the type unless a code is given. Created from an `IcmpType` for the
+/// former.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IcmpFilter {
+    pub icmp_type: IcmpType,
+    pub icmp_code: Option<IcmpCode>,
 }
 
-/// Type binding for how the listeners in `IcmpRx` are structured.
-pub type IcmpListenerLookup = HashMap<IcmpType, Vec<Box<IcmpListener>>>;
+impl IcmpFilter {
+    pub fn new(icmp_type: IcmpType, icmp_code: Option<IcmpCode>) -> IcmpFilter {
+        IcmpFilter {
+            icmp_type: icmp_type,
+            icmp_code: icmp_code,
+        }
+    }
+
+    pub fn matches(&self, message: &IcmpMessage) -> bool {
+        message.icmp_type() == self.icmp_type &&
+        self.icmp_code.map_or(true, |code| code == message.icmp_code())
+    }
+}
+
+impl From<IcmpType> for IcmpFilter {
+    fn from(icmp_type: IcmpType) -> IcmpFilter {
+        IcmpFilter::new(icmp_type, None)
+    }
+}
 
-/// Listener and parser of Icmp packets.
+/// Identifies a listener added with `add_icmp_listener`. Never reused.
+pub type IcmpListenerId = usize;
+
+static NEXT_LISTENER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Type binding for how the listeners in `IcmpRx` are structured. Listeners
+/// are stored by type together with their id and the code they want, if any.
+pub type IcmpListenerLookup = HashMap<IcmpType,
+                                      Vec<(IcmpListenerId, Option<IcmpCode>, Box<IcmpListener>)>>;
+
+/// Adds `listener` to `listeners`, receiving the messages matching `filter`.
+/// Returns the id to remove it with.
+pub fn add_icmp_listener(listeners: &mut IcmpListenerLookup,
+                         filter: IcmpFilter,
+                         listener: Box<IcmpListener>)
+                         -> IcmpListenerId {
+    let id = NEXT_LISTENER_ID.fetch_add(1, Ordering::SeqCst);
+    listeners.entry(filter.icmp_type).or_insert(vec![]).push((id, filter.icmp_code, listener));
+    id
+}
+
+/// Removes the listener with `id` from `listeners`. Returns true if it was
+/// there.
+pub fn remove_icmp_listener(listeners: &mut IcmpListenerLookup, id: IcmpListenerId) -> bool {
+    let mut removed = false;
+    for type_listeners in listeners.values_mut() {
+        let before = type_listeners.len();
+        type_listeners.retain(|&(listener_id, _, _)| listener_id != id);
+        removed |= type_listeners.len() != before;
+    }
+    listeners.retain(|_, type_listeners| !type_listeners.is_empty());
+    removed
+}
+
+/// Listener and parser of Icmp packets. Drops packets with a bad checksum or
+/// a length too short for their type.
 pub struct IcmpRx {
     listeners: Arc<Mutex<IcmpListenerLookup>>,
 }
@@ -34,19 +99,27 @@ impl IcmpRx {
 
 impl Ipv4Listener for IcmpRx {
     fn recv(&mut self, time: SystemTime, ip_pkg: Ipv4Packet) -> RxResult {
-        let (icmp_type, _icmp_code) = {
-            let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
-            (icmp_pkg.get_icmp_type(), icmp_pkg.get_icmp_code())
-        };
+        let message = try!(IcmpMessage::parse(ip_pkg.payload()));
+        let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
+        if checksum(&icmp_pkg) != icmp_pkg.get_checksum() {
+            return Err(RxError::InvalidChecksum);
+        }
         trace!("Icmp got a packet with {} bytes!", ip_pkg.payload().len());
+        let icmp_type = message.icmp_type();
         let mut listeners = self.listeners.lock().unwrap();
+        let mut delivered = false;
         if let Some(type_listeners) = listeners.get_mut(&icmp_type) {
-            for listener in type_listeners {
-                listener.recv(time, &ip_pkg);
+            for &mut (_, code, ref mut listener) in type_listeners {
+                if IcmpFilter::new(icmp_type, code).matches(&message) {
+                    listener.recv(time, &ip_pkg, &message);
+                    delivered = true;
+                }
             }
+        }
+        if delivered {
             Ok(())
         } else {
-            Err(RxError::NoListener(format!("Icmp, {:?}", icmp_type)))
+            Err(RxError::NoListener(format!("Icmp, {:?} {:?}", icmp_type, message.icmp_code())))
         }
     }
 }
@@ -5,6 +5,7 @@ use ipv4::Ipv4Tx;
 
 use pnet::packet::MutablePacket;
 use pnet::packet::icmp::{IcmpCode, IcmpPacket, IcmpType, MutableIcmpPacket, checksum, IcmpTypes};
+use pnet::packet::icmp::echo_reply::{EchoReplyPacket, MutableEchoReplyPacket};
 use pnet::packet::icmp::echo_request::{EchoRequestPacket, MutableEchoRequestPacket, IcmpCodes};
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 
@@ -88,6 +89,17 @@ impl IcmpTx {
         let builder = PingBuilder::new(payload);
         self.send(builder)
     }
+
+    /// Sends an Echo Request packet with the given identifier and sequence
+    /// number, so the reply can be matched to it.
+    pub fn send_echo_request(&mut self,
+                             identifier: u16,
+                             sequence_number: u16,
+                             payload: &[u8])
+                             -> TxResult {
+        let builder = PingBuilder::with_sequence(identifier, sequence_number, payload);
+        self.send(builder)
+    }
 }
 
 
@@ -123,12 +135,27 @@ impl<P: IcmpProtocol> Protocol for IcmpBuilder<P> {
 }
 
 pub struct PingBuilder<'a> {
+    identifier: u16,
+    sequence_number: u16,
     payload: &'a [u8],
 }
 
 impl<'a> PingBuilder<'a> {
+    /// Creates a builder of Echo Requests with identifier and sequence
+    /// number zero.
     pub fn new(payload: &'a [u8]) -> PingBuilder<'a> {
-        PingBuilder { payload: payload }
+        Self::with_sequence(0, 0, payload)
+    }
+
+    pub fn with_sequence(identifier: u16,
+                         sequence_number: u16,
+                         payload: &'a [u8])
+                         -> PingBuilder<'a> {
+        PingBuilder {
+            identifier: identifier,
+            sequence_number: sequence_number,
+            payload: payload,
+        }
     }
 }
 
@@ -150,8 +177,50 @@ impl<'a> Protocol for PingBuilder<'a> {
 
     fn build(&mut self, buffer: &mut [u8]) {
         let mut echo_pkg = MutableEchoRequestPacket::new(buffer).unwrap();
-        echo_pkg.set_identifier(0);
-        echo_pkg.set_sequence_number(0);
+        echo_pkg.set_identifier(self.identifier);
+        echo_pkg.set_sequence_number(self.sequence_number);
+        echo_pkg.set_payload(self.payload);
+    }
+}
+
+/// Builder of Echo Reply packets, answering an Echo Request with the given
+/// identifier, sequence number and payload.
+pub struct EchoReplyBuilder<'a> {
+    identifier: u16,
+    sequence_number: u16,
+    payload: &'a [u8],
+}
+
+impl<'a> EchoReplyBuilder<'a> {
+    pub fn new(identifier: u16, sequence_number: u16, payload: &'a [u8]) -> EchoReplyBuilder<'a> {
+        EchoReplyBuilder {
+            identifier: identifier,
+            sequence_number: sequence_number,
+            payload: payload,
+        }
+    }
+}
+
+impl<'a> IcmpProtocol for EchoReplyBuilder<'a> {
+    fn icmp_type(&self) -> IcmpType {
+        IcmpTypes::EchoReply
+    }
+
+    fn icmp_code(&self) -> IcmpCode {
+        IcmpCodes::NoCode
+    }
+}
+
+impl<'a> Protocol for EchoReplyBuilder<'a> {
+    fn len(&self) -> usize {
+        EchoReplyPacket::minimum_packet_size() - IcmpPacket::minimum_packet_size() +
+        self.payload.len()
+    }
+
+    fn build(&mut self, buffer: &mut [u8]) {
+        let mut echo_pkg = MutableEchoReplyPacket::new(buffer).unwrap();
+        echo_pkg.set_identifier(self.identifier);
+        echo_pkg.set_sequence_number(self.sequence_number);
         echo_pkg.set_payload(self.payload);
     }
 }
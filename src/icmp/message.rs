@@ -0,0 +1,194 @@
+use RxError;
+use util::read_u16;
+
+use pnet::packet::icmp::{IcmpCode, IcmpPacket, IcmpType, IcmpTypes};
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::net::Ipv4Addr;
+
+use super::ICMP_ERROR_QUOTE_LEN;
+
+/// Length of the fixed part of every Icmp message. Type, code, checksum and
+/// the four bytes of type specific header following the checksum.
+pub const ICMP_HEADER_LEN: usize = 8;
+
+/// A received Icmp message decoded by type. Every error message carries the
+/// `quoted` IP header and start of the datagram that caused it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IcmpMessage<'a> {
+    EchoReply {
+        identifier: u16,
+        sequence_number: u16,
+        payload: &'a [u8],
+    },
+    EchoRequest {
+        identifier: u16,
+        sequence_number: u16,
+        payload: &'a [u8],
+    },
+    DestinationUnreachable {
+        code: IcmpCode,
+        /// MTU of the next hop, sent with Fragmentation Needed (code 4) by
+        /// routers supporting RFC 1191. Zero otherwise.
+        next_hop_mtu: u16,
+        quoted: &'a [u8],
+    },
+    SourceQuench { quoted: &'a [u8] },
+    Redirect {
+        code: IcmpCode,
+        gateway: Ipv4Addr,
+        quoted: &'a [u8],
+    },
+    TimeExceeded { code: IcmpCode, quoted: &'a [u8] },
+    ParameterProblem {
+        code: IcmpCode,
+        /// Offset of the offending byte in the quoted IP header.
+        pointer: u8,
+        quoted: &'a [u8],
+    },
+    /// Any other type. `payload` is everything after the checksum.
+    Other {
+        icmp_type: IcmpType,
+        code: IcmpCode,
+        payload: &'a [u8],
+    },
+}
+
+impl<'a> IcmpMessage<'a> {
+    /// Decodes the Icmp message in `data`, the payload of an IP packet. Checks
+    /// the lengths but not the checksum, that is up to the caller.
+    pub fn parse(data: &'a [u8]) -> Result<IcmpMessage<'a>, RxError> {
+        if data.len() < ICMP_HEADER_LEN {
+            return Err(RxError::InvalidLength);
+        }
+        let icmp_type = IcmpType(data[0]);
+        let code = IcmpCode(data[1]);
+        let rest = &data[4..];
+        let body = &data[ICMP_HEADER_LEN..];
+        if is_error(icmp_type) && body.len() < Ipv4Packet::minimum_packet_size() {
+            return Err(RxError::InvalidLength);
+        }
+        let message = match icmp_type {
+            IcmpTypes::EchoReply => {
+                IcmpMessage::EchoReply {
+                    identifier: read_u16(&rest[0..2]),
+                    sequence_number: read_u16(&rest[2..4]),
+                    payload: body,
+                }
+            }
+            IcmpTypes::EchoRequest => {
+                IcmpMessage::EchoRequest {
+                    identifier: read_u16(&rest[0..2]),
+                    sequence_number: read_u16(&rest[2..4]),
+                    payload: body,
+                }
+            }
+            IcmpTypes::DestinationUnreachable => {
+                IcmpMessage::DestinationUnreachable {
+                    code: code,
+                    next_hop_mtu: read_u16(&rest[2..4]),
+                    quoted: body,
+                }
+            }
+            IcmpTypes::SourceQuench => IcmpMessage::SourceQuench { quoted: body },
+            IcmpTypes::RedirectMessage => {
+                IcmpMessage::Redirect {
+                    code: code,
+                    gateway: Ipv4Addr::new(rest[0], rest[1], rest[2], rest[3]),
+                    quoted: body,
+                }
+            }
+            IcmpTypes::TimeExceeded => {
+                IcmpMessage::TimeExceeded {
+                    code: code,
+                    quoted: body,
+                }
+            }
+            IcmpTypes::ParameterProblem => {
+                IcmpMessage::ParameterProblem {
+                    code: code,
+                    pointer: rest[0],
+                    quoted: body,
+                }
+            }
+            _ => {
+                IcmpMessage::Other {
+                    icmp_type: icmp_type,
+                    code: code,
+                    payload: &data[IcmpPacket::minimum_packet_size()..],
+                }
+            }
+        };
+        Ok(message)
+    }
+
+    pub fn icmp_type(&self) -> IcmpType {
+        match *self {
+            IcmpMessage::EchoReply { .. } => IcmpTypes::EchoReply,
+            IcmpMessage::EchoRequest { .. } => IcmpTypes::EchoRequest,
+            IcmpMessage::DestinationUnreachable { .. } => IcmpTypes::DestinationUnreachable,
+            IcmpMessage::SourceQuench { .. } => IcmpTypes::SourceQuench,
+            IcmpMessage::Redirect { .. } => IcmpTypes::RedirectMessage,
+            IcmpMessage::TimeExceeded { .. } => IcmpTypes::TimeExceeded,
+            IcmpMessage::ParameterProblem { .. } => IcmpTypes::ParameterProblem,
+            IcmpMessage::Other { icmp_type, .. } => icmp_type,
+        }
+    }
+
+    pub fn icmp_code(&self) -> IcmpCode {
+        match *self {
+            IcmpMessage::EchoReply { .. } |
+            IcmpMessage::EchoRequest { .. } |
+            IcmpMessage::SourceQuench { .. } => IcmpCode(0),
+            IcmpMessage::DestinationUnreachable { code, .. } |
+            IcmpMessage::Redirect { code, .. } |
+            IcmpMessage::TimeExceeded { code, .. } |
+            IcmpMessage::ParameterProblem { code, .. } |
+            IcmpMessage::Other { code, .. } => code,
+        }
+    }
+
+    /// Returns true for the messages reporting errors about a datagram.
+    pub fn is_error(&self) -> bool {
+        self.quoted().is_some()
+    }
+
+    /// Returns the quoted datagram of error messages.
+    pub fn quoted(&self) -> Option<&'a [u8]> {
+        match *self {
+            IcmpMessage::DestinationUnreachable { quoted, .. } |
+            IcmpMessage::SourceQuench { quoted } |
+            IcmpMessage::Redirect { quoted, .. } |
+            IcmpMessage::TimeExceeded { quoted, .. } |
+            IcmpMessage::ParameterProblem { quoted, .. } => Some(quoted),
+            _ => None,
+        }
+    }
+
+    /// Parses the quoted datagram of error messages. Returns its IP header
+    /// and the first `ICMP_ERROR_QUOTE_LEN` bytes of its payload.
+    pub fn quoted_packet(&self) -> Option<(Ipv4Packet<'a>, &'a [u8])> {
+        let quoted = match self.quoted() {
+            Some(quoted) => quoted,
+            None => return None,
+        };
+        let header_len = match Ipv4Packet::new(quoted) {
+            Some(quoted_pkg) => quoted_pkg.get_header_length() as usize * 4,
+            None => return None,
+        };
+        if header_len < Ipv4Packet::minimum_packet_size() ||
+           quoted.len() < header_len + ICMP_ERROR_QUOTE_LEN {
+            return None;
+        }
+        let header = Ipv4Packet::new(&quoted[..header_len]).unwrap();
+        Some((header, &quoted[header_len..header_len + ICMP_ERROR_QUOTE_LEN]))
+    }
+}
+
+/// Returns true if `icmp_type` is one of the error messages defined in
+/// RFC 792.
+pub fn is_error(icmp_type: IcmpType) -> bool {
+    icmp_type == IcmpTypes::DestinationUnreachable || icmp_type == IcmpTypes::SourceQuench ||
+    icmp_type == IcmpTypes::RedirectMessage || icmp_type == IcmpTypes::TimeExceeded ||
+    icmp_type == IcmpTypes::ParameterProblem
+}
@@ -1,35 +1,63 @@
+mod echo_responder;
+mod error_tx;
+mod icmp_queue;
 mod icmp_rx;
 mod icmp_tx;
+mod message;
+mod ping_rx;
+mod redirect_rx;
+
+#[cfg(not(feature = "unit-tests"))]
+mod pinger;
+#[cfg(not(feature = "unit-tests"))]
+mod resolve;
+#[cfg(not(feature = "unit-tests"))]
+mod router_discovery;
+#[cfg(not(feature = "unit-tests"))]
+mod traceroute;
+
+pub use self::echo_responder::{EchoResponder, EchoSettings, EchoState, ReplyTxFactory};
+pub use self::error_tx::{ICMP_ERROR_QUOTE_LEN, IcmpErrorSettings, IcmpErrorState, IcmpErrorTx,
+                         parse_quoted};
+pub use self::icmp_queue::{ICMP_QUEUE_LEN, IcmpQueue, IcmpQueueItem, IcmpQueueReader};
+pub use self::icmp_rx::{IcmpFilter, IcmpListener, IcmpListenerId, IcmpListenerLookup, IcmpRx,
+                        add_icmp_listener, remove_icmp_listener};
+pub use self::icmp_tx::{BasicIcmpProtocol, EchoReplyBuilder, IcmpBuilder, IcmpProtocol, IcmpTx,
+                        PingBuilder};
+pub use self::message::{ICMP_HEADER_LEN, IcmpMessage, is_error};
+pub use self::ping_rx::{PING_QUEUE_LEN, PingEvent, PingQueue, PingRx, PingerLookup};
+pub use self::redirect_rx::{MAX_REDIRECTS, REDIRECT_TIMEOUT_SECS, RedirectRoutes, RedirectRx};
+
+#[cfg(not(feature = "unit-tests"))]
+pub use self::pinger::{DEFAULT_PING_PAYLOAD_SIZE, PingOutcome, PingProbe, PingStatistics, Pinger,
+                       TIMESTAMP_LEN};
+#[cfg(not(feature = "unit-tests"))]
+pub use self::router_discovery::{DiscoveredRouter, INELIGIBLE_PREFERENCE, RouterAdvertisement,
+                                 RouterDiscovery, RouterDiscoveryConfig, all_routers_group,
+                                 all_systems_group};
+#[cfg(not(feature = "unit-tests"))]
+pub use self::traceroute::{TRACEROUTE_BASE_PORT, Traceroute, TracerouteConfig, TracerouteHop,
+                           TracerouteMethod, TracerouteReply};
 
-pub use self::icmp_rx::{IcmpListener, IcmpListenerLookup, IcmpRx};
-pub use self::icmp_tx::{BasicIcmpProtocol, IcmpBuilder, IcmpProtocol, IcmpTx, PingBuilder};
-
-
-// pub struct PingSocket {
-//     echo: Echo,
-//     reader: Option<Receiver<Box<[u8]>>>,
-//     identifier: u16,
-//     sequence_number: u16,
-// }
-
-// impl PingSocket {
-//     pub fn bind(str, stack?) -> PingSocket {
-//
-//     }
-//
-//     pub fn send_to();
-//
-//     pub fn recv();
-//
-//     pub fn take_recv() -> Result<Receiver<Box<[u8]>>, ()>;
-// }
 
 #[cfg(all(test, feature = "unit-tests"))]
 mod tests {
-    use pnet::packet::Packet;
-    use pnet::packet::icmp::IcmpTypes;
-    use pnet::packet::icmp::echo_request::EchoRequestPacket;
+    use pnet::packet::{MutablePacket, Packet};
+    use pnet::packet::icmp::{IcmpCode, IcmpPacket, IcmpType, IcmpTypes, MutableIcmpPacket,
+                             checksum};
+    use pnet::packet::icmp::echo_reply::EchoReplyPacket;
+    use pnet::packet::icmp::echo_request::{EchoRequestPacket, MutableEchoRequestPacket};
     use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+
+    use {Poll, RxError, RxResult, Wake};
+    use ipv4::Ipv4Listener;
+
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, SystemTime};
 
     use super::*;
     use testing::ipv4::Ipv4Tx;
@@ -48,4 +76,439 @@ mod tests {
         assert_eq!(echo_pkg.get_checksum(), 61128);
         assert_eq!(echo_pkg.payload(), [9, 55]);
     }
+
+    #[test]
+    fn echo_responder() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let (ipv4, read_handle) = Ipv4Tx::new();
+        let mut ipv4 = Some(ipv4);
+        let state = Arc::new(Mutex::new(EchoState::default()));
+        let mut responder = EchoResponder::new(local_ip,
+                                               state,
+                                               Box::new(move |src, dst| {
+                                                   assert_eq!((src, dst), (local_ip, remote_ip));
+                                                   ipv4.take()
+                                               }));
+
+        let request = echo_packet(remote_ip, local_ip, IcmpTypes::EchoRequest, 7, 3, &[1, 2, 3]);
+        let ip_pkg = Ipv4Packet::new(&request).unwrap();
+        deliver(&mut responder, &ip_pkg);
+
+        let (next_level_protocol, data) = read_handle.try_recv().unwrap();
+        assert_eq!(next_level_protocol, IpNextHeaderProtocols::Icmp);
+        let reply_pkg = EchoReplyPacket::new(&data).unwrap();
+        assert_eq!(reply_pkg.get_icmp_type(), IcmpTypes::EchoReply);
+        assert_eq!(reply_pkg.get_identifier(), 7);
+        assert_eq!(reply_pkg.get_sequence_number(), 3);
+        assert_eq!(reply_pkg.payload(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn echo_responder_settings() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let (ipv4, read_handle) = Ipv4Tx::new();
+        let mut ipv4 = Some(ipv4);
+        let state = Arc::new(Mutex::new(EchoState::default()));
+        let mut responder = EchoResponder::new(local_ip,
+                                               state.clone(),
+                                               Box::new(move |_, _| ipv4.take()));
+
+        // Sent to a multicast group mapped to the local address
+        let request = echo_packet(remote_ip,
+                                    Ipv4Addr::new(224, 0, 0, 1),
+                                    IcmpTypes::EchoRequest,
+                                    1,
+                                    1,
+                                    &[]);
+        deliver(&mut responder, &Ipv4Packet::new(&request).unwrap());
+        assert!(read_handle.try_recv().is_err());
+
+        let mut settings = EchoSettings::default();
+        settings.enabled = false;
+        state.lock().unwrap().set_settings(settings);
+        let request = echo_packet(remote_ip, local_ip, IcmpTypes::EchoRequest, 1, 1, &[]);
+        deliver(&mut responder, &Ipv4Packet::new(&request).unwrap());
+        assert!(read_handle.try_recv().is_err());
+
+        let mut settings = EchoSettings::default();
+        settings.rate_limit = 1;
+        settings.burst = 1;
+        state.lock().unwrap().set_settings(settings);
+        deliver(&mut responder, &Ipv4Packet::new(&request).unwrap());
+        assert!(read_handle.try_recv().is_ok());
+    }
+
+    #[test]
+    fn ping_rx() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let (queue, rx) = PingQueue::new(PING_QUEUE_LEN);
+        let mut pingers = HashMap::new();
+        pingers.insert(7, queue);
+        let mut ping_rx = PingRx::new(Arc::new(Mutex::new(pingers)));
+
+        let reply = echo_packet(remote_ip, local_ip, IcmpTypes::EchoReply, 7, 3, &[1, 2]);
+        deliver(&mut ping_rx, &Ipv4Packet::new(&reply).unwrap());
+        match rx.try_recv().unwrap() {
+            PingEvent::Reply { sequence_number, from, payload, .. } => {
+                assert_eq!(sequence_number, 3);
+                assert_eq!(from, remote_ip);
+                assert_eq!(payload, vec![1, 2]);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        // Reply for another identifier
+        let reply = echo_packet(remote_ip, local_ip, IcmpTypes::EchoReply, 8, 3, &[]);
+        deliver(&mut ping_rx, &Ipv4Packet::new(&reply).unwrap());
+        assert!(rx.try_recv().is_none());
+
+        // Destination Unreachable from a router, quoting the request
+        let router_ip = Ipv4Addr::new(10, 0, 0, 254);
+        let request = echo_packet(local_ip, remote_ip, IcmpTypes::EchoRequest, 7, 4, &[]);
+        let mut error_payload = vec![0; 4];
+        error_payload.extend_from_slice(&request[..28]);
+        let error = echo_packet(router_ip, local_ip, IcmpTypes::DestinationUnreachable, 0, 0, &[]);
+        let mut error = error[..24].to_vec();
+        error.extend_from_slice(&error_payload);
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(&mut error).unwrap();
+            let total_length = 20 + 4 + 4 + 28;
+            ip_pkg.set_total_length(total_length);
+        }
+        deliver(&mut ping_rx, &Ipv4Packet::new(&error).unwrap());
+        match rx.try_recv().unwrap() {
+            PingEvent::Error { sequence_number, from, icmp_type, .. } => {
+                assert_eq!(sequence_number, 4);
+                assert_eq!(from, router_ip);
+                assert_eq!(icmp_type, IcmpTypes::DestinationUnreachable);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn ping_rx_queue_full() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let (queue, rx) = PingQueue::new(2);
+        let mut pingers = HashMap::new();
+        pingers.insert(7, queue.clone());
+        let mut ping_rx = PingRx::new(Arc::new(Mutex::new(pingers)));
+
+        for sequence_number in 0..3 {
+            let reply =
+                echo_packet(remote_ip, local_ip, IcmpTypes::EchoReply, 7, sequence_number, &[]);
+            deliver(&mut ping_rx, &Ipv4Packet::new(&reply).unwrap());
+        }
+        assert_eq!(queue.dropped(), 1);
+        for expected in 0..2 {
+            match rx.try_recv().unwrap() {
+                PingEvent::Reply { sequence_number, .. } => assert_eq!(sequence_number, expected),
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+        assert!(rx.try_recv().is_none());
+    }
+
+    #[test]
+    fn icmp_message() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let request = echo_packet(remote_ip, local_ip, IcmpTypes::EchoRequest, 7, 3, &[1, 2]);
+        let message = IcmpMessage::parse(&request[20..]).unwrap();
+        assert_eq!(message,
+                   IcmpMessage::EchoRequest {
+                       identifier: 7,
+                       sequence_number: 3,
+                       payload: &[1, 2],
+                   });
+        assert!(!message.is_error());
+
+        // Redirect to 10.0.0.3 quoting the request
+        let mut redirect = vec![5, 1, 0, 0, 10, 0, 0, 3];
+        redirect.extend_from_slice(&request[..28]);
+        let message = IcmpMessage::parse(&redirect).unwrap();
+        assert_eq!(message.icmp_type(), IcmpTypes::RedirectMessage);
+        assert_eq!(message.icmp_code(), IcmpCode(1));
+        match message {
+            IcmpMessage::Redirect { gateway, .. } => {
+                assert_eq!(gateway, Ipv4Addr::new(10, 0, 0, 3))
+            }
+            _ => panic!("Unexpected message {:?}", message),
+        }
+        let (quoted_pkg, quoted_payload) = message.quoted_packet().unwrap();
+        assert_eq!(quoted_pkg.get_source(), remote_ip);
+        assert_eq!(quoted_payload, &request[20..28]);
+
+        // Errors must quote at least an IP header
+        assert_eq!(IcmpMessage::parse(&redirect[..27]), Err(RxError::InvalidLength));
+        assert_eq!(IcmpMessage::parse(&[8, 0, 0, 0]), Err(RxError::InvalidLength));
+    }
+
+    #[test]
+    fn icmp_rx() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let (tx, rx) = mpsc::channel();
+        let mut listeners = HashMap::new();
+        add_icmp_listener(&mut listeners,
+                          IcmpFilter::new(IcmpTypes::DestinationUnreachable, Some(IcmpCode(3))),
+                          Box::new(MockIcmpListener { tx: tx.clone() }));
+        add_icmp_listener(&mut listeners,
+                          IcmpTypes::EchoReply.into(),
+                          Box::new(MockIcmpListener { tx: tx }));
+        let mut icmp_rx = IcmpRx::new(Arc::new(Mutex::new(listeners)));
+
+        let reply = echo_packet(remote_ip, local_ip, IcmpTypes::EchoReply, 7, 3, &[]);
+        assert_eq!(recv(&mut icmp_rx, &reply), Ok(()));
+        assert_eq!(rx.try_recv().unwrap(), IcmpTypes::EchoReply);
+
+        let mut corrupt = reply.clone();
+        corrupt[27] ^= 1;
+        assert_eq!(recv(&mut icmp_rx, &corrupt), Err(RxError::InvalidChecksum));
+
+        // Only port unreachable is listened to
+        let request = echo_packet(local_ip, remote_ip, IcmpTypes::EchoRequest, 7, 3, &[]);
+        for code in 2..4 {
+            let mut error = request[..24].to_vec();
+            error.extend_from_slice(&[0; 4]);
+            error.extend_from_slice(&request[..28]);
+            {
+                let mut ip_pkg = MutableIpv4Packet::new(&mut error).unwrap();
+                ip_pkg.set_total_length(20 + 8 + 28);
+                let mut icmp_pkg = MutableIcmpPacket::new(ip_pkg.payload_mut()).unwrap();
+                icmp_pkg.set_icmp_type(IcmpTypes::DestinationUnreachable);
+                icmp_pkg.set_icmp_code(IcmpCode(code));
+            }
+            set_icmp_checksum(&mut error);
+            let result = recv(&mut icmp_rx, &error);
+            if code == 3 {
+                assert_eq!(result, Ok(()));
+                assert_eq!(rx.try_recv().unwrap(), IcmpTypes::DestinationUnreachable);
+            } else {
+                assert!(result.is_err());
+            }
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn remove_listener() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let (tx, rx) = mpsc::channel();
+        let mut listeners = HashMap::new();
+        let first = add_icmp_listener(&mut listeners,
+                                      IcmpTypes::EchoReply.into(),
+                                      Box::new(MockIcmpListener { tx: tx.clone() }));
+        let second = add_icmp_listener(&mut listeners,
+                                       IcmpTypes::EchoReply.into(),
+                                       Box::new(MockIcmpListener { tx: tx }));
+        assert!(first != second);
+        let listeners = Arc::new(Mutex::new(listeners));
+        let mut icmp_rx = IcmpRx::new(listeners.clone());
+        let reply = echo_packet(remote_ip, local_ip, IcmpTypes::EchoReply, 7, 3, &[]);
+
+        assert!(remove_icmp_listener(&mut listeners.lock().unwrap(), first));
+        assert!(!remove_icmp_listener(&mut listeners.lock().unwrap(), first));
+        assert_eq!(recv(&mut icmp_rx, &reply), Ok(()));
+        assert_eq!(rx.try_recv().unwrap(), IcmpTypes::EchoReply);
+        assert!(rx.try_recv().is_err());
+
+        assert!(remove_icmp_listener(&mut listeners.lock().unwrap(), second));
+        assert!(listeners.lock().unwrap().is_empty());
+        assert!(recv(&mut icmp_rx, &reply).is_err());
+    }
+
+    #[test]
+    fn icmp_queue() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let (mut queue, reader) = IcmpQueue::new(2);
+        let woken = Arc::new(AtomicUsize::new(0));
+        let waker = {
+            let woken = woken.clone();
+            Arc::new(move || {
+                woken.fetch_add(1, Ordering::SeqCst);
+            }) as Arc<Wake>
+        };
+
+        assert!(!reader.poll_recv(waker.clone()).is_ready());
+        for sequence_number in 0..3 {
+            let reply =
+                echo_packet(remote_ip, local_ip, IcmpTypes::EchoReply, 7, sequence_number, &[]);
+            deliver(&mut queue, &Ipv4Packet::new(&reply).unwrap());
+        }
+        assert_eq!(woken.load(Ordering::SeqCst), 1);
+        assert_eq!(reader.dropped(), 1);
+
+        match reader.poll_recv(waker.clone()) {
+            Poll::Ready((_, packet)) => {
+                let ip_pkg = Ipv4Packet::new(&packet).unwrap();
+                assert_eq!(ip_pkg.get_source(), remote_ip);
+                match IcmpMessage::parse(ip_pkg.payload()).unwrap() {
+                    IcmpMessage::EchoReply { sequence_number, .. } => {
+                        assert_eq!(sequence_number, 0)
+                    }
+                    message => panic!("Unexpected message {:?}", message),
+                }
+            }
+            Poll::Pending => panic!("Expected a packet"),
+        }
+        assert!(reader.recv(Some(Duration::new(0, 0))).is_some());
+        assert!(reader.recv(Some(Duration::from_millis(10))).is_none());
+    }
+
+    #[test]
+    fn error_tx() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let (ipv4, read_handle) = Ipv4Tx::new();
+        let state = Arc::new(Mutex::new(IcmpErrorState::default()));
+        let error_tx = IcmpErrorTx::new(state,
+                                        Box::new(move |src, dst| {
+                                            assert_eq!((src, dst), (local_ip, remote_ip));
+                                            Some(ipv4.clone())
+                                        }));
+
+        let mut datagram = vec![0; 20 + 12];
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(&mut datagram).unwrap();
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(5);
+            ip_pkg.set_total_length(20 + 12);
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+            ip_pkg.set_source(remote_ip);
+            ip_pkg.set_destination(local_ip);
+            ip_pkg.payload_mut().copy_from_slice(&[0, 1, 0, 2, 0, 12, 0, 0, 9, 9, 9, 9]);
+        }
+        error_tx.port_unreachable(&Ipv4Packet::new(&datagram).unwrap());
+
+        let (next_level_protocol, data) = read_handle.try_recv().unwrap();
+        assert_eq!(next_level_protocol, IpNextHeaderProtocols::Icmp);
+        let icmp_pkg = IcmpPacket::new(&data).unwrap();
+        assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+        assert_eq!(icmp_pkg.get_icmp_code(), IcmpCode(3));
+        // Four unused bytes followed by the IP header and 8 bytes of payload
+        assert_eq!(&icmp_pkg.payload()[..4], &[0, 0, 0, 0]);
+        assert_eq!(&icmp_pkg.payload()[4..], &datagram[..28]);
+
+        error_tx.parameter_problem(&Ipv4Packet::new(&datagram).unwrap(), 9);
+        let (_, data) = read_handle.try_recv().unwrap();
+        let icmp_pkg = IcmpPacket::new(&data).unwrap();
+        assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::ParameterProblem);
+        assert_eq!(icmp_pkg.payload()[0], 9);
+    }
+
+    #[test]
+    fn error_tx_suppressed() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let (ipv4, read_handle) = Ipv4Tx::new();
+        let state = Arc::new(Mutex::new(IcmpErrorState::default()));
+        let error_tx = IcmpErrorTx::new(state.clone(), Box::new(move |_, _| Some(ipv4.clone())));
+
+        // Icmp errors are never answered with errors, queries are
+        let unreachable = echo_packet(remote_ip,
+                                      local_ip,
+                                      IcmpTypes::DestinationUnreachable,
+                                      0,
+                                      0,
+                                      &[]);
+        error_tx.protocol_unreachable(&Ipv4Packet::new(&unreachable).unwrap());
+        assert!(read_handle.try_recv().is_err());
+        let request = echo_packet(remote_ip, local_ip, IcmpTypes::EchoRequest, 0, 0, &[]);
+        error_tx.protocol_unreachable(&Ipv4Packet::new(&request).unwrap());
+        assert!(read_handle.try_recv().is_ok());
+
+        // Multicast destination, broadcast source
+        let request = echo_packet(remote_ip,
+                                  Ipv4Addr::new(224, 0, 0, 1),
+                                  IcmpTypes::EchoRequest,
+                                  0,
+                                  0,
+                                  &[]);
+        error_tx.protocol_unreachable(&Ipv4Packet::new(&request).unwrap());
+        let request = echo_packet(Ipv4Addr::new(255, 255, 255, 255),
+                                  local_ip,
+                                  IcmpTypes::EchoRequest,
+                                  0,
+                                  0,
+                                  &[]);
+        error_tx.protocol_unreachable(&Ipv4Packet::new(&request).unwrap());
+        assert!(read_handle.try_recv().is_err());
+
+        // Non-initial fragment
+        let mut request = echo_packet(remote_ip, local_ip, IcmpTypes::EchoRequest, 0, 0, &[]);
+        MutableIpv4Packet::new(&mut request).unwrap().set_fragment_offset(10);
+        error_tx.protocol_unreachable(&Ipv4Packet::new(&request).unwrap());
+        assert!(read_handle.try_recv().is_err());
+
+        // Rate limited
+        state.lock().unwrap().set_settings(IcmpErrorSettings {
+            enabled: true,
+            rate_limit: 1,
+            burst: 1,
+        });
+        let request = echo_packet(remote_ip, local_ip, IcmpTypes::EchoRequest, 0, 0, &[]);
+        error_tx.protocol_unreachable(&Ipv4Packet::new(&request).unwrap());
+        error_tx.protocol_unreachable(&Ipv4Packet::new(&request).unwrap());
+        assert!(read_handle.try_recv().is_ok());
+        assert!(read_handle.try_recv().is_err());
+    }
+
+    struct MockIcmpListener {
+        tx: mpsc::Sender<IcmpType>,
+    }
+
+    impl IcmpListener for MockIcmpListener {
+        fn recv(&mut self, _time: SystemTime, _packet: &Ipv4Packet, message: &IcmpMessage) {
+            self.tx.send(message.icmp_type()).unwrap();
+        }
+    }
+
+    fn recv(icmp_rx: &mut IcmpRx, data: &[u8]) -> RxResult {
+        icmp_rx.recv(SystemTime::now(), Ipv4Packet::new(data).unwrap())
+    }
+
+    fn echo_packet(src: Ipv4Addr,
+                   dst: Ipv4Addr,
+                   icmp_type: IcmpType,
+                   identifier: u16,
+                   sequence_number: u16,
+                   payload: &[u8])
+                   -> Vec<u8> {
+        let mut buffer = vec![0; 20 + 8 + payload.len()];
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(&mut buffer).unwrap();
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(5);
+            ip_pkg.set_total_length((20 + 8 + payload.len()) as u16);
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+            ip_pkg.set_source(src);
+            ip_pkg.set_destination(dst);
+            let mut echo_pkg = MutableEchoRequestPacket::new(ip_pkg.payload_mut()).unwrap();
+            echo_pkg.set_icmp_type(icmp_type);
+            echo_pkg.set_identifier(identifier);
+            echo_pkg.set_sequence_number(sequence_number);
+            echo_pkg.set_payload(payload);
+        }
+        set_icmp_checksum(&mut buffer);
+        buffer
+    }
+
+    fn set_icmp_checksum(buffer: &mut [u8]) {
+        let mut icmp_pkg = MutableIcmpPacket::new(&mut buffer[20..]).unwrap();
+        let csum = checksum(&icmp_pkg.to_immutable());
+        icmp_pkg.set_checksum(csum);
+    }
+
+    /// Decodes `ip_pkg` the way `IcmpRx` does and passes it to `listener`.
+    fn deliver<L: IcmpListener>(listener: &mut L, ip_pkg: &Ipv4Packet) {
+        let message = IcmpMessage::parse(ip_pkg.payload()).unwrap();
+        listener.recv(SystemTime::now(), ip_pkg, &message);
+    }
 }
@@ -0,0 +1,123 @@
+use pnet::packet::icmp::{IcmpCode, IcmpType, IcmpTypes};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use {QueueReceiver, QueueSender, bounded_queue};
+use util::read_u16;
+
+use super::{IcmpListener, IcmpMessage};
+
+/// Something received in response to an Echo Request sent by a `Pinger`.
+#[derive(Debug)]
+pub enum PingEvent {
+    Reply {
+        sequence_number: u16,
+        from: Ipv4Addr,
+        ttl: u8,
+        time: SystemTime,
+        payload: Vec<u8>,
+    },
+    Error {
+        sequence_number: u16,
+        from: Ipv4Addr,
+        icmp_type: IcmpType,
+        icmp_code: IcmpCode,
+    },
+}
+
+/// How many events are queued for a `Pinger` by default before new ones are
+/// dropped.
+pub const PING_QUEUE_LEN: usize = 64;
+
+/// Bounded queue of the events for one `Pinger`. Events arriving when it is
+/// full are dropped and counted, so a `Pinger` not reading can not make the
+/// stack buffer replies without limit.
+#[derive(Clone)]
+pub struct PingQueue {
+    queue: QueueSender<PingEvent>,
+}
+
+impl PingQueue {
+    /// Creates a queue holding at most `len` events, and the receiver they
+    /// are read from.
+    pub fn new(len: usize) -> (PingQueue, QueueReceiver<PingEvent>) {
+        let (queue, rx) = bounded_queue(len);
+        (PingQueue { queue: queue }, rx)
+    }
+
+    /// Queues `event`, or drops it if the queue is full.
+    pub fn send(&self, event: PingEvent) {
+        self.queue.send(event)
+    }
+
+    /// Returns how many events were dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.queue.dropped()
+    }
+}
+
+/// Type binding for how `PingRx` finds the `Pinger` an identifier belongs to.
+pub type PingerLookup = HashMap<u16, PingQueue>;
+
+/// `IcmpListener` delivering Echo Replies, and Icmp errors caused by Echo
+/// Requests, to the `Pinger` with the matching identifier. The stack
+/// registers one for every local address.
+pub struct PingRx {
+    pingers: Arc<Mutex<PingerLookup>>,
+}
+
+impl PingRx {
+    pub fn new(pingers: Arc<Mutex<PingerLookup>>) -> PingRx {
+        PingRx { pingers: pingers }
+    }
+
+    /// Returns the identifier and the event for the Echo Request `message`
+    /// responds to, if it's a valid response to one.
+    fn parse(time: SystemTime,
+             ip_pkg: &Ipv4Packet,
+             message: &IcmpMessage)
+             -> Option<(u16, PingEvent)> {
+        if let IcmpMessage::EchoReply { identifier, sequence_number, payload } = *message {
+            let event = PingEvent::Reply {
+                sequence_number: sequence_number,
+                from: ip_pkg.get_source(),
+                ttl: ip_pkg.get_ttl(),
+                time: time,
+                payload: payload.to_vec(),
+            };
+            return Some((identifier, event));
+        }
+
+        let echo = match message.quoted_packet() {
+            Some((ref original_pkg, echo)) if original_pkg.get_next_level_protocol() ==
+                                              IpNextHeaderProtocols::Icmp => echo,
+            _ => return None,
+        };
+        if echo[0] != IcmpTypes::EchoRequest.0 {
+            return None;
+        }
+        let event = PingEvent::Error {
+            sequence_number: read_u16(&echo[6..8]),
+            from: ip_pkg.get_source(),
+            icmp_type: message.icmp_type(),
+            icmp_code: message.icmp_code(),
+        };
+        Some((read_u16(&echo[4..6]), event))
+    }
+}
+
+impl IcmpListener for PingRx {
+    fn recv(&mut self, time: SystemTime, ip_pkg: &Ipv4Packet, message: &IcmpMessage) {
+        if let Some((identifier, event)) = Self::parse(time, ip_pkg, message) {
+            let pingers = self.pingers.lock().unwrap();
+            if let Some(pinger) = pingers.get(&identifier) {
+                pinger.send(event);
+            }
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use {DEFAULT_DOMAIN, Flow, NetworkStack, Poll, QueueReceiver, TxError, Wake};
+use util::{read_u32, write_u32};
+
+use pnet::packet::icmp::{IcmpCode, IcmpType};
+use pnet::packet::ip::IpNextHeaderProtocols;
+
+use std::cmp;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::{IcmpTx, PING_QUEUE_LEN, PingEvent, PingQueue};
+use super::resolve::send_when_resolved;
+
+/// Size of the send time a `Pinger` puts first in the payload of its Echo
+/// Requests.
+pub const TIMESTAMP_LEN: usize = 12;
+
+/// Payload size used by a `Pinger` unless changed. Same as most ping
+/// programs, giving 64 byte Icmp packets.
+pub const DEFAULT_PING_PAYLOAD_SIZE: usize = 56;
+
+/// Outcome of one Echo Request sent by a `Pinger`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PingOutcome {
+    /// An Echo Reply came back.
+    Reply {
+        from: Ipv4Addr,
+        rtt: Duration,
+        ttl: u8,
+    },
+    /// An Icmp error, such as Destination Unreachable, came back.
+    Error {
+        from: Ipv4Addr,
+        icmp_type: IcmpType,
+        icmp_code: IcmpCode,
+    },
+    /// Nothing came back before the timeout.
+    Timeout,
+}
+
+/// The result of `Pinger::ping`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PingProbe {
+    pub sequence_number: u16,
+    pub outcome: PingOutcome,
+}
+
+/// Summary of all probes sent by a `Pinger`.
+#[derive(Clone, Debug, Default)]
+pub struct PingStatistics {
+    pub transmitted: u32,
+    pub received: u32,
+    pub errors: u32,
+    pub min_rtt: Option<Duration>,
+    pub max_rtt: Option<Duration>,
+    total_rtt: Duration,
+}
+
+impl PingStatistics {
+    /// Fraction, between 0 and 1, of the sent Echo Requests that did not get
+    /// a reply.
+    pub fn loss(&self) -> f64 {
+        if self.transmitted == 0 {
+            0.0
+        } else {
+            (self.transmitted - self.received) as f64 / self.transmitted as f64
+        }
+    }
+
+    pub fn avg_rtt(&self) -> Option<Duration> {
+        if self.received == 0 {
+            None
+        } else {
+            Some(self.total_rtt / self.received)
+        }
+    }
+
+    fn add(&mut self, outcome: &PingOutcome) {
+        self.transmitted += 1;
+        match *outcome {
+            PingOutcome::Reply { rtt, .. } => {
+                self.received += 1;
+                self.total_rtt += rtt;
+                self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| cmp::min(min, rtt)));
+                self.max_rtt = Some(self.max_rtt.map_or(rtt, |max| cmp::max(max, rtt)));
+            }
+            PingOutcome::Error { .. } => self.errors += 1,
+            PingOutcome::Timeout => (),
+        }
+    }
+}
+
+/// Library level ping. Sends numbered Echo Requests to one destination, with
+/// the send time first in the payload, and matches replies and Icmp errors to
+/// them. Every `Pinger` gets its own identifier, so many can run at the same
+/// time.
+pub struct Pinger {
+    stack: Arc<Mutex<NetworkStack>>,
+    domain: String,
+    local_ip: Ipv4Addr,
+    dst: Ipv4Addr,
+    identifier: u16,
+    next_sequence: u16,
+    timeout: Duration,
+    payload_size: usize,
+    rx: QueueReceiver<PingEvent>,
+    statistics: PingStatistics,
+}
+
+impl Pinger {
+    /// Creates a `Pinger` sending to `dst` from the local address the route
+    /// to `dst` selects.
+    pub fn new(stack: Arc<Mutex<NetworkStack>>, dst: Ipv4Addr) -> io::Result<Pinger> {
+        Self::new_in(stack, DEFAULT_DOMAIN, dst)
+    }
+
+    /// Same as `new` but routes in the given routing domain.
+    pub fn new_in(stack: Arc<Mutex<NetworkStack>>,
+                  domain: &str,
+                  dst: Ipv4Addr)
+                  -> io::Result<Pinger> {
+        let (queue, rx) = PingQueue::new(PING_QUEUE_LEN);
+        let (local_ip, identifier) = {
+            let mut stack = stack.lock().unwrap();
+            let flow = Flow::new(None, IpNextHeaderProtocols::Icmp);
+            let local_ip = try!(stack.source_ip_in(domain, dst, &flow));
+            let identifier = try!(stack.ping_register_in(domain, local_ip, queue));
+            (local_ip, identifier)
+        };
+        Ok(Pinger {
+            stack: stack,
+            domain: domain.to_owned(),
+            local_ip: local_ip,
+            dst: dst,
+            identifier: identifier,
+            next_sequence: 0,
+            timeout: Duration::new(1, 0),
+            payload_size: DEFAULT_PING_PAYLOAD_SIZE,
+            rx: rx,
+            statistics: PingStatistics::default(),
+        })
+    }
+
+    pub fn identifier(&self) -> u16 {
+        self.identifier
+    }
+
+    /// Returns the address the Echo Requests are sent from.
+    pub fn local_ip(&self) -> Ipv4Addr {
+        self.local_ip
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Sets how long `ping` waits for a reply.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets the payload size of the Echo Requests. Never smaller than
+    /// `TIMESTAMP_LEN`, the space needed for the send time.
+    pub fn set_payload_size(&mut self, payload_size: usize) {
+        self.payload_size = cmp::max(payload_size, TIMESTAMP_LEN);
+    }
+
+    pub fn statistics(&self) -> &PingStatistics {
+        &self.statistics
+    }
+
+    /// Returns how many replies and errors were dropped because more than
+    /// `PING_QUEUE_LEN` of them were waiting to be read.
+    pub fn dropped(&self) -> usize {
+        self.rx.dropped()
+    }
+
+    /// Sends the next Echo Request and waits until its reply or an Icmp
+    /// error comes back, or the timeout expires. The timeout includes
+    /// resolving the next hop, so a next hop that never answers Arp makes the
+    /// probe time out.
+    pub fn ping(&mut self) -> io::Result<PingProbe> {
+        let sequence_number = self.next_sequence;
+        self.next_sequence = sequence_number.wrapping_add(1);
+        let deadline = Instant::now() + self.timeout;
+        let flow = self.flow();
+        let sent = send_when_resolved(&self.stack,
+                                      &self.domain,
+                                      self.dst,
+                                      &flow,
+                                      deadline,
+                                      |stack| self.send(stack, sequence_number));
+        let outcome = match sent {
+            Ok(()) => self.wait(sequence_number, deadline),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => PingOutcome::Timeout,
+            Err(e) => return Err(e),
+        };
+        self.statistics.add(&outcome);
+        Ok(PingProbe {
+            sequence_number: sequence_number,
+            outcome: outcome,
+        })
+    }
+
+    /// Sends the next Echo Request without blocking or waiting for the
+    /// response, for async runtimes. Returns its sequence number, the
+    /// response is read with `poll_event`. When the next hop is not resolved
+    /// yet it returns `Poll::Pending` after sending the Arp request, and
+    /// `waker` is woken when the reply arrives. Not counted in the
+    /// statistics.
+    pub fn poll_send_request(&mut self, waker: Arc<Wake>) -> Poll<io::Result<u16>> {
+        let sequence_number = self.next_sequence;
+        let mut stack = self.stack.lock().unwrap();
+        match self.send(&mut stack, sequence_number) {
+            Ok(()) => {
+                self.next_sequence = sequence_number.wrapping_add(1);
+                Poll::Ready(Ok(sequence_number))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                match stack.wake_when_routable_in(&self.domain, self.dst, &self.flow(), waker) {
+                    Ok(()) => Poll::Pending,
+                    Err(e) => Poll::Ready(Err(e.into())),
+                }
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Returns the next Echo Reply or Icmp error for this `Pinger` without
+    /// blocking. When there is none it returns `Poll::Pending`, and `waker`
+    /// is woken when one arrives.
+    pub fn poll_event(&self, waker: Arc<Wake>) -> Poll<PingEvent> {
+        self.rx.poll_recv(waker)
+    }
+
+    /// Sends the Echo Request with `sequence_number`. Fails with
+    /// `WouldBlock` after sending an Arp request if the next hop is not
+    /// resolved yet.
+    fn send(&self, stack: &mut NetworkStack, sequence_number: u16) -> io::Result<()> {
+        let flow = self.flow();
+        let no_wait = Some(Duration::new(0, 0));
+        loop {
+            let ipv4_tx = try!(stack.ipv4_tx_in_timeout(&self.domain, self.dst, &flow, no_wait));
+            let payload = self.payload(SystemTime::now());
+            match IcmpTx::new(ipv4_tx).send_echo_request(self.identifier,
+                                                        sequence_number,
+                                                        &payload) {
+                Err(TxError::InvalidTx) => continue,
+                result => return result.map_err(|e| e.into()),
+            }
+        }
+    }
+
+    fn flow(&self) -> Flow {
+        Flow::new(Some(self.local_ip), IpNextHeaderProtocols::Icmp)
+    }
+
+    fn wait(&self, sequence_number: u16, deadline: Instant) -> PingOutcome {
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return PingOutcome::Timeout;
+            }
+            // Responses to earlier requests, that already timed out, are
+            // skipped
+            match self.rx.recv(Some(deadline - now)) {
+                Some(PingEvent::Reply { sequence_number: seq, from, ttl, time, payload }) => {
+                    if seq == sequence_number {
+                        return PingOutcome::Reply {
+                            from: from,
+                            rtt: rtt(time, &payload),
+                            ttl: ttl,
+                        };
+                    }
+                }
+                Some(PingEvent::Error { sequence_number: seq, from, icmp_type, icmp_code }) => {
+                    if seq == sequence_number {
+                        return PingOutcome::Error {
+                            from: from,
+                            icmp_type: icmp_type,
+                            icmp_code: icmp_code,
+                        };
+                    }
+                }
+                None => return PingOutcome::Timeout,
+            }
+        }
+    }
+
+    fn payload(&self, now: SystemTime) -> Vec<u8> {
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+        let mut payload = Vec::with_capacity(self.payload_size);
+        write_u32(&mut payload, (since_epoch.as_secs() >> 32) as u32);
+        write_u32(&mut payload, since_epoch.as_secs() as u32);
+        write_u32(&mut payload, since_epoch.subsec_nanos());
+        for i in TIMESTAMP_LEN..self.payload_size {
+            payload.push(i as u8);
+        }
+        payload
+    }
+}
+
+impl Drop for Pinger {
+    fn drop(&mut self) {
+        if let Ok(mut stack) = self.stack.lock() {
+            stack.ping_unregister_in(&self.domain, self.local_ip, self.identifier);
+        }
+    }
+}
+
+/// Returns the time between the send time in `payload` and `received`.
+fn rtt(received: SystemTime, payload: &[u8]) -> Duration {
+    let zero = Duration::new(0, 0);
+    if payload.len() < TIMESTAMP_LEN {
+        return zero;
+    }
+    let secs = ((read_u32(&payload[0..4]) as u64) << 32) | read_u32(&payload[4..8]) as u64;
+    let nanos = read_u32(&payload[8..12]);
+    if nanos >= 1_000_000_000 {
+        return zero;
+    }
+    let received = received.duration_since(UNIX_EPOCH).unwrap_or(zero);
+    received.checked_sub(Duration::new(secs, nanos)).unwrap_or(zero)
+}
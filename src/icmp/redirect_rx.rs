@@ -0,0 +1,268 @@
+use {Flow, Interface};
+use routing::SharedRouting;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{IcmpListener, IcmpMessage};
+use util;
+
+/// How long a host route learned from a Redirect is kept, in seconds.
+pub const REDIRECT_TIMEOUT_SECS: u64 = 300;
+
+/// Most host routes from Redirects kept per interface. The oldest one is
+/// removed to make room for a new one.
+pub const MAX_REDIRECTS: usize = 1024;
+
+/// How often the routes are checked for changes to the routes the
+/// redirects replaced, in milliseconds.
+const REDIRECT_CHECK_MS: u64 = 1000;
+
+struct Redirect {
+    gw: Ipv4Addr,
+    replaced_gw: Ipv4Addr,
+    added: Instant,
+}
+
+struct RedirectData {
+    redirects: HashMap<Ipv4Addr, Redirect>,
+    checked_generation: usize,
+    expiring: bool,
+}
+
+/// The host routes added for Redirects received on one interface. Removes
+/// them after `REDIRECT_TIMEOUT_SECS`, or as soon as the route they replaced
+/// in the main table no longer goes through the redirecting gateway.
+#[derive(Clone)]
+pub struct RedirectRoutes {
+    interface: Interface,
+    routing: Arc<Mutex<SharedRouting>>,
+    data: Arc<Mutex<RedirectData>>,
+}
+
+impl RedirectRoutes {
+    pub fn new(interface: Interface, routing: Arc<Mutex<SharedRouting>>) -> RedirectRoutes {
+        let data = RedirectData {
+            redirects: HashMap::new(),
+            checked_generation: 0,
+            expiring: false,
+        };
+        RedirectRoutes {
+            interface: interface,
+            routing: routing,
+            data: Arc::new(Mutex::new(data)),
+        }
+    }
+
+    /// Routes `dst` through `new_gw` instead of `gw`. Returns false if the
+    /// route through `gw` is not one in the main table that a host route can
+    /// replace.
+    fn add(&self, dst: Ipv4Addr, gw: Ipv4Addr, new_gw: Ipv4Addr) -> bool {
+        let mut data = self.data.lock().unwrap();
+        let mut table = self.routing.lock().unwrap().main_table();
+        let replaced_gw = match data.redirects.remove(&dst) {
+            Some(old) => {
+                table.remove_route(host(dst), Some(old.gw), &self.interface);
+                old.replaced_gw
+            }
+            None => {
+                let replaced = Some((Some(gw), self.interface.clone()));
+                if table.route(dst) != replaced || table.route_covering(host(dst)) != replaced {
+                    return false;
+                }
+                gw
+            }
+        };
+        if data.redirects.len() >= MAX_REDIRECTS {
+            let oldest = data.redirects.iter().min_by_key(|&(_, r)| r.added).map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                let old = data.redirects.remove(&oldest).unwrap();
+                table.remove_route(host(oldest), Some(old.gw), &self.interface);
+            }
+        }
+        table.add_route(host(dst), Some(new_gw), self.interface.clone());
+        let redirect = Redirect {
+            gw: new_gw,
+            replaced_gw: replaced_gw,
+            added: Instant::now(),
+        };
+        data.redirects.insert(dst, redirect);
+        if !data.expiring {
+            data.expiring = true;
+            let routes = self.clone();
+            util::spawn_timer(move || routes.expire());
+        }
+        true
+    }
+
+    /// Removes the host routes of the redirects that timed out or whose
+    /// replaced route changed. Returns when to check again, or `None` when
+    /// there are no redirects left.
+    fn expire(&self) -> Option<Instant> {
+        let mut data = self.data.lock().unwrap();
+        let routing = self.routing.lock().unwrap();
+        let mut table = routing.main_table();
+        let now = Instant::now();
+        let timeout = Duration::from_secs(REDIRECT_TIMEOUT_SECS);
+        let generation = routing.generation().current();
+        let changed = generation != data.checked_generation;
+        let stale = data.redirects
+            .iter()
+            .filter(|&(dst, r)| {
+                let replaced = Some((Some(r.replaced_gw), self.interface.clone()));
+                now.duration_since(r.added) >= timeout ||
+                (changed && table.route_covering(host(*dst)) != replaced)
+            })
+            .map(|(dst, _)| *dst)
+            .collect::<Vec<_>>();
+        for dst in stale {
+            let redirect = data.redirects.remove(&dst).unwrap();
+            debug!("Icmp: Removing redirect to {} for {}", redirect.gw, dst);
+            table.remove_route(host(dst), Some(redirect.gw), &self.interface);
+        }
+        data.checked_generation = routing.generation().current();
+        data.expiring = !data.redirects.is_empty();
+        if !data.expiring {
+            return None;
+        }
+        let next_check = now + Duration::from_millis(REDIRECT_CHECK_MS);
+        let next_timeout = data.redirects.values().map(|r| r.added + timeout).min();
+        Some(next_timeout.map_or(next_check, |t| cmp::min(t, next_check)))
+    }
+}
+
+/// `IcmpListener` turning Redirect messages into host routes in the main
+/// table of the routing domain. As RFC 1122 requires, a redirect is only
+/// accepted from the gateway currently used for the destination, and only
+/// to a new gateway on the same network. All redirects are treated as host
+/// redirects. The stack registers one for every local address, all sharing
+/// the `RedirectRoutes` of the interface.
+pub struct RedirectRx {
+    local_net: Ipv4Network,
+    interface: Interface,
+    routing: Arc<Mutex<SharedRouting>>,
+    routes: RedirectRoutes,
+    enabled: Arc<AtomicBool>,
+}
+
+impl RedirectRx {
+    /// Creates a `RedirectRx` for the local address and network `local_net`
+    /// on the interface of `routes`. Redirects are ignored while `enabled`
+    /// is false.
+    pub fn new(local_net: Ipv4Network,
+               routes: RedirectRoutes,
+               enabled: Arc<AtomicBool>)
+               -> RedirectRx {
+        RedirectRx {
+            local_net: local_net,
+            interface: routes.interface.clone(),
+            routing: routes.routing.clone(),
+            routes: routes,
+            enabled: enabled,
+        }
+    }
+
+    /// Returns the destination and the new gateway of the Redirect `message`
+    /// if it passes the sanity checks.
+    fn parse(&self,
+             ip_pkg: &Ipv4Packet,
+             message: &IcmpMessage)
+             -> Option<(Ipv4Addr, Ipv4Addr)> {
+        let new_gw = match *message {
+            IcmpMessage::Redirect { code, gateway, .. } if code.0 <= 3 => gateway,
+            _ => return None,
+        };
+        let (original_pkg, _) = match message.quoted_packet() {
+            Some(quoted) => quoted,
+            None => return None,
+        };
+        let local_ip = self.local_net.ip();
+        let dst = original_pkg.get_destination();
+        let gw = ip_pkg.get_source();
+        if original_pkg.get_source() != local_ip || dst.is_multicast() || dst.is_broadcast() {
+            return None;
+        }
+        if !self.local_net.contains(new_gw) || new_gw == local_ip || new_gw == gw {
+            return None;
+        }
+        let flow = Flow::new(Some(local_ip), original_pkg.get_next_level_protocol());
+        let routing = self.routing.lock().unwrap();
+        match routing.route(dst, &flow) {
+            Some((Some(current), ref out)) if current == gw && *out == self.interface => {
+                Some((dst, new_gw))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl IcmpListener for RedirectRx {
+    fn recv(&mut self, _time: SystemTime, ip_pkg: &Ipv4Packet, message: &IcmpMessage) {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return;
+        }
+        let gw = ip_pkg.get_source();
+        match self.parse(ip_pkg, message) {
+            Some((dst, new_gw)) if self.routes.add(dst, gw, new_gw) => {
+                debug!("Icmp: Redirected from {} to {} for {}", gw, new_gw, dst);
+            }
+            _ => debug!("Icmp: Ignoring Redirect from {}", gw),
+        }
+    }
+}
+
+fn host(ip: Ipv4Addr) -> Ipv4Network {
+    Ipv4Network::new(ip, 32).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use {Interface, RoutingPolicy};
+
+    use ipnetwork::Ipv4Network;
+
+    use pnet::util::MacAddr;
+
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn redirect_cap() {
+        let interface = Interface::new("eth0".to_owned(), MacAddr::new(1, 2, 3, 4, 5, 6));
+        let gw = Ipv4Addr::new(10, 0, 0, 1);
+        let new_gw = Ipv4Addr::new(10, 0, 0, 3);
+        let mut policy = RoutingPolicy::new();
+        policy.main_table()
+            .add_route(Ipv4Network::from_cidr("10.1.0.0/16").unwrap(),
+                       Some(gw),
+                       interface.clone());
+        let routing = Arc::new(Mutex::new(policy.shared()));
+        let routes = RedirectRoutes::new(interface.clone(), routing);
+
+        let dst = |i: usize| Ipv4Addr::new(10, 1, (i >> 8) as u8, i as u8);
+        assert!(routes.add(dst(0), gw, new_gw));
+        thread::sleep(Duration::from_millis(10));
+        for i in 1..MAX_REDIRECTS + 1 {
+            assert!(routes.add(dst(i), gw, new_gw));
+        }
+        // The oldest redirect made room for the last one
+        assert_eq!(routes.data.lock().unwrap().redirects.len(), MAX_REDIRECTS);
+        let table = policy.main_table();
+        assert_eq!(table.route(dst(0)), Some((Some(gw), interface.clone())));
+        assert_eq!(table.route(dst(MAX_REDIRECTS)), Some((Some(new_gw), interface.clone())));
+        // Only accepted in place of the route through the redirecting gateway
+        assert!(!routes.add(dst(0), new_gw, gw));
+    }
+}
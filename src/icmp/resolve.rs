@@ -0,0 +1,42 @@
+use {Flow, NetworkStack};
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::Instant;
+
+/// Calls `send` with the stack locked until it no longer fails with
+/// `WouldBlock`. In between it waits for the next hop to `dst` to be
+/// resolved, with the stack unlocked so the Arp reply can be processed and
+/// others can use the stack meanwhile. Fails with `TimedOut` if the next hop
+/// is not resolved before `deadline`. `send` must not wait for Arp itself.
+pub fn send_when_resolved<F>(stack: &Arc<Mutex<NetworkStack>>,
+                             domain: &str,
+                             dst: Ipv4Addr,
+                             flow: &Flow,
+                             deadline: Instant,
+                             mut send: F)
+                             -> io::Result<()>
+    where F: FnMut(&mut NetworkStack) -> io::Result<()>
+{
+    loop {
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut stack = stack.lock().unwrap();
+            match send(&mut *stack) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result,
+            }
+            let tx = Mutex::new(tx);
+            let waker = Arc::new(move || {
+                tx.lock().unwrap().send(()).unwrap_or(());
+            });
+            try!(stack.wake_when_routable_in(domain, dst, flow, waker));
+        }
+        let now = Instant::now();
+        if now >= deadline || rx.recv_timeout(deadline - now).is_err() {
+            let msg = format!("Next hop to {} not resolved in time", dst);
+            return Err(io::Error::new(io::ErrorKind::TimedOut, msg));
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use {DEFAULT_DOMAIN, Interface, NetworkStack, RoutingTable, RxError, StackResult, TxError};
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::Packet;
+use pnet::packet::icmp::{IcmpCode, IcmpTypes};
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{BasicIcmpProtocol, IcmpListener, IcmpListenerId, IcmpMessage, IcmpTx};
+
+/// Preference of routers that must never be used as default gateway.
+pub const INELIGIBLE_PREFERENCE: i32 = ::std::i32::MIN;
+
+/// Returns the multicast group Router Advertisements are sent to, 224.0.0.1.
+pub fn all_systems_group() -> Ipv4Addr {
+    Ipv4Addr::new(224, 0, 0, 1)
+}
+
+/// Returns the multicast group Router Solicitations are sent to, 224.0.0.2.
+pub fn all_routers_group() -> Ipv4Addr {
+    Ipv4Addr::new(224, 0, 0, 2)
+}
+
+/// A parsed Icmp Router Advertisement, as defined in RFC 1256.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouterAdvertisement {
+    /// How long the advertised addresses are valid.
+    pub lifetime: Duration,
+    /// The advertised router addresses and their preference as default
+    /// gateway. Higher is more preferred.
+    pub routers: Vec<(Ipv4Addr, i32)>,
+}
+
+impl RouterAdvertisement {
+    /// Parses a Router Advertisement from a complete Icmp message.
+    pub fn parse(data: &[u8]) -> Result<RouterAdvertisement, RxError> {
+        if data.len() < 8 {
+            return Err(RxError::InvalidLength);
+        }
+        if data[0] != IcmpTypes::RouterAdvertisement.0 || data[1] != 0 {
+            return Err(RxError::InvalidContent);
+        }
+        let num_addrs = data[4] as usize;
+        let entry_len = data[5] as usize * 4;
+        if num_addrs == 0 || entry_len < 8 {
+            return Err(RxError::InvalidContent);
+        }
+        if data.len() < 8 + num_addrs * entry_len {
+            return Err(RxError::InvalidLength);
+        }
+        let lifetime = ((data[6] as u64) << 8) | data[7] as u64;
+        let routers = data[8..8 + num_addrs * entry_len]
+            .chunks(entry_len)
+            .map(|entry| {
+                let ip = Ipv4Addr::new(entry[0], entry[1], entry[2], entry[3]);
+                let preference = ((entry[4] as u32) << 24) | ((entry[5] as u32) << 16) |
+                                 ((entry[6] as u32) << 8) |
+                                 entry[7] as u32;
+                (ip, preference as i32)
+            })
+            .collect();
+        Ok(RouterAdvertisement {
+            lifetime: Duration::from_secs(lifetime),
+            routers: routers,
+        })
+    }
+}
+
+/// Configuration for a `RouterDiscovery`.
+#[derive(Clone, Debug)]
+pub struct RouterDiscoveryConfig {
+    /// The local address to discover routers from. Routers on its network
+    /// are learned.
+    pub local_ip: Ipv4Addr,
+
+    /// The routing domain the local address is in. Default routes are
+    /// installed into the main table of this domain.
+    pub domain: String,
+
+    /// Number of Router Solicitations sent at startup, unless an
+    /// advertisement is heard before.
+    pub solicitations: u32,
+
+    /// Time between the Router Solicitations.
+    pub solicitation_interval: Duration,
+}
+
+impl RouterDiscoveryConfig {
+    /// Creates a config for discovering routers from `local_ip` with the
+    /// host defaults from RFC 1256.
+    pub fn new(local_ip: Ipv4Addr) -> RouterDiscoveryConfig {
+        RouterDiscoveryConfig {
+            local_ip: local_ip,
+            domain: DEFAULT_DOMAIN.to_owned(),
+            solicitations: 3,
+            solicitation_interval: Duration::new(3, 0),
+        }
+    }
+}
+
+/// A router learned by a `RouterDiscovery`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredRouter {
+    pub address: Ipv4Addr,
+    pub preference: i32,
+    /// When the router is forgotten unless advertised again.
+    pub expires: Instant,
+}
+
+enum Event {
+    Advertisement(Ipv4Addr, Vec<u8>),
+    Stop,
+}
+
+/// Icmp Router Discovery (RFC 1256) for hosts, running on top of a
+/// `NetworkStack`.
+///
+/// Sends Router Solicitations to 224.0.0.2 at startup and listens to Router
+/// Advertisements sent to the local address or to 224.0.0.1. Every
+/// advertised router on the local network is installed as a default route
+/// in the main table, with a metric derived from its preference so the most
+/// preferred router is used. Routers are removed when their advertised
+/// lifetime runs out. Since the metric of every learned route is above zero,
+/// a statically configured default gateway with metric zero takes
+/// precedence.
+///
+/// Runs in its own thread until `stop` is called or the `RouterDiscovery` is
+/// dropped. Learned routes are then removed from the stack.
+pub struct RouterDiscovery {
+    events: mpsc::Sender<Event>,
+    routers: Arc<Mutex<HashMap<Ipv4Addr, DiscoveredRouter>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RouterDiscovery {
+    /// Starts router discovery on the address in `config`.
+    pub fn spawn(stack: Arc<Mutex<NetworkStack>>,
+                 config: RouterDiscoveryConfig)
+                 -> io::Result<RouterDiscovery> {
+        let (events_tx, events_rx) = mpsc::channel();
+        let (net, interface, listener_id) = {
+            let mut stack = stack.lock().unwrap();
+            let (net, interface) = try!(find_link(&mut stack, &config));
+            let listener = AdvertisementListener { events: events_tx.clone() };
+            let listener_id = try!(stack.icmp_listen_id_in(&config.domain,
+                                                           config.local_ip,
+                                                           IcmpTypes::RouterAdvertisement,
+                                                           listener));
+            if let Err(e) = stack.join_ipv4_multicast_in(&config.domain,
+                                                         all_systems_group(),
+                                                         config.local_ip) {
+                stack.icmp_unlisten_id_in(&config.domain, config.local_ip, listener_id);
+                return Err(e.into());
+            }
+            (net, interface, listener_id)
+        };
+        let routers = Arc::new(Mutex::new(HashMap::new()));
+        let discovery = Discovery {
+            stack: stack,
+            net: net,
+            interface: interface,
+            listener_id: listener_id,
+            routers: routers.clone(),
+            solicitations_left: config.solicitations,
+            next_solicitation: Some(Instant::now()),
+            config: config,
+        };
+        let thread = thread::spawn(move || discovery.run(events_rx));
+        Ok(RouterDiscovery {
+            events: events_tx,
+            routers: routers,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the routers currently installed as default gateways.
+    pub fn routers(&self) -> Vec<DiscoveredRouter> {
+        self.routers.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Stops the discovery and waits for it to remove its routes from the
+    /// stack.
+    pub fn stop(mut self) {
+        self.events.send(Event::Stop).unwrap_or(());
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+impl Drop for RouterDiscovery {
+    fn drop(&mut self) {
+        self.events.send(Event::Stop).unwrap_or(());
+    }
+}
+
+/// Finds the network and interface of the local address in `config`.
+fn find_link(stack: &mut NetworkStack,
+             config: &RouterDiscoveryConfig)
+             -> io::Result<(Ipv4Network, Interface)> {
+    for interface in stack.interfaces() {
+        let stack_interface = try!(stack.interface(&interface));
+        if stack_interface.domain() != config.domain {
+            continue;
+        }
+        if let Some(net) = stack_interface.ipv4_nets()
+            .into_iter()
+            .find(|net| net.ip() == config.local_ip) {
+            return Ok((net, interface));
+        }
+    }
+    let msg = format!("{} does not exist in domain {}", config.local_ip, config.domain);
+    Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
+
+/// Passes Router Advertisements on to the discovery thread.
+struct AdvertisementListener {
+    events: mpsc::Sender<Event>,
+}
+
+impl IcmpListener for AdvertisementListener {
+    fn recv(&mut self, _time: SystemTime, ip_pkg: &Ipv4Packet, _message: &IcmpMessage) {
+        let event = Event::Advertisement(ip_pkg.get_source(), ip_pkg.payload().to_vec());
+        self.events.send(event).unwrap_or(());
+    }
+}
+
+/// The state of the discovery, living in its own thread.
+struct Discovery {
+    stack: Arc<Mutex<NetworkStack>>,
+    config: RouterDiscoveryConfig,
+    net: Ipv4Network,
+    interface: Interface,
+    listener_id: IcmpListenerId,
+    routers: Arc<Mutex<HashMap<Ipv4Addr, DiscoveredRouter>>>,
+    solicitations_left: u32,
+    next_solicitation: Option<Instant>,
+}
+
+impl Discovery {
+    fn run(mut self, events: mpsc::Receiver<Event>) {
+        loop {
+            let now = Instant::now();
+            let wait = match self.next_deadline() {
+                Some(deadline) if deadline > now => deadline - now,
+                Some(_) => Duration::new(0, 0),
+                // Nothing to do until an advertisement arrives
+                None => Duration::new(3600, 0),
+            };
+            match events.recv_timeout(wait) {
+                Ok(Event::Advertisement(from, data)) => self.handle_advertisement(from, &data),
+                Ok(Event::Stop) |
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+            }
+            self.handle_timers();
+        }
+        self.uninstall_all();
+        let mut stack = self.stack.lock().unwrap();
+        stack.icmp_unlisten_id_in(&self.config.domain, self.config.local_ip, self.listener_id);
+        stack.leave_ipv4_multicast_in(&self.config.domain,
+                                      all_systems_group(),
+                                      self.config.local_ip)
+            .unwrap_or(());
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let routers = self.routers.lock().unwrap();
+        routers.values()
+            .map(|router| router.expires)
+            .chain(self.next_solicitation)
+            .min()
+    }
+
+    fn handle_timers(&mut self) {
+        let now = Instant::now();
+        if let Some(next_solicitation) = self.next_solicitation {
+            if now >= next_solicitation {
+                self.send_solicitation();
+                self.solicitations_left = self.solicitations_left.saturating_sub(1);
+                self.next_solicitation = if self.solicitations_left > 0 {
+                    Some(now + self.config.solicitation_interval)
+                } else {
+                    None
+                };
+            }
+        }
+        let expired = {
+            let routers = self.routers.lock().unwrap();
+            routers.values()
+                .filter(|router| now >= router.expires)
+                .map(|router| router.address)
+                .collect::<Vec<_>>()
+        };
+        for address in expired {
+            debug!("Icmp: Router {} expired", address);
+            self.remove(address);
+        }
+    }
+
+    fn handle_advertisement(&mut self, from: Ipv4Addr, data: &[u8]) {
+        if !self.net.contains(from) {
+            debug!("Icmp: Ignoring Router Advertisement from {} not on link", from);
+            return;
+        }
+        let advertisement = match RouterAdvertisement::parse(data) {
+            Ok(advertisement) => advertisement,
+            Err(e) => {
+                debug!("Icmp: Invalid Router Advertisement from {}: {:?}", from, e);
+                return;
+            }
+        };
+        self.next_solicitation = None;
+        let expires = Instant::now() + advertisement.lifetime;
+        for &(address, preference) in &advertisement.routers {
+            if !self.net.contains(address) || address == self.config.local_ip {
+                continue;
+            }
+            if advertisement.lifetime == Duration::new(0, 0) ||
+               preference == INELIGIBLE_PREFERENCE {
+                self.remove(address);
+                continue;
+            }
+            let old_preference = {
+                let mut routers = self.routers.lock().unwrap();
+                let old = routers.get(&address).map(|router| router.preference);
+                routers.insert(address,
+                               DiscoveredRouter {
+                                   address: address,
+                                   preference: preference,
+                                   expires: expires,
+                               });
+                old
+            };
+            if old_preference != Some(preference) {
+                if old_preference.is_some() {
+                    self.uninstall(address);
+                }
+                self.install(address, preference);
+            }
+        }
+    }
+
+    fn remove(&mut self, address: Ipv4Addr) {
+        if self.routers.lock().unwrap().remove(&address).is_some() {
+            self.uninstall(address);
+        }
+    }
+
+    fn uninstall_all(&mut self) {
+        let addresses = self.routers.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+        for address in addresses {
+            self.remove(address);
+        }
+    }
+
+    fn send_solicitation(&self) {
+        let dst = all_routers_group();
+        let mut stack = self.stack.lock().unwrap();
+        let mut result = Err(TxError::InvalidTx);
+        while let Err(TxError::InvalidTx) = result {
+            let ipv4_tx = match stack.interface(&self.interface)
+                .and_then(|i| i.ipv4_tx_from(Some(self.config.local_ip), dst, None)) {
+                Ok(ipv4_tx) => ipv4_tx,
+                Err(e) => {
+                    warn!("Icmp: Unable to send Router Solicitation: {:?}", e);
+                    return;
+                }
+            };
+            // Router Solicitations only have four reserved bytes
+            let builder = BasicIcmpProtocol::new(IcmpTypes::RouterSolicitation,
+                                                 IcmpCode(0),
+                                                 vec![0; 4]);
+            result = IcmpTx::new(ipv4_tx).send(builder);
+        }
+        if let Err(e) = result {
+            warn!("Icmp: Unable to send Router Solicitation: {:?}", e);
+        }
+    }
+
+    fn install(&self, address: Ipv4Addr, preference: i32) {
+        debug!("Icmp: Installing default route via {} preference {}", address, preference);
+        let interface = self.interface.clone();
+        let result = self.with_main_table(|table| {
+            table.add_route_with_metric(default_net(), Some(address), interface, metric(preference))
+        });
+        if let Err(e) = result {
+            warn!("Icmp: Unable to install default route via {}: {:?}", address, e);
+        }
+    }
+
+    fn uninstall(&self, address: Ipv4Addr) {
+        debug!("Icmp: Removing default route via {}", address);
+        let interface = &self.interface;
+        let result = self.with_main_table(|table| {
+            table.remove_route(default_net(), Some(address), interface);
+        });
+        if let Err(e) = result {
+            warn!("Icmp: Unable to remove default route via {}: {:?}", address, e);
+        }
+    }
+
+    fn with_main_table<F>(&self, f: F) -> StackResult<()>
+        where F: FnOnce(&mut RoutingTable)
+    {
+        let mut stack = self.stack.lock().unwrap();
+        let policy = try!(stack.domain_routing_policy(&self.config.domain));
+        f(policy.main_table());
+        Ok(())
+    }
+}
+
+fn default_net() -> Ipv4Network {
+    Ipv4Network::new(Ipv4Addr::new(0, 0, 0, 0), 0).unwrap()
+}
+
+/// Maps a router preference to a route metric. The most preferred router
+/// gets the lowest metric, never zero.
+fn metric(preference: i32) -> u32 {
+    (::std::i32::MAX as i64 - preference as i64 + 1) as u32
+}
@@ -0,0 +1,359 @@
+use {DEFAULT_DOMAIN, Flow, NetworkStack, QueueReceiver, QueueSender, RxResult, TxError,
+     bounded_queue};
+use udp::{UdpBuilder, UdpError, UdpListener};
+
+use pnet::packet::icmp::{IcmpCode, IcmpType, IcmpTypes};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{IcmpTx, PING_QUEUE_LEN, PingEvent, PingQueue};
+use super::resolve::send_when_resolved;
+
+/// Destination port of the first Udp probe. Incremented for every probe
+/// sent, like the traceroute program does.
+pub const TRACEROUTE_BASE_PORT: u16 = 33434;
+
+/// The kind of packets a `Traceroute` probes with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TracerouteMethod {
+    /// Udp datagrams to unlikely ports, answered with Port Unreachable by
+    /// the destination.
+    Udp,
+    /// Echo Requests, answered with Echo Replies by the destination.
+    Icmp,
+}
+
+/// Settings for a `Traceroute`.
+#[derive(Clone, Debug)]
+pub struct TracerouteConfig {
+    /// Defaults to `TracerouteMethod::Udp`.
+    pub method: TracerouteMethod,
+
+    /// Time to live of the first probes. Defaults to 1.
+    pub first_ttl: u8,
+
+    /// Highest time to live probed before giving up. Defaults to 30.
+    pub max_ttl: u8,
+
+    /// Number of probes sent with every time to live. Defaults to 3.
+    pub probes_per_hop: u32,
+
+    /// How long to wait for the response to each probe, including resolving
+    /// the next hop. Defaults to 3 seconds.
+    pub timeout: Duration,
+
+    /// Payload size of the probes. Defaults to 32 bytes.
+    pub payload_size: usize,
+}
+
+impl Default for TracerouteConfig {
+    fn default() -> Self {
+        TracerouteConfig {
+            method: TracerouteMethod::Udp,
+            first_ttl: 1,
+            max_ttl: 30,
+            probes_per_hop: 3,
+            timeout: Duration::new(3, 0),
+            payload_size: 32,
+        }
+    }
+}
+
+/// The response to one probe.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TracerouteReply {
+    pub from: Ipv4Addr,
+    pub rtt: Duration,
+    /// Time Exceeded from routers on the way, Echo Reply or Destination
+    /// Unreachable from the end of the path.
+    pub icmp_type: IcmpType,
+    pub icmp_code: IcmpCode,
+}
+
+impl TracerouteReply {
+    /// Returns true if this response ends the trace, meaning the probe did
+    /// not run out of time to live on the way.
+    pub fn is_final(&self) -> bool {
+        self.icmp_type != IcmpTypes::TimeExceeded
+    }
+}
+
+/// The probes sent with one time to live.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TracerouteHop {
+    pub ttl: u8,
+    /// The response to every probe, `None` for the ones that timed out.
+    pub probes: Vec<Option<TracerouteReply>>,
+}
+
+impl TracerouteHop {
+    /// Returns the addresses that responded to probes at this hop, without
+    /// duplicates.
+    pub fn addresses(&self) -> Vec<Ipv4Addr> {
+        let mut addresses = vec![];
+        for reply in self.probes.iter().filter_map(|probe| probe.as_ref()) {
+            if !addresses.contains(&reply.from) {
+                addresses.push(reply.from);
+            }
+        }
+        addresses
+    }
+
+    /// Returns true if any probe at this hop got a final response.
+    pub fn is_final(&self) -> bool {
+        self.probes.iter().any(|probe| probe.as_ref().map_or(false, |reply| reply.is_final()))
+    }
+}
+
+/// Library level traceroute. Sends probes with increasing time to live
+/// towards one destination and collects who answers them, one probe at a
+/// time.
+pub struct Traceroute {
+    stack: Arc<Mutex<NetworkStack>>,
+    domain: String,
+    local_ip: Ipv4Addr,
+    dst: Ipv4Addr,
+    config: TracerouteConfig,
+    /// Echo identifier of Icmp probes, or source port of Udp probes.
+    local_id: u16,
+    next_probe: u16,
+    responses: Responses,
+}
+
+impl Traceroute {
+    /// Creates a `Traceroute` to `dst` from the local address the route to
+    /// `dst` selects.
+    pub fn new(stack: Arc<Mutex<NetworkStack>>,
+               dst: Ipv4Addr,
+               config: TracerouteConfig)
+               -> io::Result<Traceroute> {
+        Self::new_in(stack, DEFAULT_DOMAIN, dst, config)
+    }
+
+    /// Same as `new` but routes in the given routing domain.
+    pub fn new_in(stack: Arc<Mutex<NetworkStack>>,
+                  domain: &str,
+                  dst: Ipv4Addr,
+                  config: TracerouteConfig)
+                  -> io::Result<Traceroute> {
+        if config.first_ttl == 0 || config.first_ttl > config.max_ttl {
+            let msg = "Invalid time to live range".to_owned();
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let (local_ip, local_id, responses) = {
+            let mut stack = stack.lock().unwrap();
+            let flow = Flow::new(None, protocol(config.method));
+            let local_ip = try!(stack.source_ip_in(domain, dst, &flow));
+            match config.method {
+                TracerouteMethod::Udp => {
+                    let (tx, rx) = bounded_queue(PING_QUEUE_LEN);
+                    // Tells hash based port selection where the probes go
+                    let probe_dst = Some(SocketAddrV4::new(dst, TRACEROUTE_BASE_PORT));
+                    let port = try!(stack.udp_allocate_port_in(domain, &[local_ip], probe_dst));
+                    let listener = ProbeListener { chan: tx };
+                    try!(stack.udp_listen_in(domain, SocketAddrV4::new(local_ip, port), listener));
+                    (local_ip, port, Responses::Udp(rx))
+                }
+                TracerouteMethod::Icmp => {
+                    let (queue, rx) = PingQueue::new(PING_QUEUE_LEN);
+                    let identifier = try!(stack.ping_register_in(domain, local_ip, queue));
+                    (local_ip, identifier, Responses::Icmp(rx))
+                }
+            }
+        };
+        Ok(Traceroute {
+            stack: stack,
+            domain: domain.to_owned(),
+            local_ip: local_ip,
+            dst: dst,
+            config: config,
+            local_id: local_id,
+            next_probe: 0,
+            responses: responses,
+        })
+    }
+
+    /// Returns the address the probes are sent from.
+    pub fn local_ip(&self) -> Ipv4Addr {
+        self.local_ip
+    }
+
+    pub fn config(&self) -> &TracerouteConfig {
+        &self.config
+    }
+
+    /// Probes every time to live from `first_ttl` until a probe gets a final
+    /// response or `max_ttl` has been probed.
+    pub fn run(&mut self) -> io::Result<Vec<TracerouteHop>> {
+        let mut hops = vec![];
+        for ttl in self.config.first_ttl as u16..self.config.max_ttl as u16 + 1 {
+            let hop = try!(self.probe_hop(ttl as u8));
+            let done = hop.is_final();
+            hops.push(hop);
+            if done {
+                break;
+            }
+        }
+        Ok(hops)
+    }
+
+    /// Sends `probes_per_hop` probes with the given time to live and waits
+    /// for the response to each of them.
+    pub fn probe_hop(&mut self, ttl: u8) -> io::Result<TracerouteHop> {
+        let mut probes = vec![];
+        for _ in 0..self.config.probes_per_hop {
+            let probe = self.next_probe;
+            self.next_probe = probe.wrapping_add(1);
+            let sent = Instant::now();
+            let flow = Flow::new(Some(self.local_ip), protocol(self.config.method));
+            match send_when_resolved(&self.stack,
+                                     &self.domain,
+                                     self.dst,
+                                     &flow,
+                                     sent + self.config.timeout,
+                                     |stack| self.send(stack, &flow, probe, ttl)) {
+                Ok(()) => probes.push(self.wait(probe, sent)),
+                // The next hop did not answer Arp within the timeout
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => probes.push(None),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(TracerouteHop {
+            ttl: ttl,
+            probes: probes,
+        })
+    }
+
+    /// Sends the probe with number `probe`. Fails with `WouldBlock` after
+    /// sending an Arp request if the next hop is not resolved yet.
+    fn send(&self, stack: &mut NetworkStack, flow: &Flow, probe: u16, ttl: u8) -> io::Result<()> {
+        let payload = vec![0; self.config.payload_size];
+        let no_wait = Some(Duration::new(0, 0));
+        loop {
+            let mut ipv4_tx = try!(stack.ipv4_tx_in_timeout(&self.domain, self.dst, flow, no_wait));
+            ipv4_tx.ttl = ttl;
+            let result = match self.config.method {
+                TracerouteMethod::Udp => {
+                    let dst_port = TRACEROUTE_BASE_PORT.wrapping_add(probe);
+                    let builder = UdpBuilder::new(self.local_ip,
+                                                  self.dst,
+                                                  self.local_id,
+                                                  dst_port,
+                                                  &payload);
+                    ipv4_tx.send(builder)
+                }
+                TracerouteMethod::Icmp => {
+                    IcmpTx::new(ipv4_tx).send_echo_request(self.local_id, probe, &payload)
+                }
+            };
+            match result {
+                Err(TxError::InvalidTx) => continue,
+                result => return result.map_err(|e| e.into()),
+            }
+        }
+    }
+
+    fn wait(&self, probe: u16, sent: Instant) -> Option<TracerouteReply> {
+        let deadline = sent + self.config.timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            // Responses to earlier probes, that already timed out, are
+            // skipped
+            match self.responses.recv(deadline - now) {
+                Some((response_probe, from, icmp_type, icmp_code)) => {
+                    if response_probe == probe {
+                        return Some(TracerouteReply {
+                            from: from,
+                            rtt: sent.elapsed(),
+                            icmp_type: icmp_type,
+                            icmp_code: icmp_code,
+                        });
+                    }
+                }
+                None => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Traceroute {
+    fn drop(&mut self) {
+        if let Ok(mut stack) = self.stack.lock() {
+            match self.responses {
+                Responses::Udp(_) => {
+                    let addr = SocketAddrV4::new(self.local_ip, self.local_id);
+                    stack.udp_unlisten_in(&self.domain, addr);
+                }
+                Responses::Icmp(_) => {
+                    stack.ping_unregister_in(&self.domain, self.local_ip, self.local_id);
+                }
+            }
+        }
+    }
+}
+
+/// Where the responses to the probes of a `Traceroute` arrive.
+enum Responses {
+    Udp(QueueReceiver<UdpError>),
+    Icmp(QueueReceiver<PingEvent>),
+}
+
+impl Responses {
+    /// Waits for the next response. Returns the number of the probe it
+    /// belongs to, who sent it and its type.
+    fn recv(&self, timeout: Duration) -> Option<(u16, Ipv4Addr, IcmpType, IcmpCode)> {
+        match *self {
+            Responses::Udp(ref rx) => {
+                let error = match rx.recv(Some(timeout)) {
+                    Some(error) => error,
+                    None => return None,
+                };
+                let probe = error.dst.port().wrapping_sub(TRACEROUTE_BASE_PORT);
+                Some((probe, error.from, error.icmp_type, error.icmp_code))
+            }
+            Responses::Icmp(ref rx) => {
+                match rx.recv(Some(timeout)) {
+                    Some(PingEvent::Reply { sequence_number, from, .. }) => {
+                        Some((sequence_number, from, IcmpTypes::EchoReply, IcmpCode(0)))
+                    }
+                    Some(PingEvent::Error { sequence_number, from, icmp_type, icmp_code }) => {
+                        Some((sequence_number, from, icmp_type, icmp_code))
+                    }
+                    None => None,
+                }
+            }
+        }
+    }
+}
+
+/// `UdpListener` bound to the source port of Udp probes, passing on the Icmp
+/// errors they cause. Like for Echo probes, at most `PING_QUEUE_LEN` errors
+/// are queued and the rest dropped.
+struct ProbeListener {
+    chan: QueueSender<UdpError>,
+}
+
+impl UdpListener for ProbeListener {
+    fn recv(&mut self, _time: SystemTime, _packet: &Ipv4Packet) -> (RxResult, bool) {
+        (Ok(()), true)
+    }
+
+    fn recv_error(&mut self, _time: SystemTime, error: UdpError) {
+        self.chan.send(error);
+    }
+}
+
+fn protocol(method: TracerouteMethod) -> IpNextHeaderProtocol {
+    match method {
+        TracerouteMethod::Udp => IpNextHeaderProtocols::Udp,
+        TracerouteMethod::Icmp => IpNextHeaderProtocols::Icmp,
+    }
+}
@@ -1,5 +1,6 @@
 use {RxError, RxResult};
 use ethernet::EthernetListener;
+use icmp::IcmpErrorTx;
 
 use pnet::packet::Packet;
 use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
@@ -9,10 +10,10 @@ use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
-use super::{MORE_FRAGMENTS, NO_FLAGS};
-use util::Buffer;
+use super::{MORE_FRAGMENTS, NO_FLAGS, REASSEMBLY_TIMEOUT_SECS};
+use util::{self, Buffer};
 
 /// Anyone interested in receiving IPv4 packets from `Ipv4` must implement this.
 pub trait Ipv4Listener: Send {
@@ -27,12 +28,21 @@ pub type IpListenerLookup = HashMap<Ipv4Addr, HashMap<IpNextHeaderProtocol, Box<
 // packet
 type FragmentIdent = (Ipv4Addr, Ipv4Addr, u16);
 
+// The packets being reassembled, shared with the timer expiring them
+struct Fragments {
+    buffers: HashMap<FragmentIdent, (Buffer, usize, Instant)>,
+    expiring: bool,
+}
+
 /// Listener and parser for IPv4 packets. Receives ethernet frames from the
 /// `EthernetRx` it's owned by and forwards them to the correct `Ipv4Listener`.
 /// Will cache and reassemble fragmented packets before forwarding them.
+/// Packets that can't be delivered are reported back to the sender if an
+/// `IcmpErrorTx` is given.
 pub struct Ipv4Rx {
     listeners: Arc<Mutex<IpListenerLookup>>,
-    buffers: HashMap<FragmentIdent, (Buffer, usize)>,
+    fragments: Arc<Mutex<Fragments>>,
+    icmp_errors: Option<IcmpErrorTx>,
 }
 
 impl Ipv4Rx {
@@ -40,15 +50,35 @@ impl Ipv4Rx {
     /// changed later. Returns the instance casted for easy addition to
     /// the `EthernetRx` listener `Vec`.
     pub fn new(listeners: Arc<Mutex<IpListenerLookup>>) -> Box<EthernetListener> {
+        Self::create(listeners, None)
+    }
+
+    /// Same as `new` but sends Icmp errors about packets with malformed
+    /// headers, unknown protocols or fragments never completed.
+    pub fn with_icmp_errors(listeners: Arc<Mutex<IpListenerLookup>>,
+                            icmp_errors: IcmpErrorTx)
+                            -> Box<EthernetListener> {
+        Self::create(listeners, Some(icmp_errors))
+    }
+
+    fn create(listeners: Arc<Mutex<IpListenerLookup>>,
+              icmp_errors: Option<IcmpErrorTx>)
+              -> Box<EthernetListener> {
+        let fragments = Fragments {
+            buffers: HashMap::new(),
+            expiring: false,
+        };
         let this = Ipv4Rx {
             listeners: listeners,
-            buffers: HashMap::new(),
+            fragments: Arc::new(Mutex::new(fragments)),
+            icmp_errors: icmp_errors,
         };
         Box::new(this) as Box<EthernetListener>
     }
 
     /// Returns the Ipv4Packet contained in this EthernetPacket if it looks
-    /// valid
+    /// valid. The checksum is only verified if the header length is valid,
+    /// otherwise `check_header` reports it.
     fn get_ipv4_pkg<'a>(eth_pkg: &'a EthernetPacket) -> Result<Ipv4Packet<'a>, RxError> {
         let eth_payload = eth_pkg.payload();
         if eth_payload.len() < Ipv4Packet::minimum_packet_size() {
@@ -62,7 +92,10 @@ impl Ipv4Rx {
             Err(RxError::InvalidLength)
         } else {
             let ip_pkg = Ipv4Packet::new(&eth_payload[..total_length]).unwrap();
-            if ip_pkg.get_checksum() != checksum(&ip_pkg) {
+            let header_length = ip_pkg.get_header_length() as usize * 4;
+            let checkable = header_length >= Ipv4Packet::minimum_packet_size() &&
+                            header_length <= total_length;
+            if checkable && ip_pkg.get_checksum() != checksum(&ip_pkg) {
                 Err(RxError::InvalidChecksum)
             } else {
                 Ok(ip_pkg)
@@ -70,6 +103,48 @@ impl Ipv4Rx {
         }
     }
 
+    /// Checks the header fields not covered by `get_ipv4_pkg`. Returns the
+    /// offset of the first byte of the invalid field in the header, if any.
+    fn check_header(ip_pkg: &Ipv4Packet) -> Option<u8> {
+        let header_length = ip_pkg.get_header_length() as usize * 4;
+        if ip_pkg.get_version() != 4 || header_length < Ipv4Packet::minimum_packet_size() {
+            // Version and header length share the first byte
+            Some(0)
+        } else if header_length > ip_pkg.get_total_length() as usize {
+            // Total length too short to hold the header
+            Some(2)
+        } else {
+            let options = &ip_pkg.packet()[Ipv4Packet::minimum_packet_size()..header_length];
+            Self::check_options(options)
+                .map(|offset| (Ipv4Packet::minimum_packet_size() + offset) as u8)
+        }
+    }
+
+    /// Checks that the options are well formed, as described in RFC 791.
+    /// Returns the offset in `options` of the first invalid byte, if any.
+    fn check_options(options: &[u8]) -> Option<usize> {
+        let mut i = 0;
+        while i < options.len() {
+            match options[i] {
+                // End of option list
+                0 => return None,
+                // No operation
+                1 => i += 1,
+                _ => {
+                    if i + 1 >= options.len() {
+                        return Some(i);
+                    }
+                    let length = options[i + 1] as usize;
+                    if length < 2 || i + length > options.len() {
+                        return Some(i + 1);
+                    }
+                    i += length;
+                }
+            }
+        }
+        None
+    }
+
     fn is_fragment(ip_pkg: &Ipv4Packet) -> bool {
         let mf = (ip_pkg.get_flags() & MORE_FRAGMENTS) != 0;
         let offset = ip_pkg.get_fragment_offset() != 0;
@@ -83,13 +158,14 @@ impl Ipv4Rx {
                      ip_pkg: Ipv4Packet)
                      -> Result<Option<Ipv4Packet<'static>>, RxError> {
         let ident = Self::get_fragment_identification(&ip_pkg);
-        if !self.buffers.contains_key(&ident) {
-            try!(self.start_new_fragment(ip_pkg, ident));
+        let mut fragments = self.fragments.lock().unwrap();
+        if !fragments.buffers.contains_key(&ident) {
+            try!(self.start_new_fragment(&mut fragments, ip_pkg, ident));
             Ok(None)
         } else {
             let pkg_done = {
-                let &mut (ref mut buffer, ref mut total_length) =
-                    self.buffers.get_mut(&ident).unwrap();
+                let &mut (ref mut buffer, ref mut total_length, _) =
+                    fragments.buffers.get_mut(&ident).unwrap();
                 let offset = Ipv4Packet::minimum_packet_size() +
                              ip_pkg.get_fragment_offset() as usize * 8;
                 // Check if this is the last fragment
@@ -108,7 +184,7 @@ impl Ipv4Rx {
                 }
             };
             if pkg_done {
-                let (buffer, len) = self.buffers.remove(&ident).unwrap();
+                let (buffer, len, _) = fragments.buffers.remove(&ident).unwrap();
                 let mut ip_pkg = MutableIpv4Packet::owned(buffer.into_boxed_slice()).unwrap();
                 ip_pkg.set_flags(NO_FLAGS);
                 ip_pkg.set_total_length(len as u16);
@@ -121,17 +197,60 @@ impl Ipv4Rx {
         }
     }
 
-    fn start_new_fragment(&mut self, ip_pkg: Ipv4Packet, ident: FragmentIdent) -> RxResult {
+    fn start_new_fragment(&self,
+                          fragments: &mut Fragments,
+                          ip_pkg: Ipv4Packet,
+                          ident: FragmentIdent)
+                          -> RxResult {
         if ip_pkg.get_fragment_offset() == 0 {
             let mut buffer = Buffer::new(::std::u16::MAX as usize);
             buffer.push(0, ip_pkg.packet()).unwrap();
-            self.buffers.insert(ident, (buffer, 0));
+            fragments.buffers.insert(ident, (buffer, 0, Instant::now()));
+            if !fragments.expiring {
+                fragments.expiring = true;
+                let fragments = self.fragments.clone();
+                let icmp_errors = self.icmp_errors.clone();
+                util::spawn_timer(move || Self::expire_fragments(&fragments, &icmp_errors));
+            }
             Ok(())
         } else {
             Err(RxError::InvalidContent)
         }
     }
 
+    /// Drops the fragments of packets not completed within the reassembly
+    /// timeout and reports it to their senders. Runs from a timer as long as
+    /// any packets are being reassembled, returning when to run next.
+    fn expire_fragments(fragments: &Mutex<Fragments>,
+                        icmp_errors: &Option<IcmpErrorTx>)
+                        -> Option<Instant> {
+        let timeout = Duration::from_secs(REASSEMBLY_TIMEOUT_SECS);
+        let (expired, next) = {
+            let mut fragments = fragments.lock().unwrap();
+            let expired_idents = fragments.buffers
+                .iter()
+                .filter(|&(_, &(_, _, started))| started.elapsed() >= timeout)
+                .map(|(ident, _)| *ident)
+                .collect::<Vec<_>>();
+            let expired = expired_idents.into_iter()
+                .map(|ident| (ident, fragments.buffers.remove(&ident).unwrap().0))
+                .collect::<Vec<_>>();
+            let next = fragments.buffers.values().map(|&(_, _, started)| started + timeout).min();
+            fragments.expiring = next.is_some();
+            (expired, next)
+        };
+        for (ident, buffer) in expired {
+            debug!("Ipv4 reassembly of {:?} timed out", ident);
+            if let Some(ref icmp_errors) = *icmp_errors {
+                // The buffer starts with the first fragment, header included
+                if let Some(first_fragment) = Ipv4Packet::new(&buffer) {
+                    icmp_errors.reassembly_time_exceeded(&first_fragment);
+                }
+            }
+        }
+        next
+    }
+
     fn get_fragment_identification(ip_pkg: &Ipv4Packet) -> FragmentIdent {
         let src = ip_pkg.get_source();
         let dst = ip_pkg.get_destination();
@@ -149,6 +268,9 @@ impl Ipv4Rx {
             if let Some(mut listener) = listeners.get_mut(&next_level_protocol) {
                 listener.recv(time, ip_pkg)
             } else {
+                if let Some(ref icmp_errors) = self.icmp_errors {
+                    icmp_errors.protocol_unreachable(&ip_pkg);
+                }
                 Err(RxError::NoListener(format!("Ipv4 {:?}", next_level_protocol)))
             }
         } else {
@@ -160,6 +282,12 @@ impl Ipv4Rx {
 impl EthernetListener for Ipv4Rx {
     fn recv(&mut self, time: SystemTime, eth_pkg: &EthernetPacket) -> RxResult {
         let ip_pkg = try!(Self::get_ipv4_pkg(eth_pkg));
+        if let Some(pointer) = Self::check_header(&ip_pkg) {
+            if let Some(ref icmp_errors) = self.icmp_errors {
+                icmp_errors.parameter_problem(&ip_pkg, pointer);
+            }
+            return Err(RxError::InvalidContent);
+        }
         if Self::is_fragment(&ip_pkg) {
             if let Some(reassembled_pkg) = try!(self.save_fragment(ip_pkg)) {
                 self.forward(time, reassembled_pkg)
@@ -11,7 +11,7 @@ use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
 use std::cmp;
 use std::net::Ipv4Addr;
 
-use super::{MORE_FRAGMENTS, NO_FLAGS};
+use super::{DEFAULT_TTL, MORE_FRAGMENTS, NO_FLAGS};
 
 #[cfg(all(test, feature = "unit-tests"))]
 use testing::ethernet::EthernetTx;
@@ -66,6 +66,10 @@ pub struct Ipv4Tx {
     /// The destination IP of the packets built by this instance.
     pub dst: Ipv4Addr,
 
+    /// The time to live of the packets built by this instance. Can be
+    /// changed between packets.
+    pub ttl: u8,
+
     mtu: usize,
 
     ethernet: EthernetTx,
@@ -79,6 +83,7 @@ impl Ipv4Tx {
         Ipv4Tx {
             src: src,
             dst: dst,
+            ttl: DEFAULT_TTL,
             mtu: mtu,
             ethernet: ethernet,
             next_identification: 0,
@@ -91,7 +96,8 @@ impl Ipv4Tx {
     /// gateway.
     pub fn send<P: Ipv4Protocol>(&mut self, payload: P) -> TxResult {
         let payload_len = payload.len();
-        let builder = Ipv4Builder::new(self.src, self.dst, self.next_identification, payload);
+        let mut builder = Ipv4Builder::new(self.src, self.dst, self.next_identification, payload);
+        builder.set_ttl(self.ttl);
         self.next_identification.wrapping_add(1);
 
         let max_payload_per_fragment = self.max_payload_per_fragment();
@@ -116,6 +122,7 @@ pub struct Ipv4Builder<P: Ipv4Protocol> {
     dst: Ipv4Addr,
     offset: usize,
     identification: u16,
+    ttl: u8,
     payload: P,
 }
 
@@ -126,9 +133,15 @@ impl<P: Ipv4Protocol> Ipv4Builder<P> {
             dst: dst,
             offset: 0,
             identification: identification,
+            ttl: DEFAULT_TTL,
             payload: payload,
         }
     }
+
+    /// Sets the time to live of the built packet. `DEFAULT_TTL` if not set.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl;
+    }
 }
 
 impl<P: Ipv4Protocol> EthernetProtocol for Ipv4Builder<P> {
@@ -148,7 +161,7 @@ impl<P: Ipv4Protocol> Protocol for Ipv4Builder<P> {
         pkg.set_version(4);
         pkg.set_dscp(0); // https://en.wikipedia.org/wiki/Differentiated_services
         pkg.set_ecn(0); // https://en.wikipedia.org/wiki/Explicit_Congestion_Notification
-        pkg.set_ttl(40);
+        pkg.set_ttl(self.ttl);
         // ip_pkg.set_options(vec![]); // We currently don't support options
         pkg.set_header_length(5); // 5 is for no option fields
         pkg.set_identification(self.identification);
@@ -8,13 +8,22 @@ pub const MORE_FRAGMENTS: u8 = 0b001;
 pub const DONT_FRAGMENT: u8 = 0b010;
 pub const NO_FLAGS: u8 = 0b000;
 
+/// The time to live of sent packets unless changed.
+pub const DEFAULT_TTL: u8 = 40;
+
+/// Seconds to wait for all fragments of a packet before dropping the ones
+/// received so far.
+pub const REASSEMBLY_TIMEOUT_SECS: u64 = 30;
+
 
 #[cfg(all(test, feature = "unit-tests"))]
 mod tests {
     use {RxError, RxResult};
     use ethernet::EthernetListener;
+    use icmp::{IcmpErrorState, IcmpErrorTx};
     use pnet::packet::{MutablePacket, Packet};
     use pnet::packet::ethernet::MutableEthernetPacket;
+    use pnet::packet::icmp::{IcmpCode, IcmpPacket, IcmpTypes};
 
     use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
     use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
@@ -77,6 +86,23 @@ mod tests {
         check_pkg(&frame, src, dst, pkg_size, false, 0, 100, 99);
     }
 
+    #[test]
+    fn tx_ttl() {
+        let src = Ipv4Addr::new(192, 168, 10, 2);
+        let dst = Ipv4Addr::new(192, 168, 10, 240);
+
+        let (eth_tx, rx) = ethernet::EthernetTx::new();
+        let mut ipv4_tx = Ipv4Tx::new(eth_tx, src, dst, 1500);
+        assert!(ipv4_tx.send(TestIpv4Protocol::new(10)).is_ok());
+        ipv4_tx.ttl = 1;
+        assert!(ipv4_tx.send(TestIpv4Protocol::new(10)).is_ok());
+
+        let frame = rx.try_recv().expect("Expected a frame to have been sent");
+        assert_eq!(Ipv4Packet::new(&frame).unwrap().get_ttl(), DEFAULT_TTL);
+        let frame = rx.try_recv().expect("Expected a second frame to have been sent");
+        assert_eq!(Ipv4Packet::new(&frame).unwrap().get_ttl(), 1);
+    }
+
     #[test]
     fn rx_not_fragmented() {
         let dst = Ipv4Addr::new(127, 0, 0, 1);
@@ -89,6 +115,7 @@ mod tests {
             ip_pkg.set_destination(dst);
             ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
             ip_pkg.set_flags(DONT_FRAGMENT);
+            ip_pkg.set_version(4);
             ip_pkg.set_header_length(5); // No options
             ip_pkg.set_total_length(20 + 15);
             let csum = checksum(&ip_pkg.to_immutable());
@@ -120,6 +147,7 @@ mod tests {
             ip_pkg.set_flags(MORE_FRAGMENTS);
             ip_pkg.set_fragment_offset(0);
             ip_pkg.set_identification(137);
+            ip_pkg.set_version(4);
             ip_pkg.set_header_length(5); // No options
             ip_pkg.set_total_length(20 + 16);
             let csum = checksum(&ip_pkg.to_immutable());
@@ -160,6 +188,98 @@ mod tests {
         assert!(rx.try_recv().is_err());
     }
 
+    #[test]
+    fn rx_icmp_errors() {
+        let src = Ipv4Addr::new(10, 0, 0, 1);
+        let dst = Ipv4Addr::new(10, 0, 0, 2);
+        let (ipv4_tx, read_handle) = ipv4::Ipv4Tx::new();
+        let state = Arc::new(Mutex::new(IcmpErrorState::default()));
+        let icmp_errors = IcmpErrorTx::new(state, Box::new(move |_, _| Some(ipv4_tx.clone())));
+        let mut listeners = HashMap::new();
+        listeners.insert(dst, HashMap::new());
+        let mut ipv4_rx = Ipv4Rx::with_icmp_errors(Arc::new(Mutex::new(listeners)), icmp_errors);
+
+        let mut buffer = vec![0; 100];
+        let mut pkg = MutableEthernetPacket::new(&mut buffer).unwrap();
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(pkg.payload_mut()).unwrap();
+            ip_pkg.set_source(src);
+            ip_pkg.set_destination(dst);
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(5); // No options
+            ip_pkg.set_total_length(20 + 8);
+            let csum = checksum(&ip_pkg.to_immutable());
+            ip_pkg.set_checksum(csum);
+        }
+        assert!(ipv4_rx.recv(SystemTime::now(), &pkg.to_immutable()).is_err());
+        let (_, data) = read_handle.try_recv().expect("Expected a Protocol Unreachable");
+        let icmp_pkg = IcmpPacket::new(&data).unwrap();
+        assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+        assert_eq!(icmp_pkg.get_icmp_code(), IcmpCode(2));
+
+        // Header length shorter than the fixed header
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(pkg.payload_mut()).unwrap();
+            ip_pkg.set_header_length(4);
+            let csum = checksum(&ip_pkg.to_immutable());
+            ip_pkg.set_checksum(csum);
+        }
+        assert_eq!(ipv4_rx.recv(SystemTime::now(), &pkg.to_immutable()),
+                   Err(RxError::InvalidContent));
+        let (_, data) = read_handle.try_recv().expect("Expected a Parameter Problem");
+        let icmp_pkg = IcmpPacket::new(&data).unwrap();
+        assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::ParameterProblem);
+        assert_eq!(icmp_pkg.payload()[0], 0);
+        assert!(read_handle.try_recv().is_err());
+
+        // Each malformed header is reported with a pointer to the bad field
+        {
+            let mut expect = |version, header_length, total_length, options: &[u8], pointer| {
+                {
+                    let mut ip_pkg = MutableIpv4Packet::new(pkg.payload_mut()).unwrap();
+                    ip_pkg.set_version(version);
+                    ip_pkg.set_header_length(header_length);
+                    ip_pkg.set_total_length(total_length);
+                    ip_pkg.packet_mut()[20..20 + options.len()].copy_from_slice(options);
+                    let csum = checksum(&ip_pkg.to_immutable());
+                    ip_pkg.set_checksum(csum);
+                }
+                assert_eq!(ipv4_rx.recv(SystemTime::now(), &pkg.to_immutable()),
+                           Err(RxError::InvalidContent));
+                let (_, data) = read_handle.try_recv().expect("Expected a Parameter Problem");
+                let icmp_pkg = IcmpPacket::new(&data).unwrap();
+                assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::ParameterProblem);
+                assert_eq!(icmp_pkg.payload()[0], pointer);
+            };
+            // Not version 4
+            expect(6, 5, 20 + 8, &[], 0);
+            // Header longer than the whole packet
+            expect(4, 6, 20, &[], 2);
+            // Option length shorter than the type and length bytes
+            expect(4, 6, 24 + 8, &[1, 68, 1, 0], 22);
+            // Option running past the end of the header
+            expect(4, 6, 24 + 8, &[68, 8, 5, 0], 21);
+            // Option type without room for the length
+            expect(4, 6, 24 + 8, &[1, 1, 1, 68], 23);
+        }
+        assert!(read_handle.try_recv().is_err());
+
+        // Well formed options are accepted
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(pkg.payload_mut()).unwrap();
+            ip_pkg.set_header_length(7);
+            ip_pkg.set_total_length(28 + 8);
+            ip_pkg.packet_mut()[20..28].copy_from_slice(&[1, 68, 4, 5, 0, 0, 0, 0]);
+            let csum = checksum(&ip_pkg.to_immutable());
+            ip_pkg.set_checksum(csum);
+        }
+        assert!(ipv4_rx.recv(SystemTime::now(), &pkg.to_immutable()).is_err());
+        let (_, data) = read_handle.try_recv().expect("Expected a Protocol Unreachable");
+        let icmp_pkg = IcmpPacket::new(&data).unwrap();
+        assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+    }
+
     fn setup_rx(dst: Ipv4Addr) -> (Box<EthernetListener>, mpsc::Receiver<Vec<u8>>) {
         let (tx, rx) = mpsc::channel();
         let arp_listener = Box::new(ipv4::MockIpv4Listener { tx: tx }) as Box<Ipv4Listener>;
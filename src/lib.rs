@@ -55,26 +55,44 @@
 //!     - [ ] Correctly picking an identification field
 //!   - [ ] Reassembling incoming packets
 //!     - [x] Works in standard case
-//!     - [ ] Timing out caches of packets that were never completed
+//!     - [x] Timing out caches of packets that were never completed
 //!     - [ ] Support reassemble out of order fragments?
 //!   - [ ] Header options
 //!   - [ ] Routing
 //!     - [x] Works in standard case
-//!     - [ ] Invalidate existing Tx on update
-//!     - [ ] Metrics
-//!   - [ ] Possible to change TTL
+//!     - [x] Invalidate existing Tx on update
+//!     - [x] Metrics
+//!     - [x] Policy rules selecting between multiple tables
+//!     - [x] Dynamic routes learned with RIPv2
+//!   - [x] Possible to change TTL
 //! - [ ] IPv6
 //!   - [ ] Path MTU discovery
 //! - [ ] Icmp
 //!   - [ ] Send generic Icmp packet
-//!   - [ ] Send Echo Request
-//!   - [ ] Receive Echo Reply
-//!   - [ ] Provide convenient way to implement a ping alternative
+//!   - [x] Send Echo Request
+//!   - [x] Receive Echo Reply
+//!   - [x] Answer Echo Requests
+//!   - [x] Send errors for unreachable ports and protocols, reassembly timeouts and
+//!     malformed headers
+//!   - [x] Provide convenient way to implement a ping alternative
+//!   - [x] Traceroute with Udp or Echo Request probes
+//!   - [x] Accept Redirects from the current gateway
+//!   - [x] Learn default gateways with Router Discovery
+//!   - [x] Queue received Icmp messages with `IcmpQueue`, to read or poll with wakers
 //! - [ ] Udp
 //!   - [x] Sending Udp packets
 //!   - [x] Provide API similar to Rusts standard `UdpSocket`
+//!   - [x] Report received Icmp errors to the sending socket
+//!   - [x] Verify checksums, optionally send without
+//!   - [x] Share ports between sockets, fanning out or load balancing
+//!   - [x] Per datagram metadata and source selection with `recv_msg` and `send_msg`
+//!   - [x] Peek, truncation and vectored I/O like the standard `UdpSocket`
+//!   - [x] Bounded receive queues, counting the datagrams dropped when full
+//!   - [x] Ephemeral ports from a configurable range, randomized as in RFC 6056
+//!   - [x] Send to limited and directed broadcast addresses with `set_broadcast`
+//!   - [x] Nonblocking `poll_recv_from` and `poll_send_to` with wakers, for async runtimes
 //!   - [ ] Provide improved API for separated sending and receiving
-//!   - [ ] Correctly close and clean up closed sockets
+//!   - [x] Correctly close and clean up closed sockets
 //! - [ ] Tcp
 //!
 //! ## Architecture and terminology
@@ -95,8 +113,10 @@
 //! the stack. View the `Tx` struct as the base tx-object.
 //! The `Tx` holds the sending part of the `pnet` backend and a simple counter
 //! behind a `Mutex`. Whenever anything in the stack changes, such as updates
-//! to the Arp or routing tables, the counter inside the `Tx` is incremented
-//! automatically by the stack. The `Tx` also holds the counter value from when
+//! to the Arp table or the MTU, the counter inside the `Tx` is incremented
+//! automatically by the stack. Updates to the routing table instead increment
+//! a stack wide `RouteGeneration` that every `Tx` created from a route also
+//! checks before sending. The `Tx` also holds the counter value from when
 //! it was created. When any tx-object is used to send a packet the sending
 //! will propagate down and eventually reach the `Tx` at the bottom. There the
 //! `Mutex` is locked and the counter from the creation of that `Tx` is
@@ -151,8 +171,6 @@
 //! Here are a few problems that I ran into that I still did not solve.
 //! Feedback is welcome.
 //!
-//! * If it's possible to have the same IP on multiple interfaces, which one
-//!   will a socket bound to that local IP receive packets from? Both?
 //! * Should the IP layer reassemble fragmented packets that are out of order?
 //! * Should the `FooTx` structs not contain the underlying `BarTx` and do the
 //!   sending internally. But instead be agnostic of the underlying protocol.
@@ -166,6 +184,7 @@ extern crate ipnetwork;
 
 use std::io;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[macro_use]
 extern crate log;
@@ -192,11 +211,19 @@ pub mod icmp;
 /// Module containing Udp functionality.
 pub mod udp;
 
+/// Module containing the routing information protocol (RIPv2), for learning
+/// routes dynamically from neighboring routers.
+pub mod rip;
+
 mod routing;
-pub use routing::RoutingTable;
+pub use routing::{Flow, MAIN_RULE_PRIORITY, MAIN_TABLE, RoutingPolicy, RoutingRule, RoutingTable,
+                  SharedRouting};
 
 mod util;
 
+mod poll;
+pub use poll::{Poll, QueueReceiver, QueueSender, Wake, bounded_queue};
+
 #[cfg(any(test, feature = "unit-tests", feature = "integration-tests", feature = "benchmarks"))]
 pub mod testing;
 
@@ -204,7 +231,7 @@ pub mod testing;
 mod stack;
 
 #[cfg(not(feature = "unit-tests"))]
-pub use stack::{NetworkStack, StackError, StackResult};
+pub use stack::{DEFAULT_DOMAIN, NetworkStack, StackError, StackResult};
 
 pub static DEFAULT_BUFFER_SIZE: usize = 1024 * 128;
 
@@ -351,6 +378,34 @@ impl VersionedTx {
     }
 }
 
+/// Stack wide counter of changes to the routing. Shared between the
+/// `RoutingTable` and every `Tx` created from a route in it. Any update to the
+/// routes increments the counter and makes those `Tx` instances invalid, in
+/// the same way a revision bump in `VersionedTx` does for one interface.
+#[derive(Clone, Debug, Default)]
+pub struct RouteGeneration {
+    counter: Arc<AtomicUsize>,
+}
+
+impl RouteGeneration {
+    /// Creates a new `RouteGeneration` starting at zero.
+    pub fn new() -> RouteGeneration {
+        RouteGeneration { counter: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    /// Returns the current value of the counter.
+    pub fn current(&self) -> usize {
+        self.counter.load(Ordering::SeqCst)
+    }
+
+    /// Increments the counter by one. Used to invalidate all `Tx` instances
+    /// created while the previous routes were in effect.
+    pub fn inc(&self) {
+        let generation = self.counter.fetch_add(1, Ordering::SeqCst).wrapping_add(1);
+        debug!("RouteGeneration ticked to {}", generation);
+    }
+}
+
 enum TxSender {
     Versioned(Arc<Mutex<VersionedTx>>),
     Direct(Box<EthernetDataLinkSender>),
@@ -362,6 +417,7 @@ enum TxSender {
 pub struct Tx {
     sender: TxSender,
     rev: u64,
+    route_rev: Option<(RouteGeneration, usize)>,
 }
 
 impl Tx {
@@ -374,9 +430,19 @@ impl Tx {
         Tx {
             sender: TxSender::Versioned(vtx),
             rev: rev,
+            route_rev: None,
         }
     }
 
+    /// Makes this `Tx` also depend on the given `RouteGeneration`. Copies the
+    /// current value of `generation` and will stop working as soon as the
+    /// routes it was created from are updated.
+    pub fn routed(mut self, generation: RouteGeneration) -> Tx {
+        let route_rev = generation.current();
+        self.route_rev = Some((generation, route_rev));
+        self
+    }
+
     /// Creates a new `Tx` based directly on the given
     /// `EthernetDataLinkSender`. Does not do
     /// versioning and should only be used for tests and other special cases.
@@ -384,6 +450,7 @@ impl Tx {
         Tx {
             sender: TxSender::Direct(sender),
             rev: 0,
+            route_rev: None,
         }
     }
 
@@ -392,10 +459,16 @@ impl Tx {
     /// `VersionedTx` will first be locked and the revision compared. If the
     /// revision changed
     /// this method will return `TxError::InvalidTx` instead of sending
-    /// anything.
+    /// anything. The same goes for the `RouteGeneration` if this `Tx` was
+    /// created from a route.
     pub fn send<T>(&mut self, num_packets: usize, size: usize, builder: T) -> TxResult
         where T: FnMut(MutableEthernetPacket)
     {
+        if let Some((ref generation, route_rev)) = self.route_rev {
+            if generation.current() != route_rev {
+                return Err(TxError::InvalidTx);
+            }
+        }
         match self.sender {
             TxSender::Versioned(ref vtx) => {
                 let mut sender = vtx.lock().unwrap();
@@ -0,0 +1,169 @@
+use std::mem;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// The outcome of polling a socket for something it may not be ready to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Poll<T> {
+    Ready(T),
+    /// Not ready yet. The waker given to the poll is woken when it's worth
+    /// polling again.
+    Pending,
+}
+
+impl<T> Poll<T> {
+    pub fn is_ready(&self) -> bool {
+        match *self {
+            Poll::Ready(_) => true,
+            Poll::Pending => false,
+        }
+    }
+}
+
+/// Notified when a socket that returned `Poll::Pending` may make progress.
+/// Called from the threads of the stack, so it should only schedule the
+/// polling task and return. Implemented for closures, so an async runtime
+/// can wrap its own task wakeup in one.
+pub trait Wake: Send + Sync {
+    fn wake(&self);
+}
+
+impl<F> Wake for F
+    where F: Fn() + Send + Sync
+{
+    fn wake(&self) {
+        self()
+    }
+}
+
+/// The wakers waiting for one event. All of them are woken, and forgotten,
+/// when it happens.
+pub struct WakerSet {
+    wakers: Mutex<Vec<Arc<Wake>>>,
+}
+
+impl WakerSet {
+    pub fn new() -> WakerSet {
+        WakerSet { wakers: Mutex::new(vec![]) }
+    }
+
+    /// Adds `waker`, unless the same one is already waiting.
+    pub fn register(&self, waker: Arc<Wake>) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if !wakers.iter().any(|registered| Arc::ptr_eq(registered, &waker)) {
+            wakers.push(waker);
+        }
+    }
+
+    pub fn wake_all(&self) {
+        let wakers = mem::replace(&mut *self.wakers.lock().unwrap(), vec![]);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for WakerSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Creates a queue holding at most `len` items, passing them from the
+/// receiving threads of the stack to a reader. Items sent when it is full
+/// are dropped and counted, so a reader falling behind can not make the
+/// stack buffer without limit.
+pub fn bounded_queue<T>(len: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+    let (tx, rx) = mpsc::sync_channel(len);
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let readers = Arc::new(WakerSet::new());
+    let sender = QueueSender {
+        tx: tx,
+        dropped: dropped.clone(),
+        readers: readers.clone(),
+    };
+    let receiver = QueueReceiver {
+        rx: rx,
+        dropped: dropped,
+        readers: readers,
+    };
+    (sender, receiver)
+}
+
+/// The sending end of a `bounded_queue`.
+pub struct QueueSender<T> {
+    tx: mpsc::SyncSender<T>,
+    dropped: Arc<AtomicUsize>,
+    readers: Arc<WakerSet>,
+}
+
+impl<T> QueueSender<T> {
+    /// Queues `item`, or drops it if the queue is full.
+    pub fn send(&self, item: T) {
+        match self.tx.try_send(item) {
+            Ok(()) => self.readers.wake_all(),
+            Err(mpsc::TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => (),
+        }
+    }
+
+    /// Returns how many items were dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        QueueSender {
+            tx: self.tx.clone(),
+            dropped: self.dropped.clone(),
+            readers: self.readers.clone(),
+        }
+    }
+}
+
+/// The receiving end of a `bounded_queue`, blocking or polling.
+pub struct QueueReceiver<T> {
+    rx: mpsc::Receiver<T>,
+    dropped: Arc<AtomicUsize>,
+    readers: Arc<WakerSet>,
+}
+
+impl<T> QueueReceiver<T> {
+    /// Returns the next item, waiting at most `timeout` for one. `None`
+    /// waits forever. Returns `None` if nothing arrived in time.
+    pub fn recv(&self, timeout: Option<Duration>) -> Option<T> {
+        match timeout {
+            Some(timeout) => self.rx.recv_timeout(timeout).ok(),
+            None => self.rx.recv().ok(),
+        }
+    }
+
+    /// Returns the next item if there is one, without blocking.
+    pub fn try_recv(&self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Returns the next item without blocking. When there is none it returns
+    /// `Poll::Pending`, and `waker` is woken when one arrives.
+    pub fn poll_recv(&self, waker: Arc<Wake>) -> Poll<T> {
+        if let Some(item) = self.try_recv() {
+            return Poll::Ready(item);
+        }
+        self.readers.register(waker);
+        // Queued before the waker was registered
+        match self.try_recv() {
+            Some(item) => Poll::Ready(item),
+            None => Poll::Pending,
+        }
+    }
+
+    /// Returns how many items were dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+}
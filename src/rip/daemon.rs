@@ -0,0 +1,703 @@
+use {DEFAULT_DOMAIN, Interface, NetworkStack, RoutingTable, StackError, StackResult, TxError};
+
+use ipnetwork::Ipv4Network;
+
+use rand;
+use rand::distributions::{IndependentSample, Range};
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::message::{AFI_INET, INFINITY, MAX_ENTRIES, RIP_PORT, RipCommand, RipEntry,
+                     RipMessage, rip_multicast_group};
+use udp::{UdpSocket, UdpTx};
+
+/// How often the socket readers check if the daemon is stopping.
+const READ_TIMEOUT_MS: u64 = 100;
+
+/// Configuration for a `RipDaemon`.
+#[derive(Clone, Debug)]
+pub struct RipConfig {
+    /// The local addresses to run RIP on. Updates are sent to, and received
+    /// from, the networks these addresses belong to.
+    pub local_ips: Vec<Ipv4Addr>,
+
+    /// The routing domain the local addresses are in. Learned routes are
+    /// installed into the main table of this domain.
+    pub domain: String,
+
+    /// How often the full table is sent to the neighbors.
+    pub update_interval: Duration,
+
+    /// How long a learned route is kept without hearing about it again.
+    pub route_timeout: Duration,
+
+    /// How long a timed out route is advertised as unreachable before it is
+    /// deleted.
+    pub garbage_timeout: Duration,
+
+    /// Simple password authentication. When set, only messages carrying this
+    /// password are accepted and all sent messages carry it.
+    pub password: Option<[u8; 16]>,
+}
+
+impl RipConfig {
+    /// Creates a config for running RIP on the given local addresses with
+    /// the default timers from RFC 2453.
+    pub fn new(local_ips: Vec<Ipv4Addr>) -> RipConfig {
+        RipConfig {
+            local_ips: local_ips,
+            domain: DEFAULT_DOMAIN.to_owned(),
+            update_interval: Duration::new(30, 0),
+            route_timeout: Duration::new(180, 0),
+            garbage_timeout: Duration::new(120, 0),
+            password: None,
+        }
+    }
+}
+
+/// A route known by a `RipDaemon`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RipRoute {
+    pub net: Ipv4Network,
+    /// The router to send traffic to. `None` for directly connected networks.
+    pub next_hop: Option<Ipv4Addr>,
+    pub interface: Interface,
+    pub metric: u32,
+    pub tag: u16,
+}
+
+struct RouteState {
+    route: RipRoute,
+    /// The router this route was learned from, `None` for connected routes.
+    learned_from: Option<Ipv4Addr>,
+    /// The local address of the link the route was learned on.
+    learned_on: Ipv4Addr,
+    timeout: Option<Instant>,
+    garbage: Option<Instant>,
+    changed: bool,
+}
+
+type RouteKey = (u32, u8);
+type Routes = HashMap<RouteKey, RouteState>;
+
+#[derive(Clone)]
+struct RipLink {
+    local_ip: Ipv4Addr,
+    net: Ipv4Network,
+    interface: Interface,
+}
+
+enum Event {
+    Datagram(Ipv4Addr, SocketAddrV4, Vec<u8>),
+    Stop,
+}
+
+/// RIPv2 (RFC 2453) routing daemon running on top of a `NetworkStack`.
+///
+/// Listens to and sends RIP messages on Udp port 520 and the multicast group
+/// 224.0.0.9 on every configured local address. Routes learned from the
+/// neighbors are installed, with their metrics, into the routing table of the
+/// stack. Implements periodic and triggered updates, split horizon with
+/// poisoned reverse, route timeouts with garbage collection and simple
+/// password authentication.
+///
+/// The daemon runs in its own thread until `stop` is called or the
+/// `RipDaemon` is dropped. Learned routes are then removed from the stack.
+pub struct RipDaemon {
+    events: mpsc::Sender<Event>,
+    routes: Arc<Mutex<Routes>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    readers: Vec<JoinHandle<()>>,
+}
+
+impl RipDaemon {
+    /// Starts RIP on the addresses in `config`.
+    pub fn spawn(stack: Arc<Mutex<NetworkStack>>, config: RipConfig) -> io::Result<RipDaemon> {
+        if config.local_ips.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "No local addresses to run RIP on".to_owned()));
+        }
+        let links = try!(Self::links(&stack, &config));
+        let (events_tx, events_rx) = mpsc::channel();
+        let running = Arc::new(AtomicBool::new(true));
+        let mut readers = vec![];
+        for link in &links {
+            let addr = SocketAddrV4::new(link.local_ip, RIP_PORT);
+            let reader = UdpSocket::bind_in(stack.clone(), &config.domain, addr)
+                .and_then(|socket| spawn_reader(socket, link.local_ip, &running, &events_tx));
+            match reader {
+                Ok(reader) => readers.push(reader),
+                Err(e) => {
+                    running.store(false, Ordering::SeqCst);
+                    for reader in readers {
+                        reader.join().unwrap_or(());
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let routes = Arc::new(Mutex::new(HashMap::new()));
+        let mut rip = Rip {
+            stack: stack,
+            next_update: Instant::now() + jittered(config.update_interval),
+            config: config,
+            links: links,
+            routes: routes.clone(),
+            next_triggered: None,
+        };
+        rip.add_connected();
+        let thread = thread::spawn(move || rip.run(events_rx));
+        Ok(RipDaemon {
+            events: events_tx,
+            routes: routes,
+            running: running,
+            thread: Some(thread),
+            readers: readers,
+        })
+    }
+
+    /// Returns all routes currently known by the daemon, including the
+    /// directly connected networks and routes being garbage collected.
+    pub fn routes(&self) -> Vec<RipRoute> {
+        let routes = self.routes.lock().unwrap();
+        routes.values().map(|state| state.route.clone()).collect()
+    }
+
+    /// Stops the daemon and waits for it to remove its routes from the stack
+    /// and release port 520, so that a new daemon can be started.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.events.send(Event::Stop).unwrap_or(());
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+        for reader in self.readers.drain(..) {
+            reader.join().unwrap_or(());
+        }
+    }
+
+    fn links(stack: &Arc<Mutex<NetworkStack>>, config: &RipConfig) -> io::Result<Vec<RipLink>> {
+        let mut stack = stack.lock().unwrap();
+        let mut links = vec![];
+        for local_ip in &config.local_ips {
+            let mut found = None;
+            for interface in stack.interfaces() {
+                let stack_interface = try!(stack.interface(&interface));
+                if stack_interface.domain() != config.domain {
+                    continue;
+                }
+                if let Some(net) = stack_interface.ipv4_nets()
+                    .into_iter()
+                    .find(|net| net.ip() == *local_ip) {
+                    found = Some(RipLink {
+                        local_ip: *local_ip,
+                        net: network(net.ip(), net.prefix()),
+                        interface: interface,
+                    });
+                }
+            }
+            match found {
+                Some(link) => links.push(link),
+                None => {
+                    let msg = format!("{} does not exist in domain {}", local_ip, config.domain);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+                }
+            }
+        }
+        Ok(links)
+    }
+}
+
+impl Drop for RipDaemon {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Joins the RIP multicast group on `socket` and starts a thread reading
+/// from it until `running` is cleared. The group is then left and the socket
+/// dropped, releasing the port.
+fn spawn_reader(socket: UdpSocket,
+                local_ip: Ipv4Addr,
+                running: &Arc<AtomicBool>,
+                events: &mpsc::Sender<Event>)
+                -> io::Result<JoinHandle<()>> {
+    let read_timeout = Duration::from_millis(READ_TIMEOUT_MS);
+    try!(socket.set_read_timeout(Some(read_timeout)));
+    try!(socket.join_multicast_v4(&rip_multicast_group(), &local_ip));
+    let running = running.clone();
+    let events = events.clone();
+    Ok(thread::spawn(move || {
+        let mut buffer = vec![0; 4 + MAX_ENTRIES * 20];
+        while running.load(Ordering::SeqCst) {
+            match socket.recv_from(&mut buffer) {
+                Ok((len, SocketAddr::V4(src))) => {
+                    let event = Event::Datagram(local_ip, src, buffer[..len].to_vec());
+                    if events.send(event).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Err(e) => {
+                    debug!("Rip: Unable to read datagram: {}", e);
+                    thread::sleep(read_timeout);
+                }
+            }
+        }
+        socket.leave_multicast_v4(&rip_multicast_group(), &local_ip).unwrap_or(());
+    }))
+}
+
+/// The state of the daemon, living in the daemon thread.
+struct Rip {
+    stack: Arc<Mutex<NetworkStack>>,
+    config: RipConfig,
+    links: Vec<RipLink>,
+    routes: Arc<Mutex<Routes>>,
+    next_update: Instant,
+    next_triggered: Option<Instant>,
+}
+
+impl Rip {
+    fn run(mut self, events: mpsc::Receiver<Event>) {
+        self.send_request();
+        self.send_updates(false);
+        loop {
+            let now = Instant::now();
+            let deadline = self.next_deadline();
+            let wait = if deadline > now {
+                deadline - now
+            } else {
+                Duration::new(0, 0)
+            };
+            match events.recv_timeout(wait) {
+                Ok(Event::Datagram(local_ip, src, data)) => {
+                    self.handle_datagram(local_ip, src, &data)
+                }
+                Ok(Event::Stop) |
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+            }
+            self.handle_timers();
+        }
+        self.uninstall_all();
+    }
+
+    fn add_connected(&mut self) {
+        let mut routes = self.routes.lock().unwrap();
+        for link in &self.links {
+            let route = RipRoute {
+                net: link.net,
+                next_hop: None,
+                interface: link.interface.clone(),
+                metric: 1,
+                tag: 0,
+            };
+            routes.insert(route_key(&link.net),
+                          RouteState {
+                              route: route,
+                              learned_from: None,
+                              learned_on: link.local_ip,
+                              timeout: None,
+                              garbage: None,
+                              changed: true,
+                          });
+        }
+    }
+
+    fn next_deadline(&self) -> Instant {
+        let mut deadline = self.next_update;
+        if let Some(triggered) = self.next_triggered {
+            deadline = cmp::min(deadline, triggered);
+        }
+        let routes = self.routes.lock().unwrap();
+        for state in routes.values() {
+            if let Some(timeout) = state.timeout {
+                deadline = cmp::min(deadline, timeout);
+            }
+            if let Some(garbage) = state.garbage {
+                deadline = cmp::min(deadline, garbage);
+            }
+        }
+        deadline
+    }
+
+    fn handle_timers(&mut self) {
+        let now = Instant::now();
+        let mut expired = vec![];
+        {
+            let mut routes = self.routes.lock().unwrap();
+            let mut deleted = vec![];
+            for (key, state) in routes.iter_mut() {
+                if let Some(timeout) = state.timeout {
+                    if now >= timeout {
+                        debug!("Rip: Route to {} timed out", state.route.net);
+                        expired.push(state.route.clone());
+                        state.route.metric = INFINITY;
+                        state.timeout = None;
+                        state.garbage = Some(now + self.config.garbage_timeout);
+                        state.changed = true;
+                    }
+                } else if let Some(garbage) = state.garbage {
+                    if now >= garbage {
+                        deleted.push(*key);
+                    }
+                }
+            }
+            for key in deleted {
+                routes.remove(&key);
+            }
+        }
+        if !expired.is_empty() {
+            for route in &expired {
+                self.uninstall(route);
+            }
+            self.schedule_triggered();
+        }
+
+        if now >= self.next_update {
+            self.send_updates(false);
+            self.next_update = now + jittered(self.config.update_interval);
+            // The full update contains all the changes
+            self.next_triggered = None;
+        } else if let Some(triggered) = self.next_triggered {
+            if now >= triggered {
+                self.send_updates(true);
+                self.next_triggered = None;
+            }
+        }
+    }
+
+    fn schedule_triggered(&mut self) {
+        if self.next_triggered.is_none() {
+            let delay = Range::new(1000, 5000).ind_sample(&mut rand::thread_rng());
+            self.next_triggered = Some(Instant::now() + Duration::from_millis(delay));
+        }
+    }
+
+    fn handle_datagram(&mut self, local_ip: Ipv4Addr, src: SocketAddrV4, data: &[u8]) {
+        let link = match self.links.iter().find(|link| link.local_ip == local_ip) {
+            Some(link) => link.clone(),
+            None => return,
+        };
+        let message = match RipMessage::parse(data) {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("Rip: Invalid message from {}: {:?}", src, e);
+                return;
+            }
+        };
+        if message.password != self.config.password {
+            debug!("Rip: Dropping message from {} with invalid authentication", src);
+            return;
+        }
+        match message.command {
+            RipCommand::Request => self.handle_request(&link, src, message),
+            RipCommand::Response => self.handle_response(&link, src, message),
+        }
+    }
+
+    fn handle_request(&mut self, link: &RipLink, src: SocketAddrV4, mut message: RipMessage) {
+        if message.is_whole_table_request() {
+            let entries = self.entries_for(link, false);
+            self.send_entries(link, src, entries);
+        } else {
+            {
+                let routes = self.routes.lock().unwrap();
+                for entry in &mut message.entries {
+                    let key = route_key(&network(entry.ip, entry.prefix));
+                    entry.metric = routes.get(&key)
+                        .map(|state| state.route.metric)
+                        .unwrap_or(INFINITY);
+                }
+            }
+            message.command = RipCommand::Response;
+            message.version = 2;
+            message.password = self.config.password;
+            self.send_message(link, src, &message);
+        }
+    }
+
+    fn handle_response(&mut self, link: &RipLink, src: SocketAddrV4, message: RipMessage) {
+        let sender = *src.ip();
+        if src.port() != RIP_PORT || message.version < 2 {
+            debug!("Rip: Ignoring response from {}", src);
+            return;
+        }
+        if !link.net.contains(sender) || self.links.iter().any(|other| other.local_ip == sender) {
+            debug!("Rip: Ignoring response from {}, not a neighbor", sender);
+            return;
+        }
+        let mut changed = false;
+        for entry in message.entries {
+            if entry.afi != AFI_INET || entry.metric < 1 || entry.metric > INFINITY ||
+               !valid_destination(entry.ip, entry.prefix) {
+                continue;
+            }
+            let unspecified = Ipv4Addr::new(0, 0, 0, 0);
+            let next_hop = if entry.next_hop == unspecified || entry.next_hop == link.local_ip ||
+                              !link.net.contains(entry.next_hop) {
+                sender
+            } else {
+                entry.next_hop
+            };
+            let route = RipRoute {
+                net: network(entry.ip, entry.prefix),
+                next_hop: Some(next_hop),
+                interface: link.interface.clone(),
+                metric: cmp::min(entry.metric + 1, INFINITY),
+                tag: entry.tag,
+            };
+            if let Some((old, new)) = self.update_route(link, sender, route) {
+                if let Some(old) = old {
+                    self.uninstall(&old);
+                }
+                if let Some(new) = new {
+                    self.install(&new);
+                }
+                changed = true;
+            }
+        }
+        if changed {
+            self.schedule_triggered();
+        }
+    }
+
+    /// Updates the route table with a route received from `sender`, as
+    /// described in section 3.9.2 of RFC 2453. Returns the route to remove
+    /// from and the route to install into the stack if anything changed.
+    fn update_route(&mut self,
+                    link: &RipLink,
+                    sender: Ipv4Addr,
+                    route: RipRoute)
+                    -> Option<(Option<RipRoute>, Option<RipRoute>)> {
+        let now = Instant::now();
+        let mut routes = self.routes.lock().unwrap();
+        let key = route_key(&route.net);
+        if !routes.contains_key(&key) {
+            if route.metric >= INFINITY {
+                return None;
+            }
+            routes.insert(key,
+                          RouteState {
+                              route: route.clone(),
+                              learned_from: Some(sender),
+                              learned_on: link.local_ip,
+                              timeout: Some(now + self.config.route_timeout),
+                              garbage: None,
+                              changed: true,
+                          });
+            return Some((None, Some(route)));
+        }
+        let state = routes.get_mut(&key).unwrap();
+        if state.learned_from.is_none() {
+            // Never replace directly connected networks
+            return None;
+        }
+        let from_same = state.learned_from == Some(sender);
+        if from_same && route.metric < INFINITY {
+            state.timeout = Some(now + self.config.route_timeout);
+        }
+        if (from_same && route.metric != state.route.metric) ||
+           route.metric < state.route.metric {
+            let old = if state.route.metric < INFINITY {
+                Some(state.route.clone())
+            } else {
+                None
+            };
+            let unreachable = route.metric >= INFINITY;
+            state.route = route.clone();
+            state.learned_from = Some(sender);
+            state.learned_on = link.local_ip;
+            state.changed = true;
+            if unreachable {
+                state.timeout = None;
+                if state.garbage.is_none() {
+                    state.garbage = Some(now + self.config.garbage_timeout);
+                }
+                return Some((old, None));
+            }
+            state.timeout = Some(now + self.config.route_timeout);
+            state.garbage = None;
+            return Some((old, Some(route)));
+        }
+        None
+    }
+
+    /// Returns the entries to advertise on `link`. Routes learned on the link
+    /// are advertised as unreachable (split horizon with poisoned reverse).
+    fn entries_for(&self, link: &RipLink, only_changed: bool) -> Vec<RipEntry> {
+        let routes = self.routes.lock().unwrap();
+        let mut entries = vec![];
+        for state in routes.values() {
+            if only_changed && !state.changed {
+                continue;
+            }
+            let metric = if state.learned_from.is_some() && state.learned_on == link.local_ip {
+                INFINITY
+            } else {
+                state.route.metric
+            };
+            let mut entry = RipEntry::new(state.route.net.ip(),
+                                          state.route.net.prefix(),
+                                          Ipv4Addr::new(0, 0, 0, 0),
+                                          metric);
+            entry.tag = state.route.tag;
+            entries.push(entry);
+        }
+        entries
+    }
+
+    fn send_request(&self) {
+        let mut message = RipMessage::whole_table_request();
+        message.password = self.config.password;
+        let dst = SocketAddrV4::new(rip_multicast_group(), RIP_PORT);
+        for link in &self.links {
+            self.send_message(link, dst, &message);
+        }
+    }
+
+    fn send_updates(&mut self, only_changed: bool) {
+        let dst = SocketAddrV4::new(rip_multicast_group(), RIP_PORT);
+        for link in &self.links {
+            let entries = self.entries_for(link, only_changed);
+            self.send_entries(link, dst, entries);
+        }
+        let mut routes = self.routes.lock().unwrap();
+        for state in routes.values_mut() {
+            state.changed = false;
+        }
+    }
+
+    fn send_entries(&self, link: &RipLink, dst: SocketAddrV4, entries: Vec<RipEntry>) {
+        for chunk in entries.chunks(RipMessage::max_entries(&self.config.password)) {
+            let mut message = RipMessage::response();
+            message.password = self.config.password;
+            message.entries = chunk.to_vec();
+            self.send_message(link, dst, &message);
+        }
+    }
+
+    fn send_message(&self, link: &RipLink, dst: SocketAddrV4, message: &RipMessage) {
+        let data = message.to_bytes();
+        let mut stack = self.stack.lock().unwrap();
+        // Never wait for Arp with the stack locked. A requester that does not
+        // answer would block the daemon and everyone else using the stack.
+        let arp_timeout = Some(Duration::new(0, 0));
+        let mut result = Err(TxError::InvalidTx);
+        while let Err(TxError::InvalidTx) = result {
+            let ipv4_tx = match stack.interface(&link.interface).and_then(|i| {
+                i.ipv4_tx_from_timeout(Some(link.local_ip), *dst.ip(), None, arp_timeout)
+            }) {
+                Ok(ipv4_tx) => ipv4_tx,
+                Err(StackError::WouldBlock) => {
+                    debug!("Rip: {} is not in the Arp table, dropping message", dst.ip());
+                    return;
+                }
+                Err(e) => {
+                    warn!("Rip: Unable to send to {}: {:?}", dst, e);
+                    return;
+                }
+            };
+            result = UdpTx::new(ipv4_tx, RIP_PORT, dst.port()).send(&data);
+        }
+        if let Err(e) = result {
+            warn!("Rip: Unable to send to {}: {:?}", dst, e);
+        }
+    }
+
+    fn install(&self, route: &RipRoute) {
+        debug!("Rip: Installing route to {} via {:?} metric {}",
+               route.net,
+               route.next_hop,
+               route.metric);
+        let result = self.with_main_table(|table| {
+            table.add_route_with_metric(route.net,
+                                        route.next_hop,
+                                        route.interface.clone(),
+                                        route.metric)
+        });
+        if let Err(e) = result {
+            warn!("Rip: Unable to install route to {}: {:?}", route.net, e);
+        }
+    }
+
+    fn uninstall(&self, route: &RipRoute) {
+        debug!("Rip: Removing route to {} via {:?}", route.net, route.next_hop);
+        let result = self.with_main_table(|table| {
+            table.remove_route(route.net, route.next_hop, &route.interface);
+        });
+        if let Err(e) = result {
+            warn!("Rip: Unable to remove route to {}: {:?}", route.net, e);
+        }
+    }
+
+    fn uninstall_all(&self) {
+        let installed: Vec<RipRoute> = {
+            let routes = self.routes.lock().unwrap();
+            routes.values()
+                .filter(|state| state.learned_from.is_some() && state.route.metric < INFINITY)
+                .map(|state| state.route.clone())
+                .collect()
+        };
+        for route in &installed {
+            self.uninstall(route);
+        }
+    }
+
+    fn with_main_table<F>(&self, f: F) -> StackResult<()>
+        where F: FnOnce(&mut RoutingTable)
+    {
+        let mut stack = self.stack.lock().unwrap();
+        let policy = try!(stack.domain_routing_policy(&self.config.domain));
+        f(policy.main_table());
+        Ok(())
+    }
+}
+
+/// Returns the network `ip/prefix` with the host bits of `ip` cleared.
+fn network(ip: Ipv4Addr, prefix: u8) -> Ipv4Network {
+    let mask = if prefix == 0 {
+        0
+    } else {
+        !0u32 << (32 - prefix as u32)
+    };
+    Ipv4Network::new(Ipv4Addr::from(u32::from(ip) & mask), prefix).unwrap()
+}
+
+fn route_key(net: &Ipv4Network) -> RouteKey {
+    (u32::from(net.ip()), net.prefix())
+}
+
+/// Returns true if `ip/prefix` is a destination that may be learned from a
+/// neighbor. Loopback, multicast and reserved networks are not.
+fn valid_destination(ip: Ipv4Addr, prefix: u8) -> bool {
+    if prefix == 0 {
+        return u32::from(ip) == 0;
+    }
+    let first = ip.octets()[0];
+    !(first == 0 || first == 127 || first >= 224)
+}
+
+/// Returns `interval` offset by a random amount of up to a sixth of it in
+/// either direction, so routers don't synchronize their updates.
+fn jittered(interval: Duration) -> Duration {
+    let ms = interval.as_secs() * 1000 + (interval.subsec_nanos() / 1_000_000) as u64;
+    let jitter = ms / 6;
+    let ms = Range::new(ms - jitter, ms + jitter + 1).ind_sample(&mut rand::thread_rng());
+    Duration::from_millis(ms)
+}
@@ -0,0 +1,245 @@
+use RxError;
+use util::{read_u16, read_u32, write_u16, write_u32};
+
+use std::net::Ipv4Addr;
+
+/// The Udp port RIP routers send from and listen to.
+pub const RIP_PORT: u16 = 520;
+
+/// The metric used to mean that a destination is unreachable.
+pub const INFINITY: u32 = 16;
+
+/// Max number of entries in one RIP message, including a possible
+/// authentication entry.
+pub const MAX_ENTRIES: usize = 25;
+
+/// Address family identifier for IP in RIP entries.
+pub const AFI_INET: u16 = 2;
+
+const AFI_AUTH: u16 = 0xffff;
+const AUTH_SIMPLE_PASSWORD: u16 = 2;
+const HEADER_LEN: usize = 4;
+const ENTRY_LEN: usize = 20;
+
+/// Returns the multicast group RIPv2 messages are sent to, 224.0.0.9.
+pub fn rip_multicast_group() -> Ipv4Addr {
+    Ipv4Addr::new(224, 0, 0, 9)
+}
+
+/// The command field of a RIP message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RipCommand {
+    Request,
+    Response,
+}
+
+/// One route entry in a RIP message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RipEntry {
+    /// Address family, `AFI_INET` for normal routes. Zero in a request for
+    /// the whole table.
+    pub afi: u16,
+    pub tag: u16,
+    pub ip: Ipv4Addr,
+    pub prefix: u8,
+    /// The router to send traffic for this network to. 0.0.0.0 means the
+    /// sender of the message.
+    pub next_hop: Ipv4Addr,
+    pub metric: u32,
+}
+
+impl RipEntry {
+    /// Creates a new `AFI_INET` entry.
+    pub fn new(ip: Ipv4Addr, prefix: u8, next_hop: Ipv4Addr, metric: u32) -> RipEntry {
+        RipEntry {
+            afi: AFI_INET,
+            tag: 0,
+            ip: ip,
+            prefix: prefix,
+            next_hop: next_hop,
+            metric: metric,
+        }
+    }
+}
+
+/// A parsed RIPv2 message, as defined in RFC 2453.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RipMessage {
+    pub command: RipCommand,
+    pub version: u8,
+    /// Simple password authentication, if present in the message.
+    pub password: Option<[u8; 16]>,
+    pub entries: Vec<RipEntry>,
+}
+
+impl RipMessage {
+    /// Creates a request for the entire routing table of the receiver.
+    pub fn whole_table_request() -> RipMessage {
+        let mut entry = RipEntry::new(Ipv4Addr::new(0, 0, 0, 0),
+                                      0,
+                                      Ipv4Addr::new(0, 0, 0, 0),
+                                      INFINITY);
+        entry.afi = 0;
+        RipMessage {
+            command: RipCommand::Request,
+            version: 2,
+            password: None,
+            entries: vec![entry],
+        }
+    }
+
+    /// Creates an empty response message.
+    pub fn response() -> RipMessage {
+        RipMessage {
+            command: RipCommand::Response,
+            version: 2,
+            password: None,
+            entries: vec![],
+        }
+    }
+
+    /// Returns true if this is a request for the entire routing table.
+    pub fn is_whole_table_request(&self) -> bool {
+        self.command == RipCommand::Request && self.entries.len() == 1 &&
+        self.entries[0].afi == 0 && self.entries[0].metric == INFINITY
+    }
+
+    /// Returns how many route entries fit in one message with the given
+    /// authentication settings.
+    pub fn max_entries(password: &Option<[u8; 16]>) -> usize {
+        if password.is_some() {
+            MAX_ENTRIES - 1
+        } else {
+            MAX_ENTRIES
+        }
+    }
+
+    /// Parses a RIP message from the payload of a Udp datagram.
+    pub fn parse(data: &[u8]) -> Result<RipMessage, RxError> {
+        if data.len() < HEADER_LEN || (data.len() - HEADER_LEN) % ENTRY_LEN != 0 {
+            return Err(RxError::InvalidLength);
+        }
+        let command = match data[0] {
+            1 => RipCommand::Request,
+            2 => RipCommand::Response,
+            _ => return Err(RxError::InvalidContent),
+        };
+        let version = data[1];
+        if version == 0 {
+            return Err(RxError::InvalidContent);
+        }
+        let mut message = RipMessage {
+            command: command,
+            version: version,
+            password: None,
+            entries: vec![],
+        };
+        for (i, chunk) in data[HEADER_LEN..].chunks(ENTRY_LEN).enumerate() {
+            let afi = read_u16(&chunk[0..2]);
+            if afi == AFI_AUTH {
+                if i != 0 || read_u16(&chunk[2..4]) != AUTH_SIMPLE_PASSWORD {
+                    return Err(RxError::InvalidContent);
+                }
+                let mut password = [0; 16];
+                password.copy_from_slice(&chunk[4..20]);
+                message.password = Some(password);
+                continue;
+            }
+            let mask = read_u32(&chunk[8..12]);
+            let prefix = (!mask).leading_zeros() as u8;
+            if prefix_mask(prefix) != mask {
+                return Err(RxError::InvalidContent);
+            }
+            message.entries.push(RipEntry {
+                afi: afi,
+                tag: read_u16(&chunk[2..4]),
+                ip: Ipv4Addr::from(read_u32(&chunk[4..8])),
+                prefix: prefix,
+                next_hop: Ipv4Addr::from(read_u32(&chunk[12..16])),
+                metric: read_u32(&chunk[16..20]),
+            });
+        }
+        Ok(message)
+    }
+
+    /// Serializes this message into a buffer ready to be sent as the payload
+    /// of a Udp datagram.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let auth_entries = if self.password.is_some() { 1 } else { 0 };
+        let entries = auth_entries + self.entries.len();
+        let mut buffer = Vec::with_capacity(HEADER_LEN + entries * ENTRY_LEN);
+        buffer.push(match self.command {
+            RipCommand::Request => 1,
+            RipCommand::Response => 2,
+        });
+        buffer.push(self.version);
+        buffer.extend_from_slice(&[0, 0]);
+        if let Some(ref password) = self.password {
+            write_u16(&mut buffer, AFI_AUTH);
+            write_u16(&mut buffer, AUTH_SIMPLE_PASSWORD);
+            buffer.extend_from_slice(password);
+        }
+        for entry in &self.entries {
+            write_u16(&mut buffer, entry.afi);
+            write_u16(&mut buffer, entry.tag);
+            write_u32(&mut buffer, u32::from(entry.ip));
+            write_u32(&mut buffer, prefix_mask(entry.prefix));
+            write_u32(&mut buffer, u32::from(entry.next_hop));
+            write_u32(&mut buffer, entry.metric);
+        }
+        buffer
+    }
+}
+
+/// Returns the netmask with the `prefix` most significant bits set.
+fn prefix_mask(prefix: u8) -> u32 {
+    if prefix == 0 {
+        0
+    } else {
+        !0u32 << (32 - prefix as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use RxError;
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    #[test]
+    fn response_roundtrip() {
+        let mut message = RipMessage::response();
+        message.password = Some(*b"secretsecretsecr");
+        message.entries.push(RipEntry::new(Ipv4Addr::new(10, 1, 0, 0),
+                                           16,
+                                           Ipv4Addr::new(0, 0, 0, 0),
+                                           3));
+        message.entries.push(RipEntry::new(Ipv4Addr::new(0, 0, 0, 0),
+                                           0,
+                                           Ipv4Addr::new(10, 0, 0, 1),
+                                           INFINITY));
+        let bytes = message.to_bytes();
+        assert_eq!(bytes.len(), 4 + 3 * 20);
+        assert_eq!(&bytes[..4], &[2, 2, 0, 0]);
+        assert_eq!(RipMessage::parse(&bytes), Ok(message));
+    }
+
+    #[test]
+    fn whole_table_request() {
+        let bytes = RipMessage::whole_table_request().to_bytes();
+        let message = RipMessage::parse(&bytes).unwrap();
+        assert!(message.is_whole_table_request());
+    }
+
+    #[test]
+    fn invalid_messages() {
+        assert_eq!(RipMessage::parse(&[2, 2, 0]), Err(RxError::InvalidLength));
+        assert_eq!(RipMessage::parse(&[2, 2, 0, 0, 0]), Err(RxError::InvalidLength));
+        assert_eq!(RipMessage::parse(&[9, 2, 0, 0]), Err(RxError::InvalidContent));
+
+        let mut bytes = RipMessage::whole_table_request().to_bytes();
+        // Non contiguous netmask
+        bytes[4 + 8] = 0x0f;
+        assert_eq!(RipMessage::parse(&bytes), Err(RxError::InvalidContent));
+    }
+}
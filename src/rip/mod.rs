@@ -0,0 +1,10 @@
+mod message;
+
+pub use self::message::{AFI_INET, INFINITY, MAX_ENTRIES, RIP_PORT, RipCommand, RipEntry,
+                        RipMessage, rip_multicast_group};
+
+#[cfg(not(feature = "unit-tests"))]
+mod daemon;
+
+#[cfg(not(feature = "unit-tests"))]
+pub use self::daemon::{RipConfig, RipDaemon, RipRoute};
@@ -1,50 +1,594 @@
-use Interface;
+use {Interface, RouteGeneration};
 
 use ipnetwork::Ipv4Network;
 
-use std::collections::BTreeMap;
+use pnet::packet::ip::IpNextHeaderProtocol;
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
 use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+
+/// Name of the table that routes are added to unless another table is given.
+pub const MAIN_TABLE: &'static str = "main";
 
-// TODO: Add metric
-#[derive(Debug)]
+/// Priority of the rule that `RoutingPolicy` creates for the main table.
+pub const MAIN_RULE_PRIORITY: u32 = 32766;
+
+#[derive(Clone, Debug)]
 struct RouteEntry {
-    pub net: Ipv4Network,
     pub gw: Option<Ipv4Addr>,
     pub interface: Interface,
+    pub metric: u32,
+}
+
+/// One node in the path compressed binary trie the routes are stored in.
+/// `key` is the network address masked to `len` bits. Nodes only exist where
+/// routes are stored or where two branches split, so a lookup visits at most
+/// one node per bit in the address.
+///
+/// Children are shared between versions of the trie. Updates copy the nodes
+/// on the path they modify and leave older versions intact for the lookups
+/// still using them.
+#[derive(Clone, Debug)]
+struct TrieNode {
+    key: u32,
+    len: u8,
+    entries: Vec<RouteEntry>,
+    children: [Option<Arc<TrieNode>>; 2],
+}
+
+impl TrieNode {
+    fn new(key: u32, len: u8) -> TrieNode {
+        TrieNode {
+            key: mask(key, len),
+            len: len,
+            entries: vec![],
+            children: [None, None],
+        }
+    }
+
+    fn with_entry(key: u32, len: u8, entry: RouteEntry) -> TrieNode {
+        let mut node = TrieNode::new(key, len);
+        node.entries.push(entry);
+        node
+    }
+
+    /// Returns true if the first `self.len` bits of `key` equals this node.
+    fn matches(&self, key: u32) -> bool {
+        mask(key, self.len) == self.key
+    }
+
+    /// Returns the node for exactly the network `key/len`, if it exists.
+    fn find(&self, key: u32, len: u8) -> Option<&TrieNode> {
+        if len == self.len {
+            return if mask(key, len) == self.key { Some(self) } else { None };
+        }
+        match self.children[bit_at(key, self.len)] {
+            Some(ref child) if child.len <= len && child.matches(key) => child.find(key, len),
+            _ => None,
+        }
+    }
+
+    /// Removes the entries `is_route` returns true for from the node for
+    /// exactly the network `key/len` below this node. Returns true if any were
+    /// removed. Copies the nodes on the path to it if they are shared with
+    /// another version of the trie, and prunes the nodes no longer needed.
+    fn remove<F>(&mut self, key: u32, len: u8, is_route: &F) -> bool
+        where F: Fn(&RouteEntry) -> bool
+    {
+        if len == self.len {
+            let before = self.entries.len();
+            self.entries.retain(|e| !is_route(e));
+            return self.entries.len() != before;
+        }
+        let bit = bit_at(key, self.len);
+        let removed = match self.children[bit] {
+            Some(ref mut child) => {
+                let below = child.len <= len && child.matches(key);
+                below && Arc::make_mut(child).remove(key, len, is_route)
+            }
+            None => false,
+        };
+        if removed {
+            self.prune(bit);
+        }
+        removed
+    }
+
+    /// Removes the child at `bit` if it has neither routes nor children, or
+    /// replaces it with its only child if it has no routes and one child.
+    fn prune(&mut self, bit: usize) {
+        let replacement = match self.children[bit] {
+            Some(ref child) if child.entries.is_empty() => {
+                match (&child.children[0], &child.children[1]) {
+                    (&None, &None) => None,
+                    (&Some(ref only), &None) |
+                    (&None, &Some(ref only)) => Some(only.clone()),
+                    _ => return,
+                }
+            }
+            _ => return,
+        };
+        self.children[bit] = replacement;
+    }
+
+    /// Inserts `entry` for the network `key/len` below this node. This node
+    /// must be a prefix of the network being inserted.
+    fn insert(&mut self, key: u32, len: u8, entry: RouteEntry) {
+        if len == self.len {
+            self.entries.push(entry);
+            return;
+        }
+        let bit = bit_at(key, self.len);
+        self.children[bit] = Some(match self.children[bit].take() {
+            None => Arc::new(TrieNode::with_entry(key, len, entry)),
+            Some(mut child) => {
+                let common = common_prefix_len(child.key, key, cmp::min(child.len, len));
+                if common == child.len {
+                    Arc::make_mut(&mut child).insert(key, len, entry);
+                    child
+                } else {
+                    // The new network diverges from `child` somewhere along
+                    // its compressed path. Split it with a new node there.
+                    let mut split = TrieNode::new(key, common);
+                    let child_bit = bit_at(child.key, common);
+                    split.children[child_bit] = Some(child);
+                    if common == len {
+                        split.entries.push(entry);
+                    } else {
+                        let new_bit = bit_at(key, common);
+                        split.children[new_bit] = Some(Arc::new(TrieNode::with_entry(key,
+                                                                                     len,
+                                                                                     entry)));
+                    }
+                    Arc::new(split)
+                }
+            }
+        });
+    }
 }
 
-#[derive(Default)]
+/// IPv4 routing table. Routes are stored in a path compressed binary trie,
+/// giving longest prefix match lookups in O(prefix length) no matter how many
+/// routes the table holds.
+///
+/// Lookups walk a snapshot of the trie kept by each handle to the table, and
+/// only lock the shared routes to refresh it after the `RouteGeneration`
+/// changed.
 pub struct RoutingTable {
-    table: BTreeMap<u8, Vec<RouteEntry>>,
+    routes: Arc<Mutex<Arc<TrieNode>>>,
+    snapshot: RefCell<(usize, Arc<TrieNode>)>,
+    generation: RouteGeneration,
 }
 
 impl RoutingTable {
     pub fn new() -> RoutingTable {
-        RoutingTable { table: BTreeMap::new() }
+        Self::with_generation(RouteGeneration::new())
+    }
+
+    /// Creates a new empty `RoutingTable` that increments the given
+    /// `generation` on updates. Used when several tables route for the same
+    /// stack.
+    pub fn with_generation(generation: RouteGeneration) -> RoutingTable {
+        let root = Arc::new(TrieNode::new(0, 0));
+        RoutingTable {
+            routes: Arc::new(Mutex::new(root.clone())),
+            snapshot: RefCell::new((generation.current(), root)),
+            generation: generation,
+        }
+    }
+
+    /// Returns the `RouteGeneration` of this table. It is incremented on
+    /// every change to the routes, so any `Tx` created with it is invalidated.
+    pub fn generation(&self) -> RouteGeneration {
+        self.generation.clone()
+    }
+
+    /// Returns another handle to the routes of this table, for
+    /// `SharedRouting`.
+    fn share(&self) -> RoutingTable {
+        let current = self.generation.current();
+        let root = self.routes.lock().unwrap().clone();
+        RoutingTable {
+            routes: self.routes.clone(),
+            snapshot: RefCell::new((current, root)),
+            generation: self.generation.clone(),
+        }
     }
 
     // TODO: Check for collision
-    // TODO: Increment Tx version counter
     pub fn add_route(&mut self, net: Ipv4Network, gw: Option<Ipv4Addr>, interface: Interface) {
-        let prefix = net.prefix();
+        self.add_route_with_metric(net, gw, interface, 0)
+    }
+
+    /// Adds a route with the given metric. When multiple routes exist for the
+    /// same network the one with the lowest metric is used.
+    pub fn add_route_with_metric(&mut self,
+                                 net: Ipv4Network,
+                                 gw: Option<Ipv4Addr>,
+                                 interface: Interface,
+                                 metric: u32) {
+        let key = u32::from(net.ip());
+        let len = net.prefix();
         let entry = RouteEntry {
-            net: net,
             gw: gw,
             interface: interface,
+            metric: metric,
+        };
+        {
+            let mut root = self.routes.lock().unwrap();
+            Arc::make_mut(&mut *root).insert(key, len, entry);
+        }
+        self.generation.inc();
+    }
+
+    /// Removes the route to `net` via `gw` on `interface`. Returns true if
+    /// such a route existed.
+    pub fn remove_route(&mut self,
+                        net: Ipv4Network,
+                        gw: Option<Ipv4Addr>,
+                        interface: &Interface)
+                        -> bool {
+        let key = u32::from(net.ip());
+        let len = net.prefix();
+        let is_route = |e: &RouteEntry| e.gw == gw && e.interface == *interface;
+        let removed = {
+            let mut root = self.routes.lock().unwrap();
+            let exists = match root.find(key, len) {
+                Some(node) => node.entries.iter().any(&is_route),
+                None => false,
+            };
+            if exists {
+                Arc::make_mut(&mut *root).remove(key, len, &is_route);
+            }
+            exists
         };
-        self.table.entry(prefix).or_insert(vec![]).push(entry);
+        if removed {
+            self.generation.inc();
+        }
+        removed
     }
 
+    /// Finds the most specific route to `ip`. Returns the gateway to use, if
+    /// any, and the interface to send on.
     pub fn route(&self, ip: Ipv4Addr) -> Option<(Option<Ipv4Addr>, Interface)> {
-        for (_prefix, entries) in self.table.iter().rev() {
-            for entry in entries {
-                if entry.net.contains(ip) {
-                    return Some((entry.gw, entry.interface.clone()));
+        self.longest_match(u32::from(ip), 32)
+    }
+
+    /// Finds the most specific route to a network containing `net`, other
+    /// than the routes to `net` itself. That is the route `route` would
+    /// return for the addresses in `net` if there were no routes to `net`.
+    pub fn route_covering(&self, net: Ipv4Network) -> Option<(Option<Ipv4Addr>, Interface)> {
+        match net.prefix() {
+            0 => None,
+            len => self.longest_match(u32::from(net.ip()), len - 1),
+        }
+    }
+
+    /// Finds the most specific route to `key` with a prefix of at most
+    /// `max_len` bits.
+    fn longest_match(&self, key: u32, max_len: u8) -> Option<(Option<Ipv4Addr>, Interface)> {
+        let root = self.snapshot();
+        let mut node = &*root;
+        let mut best = None;
+        loop {
+            if let Some(entry) = node.entries.iter().min_by_key(|e| e.metric) {
+                best = Some(entry);
+            }
+            if node.len >= 32 {
+                break;
+            }
+            match node.children[bit_at(key, node.len)] {
+                Some(ref child) if child.len <= max_len && child.matches(key) => node = child,
+                _ => break,
+            }
+        }
+        best.map(|entry| (entry.gw, entry.interface.clone()))
+    }
+
+    /// Returns the current routes, refreshing the snapshot of this handle if
+    /// they changed since it was taken.
+    fn snapshot(&self) -> Arc<TrieNode> {
+        let mut snapshot = self.snapshot.borrow_mut();
+        // Read before locking. A change made in between is seen as a new
+        // generation on the next lookup.
+        let current = self.generation.current();
+        if snapshot.0 != current {
+            *snapshot = (current, self.routes.lock().unwrap().clone());
+        }
+        snapshot.1.clone()
+    }
+}
+
+impl Default for RoutingTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Description of the traffic a route is looked up for. Matched against the
+/// `RoutingRule`s to select which `RoutingTable` to use.
+#[derive(Clone, Debug, Default)]
+pub struct Flow {
+    /// Source address the packets will be sent from, if already decided.
+    pub src: Option<Ipv4Addr>,
+
+    /// The interface the traffic came in on, for replies and forwarding.
+    pub iif: Option<Interface>,
+
+    /// The interface the sender wants the traffic sent out on. Routes via
+    /// other interfaces are then ignored, and without a route via this one
+    /// the destination is assumed to be on link.
+    pub oif: Option<Interface>,
+
+    /// The protocol carried by the IP packets.
+    pub protocol: Option<IpNextHeaderProtocol>,
+
+    /// Firewall mark set on the traffic. Zero means no mark.
+    pub mark: u32,
+}
+
+impl Flow {
+    /// Creates a `Flow` for packets of the given protocol from `src`.
+    pub fn new(src: Option<Ipv4Addr>, protocol: IpNextHeaderProtocol) -> Flow {
+        Flow {
+            src: src,
+            iif: None,
+            oif: None,
+            protocol: Some(protocol),
+            mark: 0,
+        }
+    }
+}
+
+/// Policy rule selecting which `RoutingTable` to look up routes in. All
+/// selectors that are set must match the `Flow` for the rule to apply.
+#[derive(Clone, Debug)]
+pub struct RoutingRule {
+    /// Rules are evaluated in increasing priority order.
+    pub priority: u32,
+
+    /// Matches traffic with a source address in this network.
+    pub src: Option<Ipv4Network>,
+
+    /// Matches traffic that came in on this interface.
+    pub iif: Option<Interface>,
+
+    /// Matches traffic carrying this protocol.
+    pub protocol: Option<IpNextHeaderProtocol>,
+
+    /// Matches traffic with this firewall mark.
+    pub mark: Option<u32>,
+
+    /// Name of the table to look up the route in when the rule matches.
+    pub table: String,
+}
+
+impl RoutingRule {
+    /// Creates a rule matching all traffic and pointing it to `table`.
+    pub fn new(priority: u32, table: &str) -> RoutingRule {
+        RoutingRule {
+            priority: priority,
+            src: None,
+            iif: None,
+            protocol: None,
+            mark: None,
+            table: table.to_owned(),
+        }
+    }
+
+    /// Returns true if all selectors in this rule match `flow`.
+    pub fn matches(&self, flow: &Flow) -> bool {
+        if let Some(net) = self.src {
+            match flow.src {
+                Some(src) if net.contains(src) => (),
+                _ => return false,
+            }
+        }
+        if self.iif.is_some() && self.iif != flow.iif {
+            return false;
+        }
+        if self.protocol.is_some() && self.protocol != flow.protocol {
+            return false;
+        }
+        if let Some(mark) = self.mark {
+            if mark != flow.mark {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Linux style policy routing. Holds a number of named `RoutingTable`s and an
+/// ordered list of `RoutingRule`s selecting which table to use for a given
+/// `Flow`. Starts out with only the main table and a rule sending all traffic
+/// to it.
+pub struct RoutingPolicy {
+    tables: HashMap<String, RoutingTable>,
+    rules: Vec<RoutingRule>,
+    shared: SharedRouting,
+    generation: RouteGeneration,
+}
+
+impl RoutingPolicy {
+    pub fn new() -> RoutingPolicy {
+        let generation = RouteGeneration::new();
+        let main_table = RoutingTable::with_generation(generation.clone());
+        let rules = vec![RoutingRule::new(MAIN_RULE_PRIORITY, MAIN_TABLE)];
+        let mut shared_tables = HashMap::new();
+        shared_tables.insert(MAIN_TABLE.to_owned(), main_table.share());
+        let shared = SharedRouting {
+            data: Arc::new(Mutex::new(SharedData {
+                tables: shared_tables,
+                rules: rules.clone(),
+            })),
+            generation: generation.clone(),
+        };
+        let mut tables = HashMap::new();
+        tables.insert(MAIN_TABLE.to_owned(), main_table);
+        RoutingPolicy {
+            tables: tables,
+            rules: rules,
+            shared: shared,
+            generation: generation,
+        }
+    }
+
+    /// Returns the `RouteGeneration` shared by all tables in this policy.
+    /// Also incremented when the rules change.
+    pub fn generation(&self) -> RouteGeneration {
+        self.generation.clone()
+    }
+
+    /// Returns a handle routing by the tables and rules of this policy, also
+    /// after they change.
+    pub fn shared(&self) -> SharedRouting {
+        self.shared.clone()
+    }
+
+    /// Returns the main table.
+    pub fn main_table(&mut self) -> &mut RoutingTable {
+        self.table(MAIN_TABLE)
+    }
+
+    /// Returns the table with the given name, creating it if it does not
+    /// exist.
+    pub fn table(&mut self, name: &str) -> &mut RoutingTable {
+        let generation = &self.generation;
+        let shared = &self.shared;
+        self.tables
+            .entry(name.to_owned())
+            .or_insert_with(|| {
+                let table = RoutingTable::with_generation(generation.clone());
+                let mut data = shared.data.lock().unwrap();
+                data.tables.insert(name.to_owned(), table.share());
+                table
+            })
+    }
+
+    /// Returns the names of all tables in this policy.
+    pub fn table_names(&self) -> Vec<String> {
+        self.tables.keys().cloned().collect()
+    }
+
+    /// Adds a rule. It is placed after all existing rules with the same or
+    /// lower priority.
+    pub fn add_rule(&mut self, rule: RoutingRule) {
+        let index = self.rules
+            .iter()
+            .position(|r| r.priority > rule.priority)
+            .unwrap_or(self.rules.len());
+        self.rules.insert(index, rule);
+        self.rules_changed();
+    }
+
+    /// Removes all rules with the given priority. Returns how many were
+    /// removed.
+    pub fn remove_rules(&mut self, priority: u32) -> usize {
+        let before = self.rules.len();
+        self.rules.retain(|r| r.priority != priority);
+        let removed = before - self.rules.len();
+        if removed > 0 {
+            self.rules_changed();
+        }
+        removed
+    }
+
+    /// Returns all rules in the order they are evaluated.
+    pub fn rules(&self) -> &[RoutingRule] {
+        &self.rules
+    }
+
+    /// Evaluates the rules in order and returns the route from the first
+    /// table, selected by a matching rule, that has a route to `dst`.
+    pub fn route(&self, dst: Ipv4Addr, flow: &Flow) -> Option<(Option<Ipv4Addr>, Interface)> {
+        route_by_rules(&self.rules, &self.tables, dst, flow)
+    }
+
+    fn rules_changed(&mut self) {
+        self.shared.data.lock().unwrap().rules = self.rules.clone();
+        self.generation.inc();
+    }
+}
+
+impl Default for RoutingPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct SharedData {
+    tables: HashMap<String, RoutingTable>,
+    rules: Vec<RoutingRule>,
+}
+
+/// Handle to the tables and rules of a `RoutingPolicy`, kept up to date by
+/// the policy. Used by the parts of the stack that route without access to
+/// the `NetworkStack`, such as the receiving threads sending replies to
+/// incoming packets.
+#[derive(Clone)]
+pub struct SharedRouting {
+    data: Arc<Mutex<SharedData>>,
+    generation: RouteGeneration,
+}
+
+impl SharedRouting {
+    /// Returns the `RouteGeneration` of the policy.
+    pub fn generation(&self) -> RouteGeneration {
+        self.generation.clone()
+    }
+
+    /// Returns a handle to the main table of the policy. Routes added to it
+    /// are seen by the policy.
+    pub fn main_table(&self) -> RoutingTable {
+        self.data.lock().unwrap().tables[MAIN_TABLE].share()
+    }
+
+    /// Same as `RoutingPolicy::route`.
+    pub fn route(&self, dst: Ipv4Addr, flow: &Flow) -> Option<(Option<Ipv4Addr>, Interface)> {
+        let data = self.data.lock().unwrap();
+        route_by_rules(&data.rules, &data.tables, dst, flow)
+    }
+}
+
+fn route_by_rules(rules: &[RoutingRule],
+                  tables: &HashMap<String, RoutingTable>,
+                  dst: Ipv4Addr,
+                  flow: &Flow)
+                  -> Option<(Option<Ipv4Addr>, Interface)> {
+    for rule in rules {
+        if rule.matches(flow) {
+            if let Some(table) = tables.get(&rule.table) {
+                if let Some(route) = table.route(dst) {
+                    return Some(route);
                 }
             }
         }
-        None
     }
+    None
+}
+
+/// Returns `key` with all but the `len` most significant bits cleared.
+fn mask(key: u32, len: u8) -> u32 {
+    if len == 0 {
+        0
+    } else {
+        key & (!0u32 << (32 - len as u32))
+    }
+}
+
+/// Returns bit number `pos`, counted from the most significant bit, of `key`.
+fn bit_at(key: u32, pos: u8) -> usize {
+    ((key >> (31 - pos as u32)) & 1) as usize
+}
+
+/// Returns how many of the most significant bits `a` and `b` have in common,
+/// but at most `max`.
+fn common_prefix_len(a: u32, b: u32, max: u8) -> u8 {
+    cmp::min((a ^ b).leading_zeros() as u8, max)
 }
 
 
@@ -113,6 +657,177 @@ mod tests {
         assert_eq!(out_eth2, iface("eth1"));
     }
 
+    #[test]
+    fn add_route_bumps_generation() {
+        let mut table = RoutingTable::new();
+        let generation = table.generation();
+        let before = generation.current();
+        table.add_route(Ipv4Network::from_cidr("10/8").unwrap(), None, iface("eth0"));
+        assert_eq!(generation.current(), before + 1);
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut table = RoutingTable::new();
+        table.add_route(Ipv4Network::from_cidr("10.0.0.0/8").unwrap(), None, iface("eth8"));
+        table.add_route(Ipv4Network::from_cidr("10.128.0.0/9").unwrap(),
+                        None,
+                        iface("eth9"));
+        table.add_route(Ipv4Network::from_cidr("10.0.0.0/24").unwrap(),
+                        None,
+                        iface("eth24"));
+        table.add_route(Ipv4Network::from_cidr("10.0.1.0/24").unwrap(),
+                        None,
+                        iface("eth24b"));
+        table.add_route(Ipv4Network::from_cidr("10.0.0.0/16").unwrap(),
+                        None,
+                        iface("eth16"));
+
+        let lookup = |ip| table.route(ip).unwrap().1;
+        assert_eq!(lookup(Ipv4Addr::new(10, 0, 0, 7)), iface("eth24"));
+        assert_eq!(lookup(Ipv4Addr::new(10, 0, 1, 7)), iface("eth24b"));
+        assert_eq!(lookup(Ipv4Addr::new(10, 0, 2, 7)), iface("eth16"));
+        assert_eq!(lookup(Ipv4Addr::new(10, 1, 0, 7)), iface("eth8"));
+        assert_eq!(lookup(Ipv4Addr::new(10, 200, 0, 7)), iface("eth9"));
+        assert!(table.route(Ipv4Addr::new(11, 0, 0, 7)).is_none());
+    }
+
+    #[test]
+    fn metric_and_remove() {
+        let net = Ipv4Network::from_cidr("10.0.0.0/24").unwrap();
+        let gw1 = Ipv4Addr::new(192, 168, 0, 1);
+        let gw2 = Ipv4Addr::new(192, 168, 0, 2);
+        let ip = Ipv4Addr::new(10, 0, 0, 20);
+
+        let mut table = RoutingTable::new();
+        table.add_route_with_metric(net, Some(gw1), iface("eth0"), 5);
+        table.add_route_with_metric(net, Some(gw2), iface("eth0"), 2);
+        assert_eq!(table.route(ip).unwrap().0, Some(gw2));
+
+        assert!(table.remove_route(net, Some(gw2), &iface("eth0")));
+        assert!(!table.remove_route(net, Some(gw2), &iface("eth0")));
+        assert_eq!(table.route(ip).unwrap().0, Some(gw1));
+        assert!(table.remove_route(net, Some(gw1), &iface("eth0")));
+        assert!(table.route(ip).is_none());
+    }
+
+    #[test]
+    fn remove_prunes_nodes() {
+        let net8 = Ipv4Network::from_cidr("10.0.0.0/8").unwrap();
+        let net24a = Ipv4Network::from_cidr("10.0.0.0/24").unwrap();
+        let net24b = Ipv4Network::from_cidr("10.0.1.0/24").unwrap();
+        let mut table = RoutingTable::new();
+        table.add_route(net8, None, iface("eth8"));
+        table.add_route(net24a, None, iface("eth24"));
+        table.add_route(net24b, None, iface("eth24b"));
+
+        // The emptied /8 is merged into the node splitting the /24s
+        assert!(table.remove_route(net8, None, &iface("eth8")));
+        {
+            let root = table.routes.lock().unwrap();
+            let split = root.children[0].as_ref().unwrap();
+            assert_eq!((split.len, split.entries.len()), (23, 0));
+        }
+        assert_eq!(table.route(Ipv4Addr::new(10, 0, 1, 7)).unwrap().1, iface("eth24b"));
+        assert!(table.route(Ipv4Addr::new(10, 1, 0, 7)).is_none());
+
+        assert!(table.remove_route(net24a, None, &iface("eth24")));
+        {
+            let root = table.routes.lock().unwrap();
+            assert_eq!(root.children[0].as_ref().unwrap().len, 24);
+        }
+        assert!(table.remove_route(net24b, None, &iface("eth24b")));
+        {
+            let root = table.routes.lock().unwrap();
+            assert!(root.children[0].is_none() && root.children[1].is_none());
+        }
+        assert!(table.route(Ipv4Addr::new(10, 0, 1, 7)).is_none());
+    }
+
+    #[test]
+    fn route_covering() {
+        let net8 = Ipv4Network::from_cidr("10.0.0.0/8").unwrap();
+        let net24 = Ipv4Network::from_cidr("10.0.0.0/24").unwrap();
+        let host = Ipv4Network::from_cidr("10.0.0.7/32").unwrap();
+        let mut table = RoutingTable::new();
+        table.add_route(net8, None, iface("eth8"));
+        table.add_route(net24, None, iface("eth24"));
+        table.add_route(host, None, iface("host"));
+
+        assert_eq!(table.route(host.ip()).unwrap().1, iface("host"));
+        assert_eq!(table.route_covering(host).unwrap().1, iface("eth24"));
+        assert_eq!(table.route_covering(net24).unwrap().1, iface("eth8"));
+        assert!(table.route_covering(net8).is_none());
+    }
+
+    #[test]
+    fn shared_routing() {
+        let mut policy = RoutingPolicy::new();
+        let shared = policy.shared();
+        policy.table("other").add_route(Ipv4Network::from_cidr("11/8").unwrap(),
+                                        None,
+                                        iface("eth1"));
+        policy.add_rule(RoutingRule::new(10, "other"));
+        let (_, out_eth) = shared.route(Ipv4Addr::new(11, 0, 0, 1), &Flow::default()).unwrap();
+        assert_eq!(out_eth, iface("eth1"));
+
+        shared.main_table().add_route(Ipv4Network::from_cidr("10/8").unwrap(),
+                                      None,
+                                      iface("eth0"));
+        let (_, out_eth) = policy.route(Ipv4Addr::new(10, 0, 0, 1), &Flow::default()).unwrap();
+        assert_eq!(out_eth, iface("eth0"));
+    }
+
+    #[test]
+    fn policy_main_table() {
+        let mut policy = RoutingPolicy::new();
+        policy.main_table()
+            .add_route(Ipv4Network::from_cidr("10/8").unwrap(), None, iface("eth0"));
+        let (_, out_eth) = policy.route(Ipv4Addr::new(10, 0, 0, 1), &Flow::default()).unwrap();
+        assert_eq!(out_eth, iface("eth0"));
+        assert!(policy.route(Ipv4Addr::new(11, 0, 0, 1), &Flow::default()).is_none());
+    }
+
+    #[test]
+    fn policy_source_rule() {
+        let default_net = Ipv4Network::from_cidr("0/0").unwrap();
+        let gw0 = Ipv4Addr::new(10, 0, 0, 1);
+        let gw1 = Ipv4Addr::new(192, 168, 0, 1);
+
+        let mut policy = RoutingPolicy::new();
+        policy.main_table().add_route(default_net, Some(gw0), iface("eth0"));
+        policy.table("uplink1").add_route(default_net, Some(gw1), iface("eth1"));
+        let mut rule = RoutingRule::new(100, "uplink1");
+        rule.src = Some(Ipv4Network::from_cidr("192.168.0.0/24").unwrap());
+        policy.add_rule(rule);
+
+        let dst = Ipv4Addr::new(8, 8, 8, 8);
+        let mut flow = Flow::default();
+        assert_eq!(policy.route(dst, &flow).unwrap(), (Some(gw0), iface("eth0")));
+        flow.src = Some(Ipv4Addr::new(192, 168, 0, 7));
+        assert_eq!(policy.route(dst, &flow).unwrap(), (Some(gw1), iface("eth1")));
+
+        assert_eq!(policy.remove_rules(100), 1);
+        assert_eq!(policy.route(dst, &flow).unwrap(), (Some(gw0), iface("eth0")));
+    }
+
+    #[test]
+    fn policy_falls_through_empty_table() {
+        let mut policy = RoutingPolicy::new();
+        policy.main_table()
+            .add_route(Ipv4Network::from_cidr("10/8").unwrap(), None, iface("eth0"));
+        let mut rule = RoutingRule::new(10, "marked");
+        rule.mark = Some(7);
+        policy.add_rule(rule);
+
+        let mut flow = Flow::default();
+        flow.mark = 7;
+        let (_, out_eth) = policy.route(Ipv4Addr::new(10, 0, 0, 1), &flow).unwrap();
+        assert_eq!(out_eth, iface("eth0"));
+        assert_eq!(policy.rules()[0].priority, 10);
+        assert_eq!(policy.rules()[1].priority, MAIN_RULE_PRIORITY);
+    }
+
     fn iface(name: &str) -> Interface {
         Interface {
             name: name.to_string(),
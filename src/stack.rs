@@ -1,4 +1,6 @@
-use {EthernetChannel, Interface, RoutingTable, Tx, TxError, VersionedTx};
+use {EthernetChannel, Flow, Interface, RouteGeneration, RoutingPolicy, RoutingTable, RxResult,
+     Tx, TxError, VersionedTx, Wake};
+use routing::SharedRouting;
 use arp;
 use ethernet;
 use icmp;
@@ -6,25 +8,30 @@ use icmp;
 use ipnetwork::Ipv4Network;
 use ipv4;
 
-use pnet::packet::icmp::IcmpType;
-use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::Packet;
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Packet;
 use pnet::util::MacAddr;
 
 use rand;
-use rand::distributions::{IndependentSample, Range};
 
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
 use std::io;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
 
 use udp;
 use util;
 
 pub static DEFAULT_MTU: usize = 1500;
-pub static LOCAL_PORT_RANGE_START: u16 = 32768;
-pub static LOCAL_PORT_RANGE_END: u16 = 61000;
+
+/// Name of the routing domain all interfaces belong to until moved to
+/// another domain with `NetworkStack::set_interface_domain`.
+pub static DEFAULT_DOMAIN: &'static str = "default";
 
 /// Error returned upon invalid usage or state of the stack.
 #[derive(Debug)]
@@ -32,6 +39,11 @@ pub enum StackError {
     IllegalArgument,
     NoRouteToHost,
     InvalidInterface,
+    InvalidDomain,
+    /// The next hop is not resolved yet and the caller asked not to wait.
+    WouldBlock,
+    /// The next hop was not resolved within the given time.
+    TimedOut,
     TxError(TxError),
     IoError(io::Error),
 }
@@ -55,6 +67,15 @@ impl From<StackError> for io::Error {
             StackError::IllegalArgument => other("Illegal argument".to_owned()),
             StackError::NoRouteToHost => other("No route to host".to_owned()),
             StackError::InvalidInterface => other("Invalid interface".to_owned()),
+            StackError::InvalidDomain => other("Invalid routing domain".to_owned()),
+            StackError::WouldBlock => {
+                io::Error::new(io::ErrorKind::WouldBlock,
+                               "Next hop not resolved yet".to_owned())
+            }
+            StackError::TimedOut => {
+                io::Error::new(io::ErrorKind::TimedOut,
+                               "Timed out resolving next hop".to_owned())
+            }
             StackError::IoError(io_e) => io_e,
             StackError::TxError(txe) => txe.into(),
         }
@@ -67,42 +88,76 @@ struct Ipv4Data {
     net: Ipv4Network,
     udp_listeners: Arc<Mutex<udp::UdpListenerLookup>>,
     icmp_listeners: Arc<Mutex<icmp::IcmpListenerLookup>>,
+    pingers: Arc<Mutex<icmp::PingerLookup>>,
 }
 
 /// Represents the stack on one physical interface.
 /// The larger `NetworkStack` comprises multiple of these.
 pub struct StackInterface {
     interface: Interface,
-    mtu: usize,
+    mtu: Arc<AtomicUsize>,
     tx: Arc<Mutex<VersionedTx>>,
+    receiving: Arc<AtomicBool>,
+    domain: String,
+    routing: Arc<Mutex<SharedRouting>>,
+    echo: Arc<Mutex<icmp::EchoState>>,
+    icmp_errors: icmp::IcmpErrorTx,
+    accept_redirects: Arc<AtomicBool>,
+    redirects: icmp::RedirectRoutes,
+    udp_checksum_errors: Arc<AtomicUsize>,
     arp_table: arp::ArpTable,
     ipv4s: HashMap<Ipv4Addr, Ipv4Data>,
     ipv4_listeners: Arc<Mutex<ipv4::IpListenerLookup>>,
+    /// How many times each local address joined each multicast group.
+    memberships: HashMap<Ipv4Addr, HashMap<Ipv4Addr, usize>>,
 }
 
 impl StackInterface {
-    pub fn new(interface: Interface, channel: EthernetChannel) -> StackInterface {
+    pub fn new(interface: Interface,
+               channel: EthernetChannel,
+               domain: &str,
+               routing: SharedRouting,
+               echo: Arc<Mutex<icmp::EchoState>>,
+               icmp_error_state: Arc<Mutex<icmp::IcmpErrorState>>,
+               accept_redirects: Arc<AtomicBool>,
+               udp_checksum_errors: Arc<AtomicUsize>)
+               -> StackInterface {
         let sender = channel.0;
         let receiver = channel.1;
 
         let vtx = Arc::new(Mutex::new(VersionedTx::new(sender)));
+        let mtu = Arc::new(AtomicUsize::new(DEFAULT_MTU));
+        let routing = Arc::new(Mutex::new(routing));
+        let redirects = icmp::RedirectRoutes::new(interface.clone(), routing.clone());
 
         let arp_table = arp::ArpTable::new();
         let arp_rx = arp_table.arp_rx(vtx.clone());
 
+        let reply_tx = Self::new_reply_tx_factory(&interface, &vtx, &routing, &arp_table, &mtu);
+        let icmp_errors = icmp::IcmpErrorTx::new(icmp_error_state, reply_tx);
+
         let ipv4_listeners = Arc::new(Mutex::new(HashMap::new()));
-        let ipv4_rx = ipv4::Ipv4Rx::new(ipv4_listeners.clone());
+        let ipv4_rx = ipv4::Ipv4Rx::with_icmp_errors(ipv4_listeners.clone(), icmp_errors.clone());
 
         let ethernet_listeners = vec![arp_rx, ipv4_rx];
-        ethernet::EthernetRx::new(ethernet_listeners).spawn(receiver);
+        let receiving = ethernet::EthernetRx::new(ethernet_listeners).spawn(receiver);
 
         StackInterface {
             interface: interface,
-            mtu: DEFAULT_MTU,
+            mtu: mtu,
             tx: vtx,
+            receiving: receiving,
+            domain: domain.to_owned(),
+            routing: routing,
+            echo: echo,
+            icmp_errors: icmp_errors,
+            accept_redirects: accept_redirects,
+            redirects: redirects,
+            udp_checksum_errors: udp_checksum_errors,
             arp_table: arp_table,
             ipv4s: HashMap::new(),
             ipv4_listeners: ipv4_listeners,
+            memberships: HashMap::new(),
         }
     }
 
@@ -110,6 +165,30 @@ impl StackInterface {
         &self.interface
     }
 
+    /// Returns the name of the routing domain this interface belongs to.
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// Returns false if the thread receiving frames on this interface has
+    /// stopped, after too many read errors from the datalink backend. Nothing
+    /// is received on the interface after that.
+    pub fn is_receiving(&self) -> bool {
+        self.receiving.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if `ip` is configured on this interface.
+    pub fn has_ipv4(&self, ip: Ipv4Addr) -> bool {
+        self.ipv4s.contains_key(&ip)
+    }
+
+    /// Returns true if `ip` is the limited broadcast address, or the directed
+    /// broadcast address of one of the networks on this interface.
+    pub fn is_broadcast(&self, ip: Ipv4Addr) -> bool {
+        ip.is_broadcast() ||
+        self.ipv4s.values().any(|ip_data| util::directed_broadcast(&ip_data.net) == Some(ip))
+    }
+
     fn tx(&self) -> Tx {
         Tx::versioned(self.tx.clone())
     }
@@ -128,28 +207,66 @@ impl StackInterface {
 
     pub fn add_ipv4(&mut self, ip_net: Ipv4Network) -> StackResult<()> {
         let ip = ip_net.ip();
+        let echo_responder = icmp::EchoResponder::new(ip,
+                                                      self.echo.clone(),
+                                                      self.reply_tx_factory());
         match self.ipv4s.entry(ip) {
             Entry::Occupied(_) => Err(StackError::IllegalArgument),
             Entry::Vacant(entry) => {
-                let mut proto_listeners = HashMap::new();
-
+                let pingers = Arc::new(Mutex::new(HashMap::new()));
                 let udp_listeners = Arc::new(Mutex::new(HashMap::new()));
-                let udp_rx = udp::UdpRx::new(udp_listeners.clone());
-                let udp_ipv4_listener = Box::new(udp_rx) as Box<ipv4::Ipv4Listener>;
-                proto_listeners.insert(IpNextHeaderProtocols::Udp, udp_ipv4_listener);
-
-                let icmp_listeners = Arc::new(Mutex::new(HashMap::new()));
-                let icmp_rx = icmp::IcmpRx::new(icmp_listeners.clone());
-                let icmp_listener = Box::new(icmp_rx) as Box<ipv4::Ipv4Listener>;
-                proto_listeners.insert(IpNextHeaderProtocols::Icmp, icmp_listener);
-
-                let mut ipv4_listeners = self.ipv4_listeners.lock().unwrap();
-                ipv4_listeners.insert(ip, proto_listeners);
+                let mut icmp_listeners = HashMap::new();
+                icmp::add_icmp_listener(&mut icmp_listeners,
+                                        IcmpTypes::EchoRequest.into(),
+                                        Box::new(echo_responder));
+                for icmp_type in &[IcmpTypes::EchoReply,
+                                   IcmpTypes::DestinationUnreachable,
+                                   IcmpTypes::TimeExceeded,
+                                   IcmpTypes::ParameterProblem] {
+                    let ping_rx = icmp::PingRx::new(pingers.clone());
+                    icmp::add_icmp_listener(&mut icmp_listeners,
+                                            (*icmp_type).into(),
+                                            Box::new(ping_rx));
+                }
+                let redirect_rx = icmp::RedirectRx::new(ip_net,
+                                                        self.redirects.clone(),
+                                                        self.accept_redirects.clone());
+                icmp::add_icmp_listener(&mut icmp_listeners,
+                                        IcmpTypes::RedirectMessage.into(),
+                                        Box::new(redirect_rx));
+                for icmp_type in &[IcmpTypes::DestinationUnreachable, IcmpTypes::TimeExceeded] {
+                    let udp_error_rx = udp::UdpErrorRx::new(udp_listeners.clone());
+                    icmp::add_icmp_listener(&mut icmp_listeners,
+                                            (*icmp_type).into(),
+                                            Box::new(udp_error_rx));
+                }
                 let data = Ipv4Data {
                     net: ip_net,
                     udp_listeners: udp_listeners,
-                    icmp_listeners: icmp_listeners,
+                    icmp_listeners: Arc::new(Mutex::new(icmp_listeners)),
+                    pingers: pingers,
                 };
+                let mut ipv4_listeners = self.ipv4_listeners.lock().unwrap();
+                let proto_listeners =
+                    Self::proto_listeners(&data, &self.icmp_errors, &self.udp_checksum_errors);
+                ipv4_listeners.insert(ip, proto_listeners);
+                // Icmp messages to the broadcast addresses, such as broadcast
+                // pings, go to the Icmp listeners of the first address added
+                // with that broadcast address. Udp has no broadcast sockets
+                // to deliver to.
+                let limited_broadcast = Ipv4Addr::new(255, 255, 255, 255);
+                for broadcast in util::directed_broadcast(&ip_net)
+                    .into_iter()
+                    .chain(Some(limited_broadcast)) {
+                    if let Entry::Vacant(entry) = ipv4_listeners.entry(broadcast) {
+                        let icmp_rx = icmp::IcmpRx::new(data.icmp_listeners.clone());
+                        let mut proto_listeners = HashMap::new();
+                        proto_listeners.insert(IpNextHeaderProtocols::Icmp,
+                                               Box::new(icmp_rx) as Box<ipv4::Ipv4Listener>);
+                        entry.insert(proto_listeners);
+                    }
+                }
+                self.icmp_errors.add_network(ip_net);
 
                 entry.insert(data);
                 Ok(())
@@ -157,29 +274,254 @@ impl StackInterface {
         }
     }
 
+    /// Returns the IPv4 networks configured on this interface.
+    pub fn ipv4_nets(&self) -> Vec<Ipv4Network> {
+        self.ipv4s.values().map(|ip_data| ip_data.net).collect()
+    }
+
+    /// Makes this interface accept packets sent to the multicast `group`. They
+    /// are delivered to the same Udp and Icmp listeners as packets sent to
+    /// `local_ip`, so a socket bound to `local_ip` receives the group traffic.
+    /// Several local addresses can join the same group, and each join must be
+    /// matched by a `leave_ipv4_multicast` with the same address.
+    pub fn join_ipv4_multicast(&mut self, group: Ipv4Addr, local_ip: Ipv4Addr) -> StackResult<()> {
+        if !group.is_multicast() || !self.ipv4s.contains_key(&local_ip) {
+            return Err(StackError::IllegalArgument);
+        }
+        *self.memberships
+            .entry(group)
+            .or_insert_with(HashMap::new)
+            .entry(local_ip)
+            .or_insert(0) += 1;
+        self.update_multicast_listeners(group);
+        Ok(())
+    }
+
+    /// Leaves the multicast `group` joined by `local_ip`. The group keeps
+    /// being accepted as long as anyone else is still joined to it.
+    pub fn leave_ipv4_multicast(&mut self,
+                                group: Ipv4Addr,
+                                local_ip: Ipv4Addr)
+                                -> StackResult<()> {
+        let emptied = {
+            let members = match self.memberships.get_mut(&group) {
+                Some(members) => members,
+                None => return Err(StackError::IllegalArgument),
+            };
+            let left = match members.get_mut(&local_ip) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                None => return Err(StackError::IllegalArgument),
+            };
+            if left {
+                members.remove(&local_ip);
+            }
+            members.is_empty()
+        };
+        if emptied {
+            self.memberships.remove(&group);
+        }
+        self.update_multicast_listeners(group);
+        Ok(())
+    }
+
+    /// Delivers the packets sent to `group` to the listeners of all local
+    /// addresses joined to it, or stops accepting it if none are.
+    fn update_multicast_listeners(&self, group: Ipv4Addr) {
+        let mut ipv4_listeners = self.ipv4_listeners.lock().unwrap();
+        let members = match self.memberships.get(&group) {
+            Some(members) => members,
+            None => {
+                ipv4_listeners.remove(&group);
+                return;
+            }
+        };
+        let mut group_listeners = HashMap::new();
+        for local_ip in members.keys() {
+            let ip_data = &self.ipv4s[local_ip];
+            let proto_listeners =
+                Self::proto_listeners(ip_data, &self.icmp_errors, &self.udp_checksum_errors);
+            for (protocol, listener) in proto_listeners {
+                group_listeners.entry(protocol)
+                    .or_insert_with(|| MulticastListener { listeners: vec![] })
+                    .listeners
+                    .push(listener);
+            }
+        }
+        let group_listeners = group_listeners.into_iter()
+            .map(|(protocol, listener)| (protocol, Box::new(listener) as Box<ipv4::Ipv4Listener>))
+            .collect();
+        ipv4_listeners.insert(group, group_listeners);
+    }
+
+    /// Creates the protocol listeners delivering to the listeners in
+    /// `ip_data`.
+    fn proto_listeners(ip_data: &Ipv4Data,
+                       icmp_errors: &icmp::IcmpErrorTx,
+                       udp_checksum_errors: &Arc<AtomicUsize>)
+                       -> HashMap<IpNextHeaderProtocol, Box<ipv4::Ipv4Listener>> {
+        let mut proto_listeners = HashMap::new();
+
+        let udp_rx = udp::UdpRx::with_icmp_errors(ip_data.udp_listeners.clone(),
+                                                  icmp_errors.clone(),
+                                                  udp_checksum_errors.clone());
+        let udp_ipv4_listener = Box::new(udp_rx) as Box<ipv4::Ipv4Listener>;
+        proto_listeners.insert(IpNextHeaderProtocols::Udp, udp_ipv4_listener);
+
+        let icmp_rx = icmp::IcmpRx::new(ip_data.icmp_listeners.clone());
+        let icmp_listener = Box::new(icmp_rx) as Box<ipv4::Ipv4Listener>;
+        proto_listeners.insert(IpNextHeaderProtocols::Icmp, icmp_listener);
+
+        proto_listeners
+    }
+
     pub fn ipv4_tx(&mut self, dst: Ipv4Addr, gw: Option<Ipv4Addr>) -> StackResult<ipv4::Ipv4Tx> {
+        self.ipv4_tx_from(None, dst, gw)
+    }
+
+    /// Creates an `Ipv4Tx` sending from `src` to `dst` via `gw` on this
+    /// interface. If no `src` is given the local IP closest to the next hop
+    /// is used. Returns `IllegalArgument` if `src` is not configured on this
+    /// interface.
+    pub fn ipv4_tx_from(&mut self,
+                        src: Option<Ipv4Addr>,
+                        dst: Ipv4Addr,
+                        gw: Option<Ipv4Addr>)
+                        -> StackResult<ipv4::Ipv4Tx> {
+        self.ipv4_tx_from_timeout(src, dst, gw, None)
+    }
+
+    /// Same as `ipv4_tx_from` but waits at most `arp_timeout` for the Arp
+    /// reply when the next hop is not in the Arp table. Returns `TimedOut`
+    /// if no reply came in time, or `WouldBlock` right after sending the
+    /// request if `arp_timeout` is zero. `None` waits until resolved.
+    pub fn ipv4_tx_from_timeout(&mut self,
+                                src: Option<Ipv4Addr>,
+                                dst: Ipv4Addr,
+                                gw: Option<Ipv4Addr>,
+                                arp_timeout: Option<Duration>)
+                                -> StackResult<ipv4::Ipv4Tx> {
+        self.ipv4_tx_via(src, dst, gw, arp_timeout, None)
+    }
+
+    /// Creates the `Ipv4Tx` for `ipv4_tx_from_timeout`. When `gw` came from
+    /// a route lookup its `generation` is given, so the `Ipv4Tx` stops
+    /// working once the routes change.
+    fn ipv4_tx_via(&mut self,
+                   src: Option<Ipv4Addr>,
+                   dst: Ipv4Addr,
+                   gw: Option<Ipv4Addr>,
+                   arp_timeout: Option<Duration>,
+                   generation: Option<RouteGeneration>)
+                   -> StackResult<ipv4::Ipv4Tx> {
         let local_dst = gw.unwrap_or(dst);
-        if let Some(src) = self.closest_local_ip(local_dst) {
-            let dst_mac = match self.arp_table.get(local_dst) {
-                Ok(mac) => mac,
-                Err(rx) => {
-                    try!(tx_send!(|| self.arp_tx(); src, local_dst));
-                    rx.recv().unwrap()
+        if let Some(src) = src {
+            if !self.has_ipv4(src) {
+                return Err(StackError::IllegalArgument);
+            }
+        }
+        if let Some(src) = src.or_else(|| self.closest_local_ip(local_dst)) {
+            let dst_mac = if local_dst.is_multicast() {
+                ipv4_multicast_mac(local_dst)
+            } else if self.is_broadcast(local_dst) {
+                MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff)
+            } else {
+                match self.arp_table.get(local_dst) {
+                    Ok(mac) => mac,
+                    Err(rx) => {
+                        try!(tx_send!(|| self.arp_tx(); src, local_dst));
+                        match arp_timeout {
+                            None => rx.recv().unwrap(),
+                            Some(timeout) if timeout == Duration::new(0, 0) => {
+                                return Err(StackError::WouldBlock);
+                            }
+                            Some(timeout) => {
+                                try!(rx.recv_timeout(timeout).map_err(|_| StackError::TimedOut))
+                            }
+                        }
+                    }
                 }
             };
-            let ethernet_tx = self.ethernet_tx(dst_mac);
-            Ok(ipv4::Ipv4Tx::new(ethernet_tx, src, dst, self.mtu))
+            let tx = match generation {
+                Some(generation) => self.tx().routed(generation),
+                None => self.tx(),
+            };
+            let ethernet_tx = ethernet::EthernetTx::new(tx, self.interface.mac, dst_mac);
+            Ok(ipv4::Ipv4Tx::new(ethernet_tx, src, dst, self.get_mtu()))
         } else {
             Err(StackError::IllegalArgument)
         }
     }
 
+    /// Creates the function the receiving side of this interface uses to get
+    /// an `Ipv4Tx` for replies, such as Echo Replies and Icmp errors.
+    fn reply_tx_factory(&self) -> icmp::ReplyTxFactory {
+        Self::new_reply_tx_factory(&self.interface,
+                                   &self.tx,
+                                   &self.routing,
+                                   &self.arp_table,
+                                   &self.mtu)
+    }
+
+    /// Replies are routed in the domain of the interface, but only sent if
+    /// the route goes out this same interface. Since the receiving thread is
+    /// the one reading Arp replies it can't wait for one, so if the next hop
+    /// is not in the Arp table a request is sent and the reply is dropped.
+    fn new_reply_tx_factory(interface: &Interface,
+                            vtx: &Arc<Mutex<VersionedTx>>,
+                            routing: &Arc<Mutex<SharedRouting>>,
+                            arp_table: &arp::ArpTable,
+                            mtu: &Arc<AtomicUsize>)
+                            -> icmp::ReplyTxFactory {
+        let interface = interface.clone();
+        let vtx = vtx.clone();
+        let routing = routing.clone();
+        let mut arp_table = arp_table.clone();
+        let mtu = mtu.clone();
+        Box::new(move |src, dst| {
+            // Read through the shared handle since the interface may move to
+            // another routing domain
+            let routing = routing.lock().unwrap().clone();
+            let flow = Flow {
+                src: Some(src),
+                iif: Some(interface.clone()),
+                oif: None,
+                protocol: Some(IpNextHeaderProtocols::Icmp),
+                mark: 0,
+            };
+            let gw = match routing.route(dst, &flow) {
+                Some((gw, ref out)) if *out == interface => gw,
+                _ => {
+                    debug!("No route to {} out {} for reply", dst, interface.name);
+                    return None;
+                }
+            };
+            let next_hop = gw.unwrap_or(dst);
+            match arp_table.get(next_hop) {
+                Ok(mac) => {
+                    let tx = Tx::versioned(vtx.clone()).routed(routing.generation());
+                    let ethernet_tx = ethernet::EthernetTx::new(tx, interface.mac, mac);
+                    Some(ipv4::Ipv4Tx::new(ethernet_tx, src, dst, mtu.load(Ordering::SeqCst)))
+                }
+                Err(_) => {
+                    let broadcast = MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+                    let tx = Tx::versioned(vtx.clone());
+                    let ethernet_tx = ethernet::EthernetTx::new(tx, interface.mac, broadcast);
+                    arp::ArpTx::new(ethernet_tx).send(src, next_hop).unwrap_or(());
+                    None
+                }
+            }
+        })
+    }
+
     pub fn get_mtu(&self) -> usize {
-        self.mtu
+        self.mtu.load(Ordering::SeqCst)
     }
 
     pub fn set_mtu(&mut self, mtu: usize) {
-        self.mtu = mtu;
+        self.mtu.store(mtu, Ordering::SeqCst);
         self.tx.lock().unwrap().inc();
     }
 
@@ -195,19 +537,63 @@ impl StackInterface {
     }
 }
 
+/// Delivers the packets sent to a multicast group to the listeners of every
+/// local address joined to it. Accepted if any of them accepts it.
+struct MulticastListener {
+    listeners: Vec<Box<ipv4::Ipv4Listener>>,
+}
+
+impl ipv4::Ipv4Listener for MulticastListener {
+    fn recv(&mut self, time: SystemTime, packet: Ipv4Packet) -> RxResult {
+        let mut result = Ok(());
+        let mut accepted = false;
+        for listener in &mut self.listeners {
+            match listener.recv(time, Ipv4Packet::new(packet.packet()).unwrap()) {
+                Ok(()) => accepted = true,
+                Err(e) => result = Err(e),
+            }
+        }
+        if accepted { Ok(()) } else { result }
+    }
+}
+
+/// Returns the Ethernet multicast MAC that IPv4 multicast packets to `group`
+/// are sent to. The lower 23 bits of the group are mapped into 01:00:5e:00:00:00.
+fn ipv4_multicast_mac(group: Ipv4Addr) -> MacAddr {
+    let octets = group.octets();
+    MacAddr::new(0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3])
+}
+
 /// The main struct of this library, managing an entire TCP/IP stack. Takes
 /// care of ARP, routing tables, threads, TCP resends/fragmentation etc. Most
 /// of this is still unimplemented.
+///
+/// Interfaces can be grouped into separate routing domains, similar to VRFs
+/// on Linux. Every domain has its own `RoutingPolicy` and address space, so
+/// the same subnet can be used on interfaces in different domains without
+/// colliding. Interfaces start out in the `DEFAULT_DOMAIN`.
 pub struct NetworkStack {
     interfaces: HashMap<Interface, StackInterface>,
-    routing_table: RoutingTable,
+    domains: HashMap<String, RoutingPolicy>,
+    echo: Arc<Mutex<icmp::EchoState>>,
+    icmp_errors: Arc<Mutex<icmp::IcmpErrorState>>,
+    accept_redirects: Arc<AtomicBool>,
+    udp_checksum_errors: Arc<AtomicUsize>,
+    port_allocator: udp::PortAllocator,
 }
 
 impl NetworkStack {
     pub fn new() -> NetworkStack {
+        let mut domains = HashMap::new();
+        domains.insert(DEFAULT_DOMAIN.to_owned(), RoutingPolicy::new());
         NetworkStack {
             interfaces: HashMap::new(),
-            routing_table: RoutingTable::new(),
+            domains: domains,
+            echo: Arc::new(Mutex::new(icmp::EchoState::default())),
+            icmp_errors: Arc::new(Mutex::new(icmp::IcmpErrorState::default())),
+            accept_redirects: Arc::new(AtomicBool::new(true)),
+            udp_checksum_errors: Arc::new(AtomicUsize::new(0)),
+            port_allocator: udp::PortAllocator::default(),
         }
     }
 
@@ -219,7 +605,15 @@ impl NetworkStack {
             Entry::Occupied(_) => Err(StackError::InvalidInterface),
             Entry::Vacant(entry) => {
                 let interface = entry.key().clone();
-                entry.insert(StackInterface::new(interface, channel));
+                let routing = self.domains[DEFAULT_DOMAIN].shared();
+                entry.insert(StackInterface::new(interface,
+                                                 channel,
+                                                 DEFAULT_DOMAIN,
+                                                 routing,
+                                                 self.echo.clone(),
+                                                 self.icmp_errors.clone(),
+                                                 self.accept_redirects.clone(),
+                                                 self.udp_checksum_errors.clone()));
                 Ok(())
             }
         }
@@ -245,65 +639,451 @@ impl NetworkStack {
         Err(StackError::InvalidInterface)
     }
 
+    /// Returns the main routing table of the default domain.
     pub fn routing_table(&mut self) -> &mut RoutingTable {
-        &mut self.routing_table
+        self.routing_policy().main_table()
+    }
+
+    /// Returns the routing policy of the default domain, holding all routing
+    /// tables and the rules selecting between them.
+    pub fn routing_policy(&mut self) -> &mut RoutingPolicy {
+        self.domains.get_mut(DEFAULT_DOMAIN).unwrap()
+    }
+
+    /// Creates a new, empty, routing domain.
+    pub fn add_domain(&mut self, domain: &str) -> StackResult<()> {
+        match self.domains.entry(domain.to_owned()) {
+            Entry::Occupied(_) => Err(StackError::IllegalArgument),
+            Entry::Vacant(entry) => {
+                entry.insert(RoutingPolicy::new());
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the names of all routing domains in this stack.
+    pub fn domains(&self) -> Vec<String> {
+        self.domains.keys().cloned().collect()
     }
 
-    /// Attach an IPv4 network to an interface.
+    /// Returns the routing policy of the given domain.
+    pub fn domain_routing_policy(&mut self, domain: &str) -> StackResult<&mut RoutingPolicy> {
+        self.domains.get_mut(domain).ok_or(StackError::InvalidDomain)
+    }
+
+    /// Moves an interface to another routing domain. Must be done before any
+    /// addresses are added to the interface.
+    pub fn set_interface_domain(&mut self,
+                                interface: &Interface,
+                                domain: &str)
+                                -> StackResult<()> {
+        let routing = match self.domains.get(domain) {
+            Some(policy) => policy.shared(),
+            None => return Err(StackError::InvalidDomain),
+        };
+        let stack_interface = try!(self.interface(interface));
+        if !stack_interface.ipv4s.is_empty() {
+            return Err(StackError::IllegalArgument);
+        }
+        stack_interface.domain = domain.to_owned();
+        *stack_interface.routing.lock().unwrap() = routing;
+        stack_interface.tx.lock().unwrap().inc();
+        Ok(())
+    }
+
+    /// Attach an IPv4 network to an interface. Fails if the IP is already
+    /// used by another interface in the same routing domain.
     /// TODO: Deprecate and make the routing stuff better instead
     pub fn add_ipv4(&mut self, interface: &Interface, ip_net: Ipv4Network) -> StackResult<()> {
+        let domain = try!(self.interface(interface)).domain.clone();
+        if self.find_interface(&domain, ip_net.ip()).is_some() {
+            return Err(StackError::IllegalArgument);
+        }
         try!(try!(self.interface(interface)).add_ipv4(ip_net));
-        self.routing_table.add_route(ip_net, None, interface.clone());
+        self.domains
+            .get_mut(&domain)
+            .unwrap()
+            .main_table()
+            .add_route(ip_net, None, interface.clone());
         Ok(())
     }
 
     pub fn ipv4_tx(&mut self, dst: Ipv4Addr) -> StackResult<ipv4::Ipv4Tx> {
-        if let Some((gw, interface)) = self.routing_table.route(dst) {
-            if let Some(stack_interface) = self.interfaces.get_mut(&interface) {
-                stack_interface.ipv4_tx(dst, gw)
-            } else {
-                Err(StackError::IllegalArgument)
+        self.ipv4_tx_flow(dst, &Flow::default())
+    }
+
+    /// Returns the local address packets to `dst` described by `flow` are
+    /// sent from. Same selection as in `ipv4_tx_in`, but without resolving
+    /// the next hop.
+    pub fn source_ip_in(&self,
+                        domain: &str,
+                        dst: Ipv4Addr,
+                        flow: &Flow)
+                        -> StackResult<Ipv4Addr> {
+        if let Some(src) = flow.src {
+            return Ok(src);
+        }
+        let route = try!(self.route_in(domain, dst, flow));
+        match route {
+            Some((gw, interface)) => {
+                match self.interfaces.get(&interface) {
+                    Some(stack_interface) if stack_interface.domain == domain => {
+                        stack_interface.closest_local_ip(gw.unwrap_or(dst))
+                            .ok_or(StackError::IllegalArgument)
+                    }
+                    _ => Err(StackError::NoRouteToHost),
+                }
+            }
+            None => Err(StackError::NoRouteToHost),
+        }
+    }
+
+    /// Looks up the route to `dst` for `flow` in `domain`. When `flow` has an
+    /// outgoing interface, routes via other interfaces are replaced by an on
+    /// link route via it. The limited broadcast address is never forwarded,
+    /// so no route fits it. It goes out the interface with the source
+    /// address of `flow` instead, or the outgoing interface if given.
+    fn route_in(&self,
+                domain: &str,
+                dst: Ipv4Addr,
+                flow: &Flow)
+                -> StackResult<Option<(Option<Ipv4Addr>, Interface)>> {
+        let route = match self.domains.get(domain) {
+            Some(policy) => policy.route(dst, flow),
+            None => return Err(StackError::InvalidDomain),
+        };
+        Ok(match flow.oif {
+            Some(ref oif) => {
+                match route {
+                    Some((gw, ref interface)) if interface == oif => Some((gw, oif.clone())),
+                    _ => Some((None, oif.clone())),
+                }
+            }
+            None if dst.is_broadcast() => {
+                flow.src
+                    .and_then(|src| self.find_interface(domain, src))
+                    .map(|stack_interface| (None, stack_interface.interface.clone()))
+            }
+            None => route,
+        })
+    }
+
+    /// Creates an `Ipv4Tx` to `dst` for the traffic described by `flow`. The
+    /// routing rules are evaluated against `flow` to select the routing
+    /// table, and if `flow` has a source address it's used as the source of
+    /// the packets.
+    pub fn ipv4_tx_flow(&mut self, dst: Ipv4Addr, flow: &Flow) -> StackResult<ipv4::Ipv4Tx> {
+        self.ipv4_tx_in(DEFAULT_DOMAIN, dst, flow)
+    }
+
+    /// Same as `ipv4_tx_flow` but routes in the given routing domain. Only
+    /// routes out through interfaces in that domain are used.
+    pub fn ipv4_tx_in(&mut self,
+                      domain: &str,
+                      dst: Ipv4Addr,
+                      flow: &Flow)
+                      -> StackResult<ipv4::Ipv4Tx> {
+        self.ipv4_tx_in_timeout(domain, dst, flow, None)
+    }
+
+    /// Same as `ipv4_tx_in` but waits at most `arp_timeout` for the next hop
+    /// to be resolved. See `StackInterface::ipv4_tx_from_timeout`.
+    pub fn ipv4_tx_in_timeout(&mut self,
+                              domain: &str,
+                              dst: Ipv4Addr,
+                              flow: &Flow,
+                              arp_timeout: Option<Duration>)
+                              -> StackResult<ipv4::Ipv4Tx> {
+        let route = try!(self.route_in(domain, dst, flow));
+        if let Some((gw, interface)) = route {
+            match self.interfaces.get_mut(&interface) {
+                Some(stack_interface) => {
+                    if stack_interface.domain == domain {
+                        let generation = stack_interface.routing.lock().unwrap().generation();
+                        stack_interface.ipv4_tx_via(flow.src,
+                                                    dst,
+                                                    gw,
+                                                    arp_timeout,
+                                                    Some(generation))
+                    } else {
+                        Err(StackError::NoRouteToHost)
+                    }
+                }
+                None => Err(StackError::IllegalArgument),
             }
         } else {
             Err(StackError::NoRouteToHost)
         }
     }
 
+    /// Wakes `waker` when the next hop to `dst` for `flow` in `domain` is
+    /// resolved, to retry a send that failed with `WouldBlock`. Woken right
+    /// away if it already is, or is a multicast or broadcast address that
+    /// needs no resolving.
+    pub fn wake_when_routable_in(&self,
+                                 domain: &str,
+                                 dst: Ipv4Addr,
+                                 flow: &Flow,
+                                 waker: Arc<Wake>)
+                                 -> StackResult<()> {
+        let (gw, interface) = match try!(self.route_in(domain, dst, flow)) {
+            Some(route) => route,
+            None => return Err(StackError::NoRouteToHost),
+        };
+        let stack_interface = match self.interfaces.get(&interface) {
+            Some(stack_interface) if stack_interface.domain == domain => stack_interface,
+            _ => return Err(StackError::NoRouteToHost),
+        };
+        let next_hop = gw.unwrap_or(dst);
+        if next_hop.is_multicast() || stack_interface.is_broadcast(next_hop) {
+            waker.wake();
+        } else {
+            stack_interface.arp_table.wake_when_resolved(next_hop, waker);
+        }
+        Ok(())
+    }
+
+    /// Returns how the stack answers Echo Requests to its addresses.
+    pub fn echo_settings(&self) -> icmp::EchoSettings {
+        self.echo.lock().unwrap().settings().clone()
+    }
+
+    /// Changes how the stack answers Echo Requests, for all interfaces.
+    pub fn set_echo_settings(&mut self, settings: icmp::EchoSettings) {
+        self.echo.lock().unwrap().set_settings(settings);
+    }
+
+    /// Returns how the stack reports packets it can't deliver with Icmp
+    /// errors.
+    pub fn icmp_error_settings(&self) -> icmp::IcmpErrorSettings {
+        self.icmp_errors.lock().unwrap().settings().clone()
+    }
+
+    /// Changes how the stack sends Icmp errors, for all interfaces.
+    pub fn set_icmp_error_settings(&mut self, settings: icmp::IcmpErrorSettings) {
+        self.icmp_errors.lock().unwrap().set_settings(settings);
+    }
+
+    /// Returns true if Icmp Redirects are turned into host routes.
+    pub fn accept_redirects(&self) -> bool {
+        self.accept_redirects.load(Ordering::SeqCst)
+    }
+
+    /// Sets if Icmp Redirects from the current gateway of a destination are
+    /// turned into host routes in the main table. Enabled by default.
+    pub fn set_accept_redirects(&mut self, accept: bool) {
+        self.accept_redirects.store(accept, Ordering::SeqCst);
+    }
+
+    /// Returns the number of received Udp datagrams dropped because of bad
+    /// checksums, on all interfaces.
+    pub fn udp_checksum_errors(&self) -> usize {
+        self.udp_checksum_errors.load(Ordering::SeqCst)
+    }
+
+    /// Returns how local ports are picked for sockets bound to port zero.
+    pub fn port_allocator_settings(&self) -> udp::PortAllocatorSettings {
+        self.port_allocator.settings().clone()
+    }
+
+    /// Changes how local ports are picked for sockets bound to port zero.
+    /// Fails if the port range is empty or contains port zero.
+    pub fn set_port_allocator_settings(&mut self,
+                                       settings: udp::PortAllocatorSettings)
+                                       -> io::Result<()> {
+        self.port_allocator.set_settings(settings)
+    }
+
     pub fn icmp_tx(&mut self, dst_ip: Ipv4Addr) -> StackResult<icmp::IcmpTx> {
-        let ipv4_tx = try!(self.ipv4_tx(dst_ip));
+        let flow = Flow::new(None, IpNextHeaderProtocols::Icmp);
+        let ipv4_tx = try!(self.ipv4_tx_flow(dst_ip, &flow));
         Ok(icmp::IcmpTx::new(ipv4_tx))
     }
 
-    pub fn icmp_listen<L>(&mut self,
-                          local_ip: Ipv4Addr,
-                          icmp_type: IcmpType,
-                          listener: L)
-                          -> io::Result<()>
-        where L: icmp::IcmpListener + 'static
+    /// Makes `listener` receive the Icmp messages to `local_ip` matching
+    /// `filter`. Pass an `IcmpType` to receive every code of that type, or an
+    /// `IcmpFilter` with a code to only receive that code.
+    pub fn icmp_listen<F, L>(&mut self,
+                             local_ip: Ipv4Addr,
+                             filter: F,
+                             listener: L)
+                             -> io::Result<()>
+        where F: Into<icmp::IcmpFilter>,
+              L: icmp::IcmpListener + 'static
+    {
+        self.icmp_listen_in(DEFAULT_DOMAIN, local_ip, filter, listener)
+    }
+
+    /// Same as `icmp_listen` but for a local IP in the given routing domain.
+    pub fn icmp_listen_in<F, L>(&mut self,
+                                domain: &str,
+                                local_ip: Ipv4Addr,
+                                filter: F,
+                                listener: L)
+                                -> io::Result<()>
+        where F: Into<icmp::IcmpFilter>,
+              L: icmp::IcmpListener + 'static
+    {
+        self.icmp_listen_id_in(domain, local_ip, filter, listener).map(|_| ())
+    }
+
+    /// Same as `icmp_listen_in` but returns the id of the listener, to
+    /// remove it with `icmp_unlisten_id_in`.
+    pub fn icmp_listen_id_in<F, L>(&mut self,
+                                   domain: &str,
+                                   local_ip: Ipv4Addr,
+                                   filter: F,
+                                   listener: L)
+                                   -> io::Result<icmp::IcmpListenerId>
+        where F: Into<icmp::IcmpFilter>,
+              L: icmp::IcmpListener + 'static
     {
         if local_ip == Ipv4Addr::new(0, 0, 0, 0) {
             panic!("Rips does not support listening to all interfaces yet");
         } else {
-            for stack_interface in self.interfaces.values() {
-                if let Some(ip_data) = stack_interface.ipv4s.get(&local_ip) {
-                    let mut icmp_listeners = ip_data.icmp_listeners.lock().unwrap();
-                    icmp_listeners.entry(icmp_type).or_insert(vec![]).push(Box::new(listener));
-                    return Ok(());
-                }
+            if let Some(stack_interface) = self.find_interface(domain, local_ip) {
+                let ip_data = &stack_interface.ipv4s[&local_ip];
+                let mut icmp_listeners = ip_data.icmp_listeners.lock().unwrap();
+                return Ok(icmp::add_icmp_listener(&mut icmp_listeners,
+                                                  filter.into(),
+                                                  Box::new(listener)));
             }
             let msg = "Bind address does not exist in stack".to_owned();
             Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
         }
     }
 
+    /// Removes the listener with `id` on `local_ip`, as returned by
+    /// `icmp_listen_id_in`. Returns true if the listener was there.
+    pub fn icmp_unlisten_id_in(&mut self,
+                               domain: &str,
+                               local_ip: Ipv4Addr,
+                               id: icmp::IcmpListenerId)
+                               -> bool {
+        match self.find_interface(domain, local_ip) {
+            Some(stack_interface) => {
+                let ip_data = &stack_interface.ipv4s[&local_ip];
+                let mut icmp_listeners = ip_data.icmp_listeners.lock().unwrap();
+                icmp::remove_icmp_listener(&mut icmp_listeners, id)
+            }
+            None => false,
+        }
+    }
+
+    /// Registers a `Pinger` on `local_ip` in `domain`. Allocates an identifier
+    /// not used by any other `Pinger` on that address. Echo Replies and Icmp
+    /// errors for it are sent to `queue`.
+    pub fn ping_register_in(&mut self,
+                            domain: &str,
+                            local_ip: Ipv4Addr,
+                            queue: icmp::PingQueue)
+                            -> io::Result<u16> {
+        if let Some(stack_interface) = self.find_interface(domain, local_ip) {
+            let mut pingers = stack_interface.ipv4s[&local_ip].pingers.lock().unwrap();
+            if pingers.len() > ::std::u16::MAX as usize {
+                let msg = format!("No free ping identifier on {}", local_ip);
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
+            }
+            let mut identifier = rand::random();
+            while pingers.contains_key(&identifier) {
+                identifier = rand::random();
+            }
+            pingers.insert(identifier, queue);
+            return Ok(identifier);
+        }
+        let msg = "Bind address does not exist in stack".to_owned();
+        Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+    }
+
+    /// Removes a `Pinger` registered with `ping_register_in`. Returns true if
+    /// it was registered.
+    pub fn ping_unregister_in(&mut self,
+                              domain: &str,
+                              local_ip: Ipv4Addr,
+                              identifier: u16)
+                              -> bool {
+        match self.find_interface(domain, local_ip) {
+            Some(stack_interface) => {
+                let mut pingers = stack_interface.ipv4s[&local_ip].pingers.lock().unwrap();
+                pingers.remove(&identifier).is_some()
+            }
+            None => false,
+        }
+    }
+
     pub fn udp_tx(&mut self, dst_ip: Ipv4Addr, src: u16, dst_port: u16) -> StackResult<udp::UdpTx> {
-        let ipv4_tx = try!(self.ipv4_tx(dst_ip));
+        let flow = Flow::new(None, IpNextHeaderProtocols::Udp);
+        self.udp_tx_flow(dst_ip, src, dst_port, &flow)
+    }
+
+    /// Creates an `UdpTx` for the traffic described by `flow`. See
+    /// `ipv4_tx_flow`.
+    pub fn udp_tx_flow(&mut self,
+                       dst_ip: Ipv4Addr,
+                       src: u16,
+                       dst_port: u16,
+                       flow: &Flow)
+                       -> StackResult<udp::UdpTx> {
+        self.udp_tx_in(DEFAULT_DOMAIN, dst_ip, src, dst_port, flow)
+    }
+
+    /// Same as `udp_tx_flow` but routes in the given routing domain.
+    pub fn udp_tx_in(&mut self,
+                     domain: &str,
+                     dst_ip: Ipv4Addr,
+                     src: u16,
+                     dst_port: u16,
+                     flow: &Flow)
+                     -> StackResult<udp::UdpTx> {
+        self.udp_tx_in_timeout(domain, dst_ip, src, dst_port, flow, None)
+    }
+
+    /// Same as `udp_tx_in` but waits at most `arp_timeout` for the next hop
+    /// to be resolved. See `StackInterface::ipv4_tx_from_timeout`.
+    pub fn udp_tx_in_timeout(&mut self,
+                             domain: &str,
+                             dst_ip: Ipv4Addr,
+                             src: u16,
+                             dst_port: u16,
+                             flow: &Flow,
+                             arp_timeout: Option<Duration>)
+                             -> StackResult<udp::UdpTx> {
+        let ipv4_tx = try!(self.ipv4_tx_in_timeout(domain, dst_ip, flow, arp_timeout));
         Ok(udp::UdpTx::new(ipv4_tx, src, dst_port))
     }
 
     pub fn udp_listen<A, L>(&mut self, addr: A, listener: L) -> io::Result<SocketAddr>
         where A: ToSocketAddrs,
               L: udp::UdpListener + 'static
+    {
+        self.udp_listen_in(DEFAULT_DOMAIN, addr, listener)
+    }
+
+    /// Same as `udp_listen` but for a local address in the given routing
+    /// domain.
+    pub fn udp_listen_in<A, L>(&mut self,
+                               domain: &str,
+                               addr: A,
+                               listener: L)
+                               -> io::Result<SocketAddr>
+        where A: ToSocketAddrs,
+              L: udp::UdpListener + 'static
+    {
+        self.udp_listen_shared_in(domain, addr, udp::PortSharing::Exclusive, listener)
+            .map(|(addr, _)| addr)
+    }
+
+    /// Same as `udp_listen_in` but lets other listeners bind the same port
+    /// if they use the same `sharing` mode. Returns the id of the listener
+    /// on the port too, for removing it with `udp_unlisten_id_in`.
+    pub fn udp_listen_shared_in<A, L>(&mut self,
+                                      domain: &str,
+                                      addr: A,
+                                      sharing: udp::PortSharing,
+                                      listener: L)
+                                      -> io::Result<(SocketAddr, udp::UdpListenerId)>
+        where A: ToSocketAddrs,
+              L: udp::UdpListener + 'static
     {
         match try!(util::first_socket_addr(addr)) {
             SocketAddr::V4(addr) => {
@@ -312,26 +1092,28 @@ impl NetworkStack {
                 if local_ip == &Ipv4Addr::new(0, 0, 0, 0) {
                     let msg = format!("Rips does not support listening to all interfaces yet");
                     return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, msg));
-                } else {
-                    for stack_interface in self.interfaces.values() {
-                        if let Some(ip_data) = stack_interface.ipv4s.get(local_ip) {
-                            let mut udp_listeners = ip_data.udp_listeners.lock().unwrap();
-                            if local_port == 0 {
-                                local_port = self.get_random_port(&*udp_listeners);
-                            }
-                            if !udp_listeners.contains_key(&local_port) {
-                                udp_listeners.insert(local_port, Box::new(listener));
-                                return Ok(SocketAddr::V4(SocketAddrV4::new(*local_ip, local_port)));
-                            } else {
-                                let msg = format!("Port {} is already occupied on {}",
-                                                  local_port,
-                                                  local_ip);
-                                return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
-                            }
-                        }
+                }
+                let udp_listeners = match self.udp_listeners_in(domain, *local_ip) {
+                    Some(udp_listeners) => udp_listeners,
+                    None => {
+                        let msg = "Bind address does not exist in stack".to_owned();
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
                     }
-                    let msg = "Bind address does not exist in stack".to_owned();
-                    Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+                };
+                let mut udp_listeners = udp_listeners.lock().unwrap();
+                if local_port == 0 {
+                    local_port = try!(self.port_allocator
+                        .allocate(&[*local_ip], None, |port| udp_listeners.contains_key(&port)));
+                }
+                let id = udp_listeners.entry(local_port)
+                    .or_insert_with(|| udp::UdpPort::new(sharing))
+                    .add(sharing, Box::new(listener));
+                if let Some(id) = id {
+                    let addr = SocketAddr::V4(SocketAddrV4::new(*local_ip, local_port));
+                    Ok((addr, id))
+                } else {
+                    let msg = format!("Port {} is already occupied on {}", local_port, local_ip);
+                    Err(io::Error::new(io::ErrorKind::AddrInUse, msg))
                 }
             }
             SocketAddr::V6(_) => {
@@ -341,18 +1123,140 @@ impl NetworkStack {
         }
     }
 
-    fn get_random_port(&self, listeners: &udp::UdpListenerLookup) -> u16 {
-        let range = Range::new(LOCAL_PORT_RANGE_START, LOCAL_PORT_RANGE_END);
-        let mut rng = rand::thread_rng();
-        let mut port = 0;
-        while port == 0 {
-            let n = range.ind_sample(&mut rng);
-            if !listeners.contains_key(&n) {
-                port = n;
-                break;
+    /// Picks a local port free on all of `local_ips`, so the same port can
+    /// be bound on each of them afterwards. `dst` is where the traffic will
+    /// go, if known, and feeds the hash of `PortSelection::HashBased`. The
+    /// port is not reserved, so bind it before releasing the stack. Fails
+    /// with `AddrInUse` when no port in the range is free on every address.
+    pub fn udp_allocate_port_in(&mut self,
+                                domain: &str,
+                                local_ips: &[Ipv4Addr],
+                                dst: Option<SocketAddrV4>)
+                                -> io::Result<u16> {
+        let mut listeners = Vec::with_capacity(local_ips.len());
+        for local_ip in local_ips {
+            match self.udp_listeners_in(domain, *local_ip) {
+                // Each map is locked once below, so addresses given twice
+                // must not add it twice
+                Some(udp_listeners) => {
+                    if !listeners.iter().any(|l| Arc::ptr_eq(l, &udp_listeners)) {
+                        listeners.push(udp_listeners);
+                    }
+                }
+                None => {
+                    let msg = format!("{} does not exist in stack", local_ip);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+                }
             }
         }
-        port
+        let locked = listeners.iter().map(|l| l.lock().unwrap()).collect::<Vec<_>>();
+        self.port_allocator.allocate(local_ips, dst, |port| {
+            locked.iter().any(|udp_listeners| udp_listeners.contains_key(&port))
+        })
+    }
+
+    /// Removes all listeners on `addr`, freeing the port. Returns true if
+    /// there were any.
+    pub fn udp_unlisten_in(&mut self, domain: &str, addr: SocketAddrV4) -> bool {
+        match self.find_interface(domain, *addr.ip()) {
+            Some(stack_interface) => {
+                let ip_data = &stack_interface.ipv4s[addr.ip()];
+                ip_data.udp_listeners.lock().unwrap().remove(&addr.port()).is_some()
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the listener with `id` on `addr`, as returned by
+    /// `udp_listen_shared_in`. The port is freed when its last listener is
+    /// removed. Returns true if the listener was there.
+    pub fn udp_unlisten_id_in(&mut self,
+                              domain: &str,
+                              addr: SocketAddrV4,
+                              id: udp::UdpListenerId)
+                              -> bool {
+        match self.find_interface(domain, *addr.ip()) {
+            Some(stack_interface) => {
+                let ip_data = &stack_interface.ipv4s[addr.ip()];
+                let mut udp_listeners = ip_data.udp_listeners.lock().unwrap();
+                let (removed, unbound) = match udp_listeners.get_mut(&addr.port()) {
+                    Some(udp_port) => (udp_port.remove(id), udp_port.is_empty()),
+                    None => (false, false),
+                };
+                if unbound {
+                    udp_listeners.remove(&addr.port());
+                }
+                removed
+            }
+            None => false,
+        }
+    }
+
+    /// Makes the interface in `domain` that has `local_ip` accept packets
+    /// sent to the multicast `group`. See `StackInterface::join_ipv4_multicast`.
+    pub fn join_ipv4_multicast_in(&mut self,
+                                  domain: &str,
+                                  group: Ipv4Addr,
+                                  local_ip: Ipv4Addr)
+                                  -> StackResult<()> {
+        match self.find_interface_mut(domain, local_ip) {
+            Some(stack_interface) => stack_interface.join_ipv4_multicast(group, local_ip),
+            None => Err(StackError::IllegalArgument),
+        }
+    }
+
+    /// Leaves a multicast group joined with `join_ipv4_multicast_in`.
+    pub fn leave_ipv4_multicast_in(&mut self,
+                                   domain: &str,
+                                   group: Ipv4Addr,
+                                   local_ip: Ipv4Addr)
+                                   -> StackResult<()> {
+        match self.find_interface_mut(domain, local_ip) {
+            Some(stack_interface) => stack_interface.leave_ipv4_multicast(group, local_ip),
+            None => Err(StackError::IllegalArgument),
+        }
+    }
+
+    /// Returns the interface in `domain` that has the local address `ip`.
+    pub fn interface_with_ipv4_in(&self, domain: &str, ip: Ipv4Addr) -> Option<Interface> {
+        self.find_interface(domain, ip).map(|stack_interface| stack_interface.interface.clone())
+    }
+
+    /// Returns true if `ip` is a broadcast address in `domain`. The limited
+    /// broadcast address, or the directed broadcast address of a network on
+    /// one of its interfaces.
+    pub fn is_broadcast_in(&self, domain: &str, ip: Ipv4Addr) -> bool {
+        ip.is_broadcast() ||
+        self.interfaces
+            .values()
+            .any(|stack_interface| {
+                stack_interface.domain == domain && stack_interface.is_broadcast(ip)
+            })
+    }
+
+    /// Finds the interface in `domain` that has `ip` configured.
+    fn find_interface(&self, domain: &str, ip: Ipv4Addr) -> Option<&StackInterface> {
+        self.interfaces
+            .values()
+            .find(|stack_interface| {
+                stack_interface.domain == domain && stack_interface.has_ipv4(ip)
+            })
+    }
+
+    fn find_interface_mut(&mut self, domain: &str, ip: Ipv4Addr) -> Option<&mut StackInterface> {
+        self.interfaces
+            .values_mut()
+            .find(|stack_interface| {
+                stack_interface.domain == domain && stack_interface.has_ipv4(ip)
+            })
+    }
+
+    fn udp_listeners_in(&self,
+                        domain: &str,
+                        ip: Ipv4Addr)
+                        -> Option<Arc<Mutex<udp::UdpListenerLookup>>> {
+        self.find_interface(domain, ip)
+            .map(|stack_interface| stack_interface.ipv4s[&ip].udp_listeners.clone())
     }
 }
 
@@ -21,6 +21,7 @@ impl Ipv4Listener for MockIpv4Listener {
     }
 }
 
+#[derive(Clone)]
 pub struct Ipv4Tx {
     chan: mpsc::Sender<(IpNextHeaderProtocol, Box<[u8]>)>,
 }
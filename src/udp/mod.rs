@@ -1,27 +1,53 @@
 #[cfg(not(feature = "unit-tests"))]
-use {NetworkStack, StackError, StackResult};
+use {DEFAULT_DOMAIN, Flow, Interface, NetworkStack, Poll, StackError, StackResult, Wake};
 use {TxError, TxResult};
 
+#[cfg(not(feature = "unit-tests"))]
+use pnet::packet::ip::IpNextHeaderProtocols;
+
 use std::collections::HashMap;
 use std::io;
-use std::net::{SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
 use std::sync::{Arc, Mutex};
+#[cfg(not(feature = "unit-tests"))]
+use std::time::Duration;
 
 use util;
 
+mod port_allocator;
 mod udp_rx;
 mod udp_tx;
 
-pub use self::udp_rx::{UdpListener, UdpListenerLookup, UdpRx};
+pub use self::port_allocator::{PortAllocator, PortAllocatorSettings, PortSelection};
+pub use self::udp_rx::{DEFAULT_RECV_BUFFER_SIZE, PortSharing, UdpError, UdpErrorRx, UdpListener,
+                       UdpListenerId, UdpListenerLookup, UdpPort, UdpRecvMsg, UdpRx};
 use self::udp_rx::UdpSocketReader;
-pub use self::udp_tx::{UdpBuilder, UdpTx};
+pub use self::udp_tx::{UdpBuilder, UdpSendMsg, UdpTx};
 
 #[cfg(not(feature = "unit-tests"))]
 pub struct UdpSocket {
     socket_addr: SocketAddr,
+    domain: String,
+    interface: Interface,
     stack: Arc<Mutex<NetworkStack>>,
+    binding: Arc<Binding>,
     tx_cache: HashMap<SocketAddrV4, UdpTx>,
-    rx: Option<UdpSocketReader>,
+    rx: UdpSocketReader,
+    error: Arc<Mutex<Option<UdpError>>>,
+    peer: Arc<Mutex<Option<SocketAddrV4>>>,
+    mark: u32,
+    checksum: bool,
+    broadcast: bool,
+    timeouts: Arc<Mutex<Timeouts>>,
+}
+
+/// The timeouts and blocking mode of a `UdpSocket`, shared by its clones.
+#[cfg(not(feature = "unit-tests"))]
+#[derive(Default)]
+struct Timeouts {
+    read: Option<Duration>,
+    write: Option<Duration>,
+    nonblocking: bool,
 }
 
 #[cfg(not(feature = "unit-tests"))]
@@ -29,29 +55,110 @@ impl UdpSocket {
     pub fn bind<A: ToSocketAddrs>(stack: Arc<Mutex<NetworkStack>>,
                                   addr: A)
                                   -> io::Result<UdpSocket> {
-        let mut socket_reader = UdpSocketReader::new();
-        let socket_addr = {
+        Self::bind_in(stack, DEFAULT_DOMAIN, addr)
+    }
+
+    /// Creates a socket bound to `addr` in the given routing domain. Both
+    /// the bound address and all sends are scoped to that domain.
+    pub fn bind_in<A: ToSocketAddrs>(stack: Arc<Mutex<NetworkStack>>,
+                                     domain: &str,
+                                     addr: A)
+                                     -> io::Result<UdpSocket> {
+        Self::bind_shared_in(stack, domain, addr, PortSharing::Exclusive)
+    }
+
+    /// Creates a socket bound to `addr` that shares the port with the other
+    /// sockets bound to it with the same `sharing` mode. Like setting
+    /// `SO_REUSEADDR` or `SO_REUSEPORT` before binding.
+    pub fn bind_shared<A: ToSocketAddrs>(stack: Arc<Mutex<NetworkStack>>,
+                                         addr: A,
+                                         sharing: PortSharing)
+                                         -> io::Result<UdpSocket> {
+        Self::bind_shared_in(stack, DEFAULT_DOMAIN, addr, sharing)
+    }
+
+    /// Same as `bind_shared` but in the given routing domain.
+    pub fn bind_shared_in<A: ToSocketAddrs>(stack: Arc<Mutex<NetworkStack>>,
+                                            domain: &str,
+                                            addr: A,
+                                            sharing: PortSharing)
+                                            -> io::Result<UdpSocket> {
+        let error = Arc::new(Mutex::new(None));
+        let peer = Arc::new(Mutex::new(None));
+        let socket_reader = UdpSocketReader::new(error.clone(), peer.clone());
+        let (addr, listener_id, interface) = {
             let mut stack = stack.lock().unwrap();
-            try!(stack.udp_listen(addr, socket_reader.listener()))
+            let (socket_addr, listener_id) =
+                try!(stack.udp_listen_shared_in(domain, addr, sharing, socket_reader.listener()));
+            let addr = match socket_addr {
+                SocketAddr::V4(addr) => addr,
+                SocketAddr::V6(_) => unreachable!(),
+            };
+            let interface = stack.interface_with_ipv4_in(domain, *addr.ip()).unwrap();
+            (addr, listener_id, interface)
+        };
+        let binding = Binding {
+            stack: stack.clone(),
+            domain: domain.to_owned(),
+            addr: addr,
+            listener_id: listener_id,
         };
         Ok(UdpSocket {
-            socket_addr: socket_addr,
+            socket_addr: SocketAddr::V4(addr),
+            domain: domain.to_owned(),
+            interface: interface,
             stack: stack,
+            binding: Arc::new(binding),
             tx_cache: HashMap::new(),
-            rx: Some(socket_reader),
+            rx: socket_reader,
+            error: error,
+            peer: peer,
+            mark: 0,
+            checksum: true,
+            broadcast: false,
+            timeouts: Arc::new(Mutex::new(Timeouts::default())),
         })
     }
 
+    /// Reads a datagram into `buf`. Returns the number of bytes read and
+    /// where it came from. Like for the standard `UdpSocket`, a datagram not
+    /// fitting `buf` is truncated and the rest of it is lost. Use `recv_msg`
+    /// to learn its full length.
     pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        self.rx.as_ref().unwrap().recv_from(buf)
+        self.rx.recv_from(&mut [buf], self.recv_timeout(), false)
+    }
+
+    /// Same as `recv_from` but leaves the datagram in the receive queue, so
+    /// the next read returns it again.
+    pub fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.rx.recv_from(&mut [buf], self.recv_timeout(), true)
+    }
+
+    /// Same as `recv_from` but fills the buffers in `bufs` in order.
+    pub fn recv_from_vectored(&self, bufs: &mut [&mut [u8]]) -> io::Result<(usize, SocketAddr)> {
+        self.rx.recv_from(bufs, self.recv_timeout(), false)
+    }
+
+    /// Same as `recv_from` but also tells when, to what address and on what
+    /// interface the datagram arrived, and its TTL and type of service.
+    pub fn recv_msg(&self, buf: &mut [u8]) -> io::Result<UdpRecvMsg> {
+        self.rx.recv_msg(&mut [buf], self.recv_timeout(), false, &self.interface)
     }
 
     pub fn send_to<A: ToSocketAddrs>(&mut self, buf: &[u8], addr: A) -> io::Result<usize> {
+        self.send_to_vectored(&[buf], addr)
+    }
+
+    /// Sends one datagram with the concatenation of the buffers in `bufs` as
+    /// payload, without copying them together first.
+    pub fn send_to_vectored<A: ToSocketAddrs>(&mut self,
+                                              bufs: &[&[u8]],
+                                              addr: A)
+                                              -> io::Result<usize> {
         match try!(util::first_socket_addr(addr)) {
             SocketAddr::V4(dst) => {
-                self.internal_send(buf, dst)
-                    .map(|_| buf.len())
-                    .map_err(|e| e.into())
+                let arp_timeout = self.arp_timeout();
+                self.internal_send(bufs, dst, arp_timeout).map_err(|e| e.into())
             }
             SocketAddr::V6(_dst) => {
                 Err(io::Error::new(io::ErrorKind::InvalidInput,
@@ -60,43 +167,461 @@ impl UdpSocket {
         }
     }
 
+    /// Same as `recv_from` but never blocks, for driving the socket from an
+    /// async runtime without a thread per socket. When there is no datagram
+    /// to read it returns `Poll::Pending` and `waker` is woken when one
+    /// arrives. Ignores the read timeout and nonblocking mode.
+    pub fn poll_recv_from(&self,
+                          buf: &mut [u8],
+                          waker: Arc<Wake>)
+                          -> Poll<io::Result<(usize, SocketAddr)>> {
+        match self.rx.recv_from(&mut [buf], Some(Duration::new(0, 0)), false) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.rx.wake_when_readable(waker);
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    /// Same as `send_to` but never blocks. When the next hop to `addr` is
+    /// not resolved yet it sends the Arp request and returns
+    /// `Poll::Pending`, and `waker` is woken when the reply arrives. Poll
+    /// again with the same datagram then. Ignores the write timeout and
+    /// nonblocking mode.
+    pub fn poll_send_to<A: ToSocketAddrs>(&mut self,
+                                          buf: &[u8],
+                                          addr: A,
+                                          waker: Arc<Wake>)
+                                          -> Poll<io::Result<usize>> {
+        let dst = match util::first_socket_addr(addr) {
+            Ok(SocketAddr::V4(dst)) => dst,
+            Ok(SocketAddr::V6(_dst)) => {
+                let msg = "Rips does not support IPv6 yet".to_owned();
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+            }
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        match self.internal_send(&[buf], dst, Some(Duration::new(0, 0))) {
+            Err(StackError::WouldBlock) => {
+                let flow = self.flow();
+                let stack = self.stack.lock().unwrap();
+                match stack.wake_when_routable_in(&self.domain, *dst.ip(), &flow, waker) {
+                    Ok(()) => Poll::Pending,
+                    Err(e) => Poll::Ready(Err(e.into())),
+                }
+            }
+            result => Poll::Ready(result.map_err(|e| e.into())),
+        }
+    }
+
+    /// Same as `send_to` but with the source address and outgoing interface
+    /// chosen by `msg`. The source must be a local address in the domain of
+    /// the socket. Does not cache the route like `send_to`, so it costs a
+    /// lookup for every datagram sent with any option set.
+    pub fn send_msg<A: ToSocketAddrs>(&mut self,
+                                      buf: &[u8],
+                                      addr: A,
+                                      msg: &UdpSendMsg)
+                                      -> io::Result<usize> {
+        if msg.src.is_none() && msg.interface.is_none() {
+            return self.send_to(buf, addr);
+        }
+        let dst = match try!(util::first_socket_addr(addr)) {
+            SocketAddr::V4(dst) => dst,
+            SocketAddr::V6(_dst) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "Rips does not support IPv6 yet".to_owned()));
+            }
+        };
+        if buf.len() > ::std::u16::MAX as usize {
+            return Err(TxError::TooLargePayload.into());
+        }
+        let mut flow = self.flow();
+        if let Some(src) = msg.src {
+            let stack = self.stack.lock().unwrap();
+            if stack.interface_with_ipv4_in(&self.domain, src).is_none() {
+                let msg = format!("{} is not a local address", src);
+                return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, msg));
+            }
+            flow.src = Some(src);
+        }
+        flow.oif = msg.interface.clone();
+        let arp_timeout = self.arp_timeout();
+        let mut udp_tx = try!(self.udp_tx(dst, &flow, arp_timeout));
+        udp_tx.send(buf).map(|_| buf.len()).map_err(|e| e.into())
+    }
+
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         Ok(self.socket_addr)
     }
 
+    /// Connects this socket to `addr`. `send` and `recv` then only talk to
+    /// that peer, datagrams from other sources are dropped, and Icmp errors
+    /// about datagrams to the peer fail the next `send` or `recv`. The route
+    /// and next hop to the peer are resolved here. Connecting again changes
+    /// the peer. Shared with clones of the socket.
+    pub fn connect<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        match try!(util::first_socket_addr(addr)) {
+            SocketAddr::V4(dst) => {
+                if !self.tx_cache.contains_key(&dst) {
+                    let arp_timeout = self.arp_timeout();
+                    try!(self.new_udp_tx(dst, arp_timeout));
+                }
+                *self.peer.lock().unwrap() = Some(dst);
+                *self.error.lock().unwrap() = None;
+                Ok(())
+            }
+            SocketAddr::V6(_dst) => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "Rips does not support IPv6 yet".to_owned()))
+            }
+        }
+    }
+
+    /// Returns the address this socket is connected to.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match *self.peer.lock().unwrap() {
+            Some(peer) => Ok(SocketAddr::V4(peer)),
+            None => Err(not_connected()),
+        }
+    }
+
+    /// Sends `buf` to the connected peer.
+    pub fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let peer = try!(self.connected_peer());
+        let arp_timeout = self.arp_timeout();
+        self.internal_send(&[buf], peer, arp_timeout).map_err(|e| e.into())
+    }
+
+    /// Reads a datagram from the connected peer.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.connected_peer());
+        self.recv_from(buf).map(|(len, _)| len)
+    }
+
+    /// Same as `recv` but leaves the datagram in the receive queue.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.connected_peer());
+        self.peek_from(buf).map(|(len, _)| len)
+    }
+
+    /// Returns the name of the routing domain this socket is bound in.
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// Joins the multicast group `multiaddr` on the interface with the IP
+    /// `interface`. Datagrams sent to the group are delivered to the sockets
+    /// bound to that IP, so `interface` must be the address this socket is
+    /// bound to, or unspecified to use the bound address.
+    pub fn join_multicast_v4(&self, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+        let local_ip = try!(self.multicast_interface(interface));
+        let mut stack = self.stack.lock().unwrap();
+        stack.join_ipv4_multicast_in(&self.domain, *multiaddr, local_ip).map_err(|e| e.into())
+    }
+
+    /// Leaves a multicast group joined with `join_multicast_v4`.
+    pub fn leave_multicast_v4(&self,
+                              multiaddr: &Ipv4Addr,
+                              interface: &Ipv4Addr)
+                              -> io::Result<()> {
+        let local_ip = try!(self.multicast_interface(interface));
+        let mut stack = self.stack.lock().unwrap();
+        stack.leave_ipv4_multicast_in(&self.domain, *multiaddr, local_ip).map_err(|e| e.into())
+    }
+
+    /// Returns and clears the latest Icmp error caused by a datagram sent
+    /// from the port of this socket, such as `ConnectionRefused` when the
+    /// destination port was unreachable. Shared with clones of the socket.
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        Ok(self.error.lock().unwrap().take().map(io::Error::from))
+    }
+
+    /// Creates another handle to this socket. The handles share the receive
+    /// queue, so every datagram is read by only one of them, and the socket
+    /// stays bound until all of them are dropped.
     pub fn try_clone(&self) -> io::Result<UdpSocket> {
         Ok(UdpSocket {
             socket_addr: self.socket_addr,
+            domain: self.domain.clone(),
+            interface: self.interface.clone(),
             stack: self.stack.clone(),
+            binding: self.binding.clone(),
             tx_cache: HashMap::new(),
-            rx: None,
+            rx: self.rx.clone(),
+            error: self.error.clone(),
+            peer: self.peer.clone(),
+            mark: self.mark,
+            checksum: self.checksum,
+            broadcast: self.broadcast,
+            timeouts: self.timeouts.clone(),
         })
     }
 
-    fn internal_send(&mut self, buf: &[u8], dst: SocketAddrV4) -> StackResult<()> {
-        match self.internal_send_on_cached_tx(buf, dst) {
+    /// Sets the firewall mark of the traffic sent from this socket. Used by
+    /// the routing rules to select routing table, like `SO_MARK` on Linux.
+    pub fn set_mark(&mut self, mark: u32) {
+        if mark != self.mark {
+            self.mark = mark;
+            self.tx_cache.clear();
+        }
+    }
+
+    /// Returns the firewall mark of this socket.
+    pub fn mark(&self) -> u32 {
+        self.mark
+    }
+
+    /// Sets how long `recv_from` waits for a datagram before failing with
+    /// `TimedOut`. `None` waits forever. A zero duration is an error, like
+    /// for the standard `UdpSocket`. Shared with clones of the socket.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        try!(check_timeout(timeout));
+        self.timeouts.lock().unwrap().read = timeout;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.timeouts.lock().unwrap().read)
+    }
+
+    /// Sets how long `send_to` waits for the Arp reply when the next hop is
+    /// not resolved yet, before failing with `TimedOut`. `None` waits
+    /// forever. A zero duration is an error. Shared with clones of the
+    /// socket.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        try!(check_timeout(timeout));
+        self.timeouts.lock().unwrap().write = timeout;
+        Ok(())
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.timeouts.lock().unwrap().write)
+    }
+
+    /// Moves this socket in or out of nonblocking mode. In nonblocking mode
+    /// `recv_from` fails with `WouldBlock` when there is no datagram to
+    /// read, and `send_to` fails with `WouldBlock` after sending the Arp
+    /// request when the next hop is not resolved yet. Try again later.
+    /// Shared with clones of the socket, like the timeouts.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.timeouts.lock().unwrap().nonblocking = nonblocking;
+        Ok(())
+    }
+
+    /// Sets if this socket may send to broadcast addresses, like
+    /// `SO_BROADCAST`. Disabled by default, sending to the limited broadcast
+    /// address 255.255.255.255 or the directed broadcast address of a local
+    /// network then fails with `PermissionDenied`, like for the standard
+    /// `UdpSocket`.
+    pub fn set_broadcast(&mut self, broadcast: bool) -> io::Result<()> {
+        if broadcast != self.broadcast {
+            self.broadcast = broadcast;
+            self.tx_cache.clear();
+        }
+        Ok(())
+    }
+
+    pub fn broadcast(&self) -> io::Result<bool> {
+        Ok(self.broadcast)
+    }
+
+    /// Sets how many bytes of received datagrams, IP headers included, are
+    /// queued waiting to be read, like `SO_RCVBUF`. Datagrams arriving when
+    /// the queue is full are dropped and counted by `dropped_datagrams`.
+    /// Defaults to `DEFAULT_RECV_BUFFER_SIZE`. Shared with clones of the
+    /// socket.
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.rx.set_buffer_size(size);
+        Ok(())
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        Ok(self.rx.buffer_size())
+    }
+
+    /// Returns how many datagrams to this socket were dropped because its
+    /// receive queue was full.
+    pub fn dropped_datagrams(&self) -> usize {
+        self.rx.dropped()
+    }
+
+    /// Sets if datagrams sent from this socket carry a checksum. Enabled by
+    /// default. Disabling it sends a zero checksum, saving the work of
+    /// computing it for high rate traffic such as tunnels whose payload is
+    /// already protected. Received datagrams are verified either way.
+    pub fn set_checksum(&mut self, checksum: bool) {
+        if checksum != self.checksum {
+            self.checksum = checksum;
+            self.tx_cache.clear();
+        }
+    }
+
+    /// Returns true if datagrams sent from this socket carry a checksum.
+    pub fn checksum(&self) -> bool {
+        self.checksum
+    }
+
+    /// Sends the concatenation of `bufs` to `dst`. Returns the number of
+    /// bytes sent. See `udp_tx` for `arp_timeout`.
+    fn internal_send(&mut self,
+                     bufs: &[&[u8]],
+                     dst: SocketAddrV4,
+                     arp_timeout: Option<Duration>)
+                     -> StackResult<usize> {
+        match self.internal_send_on_cached_tx(bufs, dst) {
             Err(TxError::InvalidTx) => {
-                let (dst_ip, dst_port) = (*dst.ip(), dst.port());
-                let new_udp_tx = {
-                    let mut stack = self.stack.lock().unwrap();
-                    try!(stack.udp_tx(dst_ip, self.socket_addr.port(), dst_port))
-                };
-                self.tx_cache.insert(dst, new_udp_tx);
-                self.internal_send(buf, dst)
+                try!(self.new_udp_tx(dst, arp_timeout));
+                self.internal_send(bufs, dst, arp_timeout)
+            }
+            result => {
+                try!(result.map_err(StackError::TxError));
+                Ok(bufs.iter().map(|buf| buf.len()).sum())
             }
-            result => result.map_err(StackError::TxError),
         }
     }
 
-    fn internal_send_on_cached_tx(&mut self, buf: &[u8], dst: SocketAddrV4) -> TxResult {
-        if buf.len() > ::std::u16::MAX as usize {
+    /// Creates and caches the `UdpTx` used for sending to `dst`.
+    fn new_udp_tx(&mut self, dst: SocketAddrV4, arp_timeout: Option<Duration>) -> StackResult<()> {
+        let flow = self.flow();
+        let udp_tx = try!(self.udp_tx(dst, &flow, arp_timeout));
+        self.tx_cache.insert(dst, udp_tx);
+        Ok(())
+    }
+
+    /// Creates an `UdpTx` sending the traffic described by `flow` to `dst`.
+    /// Waits at most `arp_timeout` for the next hop to be resolved, see
+    /// `NetworkStack::ipv4_tx_in_timeout`.
+    fn udp_tx(&self,
+              dst: SocketAddrV4,
+              flow: &Flow,
+              arp_timeout: Option<Duration>)
+              -> StackResult<UdpTx> {
+        let mut udp_tx = {
+            let mut stack = self.stack.lock().unwrap();
+            if !self.broadcast && stack.is_broadcast_in(&self.domain, *dst.ip()) {
+                let msg = format!("Sending to broadcast address {} is not enabled", dst.ip());
+                let error = io::Error::new(io::ErrorKind::PermissionDenied, msg);
+                return Err(StackError::IoError(error));
+            }
+            try!(stack.udp_tx_in_timeout(&self.domain,
+                                         *dst.ip(),
+                                         self.socket_addr.port(),
+                                         dst.port(),
+                                         flow,
+                                         arp_timeout))
+        };
+        udp_tx.set_checksum(self.checksum);
+        Ok(udp_tx)
+    }
+
+    /// Returns how long sends wait for the next hop to be resolved.
+    fn arp_timeout(&self) -> Option<Duration> {
+        let timeouts = self.timeouts.lock().unwrap();
+        if timeouts.nonblocking {
+            Some(Duration::new(0, 0))
+        } else {
+            timeouts.write
+        }
+    }
+
+    /// Returns how long `recv_from` waits for a datagram.
+    fn recv_timeout(&self) -> Option<Duration> {
+        let timeouts = self.timeouts.lock().unwrap();
+        if timeouts.nonblocking {
+            Some(Duration::new(0, 0))
+        } else {
+            timeouts.read
+        }
+    }
+
+    /// Returns the peer of a connected socket, or the pending Icmp error
+    /// about datagrams sent to it.
+    fn connected_peer(&self) -> io::Result<SocketAddrV4> {
+        match *self.peer.lock().unwrap() {
+            Some(peer) => {
+                match self.error.lock().unwrap().take() {
+                    Some(error) => Err(error.into()),
+                    None => Ok(peer),
+                }
+            }
+            None => Err(not_connected()),
+        }
+    }
+
+    fn multicast_interface(&self, interface: &Ipv4Addr) -> io::Result<Ipv4Addr> {
+        match self.socket_addr {
+            SocketAddr::V4(addr) => {
+                if *interface == Ipv4Addr::new(0, 0, 0, 0) || interface == addr.ip() {
+                    Ok(*addr.ip())
+                } else {
+                    let msg = "Multicast interface must be the bound address".to_owned();
+                    Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+                }
+            }
+            SocketAddr::V6(_) => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "Rips does not support IPv6 yet".to_owned()))
+            }
+        }
+    }
+
+    fn flow(&self) -> Flow {
+        let src = match self.socket_addr {
+            SocketAddr::V4(addr) => Some(*addr.ip()),
+            SocketAddr::V6(_) => None,
+        };
+        let mut flow = Flow::new(src, IpNextHeaderProtocols::Udp);
+        flow.mark = self.mark;
+        flow
+    }
+
+    fn internal_send_on_cached_tx(&mut self, bufs: &[&[u8]], dst: SocketAddrV4) -> TxResult {
+        if bufs.iter().map(|buf| buf.len()).sum::<usize>() > ::std::u16::MAX as usize {
             return Err(TxError::TooLargePayload);
         }
         if let Some(udp_tx) = self.tx_cache.get_mut(&dst) {
-            udp_tx.send(buf)
+            udp_tx.send_vectored(bufs)
         } else {
             // No cached UdpTx is treated as an existing but outdated one
             Err(TxError::InvalidTx)
         }
     }
 }
+
+/// The listener of a socket in the stack. Shared by the socket and its
+/// clones, and unbinds the socket when the last of them is dropped. That
+/// releases the port unless it is shared with other sockets.
+#[cfg(not(feature = "unit-tests"))]
+struct Binding {
+    stack: Arc<Mutex<NetworkStack>>,
+    domain: String,
+    addr: SocketAddrV4,
+    listener_id: UdpListenerId,
+}
+
+#[cfg(not(feature = "unit-tests"))]
+impl Drop for Binding {
+    fn drop(&mut self) {
+        if let Ok(mut stack) = self.stack.lock() {
+            stack.udp_unlisten_id_in(&self.domain, self.addr, self.listener_id);
+        }
+    }
+}
+
+#[cfg(not(feature = "unit-tests"))]
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "Socket is not connected".to_owned())
+}
+
+/// Rejects zero timeouts, like the standard sockets do.
+#[cfg(not(feature = "unit-tests"))]
+fn check_timeout(timeout: Option<Duration>) -> io::Result<()> {
+    if timeout == Some(Duration::new(0, 0)) {
+        Err(io::Error::new(io::ErrorKind::InvalidInput,
+                           "Cannot set a zero duration timeout".to_owned()))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+use rand;
+use rand::distributions::{IndependentSample, Range};
+
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// How `PortAllocator` picks among the free ports in its range. Both are
+/// from RFC 6056 and make the ports hard to guess for off path attackers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortSelection {
+    /// Algorithm 1. Starts at a random port and takes the first free one
+    /// after it.
+    Random,
+    /// Algorithm 3. Starts at an offset given by a keyed hash of the local
+    /// addresses and the destination, plus a counter stepped for every port
+    /// tried. Different destinations see unrelated sequences of ports, while
+    /// the same destination does not get a port back until the range has
+    /// been cycled through. Only used when the destination is known, such
+    /// as for `NetworkStack::udp_allocate_port_in` with a `dst`. Otherwise
+    /// every allocation would hash the same and hand out consecutive ports,
+    /// so `Random` is used instead, for example when binding port zero.
+    HashBased,
+}
+
+/// Settings for how a stack picks local ports for sockets bound to port
+/// zero.
+#[derive(Clone, Debug)]
+pub struct PortAllocatorSettings {
+    /// First port of the range, inclusive. 32768 by default, like Linux.
+    pub first_port: u16,
+
+    /// Last port of the range, inclusive. 60999 by default, like Linux.
+    pub last_port: u16,
+
+    /// The algorithm picking the port. `Random` by default.
+    pub selection: PortSelection,
+
+    /// Ports in the range never handed out, like `ip_local_reserved_ports`
+    /// on Linux. They can still be bound explicitly.
+    pub reserved: HashSet<u16>,
+}
+
+impl Default for PortAllocatorSettings {
+    fn default() -> Self {
+        PortAllocatorSettings {
+            first_port: 32768,
+            last_port: 60999,
+            selection: PortSelection::Random,
+            reserved: HashSet::new(),
+        }
+    }
+}
+
+/// Picks ephemeral ports according to `PortAllocatorSettings`.
+pub struct PortAllocator {
+    settings: PortAllocatorSettings,
+    key: u64,
+    next_ephemeral: u64,
+}
+
+impl PortAllocator {
+    /// Creates an allocator with `settings`, that must be valid as defined
+    /// by `set_settings`.
+    pub fn new(settings: PortAllocatorSettings) -> PortAllocator {
+        PortAllocator {
+            settings: settings,
+            key: rand::random(),
+            next_ephemeral: 0,
+        }
+    }
+
+    pub fn settings(&self) -> &PortAllocatorSettings {
+        &self.settings
+    }
+
+    /// Replaces the settings. The range must be non empty and not contain
+    /// port zero.
+    pub fn set_settings(&mut self, settings: PortAllocatorSettings) -> io::Result<()> {
+        if settings.first_port == 0 || settings.first_port > settings.last_port {
+            let msg = format!("Invalid local port range {}-{}",
+                              settings.first_port,
+                              settings.last_port);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        self.settings = settings;
+        Ok(())
+    }
+
+    /// Returns a port in the range that is not reserved and for which
+    /// `in_use` returns false. `local_ips` are the addresses it will be
+    /// bound on and `dst` where the traffic goes, if known. Both only feed
+    /// the hash of `PortSelection::HashBased`, which falls back to `Random`
+    /// without a `dst`. Fails with `AddrInUse` when every port is taken.
+    pub fn allocate<F>(&mut self,
+                       local_ips: &[Ipv4Addr],
+                       dst: Option<SocketAddrV4>,
+                       in_use: F)
+                       -> io::Result<u16>
+        where F: Fn(u16) -> bool
+    {
+        let first_port = self.settings.first_port;
+        let num_ephemeral = (self.settings.last_port - first_port) as u64 + 1;
+        let hash_based = self.settings.selection == PortSelection::HashBased && dst.is_some();
+        let (offset, next_ephemeral) = if hash_based {
+            (self.hash(local_ips, dst), self.next_ephemeral)
+        } else {
+            (Range::new(0, num_ephemeral).ind_sample(&mut rand::thread_rng()), 0)
+        };
+        for step in 0..num_ephemeral {
+            let next_ephemeral = next_ephemeral.wrapping_add(step);
+            let port = first_port + (offset.wrapping_add(next_ephemeral) % num_ephemeral) as u16;
+            if !self.settings.reserved.contains(&port) && !in_use(port) {
+                if hash_based {
+                    self.next_ephemeral = next_ephemeral.wrapping_add(1);
+                }
+                return Ok(port);
+            }
+        }
+        let msg = format!("No free local port in {}-{}", first_port, self.settings.last_port);
+        Err(io::Error::new(io::ErrorKind::AddrInUse, msg))
+    }
+
+    fn hash(&self, local_ips: &[Ipv4Addr], dst: Option<SocketAddrV4>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.key.hash(&mut hasher);
+        local_ips.hash(&mut hasher);
+        dst.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Default for PortAllocator {
+    fn default() -> Self {
+        Self::new(PortAllocatorSettings::default())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+
+    fn settings(first_port: u16,
+                last_port: u16,
+                selection: PortSelection)
+                -> PortAllocatorSettings {
+        PortAllocatorSettings {
+            first_port: first_port,
+            last_port: last_port,
+            selection: selection,
+            ..PortAllocatorSettings::default()
+        }
+    }
+
+    #[test]
+    fn exhausted() {
+        let local_ips = [Ipv4Addr::new(10, 0, 0, 1)];
+        let mut settings = settings(1000, 1003, PortSelection::Random);
+        settings.reserved.insert(1001);
+        let mut allocator = PortAllocator::new(settings);
+        for _ in 0..10 {
+            let port = allocator.allocate(&local_ips, None, |port| port == 1000).unwrap();
+            assert!(port == 1002 || port == 1003);
+        }
+        let error = allocator.allocate(&local_ips, None, |port| port != 1001).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn hash_based() {
+        let local_ips = [Ipv4Addr::new(10, 0, 0, 1)];
+        let dst = Some(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 53));
+        let mut allocator = PortAllocator::new(settings(1000, 1999, PortSelection::HashBased));
+        let first = allocator.allocate(&local_ips, dst, |_| false).unwrap();
+        // The same destination steps through the range from there
+        let second = allocator.allocate(&local_ips, dst, |_| false).unwrap();
+        assert_eq!(second, if first == 1999 { 1000 } else { first + 1 });
+        // Skipping taken ports steps the counter too
+        let third = allocator.allocate(&local_ips, dst, |port| port == second + 1).unwrap();
+        assert_ne!(third, second + 1);
+        assert!(third >= 1000 && third <= 1999);
+    }
+
+    #[test]
+    fn hash_based_without_dst() {
+        let local_ips = [Ipv4Addr::new(10, 0, 0, 1)];
+        let mut allocator = PortAllocator::new(settings(1000, 60999, PortSelection::HashBased));
+        let ports = (0..10)
+            .map(|_| allocator.allocate(&local_ips, None, |_| false).unwrap())
+            .collect::<Vec<_>>();
+        // Random, not counting up from the hash of the local address
+        let sequential = ports.windows(2).filter(|pair| pair[1] == pair[0] + 1).count();
+        assert!(sequential < 9, "Sequential ports {:?}", ports);
+    }
+
+    #[test]
+    fn invalid_range() {
+        let mut allocator = PortAllocator::default();
+        assert!(allocator.set_settings(settings(0, 10, PortSelection::Random)).is_err());
+        assert!(allocator.set_settings(settings(20, 10, PortSelection::Random)).is_err());
+        assert!(allocator.set_settings(settings(10, 10, PortSelection::Random)).is_ok());
+        assert_eq!(allocator.settings().first_port, 10);
+    }
+}
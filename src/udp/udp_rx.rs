@@ -1,29 +1,240 @@
-use {RxError, RxResult};
+use {Interface, RxError, RxResult, Wake};
+use icmp::{IcmpErrorTx, IcmpListener, IcmpMessage};
 use ipv4::Ipv4Listener;
+use poll::WakerSet;
 
 use pnet::packet::Packet;
+use pnet::packet::icmp::{IcmpCode, IcmpType, IcmpTypes};
+use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::Ipv4Packet;
-use pnet::packet::udp::UdpPacket;
+use pnet::packet::udp::{UdpPacket, ipv4_checksum};
 
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::io;
-use std::net::{SocketAddr, SocketAddrV4};
-use std::sync::{Arc, Mutex, mpsc};
-use std::time::SystemTime;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 pub trait UdpListener: Send {
+    /// Called by `UdpRx` with the datagrams to the port of this listener.
+    /// The returned bool tells if the listener wants more datagrams. When it
+    /// is false the listener is removed, freeing the port if it was the
+    /// last one on it.
     fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet) -> (RxResult, bool);
+
+    /// Called with the Icmp errors caused by datagrams sent from the port of
+    /// this listener. Ignored by default.
+    fn recv_error(&mut self, _time: SystemTime, _error: UdpError) {}
 }
 
-pub type UdpListenerLookup = HashMap<u16, Box<UdpListener>>;
+/// An Icmp error received in response to a Udp datagram sent from a local
+/// port.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UdpError {
+    /// Where the datagram causing the error was sent.
+    pub dst: SocketAddrV4,
+    /// The host reporting the error.
+    pub from: Ipv4Addr,
+    pub icmp_type: IcmpType,
+    pub icmp_code: IcmpCode,
+}
 
+impl UdpError {
+    /// Returns the kind of `io::Error` this error is reported as.
+    pub fn kind(&self) -> io::ErrorKind {
+        if self.icmp_type == IcmpTypes::DestinationUnreachable &&
+           (self.icmp_code == IcmpCode(2) || self.icmp_code == IcmpCode(3)) {
+            io::ErrorKind::ConnectionRefused
+        } else {
+            io::ErrorKind::Other
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        if self.icmp_type == IcmpTypes::TimeExceeded {
+            "Time to live exceeded"
+        } else if self.icmp_code == IcmpCode(4) {
+            "Message too long, fragmentation needed"
+        } else if self.kind() == io::ErrorKind::ConnectionRefused {
+            "Connection refused"
+        } else {
+            "No route to host"
+        }
+    }
+}
+
+impl From<UdpError> for io::Error {
+    fn from(error: UdpError) -> io::Error {
+        let msg = format!("{} ({} reported by {})", error.description(), error.dst, error.from);
+        io::Error::new(error.kind(), msg)
+    }
+}
+
+/// How the listeners bound to the same local port share it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortSharing {
+    /// Only one listener can bind the port. The default.
+    Exclusive,
+    /// Every listener gets every datagram, like `SO_REUSEADDR` does for
+    /// multicast and broadcast receivers.
+    FanOut,
+    /// Each datagram goes to one of the listeners, picked by a hash of its
+    /// source address and port, like `SO_REUSEPORT`. Spreads a server over
+    /// several sockets while keeping every flow on the same one.
+    LoadBalance,
+}
+
+impl Default for PortSharing {
+    fn default() -> Self {
+        PortSharing::Exclusive
+    }
+}
+
+/// Identifies a listener among the ones bound to a port.
+pub type UdpListenerId = usize;
+
+/// The listeners bound to one local port, and how they share it.
+pub struct UdpPort {
+    sharing: PortSharing,
+    next_id: UdpListenerId,
+    listeners: Vec<(UdpListenerId, Box<UdpListener>)>,
+}
+
+impl UdpPort {
+    pub fn new(sharing: PortSharing) -> UdpPort {
+        UdpPort {
+            sharing: sharing,
+            next_id: 0,
+            listeners: vec![],
+        }
+    }
+
+    pub fn sharing(&self) -> PortSharing {
+        self.sharing
+    }
+
+    /// Adds `listener` to the port. Returns `None` if the port is taken, by
+    /// a listener not sharing it or sharing it in another mode.
+    pub fn add(&mut self,
+               sharing: PortSharing,
+               listener: Box<UdpListener>)
+               -> Option<UdpListenerId> {
+        if !self.listeners.is_empty() &&
+           (sharing != self.sharing || sharing == PortSharing::Exclusive) {
+            return None;
+        }
+        self.sharing = sharing;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.listeners.push((id, listener));
+        Some(id)
+    }
+
+    /// Removes the listener with `id`. Returns true if it was bound here.
+    pub fn remove(&mut self, id: UdpListenerId) -> bool {
+        let len = self.listeners.len();
+        self.listeners.retain(|&(listener_id, _)| listener_id != id);
+        self.listeners.len() != len
+    }
+
+    pub fn len(&self) -> usize {
+        self.listeners.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.listeners.is_empty()
+    }
+
+    /// Delivers `ip_pkg` to the listeners selected by the sharing mode and
+    /// removes the ones that want no more datagrams. Succeeds if any of
+    /// them accepted it.
+    fn recv(&mut self, time: SystemTime, ip_pkg: &Ipv4Packet) -> RxResult {
+        if self.listeners.is_empty() {
+            return Err(RxError::NoListener("Udp, port has no listeners".to_owned()));
+        }
+        let selected = match self.sharing {
+            PortSharing::FanOut => 0..self.listeners.len(),
+            _ => {
+                let i = self.flow_index(ip_pkg);
+                i..i + 1
+            }
+        };
+        let mut result = None;
+        let mut gone = vec![];
+        for &mut (id, ref mut listener) in &mut self.listeners[selected] {
+            let (listener_result, resume) = listener.recv(time, ip_pkg);
+            if !resume {
+                gone.push(id);
+            }
+            if result.as_ref().map_or(true, |r: &RxResult| r.is_err()) {
+                result = Some(listener_result);
+            }
+        }
+        for id in gone {
+            self.remove(id);
+        }
+        result.unwrap()
+    }
+
+    /// Passes `error` on to every listener on the port.
+    fn recv_error(&mut self, time: SystemTime, error: UdpError) {
+        for &mut (_, ref mut listener) in &mut self.listeners {
+            listener.recv_error(time, error.clone());
+        }
+    }
+
+    /// Picks the listener for the flow `ip_pkg` belongs to.
+    fn flow_index(&self, ip_pkg: &Ipv4Packet) -> usize {
+        let mut hasher = DefaultHasher::new();
+        ip_pkg.get_source().hash(&mut hasher);
+        UdpPacket::new(ip_pkg.payload()).unwrap().get_source().hash(&mut hasher);
+        (hasher.finish() % self.listeners.len() as u64) as usize
+    }
+}
+
+/// Type binding for how the listeners in `UdpRx` are structured. Listeners
+/// are stored by local port.
+pub type UdpListenerLookup = HashMap<u16, UdpPort>;
+
+/// Listener and parser of Udp datagrams. Drops datagrams with a bad length
+/// or checksum. A zero checksum means the sender did not compute one, as
+/// RFC 768 allows, and is accepted.
 pub struct UdpRx {
     listeners: Arc<Mutex<UdpListenerLookup>>,
+    icmp_errors: Option<IcmpErrorTx>,
+    checksum_errors: Arc<AtomicUsize>,
 }
 
 impl UdpRx {
     pub fn new(listeners: Arc<Mutex<UdpListenerLookup>>) -> UdpRx {
-        UdpRx { listeners: listeners }
+        UdpRx {
+            listeners: listeners,
+            icmp_errors: None,
+            checksum_errors: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Same as `new` but answers datagrams to ports nobody listens to with
+    /// Icmp Port Unreachable, and counts the datagrams dropped for bad
+    /// checksums in `checksum_errors`.
+    pub fn with_icmp_errors(listeners: Arc<Mutex<UdpListenerLookup>>,
+                            icmp_errors: IcmpErrorTx,
+                            checksum_errors: Arc<AtomicUsize>)
+                            -> UdpRx {
+        UdpRx {
+            listeners: listeners,
+            icmp_errors: Some(icmp_errors),
+            checksum_errors: checksum_errors,
+        }
+    }
+
+    /// Returns the number of datagrams dropped because of bad checksums.
+    pub fn checksum_errors(&self) -> usize {
+        self.checksum_errors.load(Ordering::SeqCst)
     }
 
     fn get_port(pkg: &Ipv4Packet) -> Result<u16, RxError> {
@@ -31,76 +242,430 @@ impl UdpRx {
         if payload.len() < UdpPacket::minimum_packet_size() {
             return Err(RxError::InvalidContent);
         }
-        let (port, length) = {
-            let udp_pkg = UdpPacket::new(payload).unwrap();
-            (udp_pkg.get_destination(), udp_pkg.get_length() as usize)
-        };
+        let length = UdpPacket::new(payload).unwrap().get_length() as usize;
         if length > payload.len() || length < UdpPacket::minimum_packet_size() {
-            Err(RxError::InvalidContent)
-        } else {
-            Ok(port)
+            return Err(RxError::InvalidContent);
+        }
+        let udp_pkg = UdpPacket::new(&payload[..length]).unwrap();
+        let received = udp_pkg.get_checksum();
+        if received != 0 {
+            let computed = ipv4_checksum(&udp_pkg, pkg.get_source(), pkg.get_destination());
+            // A computed checksum of zero is sent as all ones
+            if received != computed && !(computed == 0 && received == 0xffff) {
+                return Err(RxError::InvalidChecksum);
+            }
         }
+        Ok(udp_pkg.get_destination())
     }
 }
 
 impl Ipv4Listener for UdpRx {
     fn recv(&mut self, time: SystemTime, ip_pkg: Ipv4Packet) -> RxResult {
-        let port = try!(Self::get_port(&ip_pkg));
+        let port = match Self::get_port(&ip_pkg) {
+            Ok(port) => port,
+            Err(RxError::InvalidChecksum) => {
+                self.checksum_errors.fetch_add(1, Ordering::SeqCst);
+                return Err(RxError::InvalidChecksum);
+            }
+            Err(e) => return Err(e),
+        };
         let mut listeners = self.listeners.lock().unwrap();
-        if let Some(listener) = listeners.get_mut(&port) {
-            let (result, _resume) = listener.recv(time, &ip_pkg);
+        let delivered = listeners.get_mut(&port)
+            .map(|udp_port| (udp_port.recv(time, &ip_pkg), udp_port.is_empty()));
+        if let Some((result, unbound)) = delivered {
+            if unbound {
+                debug!("Udp, all listeners on port {} are gone, unbinding it", port);
+                listeners.remove(&port);
+            }
             result
-            // TODO: When resume turns false, remove this socket.
         } else {
+            if let Some(ref icmp_errors) = self.icmp_errors {
+                icmp_errors.port_unreachable(&ip_pkg);
+            }
             Err(RxError::NoListener(format!("Udp, no listener for port {:?}", port)))
         }
     }
 }
 
+/// `IcmpListener` delivering Destination Unreachable and Time Exceeded
+/// messages caused by Udp datagrams to the `UdpListener` on the port the
+/// datagram was sent from. The stack registers one for every local address.
+pub struct UdpErrorRx {
+    listeners: Arc<Mutex<UdpListenerLookup>>,
+}
+
+impl UdpErrorRx {
+    pub fn new(listeners: Arc<Mutex<UdpListenerLookup>>) -> UdpErrorRx {
+        UdpErrorRx { listeners: listeners }
+    }
+
+    /// Returns the local port and the error for the datagram `message` is an
+    /// Icmp error about.
+    fn parse(ip_pkg: &Ipv4Packet, message: &IcmpMessage) -> Option<(u16, UdpError)> {
+        let (original_pkg, udp_header) = match message.quoted_packet() {
+            Some(quoted) => quoted,
+            None => return None,
+        };
+        if original_pkg.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+            return None;
+        }
+        let udp_pkg = UdpPacket::new(udp_header).unwrap();
+        let error = UdpError {
+            dst: SocketAddrV4::new(original_pkg.get_destination(), udp_pkg.get_destination()),
+            from: ip_pkg.get_source(),
+            icmp_type: message.icmp_type(),
+            icmp_code: message.icmp_code(),
+        };
+        Some((udp_pkg.get_source(), error))
+    }
+}
+
+impl IcmpListener for UdpErrorRx {
+    fn recv(&mut self, time: SystemTime, ip_pkg: &Ipv4Packet, message: &IcmpMessage) {
+        if let Some((port, error)) = Self::parse(ip_pkg, message) {
+            let mut listeners = self.listeners.lock().unwrap();
+            if let Some(udp_port) = listeners.get_mut(&port) {
+                udp_port.recv_error(time, error);
+            }
+        }
+    }
+}
+
+/// Listener of a `UdpSocket`. When the socket is connected only datagrams
+/// from, and Icmp errors about datagrams to, the peer are let through.
+/// Stops resuming once all readers of the queue are dropped.
 #[derive(Clone)]
 pub struct UdpSocketListener {
-    chan: mpsc::Sender<(SystemTime, Box<[u8]>)>,
+    queue: Weak<RxQueue>,
+    error: Arc<Mutex<Option<UdpError>>>,
+    peer: Arc<Mutex<Option<SocketAddrV4>>>,
 }
 
 impl UdpListener for UdpSocketListener {
     fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet) -> (RxResult, bool) {
-        let data = packet.packet().to_vec().into_boxed_slice();
-        let resume = self.chan.send((time, data)).is_ok();
-        (Ok(()), resume)
+        if let Some(peer) = *self.peer.lock().unwrap() {
+            let src_port = UdpPacket::new(packet.payload()).unwrap().get_source();
+            let src = SocketAddrV4::new(packet.get_source(), src_port);
+            if src != peer {
+                let msg = format!("Udp, socket connected to {} got datagram from {}", peer, src);
+                return (Err(RxError::NoListener(msg)), true);
+            }
+        }
+        match self.queue.upgrade() {
+            Some(queue) => {
+                if queue.push(time, packet.packet().to_vec().into_boxed_slice()) {
+                    (Ok(()), true)
+                } else {
+                    (Err(RxError::Other("Udp, socket receive buffer is full".to_owned())), true)
+                }
+            }
+            None => (Err(RxError::NoListener("Udp, socket is closed".to_owned())), false),
+        }
+    }
+
+    fn recv_error(&mut self, _time: SystemTime, error: UdpError) {
+        if let Some(peer) = *self.peer.lock().unwrap() {
+            if error.dst != peer {
+                return;
+            }
+        }
+        *self.error.lock().unwrap() = Some(error);
+        // A connected socket reports the error on the next read, or to the
+        // reads already waiting
+        if let Some(queue) = self.queue.upgrade() {
+            queue.wake_all();
+        }
+    }
+}
+
+impl UdpSocketListener {
+    /// Takes the pending Icmp error if the socket is connected. Unconnected
+    /// sockets only report errors through `UdpSocket::take_error`.
+    fn take_connected_error(&self) -> Option<UdpError> {
+        if self.peer.lock().unwrap().is_some() {
+            self.error.lock().unwrap().take()
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the socket is connected and has an Icmp error pending.
+    fn has_connected_error(&self) -> bool {
+        self.peer.lock().unwrap().is_some() && self.error.lock().unwrap().is_some()
+    }
+}
+
+/// Default size of the receive buffer of a socket, in bytes. The same as
+/// the default `SO_RCVBUF` on Linux.
+pub const DEFAULT_RECV_BUFFER_SIZE: usize = 212992;
+
+/// The datagrams received for a socket, waiting to be read. Holds at most
+/// `capacity` bytes of IP packets. Datagrams arriving when it is full are
+/// dropped and counted in `dropped`. Blocking readers wait on `available`,
+/// polling ones register in `readers`.
+struct RxQueue {
+    datagrams: Mutex<Datagrams>,
+    available: Condvar,
+    readers: WakerSet,
+    dropped: AtomicUsize,
+}
+
+struct Datagrams {
+    queue: VecDeque<(SystemTime, Box<[u8]>)>,
+    len: usize,
+    capacity: usize,
+}
+
+impl RxQueue {
+    fn new() -> RxQueue {
+        RxQueue {
+            datagrams: Mutex::new(Datagrams {
+                queue: VecDeque::new(),
+                len: 0,
+                capacity: DEFAULT_RECV_BUFFER_SIZE,
+            }),
+            available: Condvar::new(),
+            readers: WakerSet::new(),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Queues `data` unless that would grow the queue past its capacity.
+    /// An empty queue takes any datagram, so a buffer smaller than the
+    /// datagrams can still receive them one at a time. Returns false if
+    /// `data` was dropped.
+    fn push(&self, time: SystemTime, data: Box<[u8]>) -> bool {
+        {
+            let mut datagrams = self.datagrams.lock().unwrap();
+            if !datagrams.queue.is_empty() && datagrams.len + data.len() > datagrams.capacity {
+                self.dropped.fetch_add(1, Ordering::SeqCst);
+                return false;
+            }
+            datagrams.len += data.len();
+            datagrams.queue.push_back((time, data));
+        }
+        self.available.notify_one();
+        self.readers.wake_all();
+        true
+    }
+
+    /// Wakes every reader, blocking or polling, without queueing anything.
+    /// Taking the lock first makes sure a blocking reader that checked for
+    /// an error before it was set is already waiting when notified.
+    fn wake_all(&self) {
+        drop(self.datagrams.lock().unwrap());
+        self.available.notify_all();
+        self.readers.wake_all();
+    }
+
+    /// Calls `f` with the first datagram in the queue and removes it, unless
+    /// `peek` is set. See `UdpSocketReader::recv_from` for how `timeout`
+    /// works. Fails with the Icmp error pending for a connected socket
+    /// instead, also when it arrives while waiting.
+    fn next<F, T>(&self,
+                  listener: &UdpSocketListener,
+                  timeout: Option<Duration>,
+                  peek: bool,
+                  f: F)
+                  -> io::Result<T>
+        where F: FnOnce(SystemTime, &[u8]) -> T
+    {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut datagrams = self.datagrams.lock().unwrap();
+        loop {
+            if let Some(error) = listener.take_connected_error() {
+                return Err(error.into());
+            }
+            if !datagrams.queue.is_empty() {
+                break;
+            }
+            datagrams = match deadline {
+                None => self.available.wait(datagrams).unwrap(),
+                Some(_) if timeout == Some(Duration::new(0, 0)) => {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                              "No datagram to read".to_owned()));
+                }
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                                  "Timed out waiting for a datagram".to_owned()));
+                    }
+                    self.available.wait_timeout(datagrams, deadline - now).unwrap().0
+                }
+            };
+        }
+        let result = {
+            let &(time, ref data) = datagrams.queue.front().unwrap();
+            f(time, data)
+        };
+        if peek {
+            // Still there, let someone else waiting have it
+            self.available.notify_one();
+        } else {
+            let (_, data) = datagrams.queue.pop_front().unwrap();
+            datagrams.len -= data.len();
+        }
+        Ok(result)
     }
 }
 
+/// Reads the datagrams `UdpSocketListener` receives for a socket. Clones
+/// share the queue, so every datagram is read by only one of them.
+#[derive(Clone)]
 pub struct UdpSocketReader {
-    port: mpsc::Receiver<(SystemTime, Box<[u8]>)>,
-    chan: UdpSocketListener,
+    queue: Arc<RxQueue>,
+    listener: UdpSocketListener,
 }
 
 impl UdpSocketReader {
-    pub fn new() -> UdpSocketReader {
-        let (tx, rx) = mpsc::channel();
+    /// Creates a reader whose listener stores the latest Icmp error for the
+    /// socket in `error`, and filters on `peer` when it is set.
+    pub fn new(error: Arc<Mutex<Option<UdpError>>>,
+               peer: Arc<Mutex<Option<SocketAddrV4>>>)
+               -> UdpSocketReader {
+        let queue = Arc::new(RxQueue::new());
         UdpSocketReader {
-            port: rx,
-            chan: UdpSocketListener { chan: tx },
+            listener: UdpSocketListener {
+                queue: Arc::downgrade(&queue),
+                error: error,
+                peer: peer,
+            },
+            queue: queue,
         }
     }
 
-    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        let (_time, data) = self.port.recv().unwrap();
-        let ipv4_pkg = Ipv4Packet::new(&data).unwrap();
-        let ip = ipv4_pkg.get_source();
-        let udp_pkg = UdpPacket::new(ipv4_pkg.payload()).unwrap();
-        let port = udp_pkg.get_source();
-        let data = udp_pkg.payload();
-        if data.len() > buf.len() {
-            Err(io::Error::new(io::ErrorKind::InvalidInput,
-                               "Data does not fit buffer".to_owned()))
-        } else {
-            buf[..data.len()].copy_from_slice(data);
-            Ok((data.len(), SocketAddr::V4(SocketAddrV4::new(ip, port))))
+    /// Reads the next datagram into `bufs`, filling them in order. Waits at
+    /// most `timeout` for one to arrive, failing with `TimedOut` after that.
+    /// A zero `timeout` fails with `WouldBlock` right away if there is none.
+    /// `None` waits forever. Returns how many bytes were copied. Datagrams
+    /// not fitting are truncated, and the rest is lost. With `peek` set the
+    /// datagram is left in the queue, to be read again.
+    pub fn recv_from(&self,
+                     bufs: &mut [&mut [u8]],
+                     timeout: Option<Duration>,
+                     peek: bool)
+                     -> io::Result<(usize, SocketAddr)> {
+        self.recv_with(bufs, timeout, peek, |len, _, _, ipv4_pkg| (len, source(ipv4_pkg)))
+    }
+
+    /// Same as `recv_from` but returns everything known about the datagram.
+    /// `interface` is the interface the socket is bound on.
+    pub fn recv_msg(&self,
+                    bufs: &mut [&mut [u8]],
+                    timeout: Option<Duration>,
+                    peek: bool,
+                    interface: &Interface)
+                    -> io::Result<UdpRecvMsg> {
+        self.recv_with(bufs, timeout, peek, |len, datagram_len, time, ipv4_pkg| {
+            UdpRecvMsg {
+                len: len,
+                datagram_len: datagram_len,
+                src: source(ipv4_pkg),
+                dst: ipv4_pkg.get_destination(),
+                timestamp: time,
+                ttl: ipv4_pkg.get_ttl(),
+                tos: (ipv4_pkg.get_dscp() << 2) | ipv4_pkg.get_ecn(),
+                interface: interface.clone(),
+            }
+        })
+    }
+
+    /// Copies the payload of the next datagram to `bufs` and calls `f` with
+    /// the number of bytes copied, the length of the payload, when it was
+    /// received and the IP packet.
+    fn recv_with<F, T>(&self,
+                       bufs: &mut [&mut [u8]],
+                       timeout: Option<Duration>,
+                       peek: bool,
+                       f: F)
+                       -> io::Result<T>
+        where F: FnOnce(usize, usize, SystemTime, &Ipv4Packet) -> T
+    {
+        self.queue.next(&self.listener, timeout, peek, |time, data| {
+            let ipv4_pkg = Ipv4Packet::new(data).unwrap();
+            let (len, datagram_len) = {
+                let udp_pkg = UdpPacket::new(ipv4_pkg.payload()).unwrap();
+                let payload = udp_pkg.payload();
+                (scatter(payload, bufs), payload.len())
+            };
+            f(len, datagram_len, time, &ipv4_pkg)
+        })
+    }
+
+    pub fn listener(&self) -> UdpSocketListener {
+        self.listener.clone()
+    }
+
+    /// Sets how many bytes of datagrams, IP headers included, the queue
+    /// holds before dropping new ones. Datagrams already queued are kept
+    /// when shrinking it.
+    pub fn set_buffer_size(&self, size: usize) {
+        self.queue.datagrams.lock().unwrap().capacity = size;
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.queue.datagrams.lock().unwrap().capacity
+    }
+
+    /// Returns how many datagrams were dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.queue.dropped.load(Ordering::SeqCst)
+    }
+
+    /// Wakes `waker` when a datagram is queued, or right away if there is
+    /// one already. Also woken by Icmp errors for the socket, right away if
+    /// it is connected and one is pending.
+    pub fn wake_when_readable(&self, waker: Arc<Wake>) {
+        {
+            // Registered with the queue locked, so a datagram or error
+            // arriving after the check still wakes it
+            let datagrams = self.queue.datagrams.lock().unwrap();
+            if datagrams.queue.is_empty() && !self.listener.has_connected_error() {
+                self.queue.readers.register(waker);
+                return;
+            }
         }
+        waker.wake();
     }
+}
+
+/// A datagram read with `UdpSocket::recv_msg`, and how it arrived.
+#[derive(Clone, Debug)]
+pub struct UdpRecvMsg {
+    /// Length of the payload copied into the buffer.
+    pub len: usize,
+    /// Length of the payload of the datagram. Larger than `len` when the
+    /// datagram did not fit the buffer and was truncated.
+    pub datagram_len: usize,
+    pub src: SocketAddr,
+    /// The address the datagram was sent to. The bound address, or a joined
+    /// multicast group.
+    pub dst: Ipv4Addr,
+    /// When the frame carrying the datagram was read from the interface.
+    pub timestamp: SystemTime,
+    pub ttl: u8,
+    /// The type of service byte. The DSCP in the upper six bits and the ECN
+    /// field in the lower two.
+    pub tos: u8,
+    /// The interface the datagram came in on.
+    pub interface: Interface,
+}
+
+/// Returns the source address and port of the Udp datagram in `ipv4_pkg`.
+fn source(ipv4_pkg: &Ipv4Packet) -> SocketAddr {
+    let udp_pkg = UdpPacket::new(ipv4_pkg.payload()).unwrap();
+    SocketAddr::V4(SocketAddrV4::new(ipv4_pkg.get_source(), udp_pkg.get_source()))
+}
 
-    pub fn listener(&mut self) -> UdpSocketListener {
-        self.chan.clone()
+/// Copies `data` to `bufs`, filling them in order. Returns how many bytes
+/// fit.
+fn scatter(data: &[u8], bufs: &mut [&mut [u8]]) -> usize {
+    let mut copied = 0;
+    for buf in bufs.iter_mut() {
+        let len = cmp::min(buf.len(), data.len() - copied);
+        buf[..len].copy_from_slice(&data[copied..copied + len]);
+        copied += len;
     }
+    copied
 }
@@ -1,14 +1,27 @@
-use {Protocol, TxResult};
+use {Interface, Protocol, TxResult};
 use ipv4::{Ipv4Protocol, Ipv4Tx};
 
+use pnet::packet::Packet;
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::udp::{MutableUdpPacket, UdpPacket, ipv4_checksum_adv};
 
+use std::cmp;
 use std::net::Ipv4Addr;
 
+/// Per datagram options for `UdpSocket::send_msg`. The defaults send like
+/// `send_to` does.
+#[derive(Clone, Debug, Default)]
+pub struct UdpSendMsg {
+    /// Local address to send from instead of the bound address.
+    pub src: Option<Ipv4Addr>,
+    /// Interface to send out on, instead of the one the routes point to.
+    pub interface: Option<Interface>,
+}
+
 pub struct UdpTx {
     src: u16,
     dst: u16,
+    checksum: bool,
     ipv4: Ipv4Tx,
 }
 
@@ -17,15 +30,29 @@ impl UdpTx {
         UdpTx {
             src: src,
             dst: dst,
+            checksum: true,
             ipv4: ipv4,
         }
     }
 
+    /// Sets if the datagrams sent get a checksum. Enabled by default. See
+    /// `UdpBuilder::set_checksum`.
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.checksum = checksum;
+    }
+
     pub fn send(&mut self, payload: &[u8]) -> TxResult {
+        self.send_vectored(&[payload])
+    }
+
+    /// Sends one datagram with the concatenation of the slices in `payload`
+    /// as payload.
+    pub fn send_vectored(&mut self, payload: &[&[u8]]) -> TxResult {
         let (src_port, dst_port) = (self.src, self.dst);
         let src_ip = self.ipv4.src;
         let dst_ip = self.ipv4.dst;
-        let builder = UdpBuilder::new(src_ip, dst_ip, src_port, dst_port, payload);
+        let mut builder = UdpBuilder::vectored(src_ip, dst_ip, src_port, dst_port, payload);
+        builder.set_checksum(self.checksum);
         self.ipv4.send(builder)
     }
 }
@@ -35,8 +62,10 @@ pub struct UdpBuilder<'a> {
     dst_ip: Ipv4Addr,
     src: u16,
     dst: u16,
+    checksum: bool,
     offset: usize,
-    payload: &'a [u8],
+    payload: Vec<&'a [u8]>,
+    payload_len: usize,
 }
 
 impl<'a> UdpBuilder<'a> {
@@ -46,14 +75,65 @@ impl<'a> UdpBuilder<'a> {
                dst_port: u16,
                payload: &'a [u8])
                -> UdpBuilder<'a> {
+        Self::vectored(src_ip, dst_ip, src_port, dst_port, &[payload])
+    }
+
+    /// Creates a builder whose payload is the concatenation of the slices in
+    /// `payload`, without copying them together first.
+    pub fn vectored(src_ip: Ipv4Addr,
+                    dst_ip: Ipv4Addr,
+                    src_port: u16,
+                    dst_port: u16,
+                    payload: &[&'a [u8]])
+                    -> UdpBuilder<'a> {
         UdpBuilder {
             src_ip: src_ip,
             dst_ip: dst_ip,
             src: src_port,
             dst: dst_port,
+            checksum: true,
             offset: 0,
-            payload: payload,
+            payload: payload.to_vec(),
+            payload_len: payload.iter().map(|part| part.len()).sum(),
+        }
+    }
+
+    /// Sets if the checksum is computed. Without it the checksum field is
+    /// zero, which RFC 768 defines as no checksum. Saves computing it over
+    /// every payload, for traffic already protected by other layers.
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.checksum = checksum;
+    }
+
+    /// Computes the checksum over the pseudo header, `header` and all parts
+    /// of the payload, as one stream of bytes.
+    fn vectored_checksum(&self, header: &[u8]) -> u16 {
+        let len = self.len() as u16;
+        let mut pseudo_header = [0; 12];
+        pseudo_header[..4].copy_from_slice(&self.src_ip.octets());
+        pseudo_header[4..8].copy_from_slice(&self.dst_ip.octets());
+        pseudo_header[9] = IpNextHeaderProtocols::Udp.0;
+        pseudo_header[10] = (len >> 8) as u8;
+        pseudo_header[11] = len as u8;
+
+        let bytes = pseudo_header.iter()
+            .chain(header.iter())
+            .chain(self.payload.iter().flat_map(|part| part.iter()));
+        let mut sum = 0u64;
+        let mut high = None;
+        for &byte in bytes {
+            match high.take() {
+                Some(high) => sum += ((high as u64) << 8) | byte as u64,
+                None => high = Some(byte),
+            }
         }
+        if let Some(high) = high {
+            sum += (high as u64) << 8;
+        }
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
     }
 }
 
@@ -65,7 +145,7 @@ impl<'a> Ipv4Protocol for UdpBuilder<'a> {
 
 impl<'a> Protocol for UdpBuilder<'a> {
     fn len(&self) -> usize {
-        UdpPacket::minimum_packet_size() + self.payload.len()
+        UdpPacket::minimum_packet_size() + self.payload_len
     }
 
     fn build(&mut self, buffer: &mut [u8]) {
@@ -76,17 +156,41 @@ impl<'a> Protocol for UdpBuilder<'a> {
                 pkg.set_source(self.src);
                 pkg.set_destination(self.dst);
                 pkg.set_length(self.len() as u16);
-                let checksum =
-                    ipv4_checksum_adv(&pkg.to_immutable(), self.payload, self.src_ip, self.dst_ip);
-                pkg.set_checksum(checksum);
+                pkg.set_checksum(0);
+                if self.checksum {
+                    let checksum = if self.payload.len() == 1 {
+                        ipv4_checksum_adv(&pkg.to_immutable(),
+                                          self.payload[0],
+                                          self.src_ip,
+                                          self.dst_ip)
+                    } else {
+                        self.vectored_checksum(pkg.packet())
+                    };
+                    // Zero means no checksum, so a computed zero is sent as
+                    // all ones
+                    pkg.set_checksum(if checksum == 0 { 0xffff } else { checksum });
+                }
             }
             &mut buffer[UdpPacket::minimum_packet_size()..]
         } else {
             buffer
         };
-        let start = self.offset;
-        let end = self.offset + payload_buffer.len();
-        payload_buffer.copy_from_slice(&self.payload[start..end]);
-        self.offset = end;
+        // Copy from the parts overlapping [offset, offset + buffer length)
+        let mut skip = self.offset;
+        let mut copied = 0;
+        for part in &self.payload {
+            if copied == payload_buffer.len() {
+                break;
+            }
+            if skip >= part.len() {
+                skip -= part.len();
+                continue;
+            }
+            let len = cmp::min(part.len() - skip, payload_buffer.len() - copied);
+            payload_buffer[copied..copied + len].copy_from_slice(&part[skip..skip + len]);
+            copied += len;
+            skip = 0;
+        }
+        self.offset += copied;
     }
 }
@@ -18,9 +18,13 @@ impl Buffer {
     /// Push new data to this `Buffer`. Returns the lowest index of missing
     /// data on success.
     /// This is equivalent to the length of the valid data at the start of the
-    /// buffer. Will fail if the given data offset is not valid.
+    /// buffer. Will fail if the given data offset is not valid or the data
+    /// does not fit in the buffer.
     // TODO: Support out of order data
     pub fn push(&mut self, offset: usize, data: &[u8]) -> Result<usize, ()> {
+        if offset + data.len() > self.data.len() {
+            return Err(());
+        }
         if offset == self.lowest_missing {
             self.lowest_missing += data.len();
         } else {
@@ -1,12 +1,18 @@
+use ipnetwork::Ipv4Network;
+
 use std::io;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
 
 // mod cachemap;
 // pub use util::cachemap::CacheMap;
 
 mod buffer;
+mod rate_limiter;
+mod timer;
 
 pub use util::buffer::Buffer;
+pub use util::rate_limiter::RateLimiter;
+pub use util::timer::spawn_timer;
 
 pub fn first_socket_addr<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
     if let Some(addr) = try!(addr.to_socket_addrs()).next() {
@@ -16,3 +22,35 @@ pub fn first_socket_addr<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
                            "Given ToSocketAddrs did not yield any address".to_owned()))
     }
 }
+
+/// Returns the directed broadcast address of `net`, the address with all
+/// host bits set. Networks with a prefix longer than 30 bits have none.
+pub fn directed_broadcast(net: &Ipv4Network) -> Option<Ipv4Addr> {
+    if net.prefix() > 30 {
+        None
+    } else {
+        Some(Ipv4Addr::from(u32::from(net.ip()) | (!0u32 >> net.prefix() as u32)))
+    }
+}
+
+/// Reads a big endian `u16` from the first two bytes of `data`.
+pub fn read_u16(data: &[u8]) -> u16 {
+    ((data[0] as u16) << 8) | data[1] as u16
+}
+
+/// Reads a big endian `u32` from the first four bytes of `data`.
+pub fn read_u32(data: &[u8]) -> u32 {
+    ((read_u16(&data[0..2]) as u32) << 16) | read_u16(&data[2..4]) as u32
+}
+
+/// Appends `value` to `buffer` in big endian byte order.
+pub fn write_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.push((value >> 8) as u8);
+    buffer.push(value as u8);
+}
+
+/// Appends `value` to `buffer` in big endian byte order.
+pub fn write_u32(buffer: &mut Vec<u8>, value: u32) {
+    write_u16(buffer, (value >> 16) as u16);
+    write_u16(buffer, value as u16);
+}
@@ -0,0 +1,66 @@
+use std::time::Instant;
+
+/// Token bucket rate limiter. Allows `rate` events per second on average,
+/// with bursts of up to `burst` events.
+pub struct RateLimiter {
+    rate: u32,
+    burst: u32,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a new `RateLimiter` with a full bucket. A `rate` of zero means
+    /// no limit.
+    pub fn new(rate: u32, burst: u32) -> RateLimiter {
+        RateLimiter {
+            rate: rate,
+            burst: burst,
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes one token from the bucket. Returns false if the bucket is empty,
+    /// meaning the event should be dropped.
+    pub fn check(&mut self) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        self.last = now;
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+        let max_tokens = if self.burst == 0 { 1 } else { self.burst } as f64;
+        self.tokens = (self.tokens + secs * self.rate as f64).min(max_tokens);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst() {
+        let mut limiter = RateLimiter::new(1, 3);
+        assert!(limiter.check());
+        assert!(limiter.check());
+        assert!(limiter.check());
+        assert!(!limiter.check());
+    }
+
+    #[test]
+    fn unlimited() {
+        let mut limiter = RateLimiter::new(0, 0);
+        for _ in 0..100 {
+            assert!(limiter.check());
+        }
+    }
+}
@@ -0,0 +1,18 @@
+use std::thread;
+use std::time::Instant;
+
+/// Calls `f` in a new thread, and again at every deadline it returns, until
+/// it returns `None`. For expiring state that must time out even when
+/// nothing else happens to the owner of it.
+pub fn spawn_timer<F>(mut f: F)
+    where F: FnMut() -> Option<Instant> + Send + 'static
+{
+    thread::spawn(move || {
+        while let Some(deadline) = f() {
+            let now = Instant::now();
+            if deadline > now {
+                thread::sleep(deadline - now);
+            }
+        }
+    });
+}
@@ -1,16 +1,17 @@
 use pnet::packet::{MutablePacket, Packet};
-use pnet::packet::arp::{ArpPacket, MutableArpPacket};
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
 use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
 use pnet::util::MacAddr;
 
-use rips::{Tx, VersionedTx};
-use rips::arp::{ArpTable, ArpTx};
+use rips::{Tx, VersionedTx, Wake};
+use rips::arp::{ARP_REQUEST_TIMEOUT_MS, ArpTable, ArpTx};
 use rips::ethernet::{EthernetRx, EthernetTx};
 use rips::testing;
 
 use std::io;
 use std::net::Ipv4Addr;
 use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread::{sleep, spawn};
 use std::time::Duration;
 
@@ -94,6 +95,51 @@ fn arp_locking() {
     assert!(arp_thread_rx.try_recv().is_err());
 }
 
+#[test]
+fn arp_wakers_expire() {
+    let unanswered = Ipv4Addr::new(10, 0, 0, 1);
+    let answered = Ipv4Addr::new(10, 0, 0, 2);
+    let mut arp_table = ArpTable::new();
+    let counting_waker = |woken: &Arc<AtomicUsize>| {
+        let woken = woken.clone();
+        Arc::new(move || {
+            woken.fetch_add(1, Ordering::SeqCst);
+        }) as Arc<Wake>
+    };
+    let woken_unanswered = Arc::new(AtomicUsize::new(0));
+    let woken_answered = Arc::new(AtomicUsize::new(0));
+
+    arp_table.wake_when_resolved(unanswered, counting_waker(&woken_unanswered));
+    sleep(Duration::from_millis(ARP_REQUEST_TIMEOUT_MS / 2));
+    arp_table.wake_when_resolved(answered, counting_waker(&woken_answered));
+    sleep(Duration::from_millis(ARP_REQUEST_TIMEOUT_MS / 2 + 100));
+    // The expired wakers are woken to retry, the others keep waiting
+    assert_eq!(woken_unanswered.load(Ordering::SeqCst), 1);
+    assert_eq!(woken_answered.load(Ordering::SeqCst), 0);
+
+    // Forgotten, so a late reply does not wake it again
+    arp_table.insert(unanswered, MacAddr::new(1, 2, 3, 4, 5, 6));
+    assert_eq!(woken_unanswered.load(Ordering::SeqCst), 1);
+    arp_table.insert(answered, MacAddr::new(1, 2, 3, 4, 5, 7));
+    assert_eq!(woken_answered.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn lone_arp_waker_expires() {
+    let arp_table = ArpTable::new();
+    let woken = Arc::new(AtomicUsize::new(0));
+    let waker_woken = woken.clone();
+    arp_table.wake_when_resolved(Ipv4Addr::new(10, 0, 0, 1),
+                                 Arc::new(move || {
+                                     waker_woken.fetch_add(1, Ordering::SeqCst);
+                                 }));
+    sleep(Duration::from_millis(ARP_REQUEST_TIMEOUT_MS / 2));
+    assert_eq!(woken.load(Ordering::SeqCst), 0);
+    // Woken without anything else happening to the table
+    sleep(Duration::from_millis(ARP_REQUEST_TIMEOUT_MS / 2 + 200));
+    assert_eq!(woken.load(Ordering::SeqCst), 1);
+}
+
 fn send_arp(inject_handle: mpsc::Sender<io::Result<Box<[u8]>>>) {
     // Send the response back to librips
     let mut buffer = vec![0; EthernetPacket::minimum_packet_size() +
@@ -102,6 +148,11 @@ fn send_arp(inject_handle: mpsc::Sender<io::Result<Box<[u8]>>>) {
         let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
         eth_pkg.set_ethertype(EtherTypes::Arp);
         let mut arp_pkg = MutableArpPacket::new(eth_pkg.payload_mut()).unwrap();
+        arp_pkg.set_hardware_type(ArpHardwareTypes::Ethernet);
+        arp_pkg.set_protocol_type(EtherTypes::Ipv4);
+        arp_pkg.set_hw_addr_len(6);
+        arp_pkg.set_proto_addr_len(4);
+        arp_pkg.set_operation(ArpOperations::Reply);
         arp_pkg.set_sender_hw_addr(MacAddr::new(9, 8, 7, 6, 5, 4));
         arp_pkg.set_sender_proto_addr(Ipv4Addr::new(10, 0, 0, 1));
     }
@@ -3,12 +3,15 @@ use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEther
 use pnet::util::MacAddr;
 
 use rips::{RxResult, Tx};
-use rips::ethernet::{EthernetListener, EthernetRx, EthernetTx};
+use rips::ethernet::{EthernetListener, EthernetRx, EthernetTx, MAX_CONSECUTIVE_READ_ERRORS,
+                     MAX_READ_ERROR_BACKOFF_MS};
 use rips::ethernet::BasicEthernetProtocol;
 use rips::testing;
 
+use std::io;
 use std::sync::mpsc;
-use std::time::SystemTime;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 pub struct MockEthernetListener {
     pub tx: mpsc::Sender<Vec<u8>>,
@@ -73,3 +76,24 @@ fn test_ethernet_send() {
     assert_eq!(sent_pkg.get_ethertype(), EtherTypes::Rarp);
     assert_eq!(sent_pkg.payload()[0], 57);
 }
+
+#[test]
+fn read_errors_stop_receiver() {
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack(0);
+    assert!(stack.interface(&interface).unwrap().is_receiving());
+
+    let start = Instant::now();
+    for _ in 0..MAX_CONSECUTIVE_READ_ERRORS {
+        inject_handle.send(Err(io::Error::new(io::ErrorKind::Other, "read failed"))).unwrap();
+    }
+    for _ in 0..1000 {
+        if !stack.interface(&interface).unwrap().is_receiving() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!stack.interface(&interface).unwrap().is_receiving());
+    // Backed off between the reads instead of spinning through the errors
+    let backoff = Duration::from_millis(MAX_READ_ERROR_BACKOFF_MS);
+    assert!(start.elapsed() > backoff * (MAX_CONSECUTIVE_READ_ERRORS as u32 / 2));
+}
@@ -0,0 +1,209 @@
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::icmp::echo_reply::EchoReplyPacket;
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
+use pnet::util::MacAddr;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use rips::icmp::EchoSettings;
+use rips::testing;
+
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use icmp::icmp_frame;
+use udp::udp_frame;
+
+/// Number of frames injected by every test.
+const FRAMES: usize = 5000;
+
+/// Fixed seed, so a failing run can be reproduced.
+const SEED: [u32; 4] = [0x5eed, 0x1, 0xf00d, 0x7];
+
+#[test]
+fn random_frames() {
+    let mut rng = XorShiftRng::from_seed(SEED);
+    let frames = (0..FRAMES)
+        .map(|_| {
+            let len = rng.gen_range(EthernetPacket::minimum_packet_size(), 200);
+            let mut frame = vec![0; len];
+            rng.fill_bytes(&mut frame);
+            frame.into_boxed_slice()
+        })
+        .collect();
+    assert_survives(frames);
+}
+
+#[test]
+fn mutated_frames() {
+    let mut rng = XorShiftRng::from_seed(SEED);
+    let templates = templates();
+    let frames = (0..FRAMES)
+        .map(|_| {
+            let template = rng.choose(&templates).unwrap();
+            mutate(&mut rng, template)
+        })
+        .collect();
+    assert_survives(frames);
+}
+
+#[test]
+fn read_errors() {
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::new(local_ip(), 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(remote_ip(), remote_mac());
+
+    // Fewer errors in a row than what stops the receiver
+    for _ in 0..10 {
+        let error = ::std::io::Error::new(::std::io::ErrorKind::Other, "Fuzz");
+        inject_handle.send(Err(error)).unwrap();
+    }
+    inject_handle.send(Ok(echo_request(0xfeed))).unwrap();
+    assert_echo_reply(&read_handle, 0xfeed);
+}
+
+/// Injects `frames` into a stack and checks it still answers pings after.
+fn assert_survives(frames: Vec<Box<[u8]>>) {
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::new(local_ip(), 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(remote_ip(), remote_mac());
+    let mut settings = EchoSettings::default();
+    settings.rate_limit = 0;
+    stack.set_echo_settings(settings);
+
+    for frame in frames {
+        inject_handle.send(Ok(frame)).unwrap();
+    }
+    inject_handle.send(Ok(echo_request(0xfeed))).unwrap();
+    assert_echo_reply(&read_handle, 0xfeed);
+}
+
+/// Waits for the Echo Reply with `identifier`, skipping everything the
+/// stack sent in response to the fuzzed frames.
+fn assert_echo_reply(read_handle: &::std::sync::mpsc::Receiver<Box<[u8]>>, identifier: u16) {
+    loop {
+        let frame = read_handle.recv_timeout(Duration::from_secs(2))
+            .expect("Stack stopped answering, the receive path died");
+        let eth_pkg = EthernetPacket::new(&frame).unwrap();
+        if eth_pkg.get_ethertype() != EtherTypes::Ipv4 {
+            continue;
+        }
+        let ip_pkg = match Ipv4Packet::new(eth_pkg.payload()) {
+            Some(ip_pkg) => ip_pkg,
+            None => continue,
+        };
+        if ip_pkg.get_destination() != remote_ip() {
+            continue;
+        }
+        if let Some(echo_pkg) = EchoReplyPacket::new(ip_pkg.payload()) {
+            if echo_pkg.get_icmp_type() == IcmpTypes::EchoReply &&
+               echo_pkg.get_identifier() == identifier {
+                return;
+            }
+        }
+    }
+}
+
+/// Valid frames of every protocol the stack parses, to mutate into
+/// almost valid ones.
+fn templates() -> Vec<Box<[u8]>> {
+    let mut quote = vec![0; 4];
+    quote.extend_from_slice(&udp_frame(local_ip(), remote_ip(), 1024, 1025, &[])[14..]);
+    let mut fragment = udp_frame(remote_ip(), local_ip(), 1024, 1025, &[1; 64]);
+    {
+        let mut ip_pkg = MutableIpv4Packet::new(&mut fragment[14..]).unwrap();
+        ip_pkg.set_flags(0b001);
+        fix_checksum(&mut ip_pkg);
+    }
+    vec![arp_frame(),
+         echo_request(7),
+         udp_frame(remote_ip(), local_ip(), 1024, 1025, &[1, 2, 3]),
+         fragment,
+         icmp_frame(remote_mac(),
+                    local_mac(),
+                    remote_ip(),
+                    local_ip(),
+                    IcmpTypes::DestinationUnreachable,
+                    quote.clone()),
+         icmp_frame(remote_mac(),
+                    local_mac(),
+                    remote_ip(),
+                    local_ip(),
+                    IcmpTypes::RedirectMessage,
+                    quote)]
+}
+
+/// Flips a few random bytes in `template` and randomly truncates it. Fixes
+/// up the IPv4 checksum half of the time, so the garbage reaches the upper
+/// layers.
+fn mutate(rng: &mut XorShiftRng, template: &[u8]) -> Box<[u8]> {
+    let mut frame = template.to_vec();
+    for _ in 0..rng.gen_range(1, 5) {
+        let i = rng.gen_range(0, frame.len());
+        frame[i] = rng.gen();
+    }
+    if rng.gen() {
+        let len = rng.gen_range(EthernetPacket::minimum_packet_size(), frame.len() + 1);
+        frame.truncate(len);
+    }
+    if rng.gen() {
+        if let Some(mut ip_pkg) = MutableIpv4Packet::new(&mut frame[14..]) {
+            fix_checksum(&mut ip_pkg);
+        }
+    }
+    frame.into_boxed_slice()
+}
+
+fn fix_checksum(ip_pkg: &mut MutableIpv4Packet) {
+    let csum = checksum(&ip_pkg.to_immutable());
+    ip_pkg.set_checksum(csum);
+}
+
+fn arp_frame() -> Box<[u8]> {
+    let mut buffer = vec![0; EthernetPacket::minimum_packet_size() +
+                             ArpPacket::minimum_packet_size()];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_ethertype(EtherTypes::Arp);
+        let mut arp_pkg = MutableArpPacket::new(eth_pkg.payload_mut()).unwrap();
+        arp_pkg.set_hardware_type(ArpHardwareTypes::Ethernet);
+        arp_pkg.set_protocol_type(EtherTypes::Ipv4);
+        arp_pkg.set_hw_addr_len(6);
+        arp_pkg.set_proto_addr_len(4);
+        arp_pkg.set_operation(ArpOperations::Reply);
+        arp_pkg.set_sender_hw_addr(MacAddr::new(9, 8, 7, 6, 5, 4));
+        arp_pkg.set_sender_proto_addr(Ipv4Addr::new(10, 0, 0, 9));
+    }
+    buffer.into_boxed_slice()
+}
+
+fn echo_request(identifier: u16) -> Box<[u8]> {
+    let payload = vec![(identifier >> 8) as u8, identifier as u8, 0, 1, 1, 2, 3];
+    icmp_frame(remote_mac(),
+               local_mac(),
+               remote_ip(),
+               local_ip(),
+               IcmpTypes::EchoRequest,
+               payload)
+}
+
+fn local_ip() -> Ipv4Addr {
+    Ipv4Addr::new(10, 0, 0, 2)
+}
+
+fn local_mac() -> MacAddr {
+    MacAddr::new(0, 0, 0, 0, 0, 0)
+}
+
+fn remote_ip() -> Ipv4Addr {
+    Ipv4Addr::new(10, 0, 0, 1)
+}
+
+fn remote_mac() -> MacAddr {
+    MacAddr::new(1, 2, 3, 4, 5, 6)
+}
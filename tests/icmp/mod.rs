@@ -1,28 +1,36 @@
 use ipnetwork::Ipv4Network;
 
 use pnet::packet::Packet;
-use pnet::packet::ethernet::MutableEthernetPacket;
-use pnet::packet::icmp::{IcmpPacket, IcmpTypes};
+use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+use pnet::packet::icmp::{IcmpPacket, IcmpType, IcmpTypes};
+use pnet::packet::icmp::echo_reply::EchoReplyPacket;
 use pnet::packet::icmp::echo_request::IcmpCodes;
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::Ipv4Packet;
 use pnet::util::MacAddr;
 
+use rips::{Poll, Wake};
 use rips::ethernet::EthernetBuilder;
-use rips::icmp::{BasicIcmpProtocol, IcmpBuilder, IcmpListener};
+use rips::icmp::{BasicIcmpProtocol, EchoSettings, IcmpBuilder, IcmpListener, IcmpMessage,
+                 PingEvent, PingOutcome, Pinger, RouterDiscovery, RouterDiscoveryConfig,
+                 Traceroute, TracerouteConfig, TracerouteMethod, all_routers_group,
+                 all_systems_group};
 use rips::ipv4::Ipv4Builder;
 use rips::testing;
 
 use std::net::Ipv4Addr;
-use std::sync::mpsc;
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use udp::udp_frame;
 
 pub struct MockIcmpListener {
     pub tx: mpsc::Sender<Vec<u8>>,
 }
 
 impl IcmpListener for MockIcmpListener {
-    fn recv(&mut self, _time: SystemTime, packet: &Ipv4Packet) {
+    fn recv(&mut self, _time: SystemTime, packet: &Ipv4Packet, _message: &IcmpMessage) {
         println!("MockIcmpListener got a packet!");
         self.tx.send(packet.packet().to_vec()).unwrap();
     }
@@ -43,19 +51,16 @@ fn recv_icmp() {
     stack.add_ipv4(&interface, local_net).unwrap();
     stack.icmp_listen(local_ip, IcmpTypes::DestinationUnreachable, listener).unwrap();
 
-    let payload_builder = BasicIcmpProtocol::new(IcmpTypes::DestinationUnreachable,
-                                                 IcmpCodes::NoCode,
-                                                 vec![6, 5]);
-    let icmp_builder = IcmpBuilder::new(payload_builder);
-    let ipv4_builder = Ipv4Builder::new(remote_ip, local_ip, 0, icmp_builder);
-    let mut eth_builder = EthernetBuilder::new(remote_mac, local_mac, ipv4_builder);
-    let mut buffer = vec![0; eth_builder.len()];
-    {
-        let eth_pkg = MutableEthernetPacket::new(&mut buffer).unwrap();
-        eth_builder.build(eth_pkg);
-    }
-
-    inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+    // Four unused bytes and the quoted datagram
+    let mut payload = vec![0; 4];
+    payload.extend_from_slice(&udp_frame(local_ip, remote_ip, 1024, 1025, &[])[14..]);
+    let frame = icmp_frame(remote_mac,
+                           local_mac,
+                           remote_ip,
+                           local_ip,
+                           IcmpTypes::DestinationUnreachable,
+                           payload);
+    inject_handle.send(Ok(frame)).unwrap();
 
     let pkg = rx.recv().unwrap();
     let ip_pkg = Ipv4Packet::new(&pkg[..]).unwrap();
@@ -66,3 +71,443 @@ fn recv_icmp() {
     let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
     assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::DestinationUnreachable);
 }
+
+#[test]
+fn answer_echo_request() {
+    let remote_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::new(local_ip, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(remote_ip, remote_mac);
+
+    // Identifier 7, sequence number 3 and payload [1, 2]
+    let echo_request = vec![0, 7, 0, 3, 1, 2];
+    let frame = icmp_frame(remote_mac,
+                           interface.mac,
+                           remote_ip,
+                           local_ip,
+                           IcmpTypes::EchoRequest,
+                           echo_request.clone());
+    inject_handle.send(Ok(frame)).unwrap();
+
+    let reply = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    let eth_pkg = EthernetPacket::new(&reply[..]).unwrap();
+    assert_eq!(eth_pkg.get_destination(), remote_mac);
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(ip_pkg.get_source(), local_ip);
+    assert_eq!(ip_pkg.get_destination(), remote_ip);
+    let echo_pkg = EchoReplyPacket::new(ip_pkg.payload()).unwrap();
+    assert_eq!(echo_pkg.get_icmp_type(), IcmpTypes::EchoReply);
+    assert_eq!(echo_pkg.get_identifier(), 7);
+    assert_eq!(echo_pkg.get_sequence_number(), 3);
+    assert_eq!(echo_pkg.payload(), [1, 2]);
+
+    let mut settings = EchoSettings::default();
+    settings.enabled = false;
+    stack.set_echo_settings(settings);
+    let frame = icmp_frame(remote_mac,
+                           interface.mac,
+                           remote_ip,
+                           local_ip,
+                           IcmpTypes::EchoRequest,
+                           echo_request);
+    inject_handle.send(Ok(frame)).unwrap();
+    assert!(read_handle.recv_timeout(Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn answer_broadcast_echo_request() {
+    let remote_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+    let broadcast_mac = MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+    let broadcasts = [Ipv4Addr::new(10, 0, 0, 255), Ipv4Addr::new(255, 255, 255, 255)];
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::new(local_ip, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(remote_ip, remote_mac);
+
+    let echo_request = vec![0, 7, 0, 3, 1, 2];
+    let send_requests = || {
+        for broadcast in &broadcasts {
+            let frame = icmp_frame(remote_mac,
+                                   broadcast_mac,
+                                   remote_ip,
+                                   *broadcast,
+                                   IcmpTypes::EchoRequest,
+                                   echo_request.clone());
+            inject_handle.send(Ok(frame)).unwrap();
+        }
+    };
+
+    // Ignored by default
+    send_requests();
+    assert!(read_handle.recv_timeout(Duration::from_millis(100)).is_err());
+
+    let mut settings = EchoSettings::default();
+    settings.ignore_broadcast = false;
+    stack.set_echo_settings(settings);
+    send_requests();
+    for _ in &broadcasts {
+        let reply = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+        let eth_pkg = EthernetPacket::new(&reply[..]).unwrap();
+        assert_eq!(eth_pkg.get_destination(), remote_mac);
+        let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+        assert_eq!(ip_pkg.get_source(), local_ip);
+        assert_eq!(ip_pkg.get_destination(), remote_ip);
+        let echo_pkg = EchoReplyPacket::new(ip_pkg.payload()).unwrap();
+        assert_eq!(echo_pkg.get_icmp_type(), IcmpTypes::EchoReply);
+        assert_eq!(echo_pkg.get_sequence_number(), 3);
+    }
+
+    // No Icmp error is sent about Udp to a broadcast address
+    let frame = udp_frame(remote_ip, broadcasts[0], 9999, 1024, &[1, 2]);
+    inject_handle.send(Ok(frame)).unwrap();
+    assert!(read_handle.recv_timeout(Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn ping() {
+    let remote_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::new(local_ip, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(remote_ip, remote_mac);
+    let stack = Arc::new(Mutex::new(stack));
+
+    let mut pinger = Pinger::new(stack, remote_ip).unwrap();
+    assert_eq!(pinger.local_ip(), local_ip);
+    pinger.set_timeout(Duration::from_millis(200));
+
+    // Answer the first request only
+    let local_mac = interface.mac;
+    thread::spawn(move || {
+        let request = read_handle.recv().unwrap();
+        let eth_pkg = EthernetPacket::new(&request[..]).unwrap();
+        let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+        let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
+        assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::EchoRequest);
+        let frame = icmp_frame(remote_mac,
+                               local_mac,
+                               remote_ip,
+                               local_ip,
+                               IcmpTypes::EchoReply,
+                               icmp_pkg.payload().to_vec());
+        inject_handle.send(Ok(frame)).unwrap();
+        for _ in read_handle.iter() {}
+    });
+
+    let probe = pinger.ping().unwrap();
+    assert_eq!(probe.sequence_number, 0);
+    match probe.outcome {
+        PingOutcome::Reply { from, ttl, .. } => {
+            assert_eq!(from, remote_ip);
+            assert_eq!(ttl, 40);
+        }
+        outcome => panic!("Unexpected outcome {:?}", outcome),
+    }
+
+    let probe = pinger.ping().unwrap();
+    assert_eq!(probe.sequence_number, 1);
+    assert_eq!(probe.outcome, PingOutcome::Timeout);
+
+    let statistics = pinger.statistics();
+    assert_eq!(statistics.transmitted, 2);
+    assert_eq!(statistics.received, 1);
+    assert_eq!(statistics.loss(), 0.5);
+    assert!(statistics.avg_rtt().is_some());
+}
+
+#[test]
+fn ping_unresolved() {
+    let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::new(local_ip, 24).unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    let mut pinger = Pinger::new(stack.clone(), remote_ip).unwrap();
+    pinger.set_timeout(Duration::from_millis(100));
+
+    // Nothing answers the Arp request
+    let start = Instant::now();
+    let probe = pinger.ping().unwrap();
+    assert_eq!(probe.outcome, PingOutcome::Timeout);
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert!(read_handle.recv_timeout(Duration::from_secs(1)).is_ok());
+    assert!(stack.try_lock().is_ok());
+
+    let statistics = pinger.statistics();
+    assert_eq!(statistics.transmitted, 1);
+    assert_eq!(statistics.received, 0);
+    assert_eq!(statistics.loss(), 1.0);
+
+    // The stack is not locked while waiting for the Arp reply
+    pinger.set_timeout(Duration::from_millis(500));
+    let pinging = thread::spawn(move || pinger.ping().unwrap());
+    thread::sleep(Duration::from_millis(100));
+    assert!(stack.try_lock().is_ok());
+    assert_eq!(pinging.join().unwrap().outcome, PingOutcome::Timeout);
+}
+
+#[test]
+fn poll_pinger() {
+    let remote_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let remote_ip = Ipv4Addr::new(10, 0, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::new(local_ip, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(remote_ip, remote_mac);
+    let stack = Arc::new(Mutex::new(stack));
+    let mut pinger = Pinger::new(stack, remote_ip).unwrap();
+    let (woken_tx, woken_rx) = mpsc::channel();
+    let waker = {
+        let woken_tx = Mutex::new(woken_tx);
+        Arc::new(move || woken_tx.lock().unwrap().send(()).unwrap_or(())) as Arc<Wake>
+    };
+
+    assert!(pinger.poll_send_request(waker.clone()).is_ready());
+    assert!(!pinger.poll_event(waker.clone()).is_ready());
+    let request = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    let eth_pkg = EthernetPacket::new(&request[..]).unwrap();
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
+    let frame = icmp_frame(remote_mac,
+                           interface.mac,
+                           remote_ip,
+                           local_ip,
+                           IcmpTypes::EchoReply,
+                           icmp_pkg.payload().to_vec());
+    inject_handle.send(Ok(frame)).unwrap();
+
+    woken_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    match pinger.poll_event(waker) {
+        Poll::Ready(PingEvent::Reply { sequence_number, from, .. }) => {
+            assert_eq!(sequence_number, 0);
+            assert_eq!(from, remote_ip);
+        }
+        event => panic!("Unexpected event {:?}", event),
+    }
+}
+
+#[test]
+fn traceroute() {
+    let gw_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let gw_ip = Ipv4Addr::new(10, 0, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+    let dst = Ipv4Addr::new(10, 1, 2, 3);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::new(local_ip, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(gw_ip, gw_mac);
+    stack.routing_table()
+        .add_route(Ipv4Network::new(dst, 16).unwrap(), Some(gw_ip), interface.clone());
+    let stack = Arc::new(Mutex::new(stack));
+
+    // The gateway answers probes with ttl 1, the destination the rest
+    let local_mac = interface.mac;
+    thread::spawn(move || {
+        for (i, request) in read_handle.iter().take(2).enumerate() {
+            let eth_pkg = EthernetPacket::new(&request[..]).unwrap();
+            let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+            assert_eq!(ip_pkg.get_ttl() as usize, i + 1);
+            let frame = if ip_pkg.get_ttl() == 1 {
+                let mut quote = vec![0; 4];
+                quote.extend_from_slice(&eth_pkg.payload()[..28]);
+                icmp_frame(gw_mac, local_mac, gw_ip, local_ip, IcmpTypes::TimeExceeded, quote)
+            } else {
+                let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
+                icmp_frame(gw_mac,
+                           local_mac,
+                           dst,
+                           local_ip,
+                           IcmpTypes::EchoReply,
+                           icmp_pkg.payload().to_vec())
+            };
+            inject_handle.send(Ok(frame)).unwrap();
+        }
+    });
+
+    let mut config = TracerouteConfig::default();
+    config.method = TracerouteMethod::Icmp;
+    config.probes_per_hop = 1;
+    config.timeout = Duration::from_secs(1);
+    let mut traceroute = Traceroute::new(stack, dst, config).unwrap();
+    let hops = traceroute.run().unwrap();
+    assert_eq!(hops.len(), 2);
+    assert_eq!(hops[0].ttl, 1);
+    assert_eq!(hops[0].addresses(), vec![gw_ip]);
+    assert!(!hops[0].is_final());
+    assert_eq!(hops[1].ttl, 2);
+    assert_eq!(hops[1].addresses(), vec![dst]);
+    assert_eq!(hops[1].probes[0].as_ref().unwrap().icmp_type, IcmpTypes::EchoReply);
+}
+
+#[test]
+fn traceroute_unresolved() {
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+    let dst = Ipv4Addr::new(10, 0, 0, 1);
+
+    let (mut stack, interface, _, _read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::new(local_ip, 24).unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    // Nothing answers the Arp requests, so every probe times out
+    let mut config = TracerouteConfig::default();
+    config.max_ttl = 2;
+    config.probes_per_hop = 2;
+    config.timeout = Duration::from_millis(50);
+    let mut traceroute = Traceroute::new(stack, dst, config).unwrap();
+    let start = Instant::now();
+    let hops = traceroute.run().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(hops.len(), 2);
+    for hop in &hops {
+        assert_eq!(hop.probes, vec![None, None]);
+    }
+}
+
+#[test]
+fn redirect() {
+    let gw_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let gw_ip = Ipv4Addr::new(10, 0, 0, 1);
+    let new_gw = Ipv4Addr::new(10, 0, 0, 3);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+    let dst = Ipv4Addr::new(10, 1, 2, 3);
+
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::new(local_ip, 24).unwrap()).unwrap();
+    stack.routing_table()
+        .add_route(Ipv4Network::new(dst, 16).unwrap(), Some(gw_ip), interface.clone());
+    let stack = Arc::new(Mutex::new(stack));
+
+    // The new gateway followed by the quoted packet sent to dst
+    let redirect = |from: Ipv4Addr| {
+        let mut payload = new_gw.octets().to_vec();
+        payload.extend_from_slice(&udp_frame(local_ip, dst, 1024, 1025, &[])[14..]);
+        icmp_frame(gw_mac,
+                   interface.mac,
+                   from,
+                   local_ip,
+                   IcmpTypes::RedirectMessage,
+                   payload)
+    };
+
+    // Only the gateway currently used for dst may redirect
+    inject_handle.send(Ok(redirect(Ipv4Addr::new(10, 0, 0, 4)))).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let route = stack.lock().unwrap().routing_table().route(dst);
+    assert_eq!(route, Some((Some(gw_ip), interface.clone())));
+
+    inject_handle.send(Ok(redirect(gw_ip))).unwrap();
+    let mut route = None;
+    for _ in 0..100 {
+        route = stack.lock().unwrap().routing_table().route(dst);
+        if route == Some((Some(new_gw), interface.clone())) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(route, Some((Some(new_gw), interface.clone())));
+    // Only the redirected host is affected
+    let route = stack.lock().unwrap().routing_table().route(Ipv4Addr::new(10, 1, 2, 4));
+    assert_eq!(route, Some((Some(gw_ip), interface.clone())));
+
+    // The redirect goes away with the route through the gateway it replaced
+    let other_gw = Ipv4Addr::new(10, 0, 0, 5);
+    {
+        let mut stack = stack.lock().unwrap();
+        let table = stack.routing_table();
+        assert!(table.remove_route(Ipv4Network::new(dst, 16).unwrap(), Some(gw_ip), &interface));
+        table.add_route(Ipv4Network::new(dst, 16).unwrap(), Some(other_gw), interface.clone());
+    }
+    for _ in 0..300 {
+        route = stack.lock().unwrap().routing_table().route(dst);
+        if route == Some((Some(other_gw), interface.clone())) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(route, Some((Some(other_gw), interface.clone())));
+}
+
+#[test]
+fn router_discovery() {
+    let router_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let router_ip = Ipv4Addr::new(10, 0, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::new(local_ip, 24).unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    let mut config = RouterDiscoveryConfig::new(local_ip);
+    config.solicitations = 1;
+    let discovery = RouterDiscovery::spawn(stack.clone(), config).unwrap();
+
+    let solicitation = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    let eth_pkg = EthernetPacket::new(&solicitation[..]).unwrap();
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(ip_pkg.get_source(), local_ip);
+    assert_eq!(ip_pkg.get_destination(), all_routers_group());
+    let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
+    assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::RouterSolicitation);
+
+    // One address, two words per entry, lifetime 30 seconds, preference 5
+    let advertisement = vec![1, 2, 0, 30, 10, 0, 0, 1, 0, 0, 0, 5];
+    let frame = icmp_frame(router_mac,
+                           interface.mac,
+                           router_ip,
+                           all_systems_group(),
+                           IcmpTypes::RouterAdvertisement,
+                           advertisement);
+    inject_handle.send(Ok(frame)).unwrap();
+
+    let mut routers = vec![];
+    for _ in 0..100 {
+        routers = discovery.routers();
+        if !routers.is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(routers.len(), 1);
+    assert_eq!(routers[0].address, router_ip);
+    assert_eq!(routers[0].preference, 5);
+
+    let route = stack.lock().unwrap().routing_table().route(Ipv4Addr::new(172, 16, 3, 4));
+    assert_eq!(route, Some((Some(router_ip), interface.clone())));
+
+    discovery.stop();
+    let route = stack.lock().unwrap().routing_table().route(Ipv4Addr::new(172, 16, 3, 4));
+    assert_eq!(route, None);
+
+    // Stopping leaves the group and removes the listener, so it can be restarted
+    let config = RouterDiscoveryConfig::new(local_ip);
+    RouterDiscovery::spawn(stack, config).unwrap().stop();
+}
+
+pub fn icmp_frame(src_mac: MacAddr,
+                  dst_mac: MacAddr,
+                  src_ip: Ipv4Addr,
+                  dst_ip: Ipv4Addr,
+                  icmp_type: IcmpType,
+                  payload: Vec<u8>)
+                  -> Box<[u8]> {
+    let payload_builder = BasicIcmpProtocol::new(icmp_type, IcmpCodes::NoCode, payload);
+    let icmp_builder = IcmpBuilder::new(payload_builder);
+    let ipv4_builder = Ipv4Builder::new(src_ip, dst_ip, 0, icmp_builder);
+    let mut eth_builder = EthernetBuilder::new(src_mac, dst_mac, ipv4_builder);
+    let mut buffer = vec![0; eth_builder.len()];
+    {
+        let eth_pkg = MutableEthernetPacket::new(&mut buffer).unwrap();
+        eth_builder.build(eth_pkg);
+    }
+    buffer.into_boxed_slice()
+}
@@ -6,6 +6,7 @@ use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
 use pnet::util::MacAddr;
 
+use rips::{Flow, StackError, TxError};
 use rips::ethernet::EthernetRx;
 use rips::ipv4::{Ipv4Listener, Ipv4Rx};
 use rips::testing;
@@ -43,6 +44,65 @@ fn simple_send() {
     assert_eq!(ip_pkg.payload(), [100, 99]);
 }
 
+#[test]
+fn invalidate_on_route_update() {
+    let source_ip = Ipv4Addr::new(10, 1, 2, 3);
+    let target_ip = Ipv4Addr::new(192, 168, 0, 5);
+    let gw = Ipv4Addr::new(10, 1, 2, 1);
+
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack(0);
+    stack.interface(&interface).unwrap().arp_table().insert(gw, MacAddr::new(9, 0, 0, 4, 0, 0));
+    stack.add_ipv4(&interface, Ipv4Network::new(source_ip, 24).unwrap()).unwrap();
+    stack.routing_table()
+        .add_route(Ipv4Network::from_cidr("0/0").unwrap(), Some(gw), interface.clone());
+
+    let mut ipv4_tx = stack.ipv4_tx(target_ip).unwrap();
+    ipv4_tx.send(TestIpv4Protocol::new(2)).unwrap();
+    assert!(read_handle.recv().is_ok());
+
+    stack.routing_table()
+        .add_route(Ipv4Network::from_cidr("192.168.0.0/24").unwrap(), None, interface.clone());
+    match ipv4_tx.send(TestIpv4Protocol::new(2)) {
+        Err(TxError::InvalidTx) => (),
+        r => panic!("Expected InvalidTx after route update, got {:?}", r),
+    }
+}
+
+#[test]
+fn arp_tx_survives_route_update() {
+    let source_ip = Ipv4Addr::new(10, 1, 2, 3);
+
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::new(source_ip, 24).unwrap()).unwrap();
+
+    let mut arp_tx = stack.interface(&interface).unwrap().arp_tx();
+    stack.routing_table()
+        .add_route(Ipv4Network::from_cidr("192.168.0.0/24").unwrap(), None, interface.clone());
+    arp_tx.send(source_ip, Ipv4Addr::new(10, 1, 2, 1)).unwrap();
+    let frame = read_handle.recv().unwrap();
+    assert_eq!(EthernetPacket::new(&frame).unwrap().get_ethertype(), EtherTypes::Arp);
+}
+
+#[test]
+fn foreign_source() {
+    let source_ip = Ipv4Addr::new(10, 1, 2, 3);
+    let target_ip = Ipv4Addr::new(10, 1, 2, 2);
+
+    let target_mac = MacAddr::new(9, 0, 0, 4, 0, 0);
+
+    let (mut stack, interface, _, _) = testing::dummy_stack(0);
+    stack.interface(&interface).unwrap().arp_table().insert(target_ip, target_mac);
+    stack.add_ipv4(&interface, Ipv4Network::new(source_ip, 24).unwrap()).unwrap();
+
+    let flow = Flow::new(Some(Ipv4Addr::new(10, 1, 2, 4)), IpNextHeaderProtocols::Udp);
+    match stack.ipv4_tx_flow(target_ip, &flow) {
+        Err(StackError::IllegalArgument) => (),
+        r => panic!("Expected IllegalArgument for a foreign source, got {:?}", r.map(|_| ())),
+    }
+    let flow = Flow::new(Some(source_ip), IpNextHeaderProtocols::Udp);
+    assert!(stack.ipv4_tx_flow(target_ip, &flow).is_ok());
+}
+
 #[test]
 fn custom_igmp_recv() {
     let source_ip = Ipv4Addr::new(10, 1, 2, 3);
@@ -67,6 +127,7 @@ fn custom_igmp_recv() {
         let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
         eth_pkg.set_ethertype(EtherTypes::Ipv4);
         let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
         ip_pkg.set_header_length(5); // 5 is for no option fields
         ip_pkg.set_source(source_ip);
         ip_pkg.set_destination(target_ip);
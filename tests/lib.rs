@@ -19,3 +19,9 @@ mod icmp;
 
 #[cfg(all(test, feature = "integration-tests"))]
 mod udp;
+
+#[cfg(all(test, feature = "integration-tests"))]
+mod rip;
+
+#[cfg(all(test, feature = "integration-tests"))]
+mod fuzz;
@@ -0,0 +1,120 @@
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::Packet;
+use pnet::packet::arp::ArpPacket;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+
+use rips::rip::{RIP_PORT, RipConfig, RipDaemon, RipEntry, RipMessage, rip_multicast_group};
+use rips::testing;
+
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use udp::udp_frame;
+
+#[test]
+fn learn_route() {
+    let neighbor_ip = Ipv4Addr::new(10, 0, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+    let remote_net = Ipv4Network::from_cidr("172.16.0.0/16").unwrap();
+
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.0.0.2/24").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    let daemon = RipDaemon::spawn(stack.clone(), RipConfig::new(vec![local_ip])).unwrap();
+
+    let mut message = RipMessage::response();
+    message.entries.push(RipEntry::new(remote_net.ip(), 16, Ipv4Addr::new(0, 0, 0, 0), 1));
+    let frame = udp_frame(neighbor_ip,
+                          rip_multicast_group(),
+                          RIP_PORT,
+                          RIP_PORT,
+                          &message.to_bytes());
+    inject_handle.send(Ok(frame)).unwrap();
+
+    let mut learned = None;
+    for _ in 0..100 {
+        learned = daemon.routes().into_iter().find(|route| route.net == remote_net);
+        if learned.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let learned = learned.expect("Route not learned");
+    assert_eq!(learned.next_hop, Some(neighbor_ip));
+    assert_eq!(learned.metric, 2);
+
+    let route = stack.lock().unwrap().routing_table().route(Ipv4Addr::new(172, 16, 3, 4));
+    assert_eq!(route, Some((Some(neighbor_ip), interface.clone())));
+
+    daemon.stop();
+    let route = stack.lock().unwrap().routing_table().route(Ipv4Addr::new(172, 16, 3, 4));
+    assert_eq!(route, None);
+}
+
+#[test]
+fn restart() {
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+
+    let (mut stack, interface, _, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.0.0.2/24").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    let daemon = RipDaemon::spawn(stack.clone(), RipConfig::new(vec![local_ip])).unwrap();
+    daemon.stop();
+    // Stopping released the port and left the multicast group
+    let daemon = RipDaemon::spawn(stack.clone(), RipConfig::new(vec![local_ip])).unwrap();
+    drop(daemon);
+    RipDaemon::spawn(stack.clone(), RipConfig::new(vec![local_ip])).unwrap().stop();
+}
+
+#[test]
+fn unresolvable_requester() {
+    let requester_ip = Ipv4Addr::new(10, 0, 0, 7);
+    let neighbor_ip = Ipv4Addr::new(10, 0, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+    let remote_net = Ipv4Network::from_cidr("172.16.0.0/16").unwrap();
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.0.0.2/24").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    let daemon = RipDaemon::spawn(stack.clone(), RipConfig::new(vec![local_ip])).unwrap();
+
+    // The reply to the request is unicast to a requester that never answers
+    // the Arp request for it
+    let request = RipMessage::whole_table_request();
+    let frame = udp_frame(requester_ip, local_ip, RIP_PORT, RIP_PORT, &request.to_bytes());
+    inject_handle.send(Ok(frame)).unwrap();
+    loop {
+        let frame = read_handle.recv_timeout(Duration::from_secs(1)).expect("No Arp request");
+        let eth_pkg = EthernetPacket::new(&frame).unwrap();
+        if eth_pkg.get_ethertype() == EtherTypes::Arp &&
+           ArpPacket::new(eth_pkg.payload()).unwrap().get_target_proto_addr() == requester_ip {
+            break;
+        }
+    }
+
+    // The reply was dropped and the daemon keeps running
+    let mut message = RipMessage::response();
+    message.entries.push(RipEntry::new(remote_net.ip(), 16, Ipv4Addr::new(0, 0, 0, 0), 1));
+    let frame = udp_frame(neighbor_ip,
+                          rip_multicast_group(),
+                          RIP_PORT,
+                          RIP_PORT,
+                          &message.to_bytes());
+    inject_handle.send(Ok(frame)).unwrap();
+    let mut learned = false;
+    for _ in 0..100 {
+        learned = daemon.routes().iter().any(|route| route.net == remote_net);
+        if learned {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(learned);
+    daemon.stop();
+}
@@ -1,16 +1,26 @@
 use ipnetwork::Ipv4Network;
 
-use pnet::packet::MutablePacket;
-use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::icmp::{IcmpCode, IcmpPacket, IcmpTypes};
 use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::ipv4::{MutableIpv4Packet, checksum};
-use pnet::packet::udp::MutableUdpPacket;
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
+use pnet::packet::udp::{MutableUdpPacket, UdpPacket, ipv4_checksum};
+use pnet::util::MacAddr;
 
+use rips::{DEFAULT_DOMAIN, Poll, RxResult, Wake};
+use rips::ethernet::EthernetBuilder;
+use rips::icmp::{BasicIcmpProtocol, IcmpBuilder, IcmpErrorSettings};
+use rips::ipv4::Ipv4Builder;
 use rips::testing;
-use rips::udp::UdpSocket;
+use rips::udp::{DEFAULT_RECV_BUFFER_SIZE, PortSharing, UdpListener, UdpSendMsg, UdpSocket};
 
+use std::io;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 #[test]
 fn socket_listen() {
@@ -28,6 +38,7 @@ fn socket_listen() {
         let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
         eth_pkg.set_ethertype(EtherTypes::Ipv4);
         let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
         ip_pkg.set_header_length(5); // 5 is for no option fields
         ip_pkg.set_total_length(20 + 8 + 4);
         ip_pkg.set_source(source_ip);
@@ -50,3 +61,868 @@ fn socket_listen() {
     assert_eq!(&buffer, &[5, 6, 7, 8]);
 
 }
+
+#[test]
+fn overlapping_domains() {
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let target_ip = Ipv4Addr::new(10, 9, 0, 254);
+    let net = Ipv4Network::from_cidr("10.9.0.254/16").unwrap();
+
+    let (mut stack, interface0, _, _) = testing::dummy_stack(0);
+    let (channel1, interface1, inject_handle1, _) = testing::dummy_ethernet(1);
+    stack.add_interface(interface1.clone(), channel1).unwrap();
+    stack.add_domain("tenant").unwrap();
+    stack.set_interface_domain(&interface1, "tenant").unwrap();
+    stack.add_ipv4(&interface0, net).unwrap();
+    stack.add_ipv4(&interface1, net).unwrap();
+    assert!(stack.add_ipv4(&interface0, net).is_err());
+    let stack = Arc::new(Mutex::new(stack));
+
+    let _default_socket = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let socket = UdpSocket::bind_in(stack.clone(), "tenant", "10.9.0.254:1024").unwrap();
+    assert!(UdpSocket::bind_in(stack, "tenant", "10.9.0.254:1024").is_err());
+
+    inject_handle1.send(Ok(udp_frame(source_ip, target_ip, 9999, 1024, &[1, 2]))).unwrap();
+
+    let mut buffer = vec![0; 2];
+    let (len, from) = socket.recv_from(&mut buffer[..]).unwrap();
+    assert_eq!(from, SocketAddr::V4(SocketAddrV4::new(source_ip, 9999)));
+    assert_eq!(len, 2);
+    assert_eq!(&buffer, &[1, 2]);
+}
+
+#[test]
+fn port_unreachable() {
+    let source_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let target_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(source_ip, source_mac);
+
+    let frame = udp_frame(source_ip, target_ip, 9999, 1024, &[5, 6, 7, 8, 9]);
+    inject_handle.send(Ok(frame.clone())).unwrap();
+
+    let reply = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    let eth_pkg = EthernetPacket::new(&reply[..]).unwrap();
+    assert_eq!(eth_pkg.get_destination(), source_mac);
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(ip_pkg.get_source(), target_ip);
+    assert_eq!(ip_pkg.get_destination(), source_ip);
+    assert_eq!(ip_pkg.get_next_level_protocol(), IpNextHeaderProtocols::Icmp);
+    let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
+    assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+    assert_eq!(icmp_pkg.get_icmp_code(), IcmpCode(3));
+    // The IP header and first 8 bytes of the datagram are quoted
+    assert_eq!(&icmp_pkg.payload()[4..], &frame[14..14 + 28]);
+
+    let mut settings = IcmpErrorSettings::default();
+    settings.enabled = false;
+    stack.set_icmp_error_settings(settings);
+    inject_handle.send(Ok(frame)).unwrap();
+    assert!(read_handle.recv_timeout(Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn take_icmp_error() {
+    let remote_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let remote_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(remote_ip, remote_mac);
+    let stack = Arc::new(Mutex::new(stack));
+
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    let clone = socket.try_clone().unwrap();
+    socket.send_to(&[1, 2, 3], "10.9.0.1:7").unwrap();
+    let sent = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert!(socket.take_error().unwrap().is_none());
+
+    let frame = port_unreachable_frame(remote_mac, interface.mac, remote_ip, local_ip, &sent);
+    inject_handle.send(Ok(frame)).unwrap();
+
+    let mut error = None;
+    for _ in 0..100 {
+        error = clone.take_error().unwrap();
+        if error.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(error.expect("Expected an error").kind(), io::ErrorKind::ConnectionRefused);
+    assert!(socket.take_error().unwrap().is_none());
+}
+
+#[test]
+fn verify_checksum() {
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let target_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let socket = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+
+    let mut corrupt = udp_frame(source_ip, target_ip, 9999, 1024, &[1, 2, 3]);
+    set_udp_checksum(&mut corrupt);
+    corrupt[14 + 20 + 8] = 9;
+    let mut valid = udp_frame(source_ip, target_ip, 9999, 1024, &[4, 5, 6]);
+    set_udp_checksum(&mut valid);
+    // Zero is no checksum and always accepted
+    let unchecked = udp_frame(source_ip, target_ip, 9999, 1024, &[7, 8, 9]);
+    inject_handle.send(Ok(corrupt)).unwrap();
+    inject_handle.send(Ok(valid)).unwrap();
+    inject_handle.send(Ok(unchecked)).unwrap();
+
+    let mut buffer = vec![0; 3];
+    socket.recv_from(&mut buffer[..]).unwrap();
+    assert_eq!(&buffer, &[4, 5, 6]);
+    socket.recv_from(&mut buffer[..]).unwrap();
+    assert_eq!(&buffer, &[7, 8, 9]);
+    assert_eq!(stack.lock().unwrap().udp_checksum_errors(), 1);
+}
+
+#[test]
+fn send_without_checksum() {
+    let remote_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let remote_ip = Ipv4Addr::new(10, 9, 0, 1);
+
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(remote_ip, remote_mac);
+    let stack = Arc::new(Mutex::new(stack));
+
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    socket.send_to(&[1, 2, 3], "10.9.0.1:7").unwrap();
+    let sent = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_ne!(UdpPacket::new(&sent[14 + 20..]).unwrap().get_checksum(), 0);
+
+    socket.set_checksum(false);
+    assert!(!socket.checksum());
+    socket.send_to(&[1, 2, 3], "10.9.0.1:7").unwrap();
+    let sent = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(UdpPacket::new(&sent[14 + 20..]).unwrap().get_checksum(), 0);
+}
+
+#[test]
+fn read_timeout_and_nonblocking() {
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let target_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    let mut buffer = vec![0; 2];
+
+    assert!(socket.set_read_timeout(Some(Duration::new(0, 0))).is_err());
+    socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    assert_eq!(socket.read_timeout().unwrap(), Some(Duration::from_millis(50)));
+    let error = socket.recv_from(&mut buffer[..]).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+    // Shared with clones, like for the standard `UdpSocket`
+    let clone = socket.try_clone().unwrap();
+    assert_eq!(clone.read_timeout().unwrap(), Some(Duration::from_millis(50)));
+    clone.set_nonblocking(true).unwrap();
+    let error = socket.recv_from(&mut buffer[..]).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+
+    inject_handle.send(Ok(udp_frame(source_ip, target_ip, 9999, 1024, &[1, 2]))).unwrap();
+    let mut result = Err(error);
+    for _ in 0..100 {
+        result = socket.recv_from(&mut buffer[..]);
+        if result.is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(result.unwrap().0, 2);
+    assert_eq!(&buffer, &[1, 2]);
+}
+
+#[test]
+fn send_unresolved() {
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+
+    // The Arp request goes out, but nobody answers it
+    socket.set_nonblocking(true).unwrap();
+    let error = socket.send_to(&[1, 2, 3], "10.9.0.1:7").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+    let request = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(EthernetPacket::new(&request).unwrap().get_ethertype(),
+               EtherTypes::Arp);
+
+    socket.set_nonblocking(false).unwrap();
+    socket.set_write_timeout(Some(Duration::from_millis(50))).unwrap();
+    let error = socket.send_to(&[1, 2, 3], "10.9.0.1:7").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn connected_socket() {
+    let remote_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let remote_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let other_ip = Ipv4Addr::new(10, 9, 0, 2);
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(remote_ip, remote_mac);
+    let stack = Arc::new(Mutex::new(stack));
+
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    let mut buffer = vec![0; 2];
+    assert_eq!(socket.peer_addr().unwrap_err().kind(), io::ErrorKind::NotConnected);
+    assert_eq!(socket.send(&[1]).unwrap_err().kind(), io::ErrorKind::NotConnected);
+    assert_eq!(socket.recv(&mut buffer).unwrap_err().kind(),
+               io::ErrorKind::NotConnected);
+
+    socket.connect("10.9.0.1:7").unwrap();
+    let peer = SocketAddrV4::new(remote_ip, 7);
+    assert_eq!(socket.peer_addr().unwrap(), SocketAddr::V4(peer));
+
+    assert_eq!(socket.send(&[1, 2, 3]).unwrap(), 3);
+    let sent = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    let ip_pkg = Ipv4Packet::new(&sent[14..]).unwrap();
+    assert_eq!(ip_pkg.get_destination(), remote_ip);
+    assert_eq!(UdpPacket::new(ip_pkg.payload()).unwrap().get_destination(), 7);
+
+    // Only the datagram from the peer gets through
+    inject_handle.send(Ok(udp_frame(other_ip, local_ip, 7, 1024, &[9, 9]))).unwrap();
+    inject_handle.send(Ok(udp_frame(remote_ip, local_ip, 8, 1024, &[8, 8]))).unwrap();
+    inject_handle.send(Ok(udp_frame(remote_ip, local_ip, 7, 1024, &[4, 5]))).unwrap();
+    assert_eq!(socket.recv(&mut buffer).unwrap(), 2);
+    assert_eq!(&buffer, &[4, 5]);
+}
+
+#[test]
+fn connected_icmp_error() {
+    let remote_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let remote_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(remote_ip, remote_mac);
+    let stack = Arc::new(Mutex::new(stack));
+
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    socket.connect("10.9.0.1:7").unwrap();
+    socket.send(&[1, 2, 3]).unwrap();
+    let sent = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    let frame = port_unreachable_frame(remote_mac, interface.mac, remote_ip, local_ip, &sent);
+    inject_handle.send(Ok(frame)).unwrap();
+
+    let mut result = Ok(0);
+    for _ in 0..100 {
+        result = socket.send(&[1, 2, 3]);
+        if result.is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[test]
+fn connected_icmp_error_wakes_recv() {
+    let remote_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let remote_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(remote_ip, remote_mac);
+    let stack = Arc::new(Mutex::new(stack));
+
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    socket.connect("10.9.0.1:7").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socket.send(&[1, 2, 3]).unwrap();
+    let sent = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+
+    let reader = socket.try_clone().unwrap();
+    let start = Instant::now();
+    let blocked = thread::spawn(move || reader.recv(&mut [0; 8]));
+    // Give the reader time to block before the error arrives
+    thread::sleep(Duration::from_millis(100));
+    let frame = port_unreachable_frame(remote_mac, interface.mac, remote_ip, local_ip, &sent);
+    inject_handle.send(Ok(frame)).unwrap();
+
+    let result = blocked.join().unwrap();
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    // The error is reported once
+    socket.set_nonblocking(true).unwrap();
+    assert_eq!(socket.recv(&mut [0; 8]).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn drop_releases_port() {
+    let (mut stack, interface, _, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    let socket = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let clone = socket.try_clone().unwrap();
+    assert!(UdpSocket::bind(stack.clone(), "10.9.0.254:1024").is_err());
+    drop(clone);
+    assert!(UdpSocket::bind(stack.clone(), "10.9.0.254:1024").is_err());
+    drop(socket);
+    UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+}
+
+#[test]
+fn listener_stops_resuming() {
+    let source_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let target_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(source_ip, source_mac);
+    stack.udp_listen("10.9.0.254:1024", OneShotListener).unwrap();
+
+    // The first datagram is taken, the second finds the port unbound
+    let frame = udp_frame(source_ip, target_ip, 9999, 1024, &[1]);
+    inject_handle.send(Ok(frame.clone())).unwrap();
+    inject_handle.send(Ok(frame)).unwrap();
+    let reply = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    let ip_pkg = Ipv4Packet::new(&reply[14..]).unwrap();
+    let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
+    assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+    assert!(read_handle.recv_timeout(Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn shared_port_fan_out() {
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let target_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    let addr = "10.9.0.254:1024";
+    let socket1 = UdpSocket::bind_shared(stack.clone(), addr, PortSharing::FanOut).unwrap();
+    let socket2 = UdpSocket::bind_shared(stack.clone(), addr, PortSharing::FanOut).unwrap();
+    assert!(UdpSocket::bind(stack.clone(), addr).is_err());
+    assert!(UdpSocket::bind_shared(stack.clone(), addr, PortSharing::LoadBalance).is_err());
+
+    inject_handle.send(Ok(udp_frame(source_ip, target_ip, 9999, 1024, &[1, 2]))).unwrap();
+    for socket in &[socket1, socket2] {
+        let mut buffer = vec![0; 2];
+        assert_eq!(socket.recv_from(&mut buffer[..]).unwrap().0, 2);
+        assert_eq!(&buffer, &[1, 2]);
+    }
+}
+
+#[test]
+fn shared_port_load_balance() {
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let target_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    let addr = "10.9.0.254:1024";
+    let mut sockets = vec![];
+    for _ in 0..2 {
+        let socket = UdpSocket::bind_shared(stack.clone(), addr, PortSharing::LoadBalance)
+            .unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        sockets.push(socket);
+    }
+
+    // Every flow twice, both datagrams must end up on the same socket
+    for _ in 0..2 {
+        for port in 2000..2020 {
+            let frame = udp_frame(source_ip, target_ip, port, 1024, &[1]);
+            inject_handle.send(Ok(frame)).unwrap();
+        }
+    }
+    let mut ports = vec![];
+    for socket in &sockets {
+        let mut socket_ports = vec![];
+        let mut buffer = vec![0; 1];
+        while let Ok((_, SocketAddr::V4(from))) = socket.recv_from(&mut buffer[..]) {
+            socket_ports.push(from.port());
+        }
+        assert!(!socket_ports.is_empty());
+        ports.push(socket_ports);
+    }
+    assert_eq!(ports[0].len() + ports[1].len(), 40);
+    for port in &ports[0] {
+        assert!(!ports[1].contains(port));
+    }
+
+    // The port stays bound until the last socket is gone
+    sockets.pop();
+    assert!(UdpSocket::bind(stack.clone(), addr).is_err());
+    sockets.pop();
+    UdpSocket::bind(stack, addr).unwrap();
+}
+
+#[test]
+fn clones_share_queue() {
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let target_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    let socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let readers = (0..3)
+        .map(|_| {
+            let clone = socket.try_clone().unwrap();
+            thread::spawn(move || {
+                let mut received = vec![];
+                let mut buffer = vec![0; 1];
+                while clone.recv_from(&mut buffer[..]).is_ok() {
+                    received.push(buffer[0]);
+                }
+                received
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(socket);
+
+    for i in 0..30 {
+        inject_handle.send(Ok(udp_frame(source_ip, target_ip, 9999, 1024, &[i]))).unwrap();
+    }
+    let mut received = readers.into_iter()
+        .flat_map(|reader| reader.join().unwrap())
+        .collect::<Vec<_>>();
+    received.sort();
+    assert_eq!(received, (0..30).collect::<Vec<_>>());
+}
+
+#[test]
+fn recv_msg() {
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let group = Ipv4Addr::new(239, 1, 2, 3);
+
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    socket.join_multicast_v4(&group, &Ipv4Addr::new(0, 0, 0, 0)).unwrap();
+
+    let mut frame = udp_frame(source_ip, group, 9999, 1024, &[1, 2, 3]);
+    {
+        let mut ip_pkg = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+        ip_pkg.set_ttl(17);
+        ip_pkg.set_dscp(46);
+        ip_pkg.set_ecn(1);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+    }
+    let before = SystemTime::now();
+    inject_handle.send(Ok(frame)).unwrap();
+
+    let mut buffer = vec![0; 3];
+    let msg = socket.recv_msg(&mut buffer[..]).unwrap();
+    assert_eq!(msg.len, 3);
+    assert_eq!(&buffer, &[1, 2, 3]);
+    assert_eq!(msg.src, SocketAddr::V4(SocketAddrV4::new(source_ip, 9999)));
+    assert_eq!(msg.dst, group);
+    assert!(msg.timestamp >= before);
+    assert_eq!(msg.ttl, 17);
+    assert_eq!(msg.tos, (46 << 2) | 1);
+    assert_eq!(msg.interface, interface);
+}
+
+#[test]
+fn multicast_memberships() {
+    let source_ip = Ipv4Addr::new(10, 9, 0, 9);
+    let group = Ipv4Addr::new(239, 1, 2, 3);
+    let timeout = Some(Duration::from_millis(100));
+
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.1/16").unwrap()).unwrap();
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.2/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let socket1 = UdpSocket::bind(stack.clone(), "10.9.0.1:1024").unwrap();
+    let socket2 = UdpSocket::bind(stack.clone(), "10.9.0.2:1024").unwrap();
+    let other1 = UdpSocket::bind(stack.clone(), "10.9.0.1:1025").unwrap();
+    socket1.set_read_timeout(timeout).unwrap();
+    socket2.set_read_timeout(timeout).unwrap();
+    let any = Ipv4Addr::new(0, 0, 0, 0);
+
+    // Both addresses joined, so the group goes to the sockets of both
+    socket1.join_multicast_v4(&group, &any).unwrap();
+    socket2.join_multicast_v4(&group, &any).unwrap();
+    other1.join_multicast_v4(&group, &any).unwrap();
+    inject_handle.send(Ok(udp_frame(source_ip, group, 9999, 1024, &[1]))).unwrap();
+    let mut buffer = [0; 1];
+    assert_eq!(socket1.recv_from(&mut buffer).unwrap().0, 1);
+    assert_eq!(socket2.recv_from(&mut buffer).unwrap().0, 1);
+
+    // Leaving only affects the address left with
+    socket2.leave_multicast_v4(&group, &any).unwrap();
+    inject_handle.send(Ok(udp_frame(source_ip, group, 9999, 1024, &[2]))).unwrap();
+    assert_eq!(socket1.recv_from(&mut buffer).unwrap().0, 1);
+    assert_eq!(buffer, [2]);
+    assert!(socket2.recv_from(&mut buffer).is_err());
+
+    // 10.9.0.1 joined twice and stays joined until both left
+    socket1.leave_multicast_v4(&group, &any).unwrap();
+    inject_handle.send(Ok(udp_frame(source_ip, group, 9999, 1024, &[3]))).unwrap();
+    assert_eq!(socket1.recv_from(&mut buffer).unwrap().0, 1);
+    assert_eq!(buffer, [3]);
+    other1.leave_multicast_v4(&group, &any).unwrap();
+    inject_handle.send(Ok(udp_frame(source_ip, group, 9999, 1024, &[4]))).unwrap();
+    assert!(socket1.recv_from(&mut buffer).is_err());
+    assert!(other1.leave_multicast_v4(&group, &any).is_err());
+}
+
+#[test]
+fn send_msg() {
+    let remote_ip = Ipv4Addr::new(10, 9, 0, 1);
+
+    let (mut stack, interface0, _, read_handle0) = testing::dummy_stack(0);
+    let (channel1, interface1, _, read_handle1) = testing::dummy_ethernet(1);
+    stack.add_interface(interface1.clone(), channel1).unwrap();
+    stack.add_ipv4(&interface0, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    stack.add_ipv4(&interface1, Ipv4Network::from_cidr("10.10.0.254/16").unwrap()).unwrap();
+    let (mac0, mac1) = (MacAddr::new(1, 1, 1, 1, 1, 1), MacAddr::new(2, 2, 2, 2, 2, 2));
+    stack.interface(&interface0).unwrap().arp_table().insert(remote_ip, mac0);
+    stack.interface(&interface1).unwrap().arp_table().insert(remote_ip, mac1);
+    let stack = Arc::new(Mutex::new(stack));
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+
+    // Out on the second interface from its address, though routed via the first
+    let mut msg = UdpSendMsg::default();
+    msg.src = Some(Ipv4Addr::new(10, 10, 0, 254));
+    msg.interface = Some(interface1.clone());
+    assert_eq!(socket.send_msg(&[1, 2], "10.9.0.1:7", &msg).unwrap(), 2);
+    let sent = read_handle1.recv_timeout(Duration::from_secs(1)).unwrap();
+    let eth_pkg = EthernetPacket::new(&sent).unwrap();
+    assert_eq!(eth_pkg.get_destination(), mac1);
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(ip_pkg.get_source(), Ipv4Addr::new(10, 10, 0, 254));
+    assert_eq!(ip_pkg.get_destination(), remote_ip);
+    assert!(read_handle0.try_recv().is_err());
+
+    msg.src = Some(Ipv4Addr::new(10, 11, 0, 1));
+    let error = socket.send_msg(&[1, 2], "10.9.0.1:7", &msg).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::AddrNotAvailable);
+
+    // Without options it's a plain send_to
+    socket.send_msg(&[1, 2], "10.9.0.1:7", &UdpSendMsg::default()).unwrap();
+    let sent = read_handle0.recv_timeout(Duration::from_secs(1)).unwrap();
+    let ip_pkg = Ipv4Packet::new(&sent[14..]).unwrap();
+    assert_eq!(ip_pkg.get_source(), Ipv4Addr::new(10, 9, 0, 254));
+}
+
+#[test]
+fn truncate_and_peek() {
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let target_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+
+    let frame = udp_frame(source_ip, target_ip, 9999, 1024, &[1, 2, 3, 4, 5]);
+    inject_handle.send(Ok(frame)).unwrap();
+    inject_handle.send(Ok(udp_frame(source_ip, target_ip, 9999, 1024, &[6, 7, 8]))).unwrap();
+    let from = SocketAddr::V4(SocketAddrV4::new(source_ip, 9999));
+
+    let mut buffer = vec![0; 2];
+    assert_eq!(socket.peek_from(&mut buffer[..]).unwrap(), (2, from));
+    assert_eq!(&buffer, &[1, 2]);
+    let mut buffer = vec![0; 8];
+    assert_eq!(socket.peek_from(&mut buffer[..]).unwrap(), (5, from));
+    let mut buffer = vec![0; 3];
+    let msg = socket.recv_msg(&mut buffer[..]).unwrap();
+    assert_eq!((msg.len, msg.datagram_len), (3, 5));
+    assert_eq!(&buffer, &[1, 2, 3]);
+
+    // The rest of the truncated datagram is gone
+    let mut head = vec![0; 1];
+    let mut tail = vec![0; 4];
+    let (len, _) = socket.recv_from_vectored(&mut [&mut head[..], &mut tail[..]]).unwrap();
+    assert_eq!(len, 3);
+    assert_eq!((&head[..], &tail[..]), (&[6][..], &[7, 8, 0, 0][..]));
+}
+
+#[test]
+fn send_vectored() {
+    let remote_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    let remote_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    stack.interface(&interface).unwrap().arp_table().insert(remote_ip, remote_mac);
+    let stack = Arc::new(Mutex::new(stack));
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+
+    // Odd lengths, so the checksum has to carry bytes between the parts
+    let bufs: [&[u8]; 4] = [&[1, 2, 3], &[], &[4], &[5, 6, 7, 8]];
+    let len = socket.send_to_vectored(&bufs, "10.9.0.1:7").unwrap();
+    assert_eq!(len, 8);
+    let sent = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    let udp_pkg = UdpPacket::new(&sent[14 + 20..]).unwrap();
+    assert_eq!(udp_pkg.payload(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(udp_pkg.get_checksum(), ipv4_checksum(&udp_pkg, local_ip, remote_ip));
+}
+
+#[test]
+fn recv_buffer_full() {
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let target_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    assert_eq!(socket.recv_buffer_size().unwrap(), DEFAULT_RECV_BUFFER_SIZE);
+    socket.set_recv_buffer_size(100).unwrap();
+
+    // IP packets of 68, 68 and 32 bytes. Only the second does not fit.
+    for payload in &[[1; 40], [2; 40]] {
+        inject_handle.send(Ok(udp_frame(source_ip, target_ip, 9999, 1024, payload))).unwrap();
+    }
+    inject_handle.send(Ok(udp_frame(source_ip, target_ip, 9999, 1024, &[3; 4]))).unwrap();
+
+    let mut buffer = vec![0; 64];
+    assert_eq!(socket.recv_from(&mut buffer[..]).unwrap().0, 40);
+    assert_eq!(buffer[0], 1);
+    assert_eq!(socket.recv_from(&mut buffer[..]).unwrap().0, 4);
+    assert_eq!(buffer[0], 3);
+
+    // Frames are handled in order, so the drop is counted once this arrives
+    inject_handle.send(Ok(udp_frame(source_ip, target_ip, 9999, 1024, &[4; 40]))).unwrap();
+    assert_eq!(socket.recv_from(&mut buffer[..]).unwrap().0, 40);
+    assert_eq!(buffer[0], 4);
+    assert_eq!(socket.dropped_datagrams(), 1);
+    assert_eq!(socket.try_clone().unwrap().dropped_datagrams(), 1);
+}
+
+#[test]
+fn ephemeral_ports() {
+    let ip0 = Ipv4Addr::new(10, 9, 0, 254);
+    let ip1 = Ipv4Addr::new(10, 10, 0, 254);
+
+    let (mut stack, interface, _, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.10.0.254/16").unwrap()).unwrap();
+    let mut settings = stack.port_allocator_settings();
+    settings.first_port = 40000;
+    settings.last_port = 40003;
+    settings.selection = PortSelection::HashBased;
+    settings.reserved.insert(40001);
+    stack.set_port_allocator_settings(settings).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    let _taken0 = UdpSocket::bind(stack.clone(), "10.9.0.254:40000").unwrap();
+    let _taken1 = UdpSocket::bind(stack.clone(), "10.10.0.254:40002").unwrap();
+    // The only port free on both addresses
+    let port = stack.lock().unwrap().udp_allocate_port_in(DEFAULT_DOMAIN, &[ip0, ip1], None);
+    let port = port.unwrap();
+    assert_eq!(port, 40003);
+    // Giving an address twice is the same as giving it once
+    let port = stack.lock().unwrap().udp_allocate_port_in(DEFAULT_DOMAIN, &[ip0, ip1, ip0], None);
+    assert_eq!(port.unwrap(), 40003);
+    let _shared0 = UdpSocket::bind(stack.clone(), SocketAddrV4::new(ip0, port)).unwrap();
+    let _shared1 = UdpSocket::bind(stack.clone(), SocketAddrV4::new(ip1, port)).unwrap();
+
+    let socket = UdpSocket::bind(stack.clone(), "10.9.0.254:0").unwrap();
+    assert_eq!(socket.local_addr().unwrap().port(), 40002);
+    let error = UdpSocket::bind(stack.clone(), "10.9.0.254:0").err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+    // Reserved ports can still be bound explicitly
+    UdpSocket::bind(stack.clone(), "10.9.0.254:40001").unwrap();
+    drop(socket);
+    let socket = UdpSocket::bind(stack, "10.9.0.254:0").unwrap();
+    assert_eq!(socket.local_addr().unwrap().port(), 40002);
+}
+
+#[test]
+fn hash_based_bind_not_sequential() {
+    let (mut stack, interface, _, _) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    let mut settings = stack.port_allocator_settings();
+    settings.selection = PortSelection::HashBased;
+    stack.set_port_allocator_settings(settings).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    // No destination is known when binding, so the ports are random
+    let sockets = (0..10)
+        .map(|_| UdpSocket::bind(stack.clone(), "10.9.0.254:0").unwrap())
+        .collect::<Vec<_>>();
+    let ports = sockets.iter()
+        .map(|socket| socket.local_addr().unwrap().port())
+        .collect::<Vec<_>>();
+    let sequential = ports.windows(2).filter(|pair| pair[1] == pair[0] + 1).count();
+    assert!(sequential < 9, "Sequential ports {:?}", ports);
+}
+
+#[test]
+fn send_broadcast() {
+    let gw = Ipv4Addr::new(10, 9, 0, 1);
+    let broadcast_mac = MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+
+    let (mut stack, interface0, _, read_handle0) = testing::dummy_stack(0);
+    let (channel1, interface1, _, read_handle1) = testing::dummy_ethernet(1);
+    stack.add_interface(interface1.clone(), channel1).unwrap();
+    stack.add_ipv4(&interface0, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    stack.add_ipv4(&interface1, Ipv4Network::from_cidr("10.10.0.254/16").unwrap()).unwrap();
+    stack.interface(&interface0).unwrap().arp_table().insert(gw, MacAddr::new(1, 1, 1, 1, 1, 1));
+    stack.routing_table()
+        .add_route(Ipv4Network::from_cidr("0/0").unwrap(), Some(gw), interface0.clone());
+    let stack = Arc::new(Mutex::new(stack));
+    let mut socket = UdpSocket::bind(stack, "10.10.0.254:68").unwrap();
+
+    assert!(!socket.broadcast().unwrap());
+    for dst in &["255.255.255.255:67", "10.10.255.255:67"] {
+        let error = socket.send_to(&[1], dst).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    // Both go out the interface of the bound address, without Arp, though
+    // the default route points elsewhere
+    socket.set_broadcast(true).unwrap();
+    for dst in &[Ipv4Addr::new(255, 255, 255, 255), Ipv4Addr::new(10, 10, 255, 255)] {
+        socket.send_to(&[1], SocketAddrV4::new(*dst, 67)).unwrap();
+        let sent = read_handle1.recv_timeout(Duration::from_secs(1)).unwrap();
+        let eth_pkg = EthernetPacket::new(&sent).unwrap();
+        assert_eq!(eth_pkg.get_destination(), broadcast_mac);
+        assert_eq!(eth_pkg.get_ethertype(), EtherTypes::Ipv4);
+        let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+        assert_eq!(ip_pkg.get_source(), Ipv4Addr::new(10, 10, 0, 254));
+        assert_eq!(ip_pkg.get_destination(), *dst);
+    }
+    assert!(read_handle0.try_recv().is_err());
+}
+
+#[test]
+fn poll_socket() {
+    let remote_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack(0);
+    stack.add_ipv4(&interface, Ipv4Network::from_cidr("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let mut socket = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let woken = Arc::new(AtomicUsize::new(0));
+    let waker = {
+        let woken = woken.clone();
+        Arc::new(move || {
+            woken.fetch_add(1, Ordering::SeqCst);
+        }) as Arc<Wake>
+    };
+
+    let mut buffer = vec![0; 4];
+    assert!(!socket.poll_recv_from(&mut buffer, waker.clone()).is_ready());
+    inject_handle.send(Ok(udp_frame(remote_ip, local_ip, 7, 1024, &[1, 2, 3]))).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while woken.load(Ordering::SeqCst) == 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(woken.load(Ordering::SeqCst), 1);
+    match socket.poll_recv_from(&mut buffer, waker.clone()) {
+        Poll::Ready(result) => {
+            assert_eq!(result.unwrap(), (3, SocketAddr::V4(SocketAddrV4::new(remote_ip, 7))))
+        }
+        Poll::Pending => panic!("Expected a datagram"),
+    }
+
+    // Pending until the Arp reply is in
+    assert!(!socket.poll_send_to(&[4, 5], "10.9.0.1:7", waker.clone()).is_ready());
+    let request = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(EthernetPacket::new(&request).unwrap().get_ethertype(),
+               EtherTypes::Arp);
+    assert_eq!(woken.load(Ordering::SeqCst), 1);
+    let remote_mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+    {
+        let mut stack = stack.lock().unwrap();
+        stack.interface(&interface).unwrap().arp_table().insert(remote_ip, remote_mac);
+    }
+    assert_eq!(woken.load(Ordering::SeqCst), 2);
+    match socket.poll_send_to(&[4, 5], "10.9.0.1:7", waker) {
+        Poll::Ready(result) => assert_eq!(result.unwrap(), 2),
+        Poll::Pending => panic!("Expected the datagram to be sent"),
+    }
+    let sent = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(EthernetPacket::new(&sent).unwrap().get_destination(), remote_mac);
+}
+
+pub fn udp_frame(source_ip: Ipv4Addr,
+                 target_ip: Ipv4Addr,
+                 src_port: u16,
+                 dst_port: u16,
+                 payload: &[u8])
+                 -> Box<[u8]> {
+    let mut buffer = vec![0; 14 + 20 + 8 + payload.len()];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5); // 5 is for no option fields
+        ip_pkg.set_total_length((20 + 8 + payload.len()) as u16);
+        ip_pkg.set_source(source_ip);
+        ip_pkg.set_destination(target_ip);
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+        let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+        udp_pkg.set_source(src_port);
+        udp_pkg.set_destination(dst_port);
+        udp_pkg.set_length((8 + payload.len()) as u16);
+        udp_pkg.set_payload(payload);
+    }
+    buffer.into_boxed_slice()
+}
+
+/// Computes and sets the Udp checksum of a frame from `udp_frame`.
+/// Builds the Port Unreachable `remote_ip` sends about the datagram in the
+/// frame `sent`, quoting its IP header and Udp header.
+fn port_unreachable_frame(remote_mac: MacAddr,
+                          local_mac: MacAddr,
+                          remote_ip: Ipv4Addr,
+                          local_ip: Ipv4Addr,
+                          sent: &[u8])
+                          -> Box<[u8]> {
+    let mut payload = vec![0; 4];
+    payload.extend_from_slice(&sent[14..14 + 28]);
+    let icmp_builder = IcmpBuilder::new(BasicIcmpProtocol::new(IcmpTypes::DestinationUnreachable,
+                                                               IcmpCode(3),
+                                                               payload));
+    let ipv4_builder = Ipv4Builder::new(remote_ip, local_ip, 0, icmp_builder);
+    let mut eth_builder = EthernetBuilder::new(remote_mac, local_mac, ipv4_builder);
+    let mut frame = vec![0; eth_builder.len()];
+    eth_builder.build(MutableEthernetPacket::new(&mut frame).unwrap());
+    frame.into_boxed_slice()
+}
+
+fn set_udp_checksum(frame: &mut [u8]) {
+    let mut ip_pkg = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+    let (src, dst) = (ip_pkg.get_source(), ip_pkg.get_destination());
+    let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+    let csum = ipv4_checksum(&udp_pkg.to_immutable(), src, dst);
+    udp_pkg.set_checksum(csum);
+}
+
+/// Wants nothing more after the first datagram.
+struct OneShotListener;
+
+impl UdpListener for OneShotListener {
+    fn recv(&mut self, _time: SystemTime, _packet: &Ipv4Packet) -> (RxResult, bool) {
+        (Ok(()), false)
+    }
+}